@@ -0,0 +1,184 @@
+use crate::repositories::crates::git::IndexRepository;
+use actix_web::dev::HttpServiceFactory;
+use actix_web::error::{ErrorNotFound, ErrorUnauthorized};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use std::collections::HashMap;
+
+/// Path of the admin API, which no repository can be named after
+pub const SCOPE: &str = "admin";
+
+pub struct AdminState {
+    token: String,
+    indexes: HashMap<String, IndexRepository>,
+}
+
+impl AdminState {
+    fn authorize(&self, req: &HttpRequest) -> Result<(), actix_web::Error> {
+        let provided = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        if constant_time_eq(provided.as_bytes(), self.token.as_bytes()) {
+            Ok(())
+        } else {
+            Err(ErrorUnauthorized("Invalid or missing bearer token"))
+        }
+    }
+
+    fn index(&self, req: &HttpRequest, scope: &str) -> Result<&IndexRepository, actix_web::Error> {
+        self.authorize(req)?;
+        self.indexes
+            .get(scope)
+            .ok_or_else(|| ErrorNotFound(format!("No crates index for {scope}")))
+    }
+}
+
+/// Compare without short-circuiting, so the token cannot be guessed from response times.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[get("/indexes")]
+async fn list_indexes(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    state.authorize(&req)?;
+    let indexes = state.indexes.clone();
+    let statuses = web::block(move || {
+        indexes
+            .into_iter()
+            .map(|(scope, index)| (scope, index.status()))
+            .collect::<HashMap<_, _>>()
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(statuses))
+}
+
+#[get("/indexes/{scope}")]
+async fn index_status(
+    req: HttpRequest,
+    scope: web::Path<String>,
+    state: web::Data<AdminState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let index = state.index(&req, &scope)?.clone();
+    let status = web::block(move || index.status()).await?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
+#[post("/indexes/{scope}/update")]
+async fn update(
+    req: HttpRequest,
+    scope: web::Path<String>,
+    state: web::Data<AdminState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if state.index(&req, &scope)?.trigger_update() {
+        Ok(HttpResponse::Accepted().finish())
+    } else {
+        Ok(HttpResponse::Conflict().body("An update is already running or the index is offline"))
+    }
+}
+
+#[post("/indexes/{scope}/reset")]
+async fn reset(
+    req: HttpRequest,
+    scope: web::Path<String>,
+    state: web::Data<AdminState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if state.index(&req, &scope)?.reset() {
+        Ok(HttpResponse::Accepted().finish())
+    } else {
+        Ok(HttpResponse::Conflict().body("An update is already running or the index is offline"))
+    }
+}
+
+pub fn service(
+    token: String,
+    indexes: HashMap<String, IndexRepository>,
+) -> impl HttpServiceFactory {
+    web::scope(&format!("/{SCOPE}"))
+        .app_data(web::Data::new(AdminState { token, indexes }))
+        .service(list_indexes)
+        .service(index_status)
+        .service(update)
+        .service(reset)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::crates::git::test::{begin_update, finish_update, mirror};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use serde_json::Value;
+
+    #[actix_web::test]
+    async fn admin_api() {
+        let (index_repository, upstream) = mirror("admin", false).await;
+        let indexes = HashMap::from([(String::from("crates"), index_repository.clone())]);
+        let app =
+            init_service(actix_web::App::new().service(service(String::from("s3cr3t"), indexes)))
+                .await;
+        let get = |uri: &str, token: Option<&str>| {
+            let request = TestRequest::get().uri(uri);
+            match token {
+                Some(token) => request.insert_header((AUTHORIZATION, format!("Bearer {token}"))),
+                None => request,
+            }
+            .to_request()
+        };
+        let post = |uri: &str| {
+            TestRequest::post()
+                .uri(uri)
+                .insert_header((AUTHORIZATION, "Bearer s3cr3t"))
+                .to_request()
+        };
+
+        for token in [None, Some("secret"), Some("s3cr3t ")] {
+            let response = call_service(&app, get("/admin/indexes", token)).await;
+            assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        }
+        let response = call_service(&app, get("/admin/indexes", Some("s3cr3t"))).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let statuses: Value = read_body_json(response).await;
+        assert_eq!(
+            Some(index_repository.get_repo().as_str()),
+            statuses.pointer("/crates/upstream").and_then(Value::as_str)
+        );
+
+        let response = call_service(&app, get("/admin/indexes/crates", Some("s3cr3t"))).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let status: Value = read_body_json(response).await;
+        let upstream_head = upstream.head().unwrap().target().unwrap().to_string();
+        assert_eq!(Some(upstream_head.as_str()), status["tagged"].as_str());
+        assert_eq!(Some(upstream_head.as_str()), status["remote_head"].as_str());
+        assert_eq!(Some(false), status["updating"].as_bool());
+        assert!(status["last_success"].is_u64());
+        assert!(status["last_error"].is_null());
+        let response = call_service(&app, get("/admin/indexes/npm", Some("s3cr3t"))).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        assert!(begin_update(&index_repository));
+        for action in ["update", "reset"] {
+            let response =
+                call_service(&app, post(&format!("/admin/indexes/crates/{action}"))).await;
+            assert_eq!(StatusCode::CONFLICT, response.status());
+        }
+        finish_update(&index_repository);
+        let response = call_service(&app, post("/admin/indexes/crates/update")).await;
+        assert_eq!(StatusCode::ACCEPTED, response.status());
+        let response = call_service(&app, post("/admin/indexes/npm/update")).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        let response = call_service(
+            &app,
+            TestRequest::post()
+                .uri("/admin/indexes/crates/reset")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    }
+}
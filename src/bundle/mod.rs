@@ -0,0 +1,604 @@
+/*
+ Bundle module used to carry the local cache across an air gap.
+
+ A bundle is a gzipped tarball holding the crates index mirrors and the cached artifacts, along
+ with the policy verdicts recorded when they were cached. The manifest written at the end of the
+ tarball lists the sha256 of every file and is signed, so an offline proxy only imports content
+ exported by a trusted instance. Artifacts which were only let through by a `warn` decision are
+ never exported.
+*/
+
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Formatter},
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sigstore::crypto::{
+    signing_key::{ecdsa::ECDSAKeys, SigStoreSigner},
+    CosignVerificationKey, Signature,
+};
+
+use crate::config::repositories::RepositoryType;
+use crate::config::Config;
+use crate::errors::{Error, Result};
+use crate::policy::Outcome;
+use crate::proxy::Proxy;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const SIGNATURE_FILE: &str = "manifest.json.sig";
+const INDEX_DIR: &str = "index";
+const ARTIFACTS_DIR: &str = "artifacts";
+const BUNDLE_VERSION: u32 = 1;
+
+/// Environment variable holding the password of an encrypted signing key
+pub const KEY_PASSWORD_ENV: &str = "SEEDWING_KEY_PASSWORD";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    version: u32,
+    /// Seconds since the unix epoch
+    created: u64,
+    repositories: BTreeMap<String, RepositoryType>,
+    /// sha256 of every file in the bundle, keyed by path
+    files: BTreeMap<String, String>,
+}
+
+#[derive(Default, Debug)]
+pub struct Summary {
+    indexes: usize,
+    artifacts: usize,
+    skipped: usize,
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} index mirrors, {} artifacts, {} skipped",
+            self.indexes, self.artifacts, self.skipped
+        )
+    }
+}
+
+/// Computes the sha256 of everything read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    fn digest(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.hasher.update(&buf[..count]);
+        Ok(count)
+    }
+}
+
+struct BundleWriter<W: Write> {
+    builder: tar::Builder<W>,
+    files: BTreeMap<String, String>,
+    mtime: u64,
+}
+
+impl<W: Write> BundleWriter<W> {
+    fn header(&self, size: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(self.mtime);
+        header
+    }
+
+    fn append_file(&mut self, path: String, file: &Path) -> Result<()> {
+        let source = File::open(file)?;
+        let mut header = self.header(source.metadata()?.len());
+        let mut reader = HashingReader::new(source);
+        self.builder.append_data(&mut header, &path, &mut reader)?;
+        self.files.insert(path, reader.digest());
+        Ok(())
+    }
+
+    fn append_bytes(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        let mut header = self.header(data.len() as u64);
+        self.builder.append_data(&mut header, path, data)?;
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+fn bundle_path(prefix: &str, scope: &str, relative: &Path) -> String {
+    let mut path = format!("{prefix}/{scope}");
+    for component in relative.components() {
+        path.push('/');
+        path.push_str(&component.as_os_str().to_string_lossy());
+    }
+    path
+}
+
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+fn load_signer(key: &Path) -> Result<SigStoreSigner> {
+    let pem = fs::read(key)?;
+    let keys = match std::env::var(KEY_PASSWORD_ENV) {
+        Ok(password) => ECDSAKeys::from_encrypted_pem(&pem, password.as_bytes())?,
+        Err(_) => ECDSAKeys::from_pem(&pem)?,
+    };
+    Ok(keys.to_sigstore_signer()?)
+}
+
+fn write_bundle(
+    proxy: &Proxy,
+    bundle: File,
+    signer: &SigStoreSigner,
+    scopes: &[String],
+) -> Result<Summary> {
+    let mut writer = BundleWriter {
+        builder: tar::Builder::new(GzEncoder::new(bundle, Compression::default())),
+        files: BTreeMap::new(),
+        mtime: now(),
+    };
+    let mut repositories = BTreeMap::new();
+    let mut summary = Summary::default();
+
+    for (scope, repository) in proxy.config().repositories().iter() {
+        if !scopes.is_empty() && !scopes.contains(scope) {
+            continue;
+        }
+        repositories.insert(scope.clone(), repository.repository_type());
+
+        if let Some(index_repository) = proxy.index_repository(scope) {
+            match index_repository.lock_mirror()? {
+                Some((_lock, git_repository_dir)) => {
+                    let mut files = Vec::new();
+                    walk(&git_repository_dir, &mut files)?;
+                    for file in files {
+                        let relative = file.strip_prefix(&git_repository_dir).unwrap();
+                        writer.append_file(bundle_path(INDEX_DIR, scope, relative), &file)?;
+                    }
+                    summary.indexes += 1;
+                }
+                None => log::warn!("No index mirror has been created for {scope}"),
+            }
+        }
+
+        let cache = proxy.artifact_cache(scope);
+        for (entry, data_file, entry_file) in cache.entries()? {
+            if entry.verdict().map(|verdict| verdict.outcome()) == Some(Outcome::Warned) {
+                log::warn!("Skipping {} which was not approved by policy", entry.key());
+                summary.skipped += 1;
+                continue;
+            }
+            for file in [data_file, entry_file] {
+                let relative = file.strip_prefix(cache.get_root()).unwrap();
+                writer.append_file(bundle_path(ARTIFACTS_DIR, scope, relative), &file)?;
+            }
+            summary.artifacts += 1;
+        }
+    }
+
+    let manifest = Manifest {
+        version: BUNDLE_VERSION,
+        created: writer.mtime,
+        repositories,
+        files: std::mem::take(&mut writer.files),
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest).map_err(io::Error::from)?;
+    let signature = signer.sign(&manifest)?;
+    writer.append_bytes(MANIFEST_FILE, &manifest)?;
+    writer.append_bytes(SIGNATURE_FILE, &signature)?;
+    writer.builder.into_inner()?.finish()?;
+    Ok(summary)
+}
+
+/// Export the index mirrors and approved artifacts of the given scopes, or of every repository
+/// when none are given, into a bundle signed with the ECDSA private key.
+pub fn export(config: Config, bundle: &Path, key: &Path, scopes: &[String]) -> Result<Summary> {
+    let signer = load_signer(key)?;
+    let mut proxy = Proxy::new(config);
+    proxy.init_repositories();
+
+    let result = write_bundle(&proxy, File::create(bundle)?, &signer, scopes);
+    if result.is_err() {
+        // Never leave a partial bundle behind
+        let _ = fs::remove_file(bundle);
+    }
+    result
+}
+
+/// Extract a bundle into the staging directory, verifying the manifest signature and the
+/// digest of every file.
+fn extract(bundle: &Path, staging: &Path, key: &CosignVerificationKey) -> Result<Manifest> {
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(bundle)?));
+    let mut files = BTreeMap::new();
+    let mut manifest = None;
+    let mut signature = None;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let name = path.to_string_lossy().into_owned();
+        if name == MANIFEST_FILE || name == SIGNATURE_FILE {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            if name == MANIFEST_FILE {
+                manifest = Some(data);
+            } else {
+                signature = Some(data);
+            }
+            continue;
+        }
+        if !entry.header().entry_type().is_file()
+            || !path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(Error::Other {
+                message: format!("Unexpected entry {name} in bundle"),
+            });
+        }
+        let target = staging.join(&path);
+        fs::create_dir_all(target.parent().unwrap())?;
+        let mut reader = HashingReader::new(entry);
+        io::copy(&mut reader, &mut File::create(&target)?)?;
+        files.insert(name, reader.digest());
+    }
+
+    let (Some(manifest), Some(signature)) = (manifest, signature) else {
+        return Err(Error::Other {
+            message: String::from("Bundle does not contain a signed manifest"),
+        });
+    };
+    key.verify_signature(Signature::Raw(&signature), &manifest)
+        .map_err(|_| Error::Other {
+            message: String::from("Bundle manifest signature does not match the public key"),
+        })?;
+    let manifest: Manifest = serde_json::from_slice(&manifest).map_err(io::Error::from)?;
+    if manifest.version != BUNDLE_VERSION {
+        return Err(Error::Other {
+            message: format!("Unsupported bundle version {}", manifest.version),
+        });
+    }
+    if manifest.files != files {
+        return Err(Error::Other {
+            message: String::from("Bundle contents do not match the signed manifest"),
+        });
+    }
+    Ok(manifest)
+}
+
+fn install(proxy: &Proxy, staging: &Path, manifest: &Manifest) -> Result<Summary> {
+    let mut summary = Summary::default();
+    for (scope, repository_type) in &manifest.repositories {
+        match proxy.config().repositories().get(scope) {
+            Some(repository) if repository.repository_type() == *repository_type => {}
+            _ => {
+                log::warn!("Skipping {scope}, no {repository_type} repository is configured");
+                summary.skipped += 1;
+                continue;
+            }
+        }
+
+        let staged_index = staging.join(INDEX_DIR).join(scope);
+        if let Some(index_repository) = proxy.index_repository(scope) {
+            if staged_index.exists() {
+                log::info!("Importing index mirror for {scope}");
+                index_repository.import_mirror(&staged_index)?;
+                summary.indexes += 1;
+            }
+        }
+
+        let staged_artifacts = staging.join(ARTIFACTS_DIR).join(scope);
+        if staged_artifacts.exists() {
+            let root = proxy.artifact_cache(scope).get_root().clone();
+            let mut files = Vec::new();
+            walk(&staged_artifacts, &mut files)?;
+            // Data files sort before their entries, so a partially imported artifact is never served
+            files.sort();
+            for file in &files {
+                let target = root.join(file.strip_prefix(&staged_artifacts).unwrap());
+                fs::create_dir_all(target.parent().unwrap())?;
+                fs::rename(file, target)?;
+            }
+            log::info!("Imported {} artifacts for {scope}", files.len() / 2);
+            summary.artifacts += files.len() / 2;
+        }
+    }
+    Ok(summary)
+}
+
+/// Import a bundle exported by another proxy, after verifying it was signed by the holder of
+/// the private key matching the public key.
+pub fn import(config: Config, bundle: &Path, public_key: &Path) -> Result<Summary> {
+    let key = CosignVerificationKey::try_from_pem(&fs::read(public_key)?)?;
+    let mut proxy = Proxy::new(config);
+    proxy.init_repositories();
+
+    let staging = proxy
+        .config()
+        .proxy()
+        .expanded_cache_dir()
+        .join(format!(".import-{}", std::process::id()));
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+
+    let result =
+        extract(bundle, &staging, &key).and_then(|manifest| install(&proxy, &staging, &manifest));
+    if let Err(error) = fs::remove_dir_all(&staging) {
+        log::warn!("Unable to remove {}: {error}", staging.display());
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::policy::context::Context;
+    use crate::repositories::crates::git::test::commit;
+    use bytes::Bytes;
+    use git2::Repository;
+    use sigstore::crypto::SigningScheme;
+    use url::Url;
+
+    /// A proxy caching in the given directory, with a crates repository mirroring the index at
+    /// `root/upstream`.
+    fn proxy(root: &Path, cache_dir: &str) -> Proxy {
+        let toml = format!(
+            r#"
+            [proxy]
+            cache_dir = "{}"
+
+            [policy]
+            url = "http://localhost:8080/"
+
+            [repositories.npm]
+            type = "npm"
+            url = "https://registry.npmjs.org/"
+
+            [repositories.crates]
+            type = "crates"
+            url = "{}"
+            periodic_update = 0
+            "#,
+            root.join(cache_dir).display(),
+            Url::from_directory_path(root.join("upstream")).unwrap()
+        );
+        let mut proxy = Proxy::new(Config::new(toml.as_bytes(), None, None, None).unwrap());
+        proxy.init_repositories();
+        proxy
+    }
+
+    /// Copy a bundle, passing the contents of each of its files through `edit`.
+    fn tamper(bundle: &Path, tampered: &Path, edit: impl Fn(&str, Vec<u8>) -> Vec<u8>) {
+        let mut archive = tar::Archive::new(GzDecoder::new(File::open(bundle).unwrap()));
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            File::create(tampered).unwrap(),
+            Compression::default(),
+        ));
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            let data = edit(&path, data);
+            let mut header = entry.header().clone();
+            header.set_size(data.len() as u64);
+            builder
+                .append_data(&mut header, &path, data.as_slice())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[actix_web::test]
+    async fn export_and_import() {
+        let root = std::env::temp_dir().join(format!("seedwing-bundle-{}", std::process::id()));
+        let exporter = proxy(&root, "exporter");
+        let importer = proxy(&root, "importer");
+        let signer = SigningScheme::ECDSA_P256_SHA256_ASN1
+            .create_signer()
+            .unwrap();
+        let key = signer.to_verification_key().unwrap();
+
+        let context = Context::new(
+            "pkg:npm/lodash@4.17.21".into(),
+            "https://registry.npmjs.org/lodash/-/lodash-4.17.21.tgz".into(),
+            "8675309".into(),
+        );
+        exporter
+            .artifact_cache("npm")
+            .store(
+                "lodash/-/lodash-4.17.21.tgz",
+                None,
+                Some(&context),
+                None,
+                Bytes::from("tarball"),
+            )
+            .await;
+
+        let bundle = root.join("bundle.tar.gz");
+        let summary =
+            write_bundle(&exporter, File::create(&bundle).unwrap(), &signer, &[]).unwrap();
+        assert_eq!(1, summary.artifacts);
+
+        let staging = root.join("staging");
+        let manifest = extract(&bundle, &staging, &key).unwrap();
+        install(&importer, &staging, &manifest).unwrap();
+        let (entry, payload) = importer
+            .artifact_cache("npm")
+            .load("lodash/-/lodash-4.17.21.tgz")
+            .await
+            .unwrap();
+        assert_eq!("pkg:npm/lodash@4.17.21", entry.context().unwrap().purl());
+        assert_eq!(Bytes::from("tarball"), payload);
+
+        let other_key = SigningScheme::ECDSA_P256_SHA256_ASN1
+            .create_signer()
+            .unwrap()
+            .to_verification_key()
+            .unwrap();
+        assert!(extract(&bundle, &root.join("rejected"), &other_key).is_err());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn import_index_mirror() {
+        let root = std::env::temp_dir().join(format!("seedwing-mirror-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let upstream = Repository::init(root.join("upstream")).unwrap();
+        commit(
+            &upstream,
+            &[
+                (
+                    "config.json",
+                    r#"{"dl": "https://static.example.com/{crate}"}"#,
+                ),
+                ("se/rd/serde", "{\"name\":\"serde\",\"vers\":\"1.0.0\"}\n"),
+            ],
+        );
+        let exporter = proxy(&root, "exporter");
+        assert!(
+            exporter
+                .index_repository("crates")
+                .unwrap()
+                .init_cache()
+                .await
+        );
+        let signer = SigningScheme::ECDSA_P256_SHA256_ASN1
+            .create_signer()
+            .unwrap();
+
+        let bundle = root.join("bundle.tar.gz");
+        let summary =
+            write_bundle(&exporter, File::create(&bundle).unwrap(), &signer, &[]).unwrap();
+        assert_eq!(1, summary.indexes);
+
+        // The importer has never reached the upstream, it gets the index from the bundle
+        fs::remove_dir_all(root.join("upstream")).unwrap();
+        let importer = proxy(&root, "importer");
+        let staging = root.join("staging");
+        let key = signer.to_verification_key().unwrap();
+        let manifest = extract(&bundle, &staging, &key).unwrap();
+        let summary = install(&importer, &staging, &manifest).unwrap();
+        assert_eq!(1, summary.indexes);
+
+        let index_repository = importer.index_repository("crates").unwrap();
+        let git_dir = index_repository.get_git_repository_dir();
+        let repo = Repository::open(&git_dir).unwrap();
+        let tree = repo.head().unwrap().peel_to_tree().unwrap();
+        assert!(tree.get_path(Path::new("se/rd/serde")).is_ok());
+        // And downloads through itself rather than the exporter's upstream
+        let config_json = fs::read_to_string(git_dir.join("config.json")).unwrap();
+        assert!(config_json.contains(index_repository.get_dl_url().as_str()));
+        assert!(!config_json.contains("static.example.com"));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn reject_tampered_bundles() {
+        let root = std::env::temp_dir().join(format!("seedwing-tamper-{}", std::process::id()));
+        let exporter = proxy(&root, "exporter");
+        exporter
+            .artifact_cache("npm")
+            .store(
+                "lodash/-/lodash-4.17.21.tgz",
+                None,
+                None,
+                None,
+                Bytes::from("tarball"),
+            )
+            .await;
+        let signer = SigningScheme::ECDSA_P256_SHA256_ASN1
+            .create_signer()
+            .unwrap();
+        let key = signer.to_verification_key().unwrap();
+        let bundle = root.join("bundle.tar.gz");
+        write_bundle(&exporter, File::create(&bundle).unwrap(), &signer, &[]).unwrap();
+        let tarball = sha256::digest("tarball");
+        let malware = sha256::digest("malware");
+        let rejected = |edit: &dyn Fn(&str, Vec<u8>) -> Vec<u8>| {
+            let tampered = root.join("tampered.tar.gz");
+            tamper(&bundle, &tampered, edit);
+            extract(&tampered, &root.join("staging"), &key)
+                .unwrap_err()
+                .to_string()
+        };
+
+        // Untouched, the copy is accepted
+        tamper(&bundle, &root.join("copy.tar.gz"), |_, data| data);
+        assert!(extract(&root.join("copy.tar.gz"), &root.join("copy"), &key).is_ok());
+
+        // A replaced artifact no longer matches its digest
+        let replace = |path: &str, data: Vec<u8>| match data.as_slice() {
+            b"tarball" if path.starts_with(ARTIFACTS_DIR) => b"malware".to_vec(),
+            _ => data,
+        };
+        assert!(rejected(&replace).contains("do not match the signed manifest"));
+
+        // Nor does the manifest once its digest is replaced too
+        let rewrite = |path: &str, data: Vec<u8>| match path {
+            MANIFEST_FILE => String::from_utf8(data)
+                .unwrap()
+                .replace(&tarball, &malware)
+                .into_bytes(),
+            _ => replace(path, data),
+        };
+        assert!(rejected(&rewrite).contains("signature does not match"));
+
+        // A corrupted signature is refused as well
+        let corrupt = |path: &str, mut data: Vec<u8>| {
+            if path == SIGNATURE_FILE {
+                let last = data.len() - 1;
+                data[last] ^= 1;
+            }
+            data
+        };
+        assert!(rejected(&corrupt).contains("signature does not match"));
+        let unsigned = |path: &str, data: Vec<u8>| match path {
+            SIGNATURE_FILE => Vec::new(),
+            _ => data,
+        };
+        assert!(rejected(&unsigned).contains("signature does not match"));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
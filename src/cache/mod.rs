@@ -0,0 +1,466 @@
+/*
+ Local artifact cache shared by the repository handlers.
+
+ Every artifact which has been served to a client (and has passed policy evaluation) is written
+ to disk so it can later be served without access to the upstream repository. Entries are stored
+ under the sha256 of their key to avoid clashes between keys which would otherwise map to both a
+ file and a directory (e.g. an npm packument and the tarballs below it).
+*/
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use actix_web::{http::header::CONTENT_TYPE, web, HttpResponse};
+use bytes::Bytes;
+use futures::{stream, Stream};
+use serde::{Deserialize, Serialize};
+
+use crate::policy::{context::Context, PolicyVerdict};
+
+const DATA_EXTENSION: &str = "data";
+const ENTRY_EXTENSION: &str = "json";
+const PENDING_EXTENSION: &str = "pending";
+/// The size of the chunks cached artifacts are streamed in
+const CHUNK_SIZE: usize = 64 << 10;
+
+/// Distinguishes the temporary files of artifacts being written concurrently
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CacheEntry {
+    key: String,
+    content_type: Option<String>,
+    context: Option<Context>,
+    #[serde(default)]
+    verdict: Option<PolicyVerdict>,
+}
+
+impl CacheEntry {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    pub fn context(&self) -> Option<&Context> {
+        self.context.as_ref()
+    }
+
+    /// The policy verdict recorded when the artifact was cached
+    pub fn verdict(&self) -> Option<&PolicyVerdict> {
+        self.verdict.as_ref()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ArtifactCache {
+    root: PathBuf,
+}
+
+impl ArtifactCache {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    pub fn get_root(&self) -> &PathBuf {
+        &self.root
+    }
+
+    fn get_paths(root: &Path, key: &str) -> (PathBuf, PathBuf) {
+        let digest = sha256::digest(key);
+        let dir = root.join(&digest[0..2]);
+        (
+            dir.join(format!("{digest}.{DATA_EXTENSION}")),
+            dir.join(format!("{digest}.{ENTRY_EXTENSION}")),
+        )
+    }
+
+    fn read(root: &Path, key: &str) -> io::Result<Option<(CacheEntry, Bytes)>> {
+        let (data_file, entry_file) = Self::get_paths(root, key);
+        if !entry_file.exists() || !data_file.exists() {
+            return Ok(None);
+        }
+        let entry: CacheEntry = serde_json::from_slice(&fs::read(entry_file)?)?;
+        let payload = fs::read(data_file)?;
+        Ok(Some((entry, Bytes::from(payload))))
+    }
+
+    fn write(root: &Path, entry: &CacheEntry, payload: &[u8]) -> io::Result<()> {
+        // Written aside and renamed, so a reader never sees a partially written artifact
+        let (path, mut file) = Self::create_pending(root, &entry.key)?;
+        let written = file
+            .write_all(payload)
+            .and_then(|()| file.sync_all())
+            .and_then(|()| Self::commit_pending(root, entry, &path));
+        if written.is_err() {
+            let _ = fs::remove_file(&path);
+        }
+        written
+    }
+
+    fn create_pending(root: &Path, key: &str) -> io::Result<(PathBuf, fs::File)> {
+        let (data_file, _) = Self::get_paths(root, key);
+        fs::create_dir_all(data_file.parent().unwrap())?;
+        let id = PENDING.fetch_add(1, Ordering::Relaxed);
+        let path =
+            data_file.with_extension(format!("{}.{id}.{PENDING_EXTENSION}", std::process::id()));
+        let file = fs::File::create(&path)?;
+        Ok((path, file))
+    }
+
+    fn commit_pending(root: &Path, entry: &CacheEntry, path: &Path) -> io::Result<()> {
+        let (data_file, entry_file) = Self::get_paths(root, &entry.key);
+        // Renamed before the entry is written, so a partially written artifact is never served
+        fs::rename(path, data_file)?;
+        fs::write(entry_file, serde_json::to_vec_pretty(entry)?)
+    }
+
+    /// List every cached artifact along with its data and entry files.
+    pub fn entries(&self) -> io::Result<Vec<(CacheEntry, PathBuf, PathBuf)>> {
+        let mut entries = Vec::new();
+        if !self.root.exists() {
+            return Ok(entries);
+        }
+        for dir in fs::read_dir(&self.root)? {
+            let dir = dir?.path();
+            if !dir.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(dir)? {
+                let entry_file = file?.path();
+                if entry_file.extension().and_then(|ext| ext.to_str()) != Some(ENTRY_EXTENSION) {
+                    continue;
+                }
+                let data_file = entry_file.with_extension(DATA_EXTENSION);
+                if data_file.exists() {
+                    let entry = serde_json::from_slice(&fs::read(&entry_file)?)?;
+                    entries.push((entry, data_file, entry_file));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Load an artifact from the cache, returning None when it has not been cached.
+    pub async fn load(&self, key: &str) -> Option<(CacheEntry, Bytes)> {
+        let root = self.root.clone();
+        let owned_key = String::from(key);
+        match web::block(move || Self::read(&root, &owned_key)).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(error)) => {
+                log::warn!("Unable to read {key} from the cache: {error}");
+                None
+            }
+            Err(error) => {
+                log::warn!("Unable to read {key} from the cache: {error}");
+                None
+            }
+        }
+    }
+
+    /// Store an artifact in the cache. Failures are logged but never fail the request.
+    pub async fn store(
+        &self,
+        key: &str,
+        content_type: Option<&str>,
+        context: Option<&Context>,
+        verdict: Option<PolicyVerdict>,
+        payload: Bytes,
+    ) {
+        let root = self.root.clone();
+        let entry = CacheEntry {
+            key: String::from(key),
+            content_type: content_type.map(String::from),
+            context: context.cloned(),
+            verdict,
+        };
+        match web::block(move || Self::write(&root, &entry, &payload)).await {
+            Ok(Ok(())) => log::debug!("Cached {key}"),
+            Ok(Err(error)) => log::warn!("Unable to write {key} to the cache: {error}"),
+            Err(error) => log::warn!("Unable to write {key} to the cache: {error}"),
+        }
+    }
+
+    /// Start writing an artifact to the cache as it is received. It is only stored once committed.
+    pub async fn begin(&self, key: &str) -> io::Result<PendingArtifact> {
+        let root = self.root.clone();
+        let owned_key = String::from(key);
+        let (path, file) = web::block(move || Self::create_pending(&root, &owned_key))
+            .await
+            .map_err(io::Error::other)??;
+        Ok(PendingArtifact {
+            root: self.root.clone(),
+            key: String::from(key),
+            path,
+            file: Some(file),
+            written: 0,
+        })
+    }
+
+    /// Read a cached artifact in chunks, along with its length, returning None when it has not
+    /// been cached.
+    pub async fn stream(
+        &self,
+        key: &str,
+    ) -> Option<(
+        CacheEntry,
+        u64,
+        impl Stream<Item = io::Result<Bytes>> + 'static,
+    )> {
+        let root = self.root.clone();
+        let owned_key = String::from(key);
+        let opened = web::block(move || -> io::Result<Option<(CacheEntry, fs::File, u64)>> {
+            let (data_file, entry_file) = Self::get_paths(&root, &owned_key);
+            if !entry_file.exists() || !data_file.exists() {
+                return Ok(None);
+            }
+            let entry: CacheEntry = serde_json::from_slice(&fs::read(entry_file)?)?;
+            let file = fs::File::open(data_file)?;
+            let len = file.metadata()?.len();
+            Ok(Some((entry, file, len)))
+        })
+        .await;
+        let (entry, file, len) = match opened {
+            Ok(Ok(opened)) => opened?,
+            Ok(Err(error)) => {
+                log::warn!("Unable to read {key} from the cache: {error}");
+                return None;
+            }
+            Err(error) => {
+                log::warn!("Unable to read {key} from the cache: {error}");
+                return None;
+            }
+        };
+        let chunks = stream::try_unfold(file, |mut file| async move {
+            let (file, chunk) = web::block(move || -> io::Result<(fs::File, Vec<u8>)> {
+                let mut chunk = vec![0; CHUNK_SIZE];
+                let read = file.read(&mut chunk)?;
+                chunk.truncate(read);
+                Ok((file, chunk))
+            })
+            .await
+            .map_err(io::Error::other)??;
+            Ok((!chunk.is_empty()).then(|| (Bytes::from(chunk), file)))
+        });
+        Some((entry, len, chunks))
+    }
+
+    /// Build a response for a cached artifact, returning None when it has not been cached.
+    pub async fn respond(&self, key: &str) -> Option<HttpResponse> {
+        self.load(key).await.map(|(entry, payload)| {
+            log::debug!("Serving {key} from the cache");
+            let mut response = HttpResponse::Ok();
+            if let Some(content_type) = entry.content_type() {
+                response.insert_header((CONTENT_TYPE, content_type));
+            }
+            response.body(payload)
+        })
+    }
+}
+
+/// An artifact being written to a temporary file of the cache, removed unless it is committed.
+pub struct PendingArtifact {
+    root: PathBuf,
+    key: String,
+    path: PathBuf,
+    file: Option<fs::File>,
+    written: u64,
+}
+
+impl PendingArtifact {
+    /// The number of bytes written so far
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// The file written to, to read back what was received before committing it
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn write(&mut self, chunk: Bytes) -> io::Result<()> {
+        let mut file = self
+            .file
+            .take()
+            .ok_or_else(|| io::Error::other("a previous write failed"))?;
+        let len = chunk.len() as u64;
+        let file = web::block(move || file.write_all(&chunk).map(|()| file))
+            .await
+            .map_err(io::Error::other)??;
+        self.file = Some(file);
+        self.written += len;
+        Ok(())
+    }
+
+    /// Store the artifact in the cache under its key.
+    pub async fn commit(
+        mut self,
+        content_type: Option<&str>,
+        context: Option<&Context>,
+        verdict: Option<PolicyVerdict>,
+    ) -> io::Result<()> {
+        let file = self
+            .file
+            .take()
+            .ok_or_else(|| io::Error::other("a previous write failed"))?;
+        let root = self.root.clone();
+        let path = self.path.clone();
+        let entry = CacheEntry {
+            key: self.key.clone(),
+            content_type: content_type.map(String::from),
+            context: context.cloned(),
+            verdict,
+        };
+        web::block(move || {
+            file.sync_all()?;
+            drop(file);
+            ArtifactCache::commit_pending(&root, &entry, &path)
+        })
+        .await
+        .map_err(io::Error::other)??;
+        log::debug!("Cached {}", self.key);
+        Ok(())
+    }
+}
+
+impl Drop for PendingArtifact {
+    fn drop(&mut self) {
+        // Gone once committed
+        if let Err(error) = fs::remove_file(&self.path) {
+            if error.kind() != io::ErrorKind::NotFound {
+                log::warn!("Unable to remove {}: {error}", self.path.display());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[actix_web::test]
+    async fn store_and_load() {
+        let root = std::env::temp_dir().join(format!("seedwing-cache-{}", std::process::id()));
+        let cache = ArtifactCache::new(root.clone());
+
+        assert!(cache.load("lodash").await.is_none());
+
+        let context = Context::new(
+            "pkg:npm/lodash@4.17.21".into(),
+            "https://registry.npmjs.org/lodash/-/lodash-4.17.21.tgz".into(),
+            "8675309".into(),
+        );
+        cache
+            .store(
+                "lodash",
+                Some("application/json"),
+                None,
+                None,
+                Bytes::from("{}"),
+            )
+            .await;
+        cache
+            .store(
+                "lodash/-/lodash-4.17.21.tgz",
+                None,
+                Some(&context),
+                None,
+                Bytes::from("tarball"),
+            )
+            .await;
+
+        let (entry, payload) = cache.load("lodash").await.unwrap();
+        assert_eq!(Some("application/json"), entry.content_type());
+        assert_eq!(Bytes::from("{}"), payload);
+
+        let (entry, payload) = cache.load("lodash/-/lodash-4.17.21.tgz").await.unwrap();
+        assert_eq!("pkg:npm/lodash@4.17.21", entry.context().unwrap().purl());
+        assert_eq!(Bytes::from("tarball"), payload);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn pending_artifacts() {
+        use futures::TryStreamExt;
+
+        let root = std::env::temp_dir().join(format!("seedwing-pending-{}", std::process::id()));
+        let cache = ArtifactCache::new(root.clone());
+        let pending_files = || {
+            fs::read_dir(root.join(&sha256::digest("wheel")[0..2]))
+                .unwrap()
+                .filter(|file| {
+                    let path = file.as_ref().unwrap().path();
+                    path.extension().and_then(|ext| ext.to_str()) == Some(PENDING_EXTENSION)
+                })
+                .count()
+        };
+
+        // Dropped before being committed, nothing is cached
+        let mut pending = cache.begin("wheel").await.unwrap();
+        pending.write(Bytes::from("partial")).await.unwrap();
+        assert_eq!(1, pending_files());
+        drop(pending);
+        assert_eq!(0, pending_files());
+        assert!(cache.stream("wheel").await.is_none());
+
+        let payload = vec![7; CHUNK_SIZE + 10];
+        let mut pending = cache.begin("wheel").await.unwrap();
+        pending
+            .write(Bytes::from(payload[..10].to_vec()))
+            .await
+            .unwrap();
+        pending
+            .write(Bytes::from(payload[10..].to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(payload.len() as u64, pending.written());
+        pending
+            .commit(Some("application/octet-stream"), None, None)
+            .await
+            .unwrap();
+        assert_eq!(0, pending_files());
+
+        let (entry, len, chunks) = cache.stream("wheel").await.unwrap();
+        assert_eq!(Some("application/octet-stream"), entry.content_type());
+        assert_eq!(payload.len() as u64, len);
+        let chunks: Vec<Bytes> = chunks.try_collect().await.unwrap();
+        assert_eq!(2, chunks.len());
+        assert_eq!(payload, chunks.concat());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn replaced_artifacts() {
+        use futures::StreamExt;
+
+        let root = std::env::temp_dir().join(format!("seedwing-replaced-{}", std::process::id()));
+        let cache = ArtifactCache::new(root.clone());
+        let old = Bytes::from(vec![1; CHUNK_SIZE * 2]);
+        let new = Bytes::from(vec![2; CHUNK_SIZE]);
+        cache.store("index", None, None, None, old.clone()).await;
+
+        // An artifact being served is not overwritten in place when it is stored again
+        let (_, _, chunks) = cache.stream("index").await.unwrap();
+        let mut chunks = Box::pin(chunks);
+        let first = chunks.next().await.unwrap().unwrap();
+        cache.store("index", None, None, None, new.clone()).await;
+        let second = chunks.next().await.unwrap().unwrap();
+        assert_eq!(old, [first, second].concat());
+        assert_eq!(new, cache.load("index").await.unwrap().1);
+
+        let files = fs::read_dir(root.join(&sha256::digest("index")[0..2]))
+            .unwrap()
+            .count();
+        assert_eq!(2, files);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,8 +1,14 @@
 use clap::builder::PathBufValueParser;
-use clap::{value_parser, Arg, Command};
+use clap::{value_parser, Arg, ArgAction, Command};
+
+use crate::prefetch::lockfiles::FORMAT_NAMES;
 
 pub const COMMAND_NAME: &str = "seedwing-proxy";
 
+pub const PREFETCH_COMMAND: &str = "prefetch";
+pub const EXPORT_COMMAND: &str = "export";
+pub const IMPORT_COMMAND: &str = "import";
+
 pub fn cli() -> Command {
     Command::new(COMMAND_NAME)
         .arg(
@@ -25,4 +31,86 @@ pub fn cli() -> Command {
                 .value_name("listen port")
                 .value_parser(value_parser!(u16)),
         )
+        .arg(
+            Arg::new("offline")
+                .long("offline")
+                .help("serve artifacts from the local cache only")
+                .action(ArgAction::SetTrue),
+        )
+        .subcommand(
+            Command::new(PREFETCH_COMMAND)
+                .about("populate the local cache from lockfiles, reporting denied packages")
+                .arg(
+                    Arg::new("lockfile")
+                        .required(true)
+                        .num_args(1..)
+                        .value_name(
+                            "Cargo.lock, package-lock.json, yarn.lock, Gemfile.lock or mvn dependency:list output",
+                        )
+                        .value_parser(PathBufValueParser::default()),
+                )
+                .arg(
+                    Arg::new("report")
+                        .long("report")
+                        .short('r')
+                        .value_name("path of JSON report")
+                        .value_parser(PathBufValueParser::default()),
+                )
+                .arg(
+                    Arg::new("scope")
+                        .long("scope")
+                        .short('s')
+                        .value_name("repository scope"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .short('f')
+                        .value_name("lockfile format, detected from the file name by default")
+                        .value_parser(FORMAT_NAMES),
+                ),
+        )
+        .subcommand(
+            Command::new(EXPORT_COMMAND)
+                .about("export index mirrors and approved artifacts into a signed bundle")
+                .arg(
+                    Arg::new("bundle")
+                        .required(true)
+                        .value_name("path of bundle [.tar.gz]")
+                        .value_parser(PathBufValueParser::default()),
+                )
+                .arg(
+                    Arg::new("key")
+                        .long("key")
+                        .short('k')
+                        .required(true)
+                        .value_name("path of PEM encoded ECDSA private key")
+                        .value_parser(PathBufValueParser::default()),
+                )
+                .arg(
+                    Arg::new("scope")
+                        .long("scope")
+                        .short('s')
+                        .value_name("repository scope")
+                        .action(ArgAction::Append),
+                ),
+        )
+        .subcommand(
+            Command::new(IMPORT_COMMAND)
+                .about("import a signed bundle exported by another proxy into the local cache")
+                .arg(
+                    Arg::new("bundle")
+                        .required(true)
+                        .value_name("path of bundle [.tar.gz]")
+                        .value_parser(PathBufValueParser::default()),
+                )
+                .arg(
+                    Arg::new("public-key")
+                        .long("public-key")
+                        .short('k')
+                        .required(true)
+                        .value_name("path of PEM encoded public key")
+                        .value_parser(PathBufValueParser::default()),
+                ),
+        )
 }
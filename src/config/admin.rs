@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct AdminConfig {
+    /// Bearer token required by the admin API, which is disabled when unset. Never serialized,
+    /// as the configuration is rendered by the UI.
+    #[serde(default, skip_serializing)]
+    token: Option<String>,
+}
+
+impl AdminConfig {
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref().filter(|token| !token.is_empty())
+    }
+}
@@ -1,10 +1,15 @@
+pub(crate) mod admin;
 pub(crate) mod policy;
 pub(crate) mod proxy;
 pub(crate) mod repositories;
+pub(crate) mod sigstore;
 
+use crate::admin::SCOPE as ADMIN_SCOPE;
+use crate::config::admin::AdminConfig;
 use crate::config::policy::PolicyConfig;
 use crate::config::proxy::ProxyConfig;
 use crate::config::repositories::Repositories;
+use crate::config::sigstore::SigstoreConfig;
 use serde::{Deserialize, Serialize};
 use std::io::Error as IoError;
 use std::io::Read;
@@ -14,6 +19,7 @@ use toml::de::Error as TomlError;
 pub enum ConfigError {
     Serialization(TomlError),
     Io(IoError),
+    Invalid(String),
 }
 
 impl From<TomlError> for ConfigError {
@@ -35,6 +41,10 @@ pub struct Config {
     policy: PolicyConfig,
     #[serde(default)]
     repositories: Repositories,
+    #[serde(default)]
+    admin: AdminConfig,
+    #[serde(default)]
+    sigstore: SigstoreConfig,
 }
 
 impl Config {
@@ -42,10 +52,16 @@ impl Config {
         mut input: R,
         bind_override: Option<String>,
         port_override: Option<u16>,
+        offline_override: Option<bool>,
     ) -> Result<Self, ConfigError> {
         let mut vec = Vec::new();
         let len = input.read_to_end(&mut vec)?;
         let mut config: Config = toml::from_slice(&vec[0..len])?;
+        if config.repositories.get(ADMIN_SCOPE).is_some() {
+            return Err(ConfigError::Invalid(format!(
+                "No repository can be named {ADMIN_SCOPE}, it is the path of the admin API"
+            )));
+        }
 
         if let Some(bind) = bind_override {
             *config.proxy_mut().bind_mut() = bind;
@@ -55,6 +71,10 @@ impl Config {
             *config.proxy_mut().port_mut() = port;
         }
 
+        if let Some(offline) = offline_override {
+            *config.proxy_mut().offline_mut() = offline;
+        }
+
         Ok(config)
     }
 
@@ -70,9 +90,27 @@ impl Config {
         &self.policy
     }
 
+    pub(crate) fn policy_mut(&mut self) -> &mut PolicyConfig {
+        &mut self.policy
+    }
+
     pub fn repositories(&self) -> &Repositories {
         &self.repositories
     }
+
+    pub fn admin(&self) -> &AdminConfig {
+        &self.admin
+    }
+
+    pub fn sigstore(&self) -> &SigstoreConfig {
+        &self.sigstore
+    }
+
+    /// Clear every offline setting so all repositories contact their upstream.
+    pub(crate) fn force_online(&mut self) {
+        *self.proxy_mut().offline_mut() = false;
+        self.repositories.clear_offline();
+    }
 }
 
 #[cfg(test)]
@@ -140,6 +178,7 @@ mod test {
             port = 8181
             cache_dir = '~/.seedwing_proxy/cache'
             git_cmd = "git"
+            offline = true
 
             [policy]
             url = 'http://localhost:8080/'
@@ -148,14 +187,30 @@ mod test {
             [repositories.crates-io]
             type = "crates"
             url = "https://crates.io/"
+            squash = true
+            update_on_fetch = 300
+
+            [repositories.crates-io.credentials]
+            username = "seedwing"
+            token = "t0k3n"
 
             [repositories.m2]
             type = "m2"
             url = "https://repo.maven.apache.org/maven2"
+            offline = false
+
+            [repositories.maven]
+            type = "m2-group"
+            url = "https://repo.maven.apache.org/maven2"
+            upstreams = ["https://maven.google.com"]
+
+            [admin]
+            token = "s3cr3t"
         "#,
         )
         .unwrap();
 
+        assert!(config.proxy.offline());
         assert_eq!(
             Url::parse("http://localhost:8080/").unwrap(),
             config.policy.url()
@@ -167,12 +222,55 @@ mod test {
         let crates_io = repo_iter.next().unwrap();
         assert_eq!("crates-io", crates_io.0);
         assert_eq!(RepositoryType::Crates, crates_io.1.repository_type());
+        assert_eq!(None, crates_io.1.offline());
+        assert!(crates_io.1.squash());
+        assert_eq!(300, crates_io.1.update_on_fetch());
+        assert_eq!(Some("seedwing"), crates_io.1.credentials().username());
+        assert_eq!(Some("t0k3n"), crates_io.1.credentials().token());
+        assert_eq!(None, crates_io.1.credentials().ssh_key());
 
         let m2 = repo_iter.next().unwrap();
         assert_eq!("m2", m2.0);
         assert_eq!(RepositoryType::M2, m2.1.repository_type());
+        assert_eq!(Some(false), m2.1.offline());
+        assert!(!m2.1.squash());
+        assert_eq!(1, m2.1.upstreams().len());
+
+        let maven = repo_iter.next().unwrap();
+        assert_eq!(RepositoryType::M2Group, maven.1.repository_type());
+        assert_eq!(
+            vec![
+                Url::parse("https://repo.maven.apache.org/maven2").unwrap(),
+                Url::parse("https://maven.google.com").unwrap()
+            ],
+            maven.1.upstreams()
+        );
+
+        assert!(repo_iter.next().is_none());
+
+        assert_eq!(Some("s3cr3t"), config.admin.token());
+        let serialized = serde_json::to_string(&config).unwrap();
+        assert!(!serialized.contains("s3cr3t"));
+        assert!(!serialized.contains("t0k3n"));
+    }
 
-        assert!(repo_iter.next().is_none())
+    #[test]
+    fn reserved_repository_name() {
+        let config = Config::new(
+            r#"
+            [policy]
+            url = 'http://localhost:8080/'
+
+            [repositories.admin]
+            type = "npm"
+            url = "https://registry.npmjs.org/"
+        "#
+            .as_bytes(),
+            None,
+            None,
+            None,
+        );
+        assert!(matches!(config, Err(ConfigError::Invalid(_))));
     }
 
     #[test]
@@ -185,7 +283,7 @@ mod test {
             url = 'http://localhost:8080/'
         "#,
         );
-        assert!(!config.is_err());
+        assert!(config.is_ok());
         let config = config.unwrap();
         assert_eq!("255.255.255.255", config.proxy.bind());
 
@@ -197,7 +295,7 @@ mod test {
             url = 'http://localhost:8080/'
         "#,
         );
-        assert!(!config.is_err());
+        assert!(config.is_ok());
         let config = config.unwrap();
         assert_eq!(9999, config.proxy.port());
 
@@ -209,7 +307,7 @@ mod test {
             url = 'http://localhost:8080/'
         "#,
         );
-        assert!(!config.is_err());
+        assert!(config.is_ok());
         let config = config.unwrap();
         assert_eq!("~/.test/cache_dir", config.proxy.cache_dir());
 
@@ -221,7 +319,7 @@ mod test {
             url = 'http://localhost:8080/'
         "#,
         );
-        assert!(!config.is_err());
+        assert!(config.is_ok());
         let config = config.unwrap();
         assert_eq!("mygitcmd", config.proxy.git_cmd());
     }
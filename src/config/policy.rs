@@ -14,6 +14,10 @@ impl PolicyConfig {
         self.decision
     }
 
+    pub(crate) fn decision_mut(&mut self) -> &mut Decision {
+        &mut self.decision
+    }
+
     pub fn url(&self) -> Url {
         self.url.clone()
     }
@@ -12,6 +12,8 @@ pub struct ProxyConfig {
     cache_dir: String,
     #[serde(default = "default_git_cmd")]
     git_cmd: String,
+    #[serde(default)]
+    offline: bool,
 }
 
 impl Default for ProxyConfig {
@@ -21,6 +23,7 @@ impl Default for ProxyConfig {
             port: default_port(),
             cache_dir: default_cache_dir(),
             git_cmd: default_git_cmd(),
+            offline: false,
         }
     }
 }
@@ -74,9 +77,20 @@ impl ProxyConfig {
         }
     }
 
+    /// No longer used, the index is served without a system git binary. Kept so existing
+    /// configuration files still parse.
     pub fn git_cmd(&self) -> String {
         self.git_cmd.clone()
     }
+
+    /// Serve every repository from the local cache only, unless overridden per repository.
+    pub fn offline(&self) -> bool {
+        self.offline
+    }
+
+    pub(crate) fn offline_mut(&mut self) -> &mut bool {
+        &mut self.offline
+    }
 }
 
 // Used to create address binding information for the HTTP server
@@ -1,6 +1,7 @@
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
 use url::Url;
 
 #[derive(Serialize, Deserialize, Default, Clone, Debug)]
@@ -10,20 +11,45 @@ impl Repositories {
     pub fn iter(&self) -> impl Iterator<Item = (&String, &RepositoryConfig)> {
         self.0.iter()
     }
+
+    pub fn get(&self, scope: &str) -> Option<&RepositoryConfig> {
+        self.0.get(scope)
+    }
+
+    pub(crate) fn clear_offline(&mut self) {
+        for repository in self.0.values_mut() {
+            repository.offline = None;
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum RepositoryType {
     #[serde(rename = "crates")]
     Crates,
+    /// A crates git index mirror served over both the git and sparse protocols
+    #[serde(rename = "cargo")]
+    Cargo,
     #[serde(rename = "m2")]
     M2,
+    /// Several Maven repositories served as one, tried in priority order
+    #[serde(rename = "m2-group")]
+    M2Group,
     #[serde(rename = "sparse-crates")]
     SparseCrates,
     #[serde(rename = "npm")]
     Npm,
     #[serde(rename = "gems")]
     Gems,
+    /// A Python package index serving the Simple Repository API
+    #[serde(rename = "pypi")]
+    Pypi,
+    /// A Go module proxy
+    #[serde(rename = "go")]
+    Go,
+    /// An OCI distribution registry, such as a container registry
+    #[serde(rename = "oci")]
+    Oci,
 }
 
 impl Display for RepositoryType {
@@ -32,9 +58,15 @@ impl Display for RepositoryType {
             RepositoryType::Crates => {
                 write!(f, "crates")
             }
+            RepositoryType::Cargo => {
+                write!(f, "cargo")
+            }
             RepositoryType::M2 => {
                 write!(f, "m2")
             }
+            RepositoryType::M2Group => {
+                write!(f, "m2-group")
+            }
             RepositoryType::SparseCrates => {
                 write!(f, "sparse-crates")
             }
@@ -44,6 +76,15 @@ impl Display for RepositoryType {
             RepositoryType::Gems => {
                 write!(f, "gems")
             }
+            RepositoryType::Pypi => {
+                write!(f, "pypi")
+            }
+            RepositoryType::Go => {
+                write!(f, "go")
+            }
+            RepositoryType::Oci => {
+                write!(f, "oci")
+            }
         }
     }
 }
@@ -55,6 +96,32 @@ pub struct RepositoryConfig {
     url: Url,
     #[serde(default = "default_periodic_update")]
     periodic_update: u64,
+    #[serde(default)]
+    update_on_fetch: u64,
+    #[serde(default)]
+    squash: bool,
+    #[serde(default)]
+    sparse: bool,
+    #[serde(default)]
+    offline: Option<bool>,
+    #[serde(default)]
+    credentials: Credentials,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    packaging: Option<Vec<String>>,
+    #[serde(default)]
+    block_snapshots: bool,
+    #[serde(default)]
+    filter_metadata: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    upstreams: Vec<Url>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    operations: Option<Vec<String>>,
+    #[serde(default)]
+    verify_signatures: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum_db: Option<ChecksumDb>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    cosign_keys: Vec<PathBuf>,
 }
 
 impl RepositoryConfig {
@@ -66,11 +133,166 @@ impl RepositoryConfig {
         self.url.clone()
     }
 
+    /// Upstreams in priority order: the `url`, followed by the `upstreams` of a Maven group.
+    pub fn upstreams(&self) -> Vec<Url> {
+        let mut upstreams = vec![self.url.clone()];
+        if self.repository_type == RepositoryType::M2Group {
+            upstreams.extend(self.upstreams.iter().cloned());
+        }
+        upstreams
+    }
+
     pub fn periodic_update(&self) -> u64 {
         self.periodic_update
     }
+
+    /// Seconds after which a client fetch triggers an update of a crates index, 0 to disable.
+    pub fn update_on_fetch(&self) -> u64 {
+        self.update_on_fetch
+    }
+
+    /// Serve a crates index as a single commit rather than the full upstream history.
+    pub fn squash(&self) -> bool {
+        self.squash
+    }
+
+    /// Also serve a crates git index over the sparse protocol, as the cargo type always does.
+    pub fn sparse(&self) -> bool {
+        self.sparse || self.repository_type == RepositoryType::Cargo
+    }
+
+    /// Per repository override of the global `offline` setting.
+    pub fn offline(&self) -> Option<bool> {
+        self.offline
+    }
+
+    pub fn credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    /// Extensions of the Maven artifacts evaluated against the policy.
+    pub fn packaging(&self) -> Vec<String> {
+        match &self.packaging {
+            Some(packaging) => packaging.clone(),
+            None => DEFAULT_PACKAGING.iter().map(|p| String::from(*p)).collect(),
+        }
+    }
+
+    /// Refuse SNAPSHOT artifacts and leave them out of the Maven metadata.
+    pub fn block_snapshots(&self) -> bool {
+        self.block_snapshots
+    }
+
+    /// npm API operations besides installing packages which are forwarded to the registry.
+    pub fn operations(&self) -> Vec<String> {
+        match &self.operations {
+            Some(operations) => operations.clone(),
+            None => DEFAULT_OPERATIONS
+                .iter()
+                .map(|o| String::from(*o))
+                .collect(),
+        }
+    }
+
+    /// Only advertise the versions allowed by the policy in Maven metadata and npm packuments.
+    pub fn filter_metadata(&self) -> bool {
+        self.filter_metadata
+    }
+
+    /// Verify the registry signatures and the provenance of npm tarballs, or require OCI images
+    /// to be signed with cosign.
+    pub fn verify_signatures(&self) -> bool {
+        self.verify_signatures
+    }
+
+    /// The checksum database Go modules are verified against, if any.
+    pub fn checksum_db(&self) -> Option<&ChecksumDb> {
+        self.checksum_db.as_ref()
+    }
+
+    /// Public keys OCI images may be signed with, besides keyless signatures.
+    pub fn cosign_keys(&self) -> &[PathBuf] {
+        &self.cosign_keys
+    }
+}
+
+/// A Go checksum database, or a mirror of it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChecksumDb {
+    #[serde(default = "default_checksum_db_url")]
+    url: Url,
+    /// The verifier key of the database, as set in `GOSUMDB`
+    #[serde(default = "default_checksum_db_key")]
+    key: String,
+    /// Module path prefixes which are not in the database, as set in `GONOSUMDB`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    exclude: Vec<String>,
+}
+
+impl ChecksumDb {
+    pub fn url(&self) -> Url {
+        self.url.clone()
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Whether a module is left out of the database.
+    pub fn excludes(&self, module: &str) -> bool {
+        self.exclude.iter().any(|prefix| {
+            let prefix = prefix.trim_end_matches('/');
+            module == prefix
+                || module
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+    }
 }
 
+/// Credentials used to fetch a private index, and its crates when the index requires it.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct Credentials {
+    #[serde(default)]
+    username: Option<String>,
+    /// HTTPS password or token. Never serialized, as the configuration is rendered by the UI.
+    #[serde(default, skip_serializing)]
+    token: Option<String>,
+    #[serde(default)]
+    ssh_key: Option<PathBuf>,
+    #[serde(default, skip_serializing)]
+    ssh_key_passphrase: Option<String>,
+}
+
+impl Credentials {
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn ssh_key(&self) -> Option<&PathBuf> {
+        self.ssh_key.as_ref()
+    }
+
+    pub fn ssh_key_passphrase(&self) -> Option<&str> {
+        self.ssh_key_passphrase.as_deref()
+    }
+}
+
+const DEFAULT_PACKAGING: [&str; 4] = ["jar", "war", "ear", "aar"];
+const DEFAULT_OPERATIONS: [&str; 2] = ["audit", "search"];
+
 fn default_periodic_update() -> u64 {
     0
 }
+
+fn default_checksum_db_url() -> Url {
+    Url::parse("https://sum.golang.org/").unwrap()
+}
+
+fn default_checksum_db_key() -> String {
+    String::from("sum.golang.org+033de0ae+Ac4zctda0e5eza+HJyk9SxEdh+s3Ry18zy3I7Z3/1ML")
+}
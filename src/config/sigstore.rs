@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct SigstoreConfig {
+    /// Sigstore trusted root (trusted_root.json) holding the Fulcio certificate authorities and
+    /// the Rekor keys. Provenance is not verified without it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trusted_root: Option<PathBuf>,
+}
+
+impl SigstoreConfig {
+    pub fn trusted_root(&self) -> Option<&Path> {
+        self.trusted_root.as_deref()
+    }
+}
@@ -1,3 +1,4 @@
+use actix_web::{http::StatusCode, HttpResponse};
 use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -16,6 +17,8 @@ pub enum Error {
     PayloadError(#[from] actix_web::error::PayloadError),
     #[error("Proxy init error")] // todo: remove?
     ProxyInitializationError,
+    #[error("Sigstore error: {0}")]
+    SigstoreError(String),
     #[error("Send request error: {0}")]
     SendRequestError(#[from] awc::error::SendRequestError),
     #[error("IO error: {0}")]
@@ -24,4 +27,26 @@ pub enum Error {
     Other { message: String },
 }
 
-impl actix_web::ResponseError for Error {}
+impl actix_web::ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::ActixWeb(error) => error.as_response_error().status_code(),
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            // Keep the status and message chosen by the handler
+            Error::ActixWeb(error) => error.error_response(),
+            _ => HttpResponse::build(self.status_code()).body(self.to_string()),
+        }
+    }
+}
+
+// Only the message is kept, SigstoreError is too large to embed
+impl From<sigstore::errors::SigstoreError> for Error {
+    fn from(error: sigstore::errors::SigstoreError) -> Self {
+        Error::SigstoreError(error.to_string())
+    }
+}
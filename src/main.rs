@@ -1,13 +1,18 @@
-use crate::cli::cli;
+use crate::cli::{cli, EXPORT_COMMAND, IMPORT_COMMAND, PREFETCH_COMMAND};
 use crate::config::Config;
+use crate::prefetch::lockfiles::LockfileFormat;
 use crate::proxy::Proxy;
 use std::fs::File;
 use std::path::PathBuf;
 
+pub mod admin;
+pub mod bundle;
+pub mod cache;
 pub mod cli;
 pub mod config;
 pub mod errors;
 pub mod policy;
+pub mod prefetch;
 pub mod proxy;
 pub mod repositories;
 pub mod sigstore;
@@ -33,13 +38,80 @@ async fn main() -> std::io::Result<()> {
 
     let bind = matches.get_one("bind").cloned();
     let port = matches.get_one("port").cloned();
+    let offline = matches.get_flag("offline").then_some(true);
 
     if let Ok(config_toml) = File::open(config_toml.clone()) {
-        match Config::new(config_toml, bind, port) {
-            Ok(config) => {
-                let proxy: Proxy = Proxy::new(config);
-                proxy.run().await
-            }
+        match Config::new(config_toml, bind, port, offline) {
+            Ok(config) => match matches.subcommand() {
+                Some((PREFETCH_COMMAND, prefetch_matches)) => {
+                    let lockfiles: Vec<PathBuf> = prefetch_matches
+                        .get_many("lockfile")
+                        .unwrap()
+                        .cloned()
+                        .collect();
+                    let scope = prefetch_matches.get_one::<String>("scope");
+                    let format = prefetch_matches
+                        .get_one::<String>("format")
+                        .and_then(|format| LockfileFormat::named(format));
+                    match prefetch::prefetch(config, scope.map(String::as_str), format, &lockfiles)
+                        .await
+                    {
+                        Ok(report) => {
+                            println!("{report}");
+                            if let Some(report_file) = prefetch_matches.get_one::<PathBuf>("report")
+                            {
+                                if let Err(err) = report.write(report_file) {
+                                    eprintln!("Unable to write the report: {err}");
+                                }
+                            }
+                            if !report.is_clean() {
+                                std::process::exit(-3);
+                            }
+                            Ok(())
+                        }
+                        Err(err) => {
+                            eprintln!("Unable to prefetch: {err}");
+                            std::process::exit(-3);
+                        }
+                    }
+                }
+                Some((EXPORT_COMMAND, export_matches)) => {
+                    let bundle: &PathBuf = export_matches.get_one("bundle").unwrap();
+                    let key: &PathBuf = export_matches.get_one("key").unwrap();
+                    let scopes: Vec<String> = export_matches
+                        .get_many("scope")
+                        .map(|scopes| scopes.cloned().collect())
+                        .unwrap_or_default();
+                    match bundle::export(config, bundle, key, &scopes) {
+                        Ok(summary) => {
+                            println!("Exported {summary} to {}", bundle.display());
+                            Ok(())
+                        }
+                        Err(err) => {
+                            eprintln!("Unable to export: {err}");
+                            std::process::exit(-3);
+                        }
+                    }
+                }
+                Some((IMPORT_COMMAND, import_matches)) => {
+                    let bundle: &PathBuf = import_matches.get_one("bundle").unwrap();
+                    let public_key: &PathBuf = import_matches.get_one("public-key").unwrap();
+                    match bundle::import(config, bundle, public_key) {
+                        Ok(summary) => {
+                            println!("Imported {summary} from {}", bundle.display());
+                            Ok(())
+                        }
+                        Err(err) => {
+                            eprintln!("Unable to import: {err}");
+                            std::process::exit(-3);
+                        }
+                    }
+                }
+                _ => {
+                    let proxy: Proxy = Proxy::new(config);
+                    proxy.run().await
+                }
+            },
             Err(err) => {
                 eprintln!("Unable to read the configuration file {err:?}");
                 std::process::exit(-1);
@@ -1,15 +1,58 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use url::Url;
 
-#[derive(Serialize)]
+use crate::sigstore::bundle::Identity;
+
+/// Verified build provenance of an artifact.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Provenance {
+    /// The build platform, such as a GitHub Actions runner
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub builder: Option<String>,
+    #[serde(flatten)]
+    pub identity: Identity,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Context {
     purl: String,
     url: String,
     hash: String,
+    /// The upstream which served the artifact, when a repository has several
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upstream: Option<String>,
+    /// Ids of the registry keys whose signature of the artifact was verified
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    signatures: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    provenance: Option<Provenance>,
 }
 
 impl Context {
     pub fn new(purl: String, url: String, hash: String) -> Context {
-        Context { purl, url, hash }
+        Context {
+            purl,
+            url,
+            hash,
+            upstream: None,
+            signatures: Vec::new(),
+            provenance: None,
+        }
+    }
+
+    pub fn with_upstream(mut self, upstream: &Url) -> Context {
+        self.upstream = Some(upstream.to_string());
+        self
+    }
+
+    pub fn with_signatures(mut self, signatures: Vec<String>) -> Context {
+        self.signatures = signatures;
+        self
+    }
+
+    pub fn with_provenance(mut self, provenance: Option<Provenance>) -> Context {
+        self.provenance = provenance;
+        self
     }
 
     pub fn url(&self) -> &str {
@@ -19,6 +62,14 @@ impl Context {
     pub fn purl(&self) -> &str {
         &self.purl
     }
+
+    pub fn signatures(&self) -> &[String] {
+        &self.signatures
+    }
+
+    pub fn provenance(&self) -> Option<&Provenance> {
+        self.provenance.as_ref()
+    }
 }
 
 #[cfg(test)]
@@ -31,6 +82,9 @@ mod test {
             purl: "pkg:cargo/crate@0.1.0".into(),
             url: "http://crates.io/not/a/real/crate.crate".into(),
             hash: "8675309".into(),
+            upstream: None,
+            signatures: Vec::new(),
+            provenance: None,
         };
 
         let json = serde_json::to_string(&context).unwrap();
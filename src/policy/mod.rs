@@ -2,12 +2,17 @@ use crate::config::policy::PolicyConfig;
 use crate::policy::context::Context;
 use actix_web::{error::ErrorInternalServerError, HttpResponse, HttpResponseBuilder};
 use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub mod context;
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+/// Header added to every response rejected by the policy server
+pub const POLICY_HEADER: &str = "x-seedwing-policy";
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Default)]
 pub enum Decision {
     #[serde(rename = "disable")]
+    #[default]
     Disable,
     #[serde(rename = "warn")]
     Warn,
@@ -15,9 +20,51 @@ pub enum Decision {
     Enforce,
 }
 
-impl Default for Decision {
-    fn default() -> Self {
-        Self::Disable
+/// Result of evaluating a Context against the policy
+pub enum Verdict {
+    /// Policy checking is disabled or not required for the resource
+    Skipped,
+    Approved,
+    /// Rejected by the policy server while the decision is `warn`
+    Warned(String),
+    /// Rejected while the decision is `enforce`, with the response returned to the client
+    Denied(HttpResponse),
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Skipped,
+    Approved,
+    Warned,
+}
+
+/// A verdict recorded with a cached artifact
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PolicyVerdict {
+    outcome: Outcome,
+    decision: Decision,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    /// Seconds since the unix epoch
+    evaluated_at: u64,
+}
+
+impl PolicyVerdict {
+    pub fn outcome(&self) -> Outcome {
+        self.outcome
+    }
+
+    pub fn decision(&self) -> Decision {
+        self.decision
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+
+    pub fn evaluated_at(&self) -> u64 {
+        self.evaluated_at
     }
 }
 
@@ -45,14 +92,26 @@ impl PolicyEngine {
         context: &Context,
         extension: Option<&str>,
     ) -> Result<Option<HttpResponse>, actix_web::Error> {
+        match self.check(context, extension).await? {
+            Verdict::Denied(response) => Ok(Some(response)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Query the policy server, returning the outcome so it can be recorded alongside the artifact
+    pub async fn check(
+        &self,
+        context: &Context,
+        extension: Option<&str>,
+    ) -> Result<Verdict, actix_web::Error> {
         if let Decision::Disable = self.config.decision() {
             // short-circuit if policy checking is disabled
-            return Ok(None);
+            return Ok(Verdict::Skipped);
         }
         if let Some(ext) = extension {
             if !context.url().ends_with(&format!(".{ext}")) {
                 // short-circuit for any resource without required extension
-                return Ok(None);
+                return Ok(Verdict::Skipped);
             }
         }
         log::debug!("purl: {}", context.purl());
@@ -65,7 +124,7 @@ impl PolicyEngine {
         {
             Ok(mut response) => {
                 if response.status().is_success() {
-                    Ok(None)
+                    Ok(Verdict::Approved)
                 } else {
                     match response.body().await {
                         Ok(payload) => {
@@ -80,9 +139,10 @@ impl PolicyEngine {
                                 for header in response.headers().iter() {
                                     result.insert_header(header);
                                 }
-                                Ok(Some(result.body(payload)))
+                                result.insert_header((POLICY_HEADER, "denied"));
+                                Ok(Verdict::Denied(result.body(payload)))
                             } else {
-                                Ok(None)
+                                Ok(Verdict::Warned(reason))
                             }
                         }
                         Err(e) => Err(actix_web::Error::from(e)),
@@ -95,4 +155,23 @@ impl PolicyEngine {
             }
         }
     }
+
+    /// The verdict to store with a cached artifact, None when the artifact was denied
+    pub fn record(&self, verdict: &Verdict) -> Option<PolicyVerdict> {
+        let (outcome, reason) = match verdict {
+            Verdict::Skipped => (Outcome::Skipped, None),
+            Verdict::Approved => (Outcome::Approved, None),
+            Verdict::Warned(reason) => (Outcome::Warned, Some(reason.clone())),
+            Verdict::Denied(_) => return None,
+        };
+        let evaluated_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        Some(PolicyVerdict {
+            outcome,
+            decision: self.config.decision(),
+            reason,
+            evaluated_at,
+        })
+    }
 }
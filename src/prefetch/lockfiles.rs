@@ -0,0 +1,501 @@
+/*
+ Parsers for the lockfiles and dependency lists understood by the prefetch command.
+
+ Only packages resolved from a registry are returned, path, git and workspace dependencies are
+ skipped as they cannot be served by the proxy.
+*/
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::config::repositories::RepositoryType;
+use crate::errors::{Error, Result};
+
+/// The names of the formats which can be given to the prefetch command.
+pub const FORMAT_NAMES: [&str; 5] = ["cargo", "npm", "yarn", "gemfile", "maven"];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LockfileFormat {
+    Cargo,
+    PackageLock,
+    Yarn,
+    Gemfile,
+    Maven,
+}
+
+impl LockfileFormat {
+    /// Detect the format from the name of the file. The output of `mvn dependency:list` has no
+    /// conventional name, so its format has to be given.
+    pub fn detect(lockfile: &Path) -> Result<Self> {
+        match lockfile.file_name().and_then(|name| name.to_str()) {
+            Some("Cargo.lock") => Ok(Self::Cargo),
+            Some("package-lock.json") | Some("npm-shrinkwrap.json") => Ok(Self::PackageLock),
+            Some("yarn.lock") => Ok(Self::Yarn),
+            Some("Gemfile.lock") | Some("gems.locked") => Ok(Self::Gemfile),
+            _ => Err(Error::Other {
+                message: format!(
+                    "Unknown lockfile {}, its format has to be given with --format",
+                    lockfile.display()
+                ),
+            }),
+        }
+    }
+
+    /// The format named by the `--format` flag, one of [`FORMAT_NAMES`].
+    pub fn named(name: &str) -> Option<Self> {
+        match name {
+            "cargo" => Some(Self::Cargo),
+            "npm" => Some(Self::PackageLock),
+            "yarn" => Some(Self::Yarn),
+            "gemfile" => Some(Self::Gemfile),
+            "maven" => Some(Self::Maven),
+            _ => None,
+        }
+    }
+
+    /// Whether a repository of the given type can serve packages from this lockfile.
+    pub fn is_served_by(&self, repository_type: RepositoryType) -> bool {
+        match self {
+            Self::Cargo => matches!(
+                repository_type,
+                RepositoryType::Crates | RepositoryType::Cargo | RepositoryType::SparseCrates
+            ),
+            Self::PackageLock | Self::Yarn => repository_type == RepositoryType::Npm,
+            Self::Gemfile => repository_type == RepositoryType::Gems,
+            Self::Maven => matches!(
+                repository_type,
+                RepositoryType::M2 | RepositoryType::M2Group
+            ),
+        }
+    }
+
+    pub fn parse(&self, contents: &str) -> Result<Vec<Package>> {
+        let mut packages = match self {
+            Self::Cargo => parse_cargo_lock(contents),
+            Self::PackageLock => parse_package_lock(contents),
+            Self::Yarn => Ok(parse_yarn_lock(contents)),
+            Self::Gemfile => Ok(parse_gemfile_lock(contents)),
+            Self::Maven => Ok(parse_maven_dependency_list(contents)),
+        }?;
+        let mut seen = BTreeSet::new();
+        packages.retain(|package| seen.insert(package.clone()));
+        Ok(packages)
+    }
+}
+
+/// A package resolved by a lockfile. Maven packages use `group:artifact` as their name.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    pub classifier: Option<String>,
+    pub packaging: Option<String>,
+}
+
+impl Package {
+    fn new(name: &str, version: &str) -> Self {
+        Self {
+            name: String::from(name),
+            version: String::from(version),
+            classifier: None,
+            packaging: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CargoLock {
+    #[serde(default)]
+    package: Vec<CargoLockPackage>,
+}
+
+#[derive(Deserialize)]
+struct CargoLockPackage {
+    name: String,
+    version: String,
+    source: Option<String>,
+}
+
+fn parse_cargo_lock(contents: &str) -> Result<Vec<Package>> {
+    let lock: CargoLock = toml::from_str(contents).map_err(|e| Error::Other {
+        message: format!("Unable to parse Cargo.lock: {e}"),
+    })?;
+    Ok(lock
+        .package
+        .into_iter()
+        .filter(|package| {
+            package.source.as_ref().is_some_and(|source| {
+                source.starts_with("registry+") || source.starts_with("sparse+")
+            })
+        })
+        .map(|package| Package::new(&package.name, &package.version))
+        .collect())
+}
+
+fn is_registry_tarball(resolved: Option<&Value>) -> bool {
+    match resolved.and_then(Value::as_str) {
+        Some(resolved) => {
+            (resolved.starts_with("https://") || resolved.starts_with("http://"))
+                && resolved.ends_with(".tgz")
+        }
+        // Older lockfiles omit the resolved URL for registry packages
+        None => true,
+    }
+}
+
+fn collect_package_lock_v1(
+    dependencies: &serde_json::Map<String, Value>,
+    packages: &mut Vec<Package>,
+) {
+    for (name, dependency) in dependencies {
+        if let Some(version) = dependency.get("version").and_then(Value::as_str) {
+            if is_registry_tarball(dependency.get("resolved")) && !version.contains(':') {
+                packages.push(Package::new(name, version));
+            }
+        }
+        if let Some(nested) = dependency.get("dependencies").and_then(Value::as_object) {
+            collect_package_lock_v1(nested, packages);
+        }
+    }
+}
+
+fn parse_package_lock(contents: &str) -> Result<Vec<Package>> {
+    let lock: Value = serde_json::from_str(contents).map_err(|e| Error::Other {
+        message: format!("Unable to parse package-lock.json: {e}"),
+    })?;
+    let mut packages = Vec::new();
+    if let Some(entries) = lock.get("packages").and_then(Value::as_object) {
+        for (path, entry) in entries {
+            let name = match entry.get("name").and_then(Value::as_str) {
+                Some(name) => name,
+                None => match path.rsplit_once("node_modules/") {
+                    Some((_, name)) => name,
+                    None => continue,
+                },
+            };
+            if entry.get("link").and_then(Value::as_bool).unwrap_or(false)
+                || !path.contains("node_modules/")
+            {
+                continue;
+            }
+            if let Some(version) = entry.get("version").and_then(Value::as_str) {
+                if is_registry_tarball(entry.get("resolved")) {
+                    packages.push(Package::new(name, version));
+                }
+            }
+        }
+    } else if let Some(dependencies) = lock.get("dependencies").and_then(Value::as_object) {
+        collect_package_lock_v1(dependencies, &mut packages);
+    }
+    Ok(packages)
+}
+
+/// Name of the package from a yarn descriptor such as `@babel/core@^7.0.0` or `lodash@npm:^4`
+fn yarn_descriptor_name(descriptor: &str) -> Option<&str> {
+    let descriptor = descriptor.trim().trim_matches('"');
+    // The first character is skipped as it is the `@` of a scoped name
+    let (index, _) = descriptor.char_indices().skip(1).find(|(_, c)| *c == '@')?;
+    let (name, range) = (&descriptor[..index], &descriptor[index + 1..]);
+    match range.split_once(':') {
+        Some((protocol, _)) if protocol != "npm" => None,
+        _ => Some(name),
+    }
+}
+
+fn parse_yarn_lock(contents: &str) -> Vec<Package> {
+    let mut packages = Vec::new();
+    let mut name: Option<String> = None;
+    for line in contents.lines() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        if !line.starts_with(' ') {
+            name = line
+                .trim_end_matches(':')
+                .split(", ")
+                .next()
+                .and_then(yarn_descriptor_name)
+                .map(String::from);
+        } else if let Some(current) = &name {
+            let line = line.trim();
+            // yarn v1 uses `version "1.0.0"`, later versions use `version: 1.0.0`
+            if let Some(version) = line
+                .strip_prefix("version ")
+                .or_else(|| line.strip_prefix("version: "))
+            {
+                packages.push(Package::new(current, version.trim().trim_matches('"')));
+                name = None;
+            }
+        }
+    }
+    packages
+}
+
+fn parse_gemfile_lock(contents: &str) -> Vec<Package> {
+    let mut packages = Vec::new();
+    let mut in_gem_specs = false;
+    let mut in_gem_section = false;
+    for line in contents.lines() {
+        if !line.starts_with(' ') {
+            in_gem_section = line == "GEM";
+            in_gem_specs = false;
+        } else if in_gem_section && line.trim() == "specs:" {
+            in_gem_specs = true;
+        } else if in_gem_specs && line.starts_with("    ") && !line.starts_with("     ") {
+            // Only the resolved gems are indented by four spaces, their dependencies by six
+            if let Some((name, version)) = line.trim().split_once(" (") {
+                packages.push(Package::new(name, version.trim_end_matches(')')));
+            }
+        }
+    }
+    packages
+}
+
+/// Maven scopes, which end the coordinates listed by `mvn dependency:list` unless
+/// `-DoutputScope=false` is given.
+const MAVEN_SCOPES: [&str; 6] = ["compile", "provided", "runtime", "test", "system", "import"];
+
+/// Parse the output of `mvn dependency:list`, where each dependency is listed as
+/// `group:artifact:type[:classifier]:version[:scope]`, or a plain list of `group:artifact:version`.
+/// Coordinates which cannot be parsed are logged and skipped.
+fn parse_maven_dependency_list(contents: &str) -> Vec<Package> {
+    let mut packages = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim().trim_start_matches("[INFO]").trim();
+        let coordinates = line.split_whitespace().next().unwrap_or_default();
+        let parts: Vec<&str> = coordinates.split(':').collect();
+        if parts.iter().any(|part| part.is_empty()) {
+            continue;
+        }
+        let parts = match parts.split_last() {
+            Some((last, rest)) if parts.len() > 4 && MAVEN_SCOPES.contains(last) => rest,
+            _ => &parts,
+        };
+        let name = |parts: &[&str]| format!("{}:{}", parts[0], parts[1]);
+        let package = match parts.len() {
+            3 => Package::new(&name(parts), parts[2]),
+            4 => Package {
+                packaging: Some(String::from(parts[2])),
+                ..Package::new(&name(parts), parts[3])
+            },
+            5 => Package {
+                packaging: Some(String::from(parts[2])),
+                classifier: Some(String::from(parts[3])),
+                ..Package::new(&name(parts), parts[4])
+            },
+            _ => {
+                if parts.len() > 2 {
+                    log::warn!("Skipping unparsed Maven coordinates {coordinates}");
+                }
+                continue;
+            }
+        };
+        packages.push(package);
+    }
+    packages
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lockfile_formats() {
+        assert_eq!(
+            Some(LockfileFormat::Cargo),
+            LockfileFormat::detect(Path::new("project/Cargo.lock")).ok()
+        );
+        assert_eq!(
+            Some(LockfileFormat::Gemfile),
+            LockfileFormat::detect(Path::new("gems.locked")).ok()
+        );
+        // The output of mvn dependency:list has to be named
+        assert!(LockfileFormat::detect(Path::new("maven-dependencies.txt")).is_err());
+        for name in FORMAT_NAMES {
+            assert!(LockfileFormat::named(name).is_some(), "{name}");
+        }
+        assert_eq!(None, LockfileFormat::named("pom"));
+    }
+
+    #[test]
+    fn cargo_lock_registry_packages() {
+        let packages = LockfileFormat::Cargo
+            .parse(
+                r#"
+            version = 3
+
+            [[package]]
+            name = "seedwing-proxy"
+            version = "0.1.0-alpha.1"
+
+            [[package]]
+            name = "url"
+            version = "2.3.1"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+            checksum = "0d68c799ae75762b8c3fe375feb6600ef5602c883c5d21eb51c09f22b83c4643"
+
+            [[package]]
+            name = "git2"
+            version = "0.16.1"
+            source = "git+https://github.com/rust-lang/git2-rs#abcdef"
+        "#,
+            )
+            .unwrap();
+
+        assert_eq!(vec![Package::new("url", "2.3.1")], packages);
+    }
+
+    #[test]
+    fn package_lock_packages() {
+        let packages = LockfileFormat::PackageLock
+            .parse(
+                r#"{
+              "name": "app",
+              "lockfileVersion": 3,
+              "packages": {
+                "": { "name": "app", "version": "1.0.0" },
+                "node_modules/@babel/code-frame": {
+                  "version": "7.22.13",
+                  "resolved": "https://registry.npmjs.org/@babel/code-frame/-/code-frame-7.22.13.tgz"
+                },
+                "node_modules/a/node_modules/ms": {
+                  "version": "2.0.0",
+                  "resolved": "https://registry.npmjs.org/ms/-/ms-2.0.0.tgz"
+                },
+                "node_modules/local": { "resolved": "packages/local", "link": true },
+                "node_modules/forked": {
+                  "version": "1.0.0",
+                  "resolved": "git+ssh://git@github.com/example/forked.git#abcdef"
+                }
+              }
+            }"#,
+            )
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                Package::new("@babel/code-frame", "7.22.13"),
+                Package::new("ms", "2.0.0"),
+            ],
+            packages
+        );
+    }
+
+    #[test]
+    fn yarn_lock_packages() {
+        let packages = LockfileFormat::Yarn.parse(
+            r#"# THIS IS AN AUTOGENERATED FILE. DO NOT EDIT THIS FILE DIRECTLY.
+# yarn lockfile v1
+
+
+"@babel/code-frame@^7.0.0", "@babel/code-frame@^7.10.4":
+  version "7.12.13"
+  resolved "https://registry.yarnpkg.com/@babel/code-frame/-/code-frame-7.12.13.tgz#abcdef"
+  dependencies:
+    "@babel/highlight" "^7.12.13"
+
+lodash@^4.17.21:
+  version "4.17.21"
+
+"typescript@patch:typescript@^5.0.0#~builtin<compat/typescript>":
+  version: 5.2.2
+
+"ms@npm:2.1.2":
+  version: 2.1.2
+"#,
+        );
+
+        assert_eq!(
+            vec![
+                Package::new("@babel/code-frame", "7.12.13"),
+                Package::new("lodash", "4.17.21"),
+                Package::new("ms", "2.1.2"),
+            ],
+            packages.unwrap()
+        );
+        assert_eq!(Some("@scope/a"), yarn_descriptor_name("@scope/a@^1.0.0"));
+        assert_eq!(Some("é"), yarn_descriptor_name("é@1.0.0"));
+        assert_eq!(None, yarn_descriptor_name(""));
+        assert_eq!(None, yarn_descriptor_name("@"));
+        assert_eq!(None, yarn_descriptor_name("\"\""));
+    }
+
+    #[test]
+    fn gemfile_lock_packages() {
+        let packages = LockfileFormat::Gemfile.parse(
+            r#"GIT
+  remote: https://github.com/example/forked.git
+  specs:
+    forked (0.1.0)
+
+GEM
+  remote: https://rubygems.org/
+  specs:
+    mini_portile2 (2.8.4)
+    nokogiri (1.15.4-x86_64-linux)
+      racc (~> 1.4)
+    racc (1.7.1)
+
+PLATFORMS
+  x86_64-linux
+"#,
+        );
+
+        assert_eq!(
+            vec![
+                Package::new("mini_portile2", "2.8.4"),
+                Package::new("nokogiri", "1.15.4-x86_64-linux"),
+                Package::new("racc", "1.7.1"),
+            ],
+            packages.unwrap()
+        );
+    }
+
+    #[test]
+    fn maven_dependency_list_packages() {
+        let packages = LockfileFormat::Maven.parse(
+            r#"
+The following files have been resolved:
+   org.slf4j:slf4j-api:jar:1.7.36:compile
+   com.google.guava:guava:jar:31.1-jre:compile -- module com.google.common
+   io.netty:netty-transport-native-epoll:jar:linux-x86_64:4.1.94.Final:runtime
+   junit:junit:4.13.2
+   org.apache.commons:commons-lang3:jar:3.12.0
+   io.netty:netty-codec:jar:linux-aarch_64:4.1.94.Final
+   a:b:c:d:e:f:g
+"#,
+        );
+
+        assert_eq!(
+            vec![
+                Package {
+                    packaging: Some("jar".into()),
+                    ..Package::new("org.slf4j:slf4j-api", "1.7.36")
+                },
+                Package {
+                    packaging: Some("jar".into()),
+                    ..Package::new("com.google.guava:guava", "31.1-jre")
+                },
+                Package {
+                    packaging: Some("jar".into()),
+                    classifier: Some("linux-x86_64".into()),
+                    ..Package::new("io.netty:netty-transport-native-epoll", "4.1.94.Final")
+                },
+                Package::new("junit:junit", "4.13.2"),
+                Package {
+                    packaging: Some("jar".into()),
+                    ..Package::new("org.apache.commons:commons-lang3", "3.12.0")
+                },
+                Package {
+                    packaging: Some("jar".into()),
+                    classifier: Some("linux-aarch_64".into()),
+                    ..Package::new("io.netty:netty-codec", "4.1.94.Final")
+                },
+            ],
+            packages.unwrap()
+        );
+    }
+}
@@ -0,0 +1,409 @@
+/*
+ Prefetch module used to warm the local cache from lockfiles on a connected machine.
+
+ Every artifact is requested through the same repository handlers the proxy serves, so policy is
+ evaluated and approved artifacts are cached exactly as they would be for a real client. Anything
+ rejected by the policy server is collected into a report before a build ever runs.
+*/
+
+use std::{
+    collections::BTreeSet,
+    fmt::{Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use actix_web::{rt, App, HttpServer};
+use awc::Client;
+use futures::StreamExt;
+use serde::Serialize;
+
+use crate::config::repositories::RepositoryType;
+use crate::config::Config;
+use crate::errors::{Error, Result};
+use crate::policy::{Decision, POLICY_HEADER};
+use crate::proxy::{Proxy, API_PATH, INDEX_PATH};
+use crate::repositories::crates::sparse::index_path;
+
+use self::lockfiles::{LockfileFormat, Package};
+
+pub mod lockfiles;
+
+/// A request made through the repository handlers for a single package.
+struct PrefetchRequest {
+    package: String,
+    uri: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReportEntry {
+    package: String,
+    uri: String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct Report {
+    fetched: Vec<ReportEntry>,
+    denied: Vec<ReportEntry>,
+    failed: Vec<ReportEntry>,
+}
+
+impl Report {
+    /// True when every package was fetched and approved.
+    pub fn is_clean(&self) -> bool {
+        self.denied.is_empty() && self.failed.is_empty()
+    }
+
+    pub fn write(&self, report_file: &Path) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self).map_err(|e| Error::Other {
+            message: format!("Unable to serialize the prefetch report: {e}"),
+        })?;
+        fs::write(report_file, json)?;
+        Ok(())
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Fetched {} artifacts", self.fetched.len())?;
+        for entry in &self.denied {
+            writeln!(f, "DENIED  {} ({})", entry.package, entry.status)?;
+            if let Some(reason) = &entry.reason {
+                for line in reason.lines() {
+                    writeln!(f, "        {line}")?;
+                }
+            }
+        }
+        for entry in &self.failed {
+            writeln!(
+                f,
+                "FAILED  {} {} ({})",
+                entry.package, entry.uri, entry.status
+            )?;
+        }
+        write!(
+            f,
+            "{} denied, {} failed",
+            self.denied.len(),
+            self.failed.len()
+        )
+    }
+}
+
+fn find_scope(
+    config: &Config,
+    format: LockfileFormat,
+    scope: Option<&str>,
+) -> Result<(String, RepositoryType)> {
+    let candidates: Vec<_> = config
+        .repositories()
+        .iter()
+        .filter(|(_, repository)| format.is_served_by(repository.repository_type()))
+        .collect();
+    let found = match scope {
+        Some(scope) => candidates
+            .iter()
+            .find(|(name, _)| scope == name.as_str())
+            .ok_or_else(|| Error::Other {
+                message: format!("No repository {scope} which can serve {format:?} packages"),
+            })?,
+        None => candidates.first().ok_or_else(|| Error::Other {
+            message: format!("No repository configured which can serve {format:?} packages"),
+        })?,
+    };
+    Ok((found.0.to_string(), found.1.repository_type()))
+}
+
+fn npm_tarball(name: &str, version: &str) -> String {
+    let basename = name.rsplit_once('/').map_or(name, |(_, basename)| basename);
+    format!("{name}/-/{basename}-{version}.tgz")
+}
+
+fn maven_files(package: &Package) -> Vec<String> {
+    let (group, artifact) = package.name.split_once(':').unwrap_or(("", &package.name));
+    let version = &package.version;
+    let dir = format!("{}/{artifact}/{version}", group.replace('.', "/"));
+    let mut files = vec![format!("{dir}/{artifact}-{version}.pom")];
+    let (classifier, extension) = match package.packaging.as_deref() {
+        Some("pom") => return files,
+        Some("test-jar") => (Some("tests"), "jar"),
+        None | Some("bundle") | Some("maven-plugin") | Some("ejb") => {
+            (package.classifier.as_deref(), "jar")
+        }
+        Some(other) => (package.classifier.as_deref(), other),
+    };
+    match classifier {
+        Some(classifier) => files.push(format!(
+            "{dir}/{artifact}-{version}-{classifier}.{extension}"
+        )),
+        None => files.push(format!("{dir}/{artifact}-{version}.{extension}")),
+    }
+    files
+}
+
+/// Requests needed to serve the packages offline: index metadata followed by the artifacts.
+fn requests(
+    scope: &str,
+    repository_type: RepositoryType,
+    packages: &[Package],
+) -> Vec<PrefetchRequest> {
+    let request = |package: &str, uri: String| PrefetchRequest {
+        package: format!("{scope}:{package}"),
+        uri,
+    };
+    let mut requests = Vec::new();
+    let names: BTreeSet<&str> = packages
+        .iter()
+        .map(|package| package.name.as_str())
+        .collect();
+    match repository_type {
+        RepositoryType::Crates | RepositoryType::Cargo => {
+            // Ensure the index mirror has been created before going offline
+            requests.push(request(
+                "index",
+                format!("/{scope}/info/refs?service=git-upload-pack"),
+            ));
+        }
+        RepositoryType::SparseCrates => {
+            // Invalid crate names have no index file, their downloads are reported as failed
+            let index_files: BTreeSet<String> =
+                names.iter().filter_map(|name| index_path(name)).collect();
+            for index_file in index_files {
+                requests.push(request(
+                    &index_file,
+                    format!("/{scope}{INDEX_PATH}/{index_file}"),
+                ));
+            }
+        }
+        RepositoryType::Npm => {
+            for name in &names {
+                requests.push(request(name, format!("/{scope}/{name}")));
+            }
+        }
+        RepositoryType::Gems => {
+            requests.push(request("versions", format!("/{scope}/versions")));
+            for name in &names {
+                requests.push(request(name, format!("/{scope}/info/{name}")));
+            }
+        }
+        RepositoryType::M2
+        | RepositoryType::M2Group
+        | RepositoryType::Pypi
+        | RepositoryType::Go
+        | RepositoryType::Oci => {}
+    }
+    for package in packages {
+        let label = format!("{}@{}", package.name, package.version);
+        match repository_type {
+            RepositoryType::Crates | RepositoryType::Cargo | RepositoryType::SparseCrates => {
+                requests.push(request(
+                    &label,
+                    format!(
+                        "/{scope}{API_PATH}/crates/{}/{}/download",
+                        package.name, package.version
+                    ),
+                ))
+            }
+            RepositoryType::Npm => requests.push(request(
+                &label,
+                format!("/{scope}/{}", npm_tarball(&package.name, &package.version)),
+            )),
+            RepositoryType::Gems => requests.push(request(
+                &label,
+                format!("/{scope}/gems/{}-{}.gem", package.name, package.version),
+            )),
+            RepositoryType::Pypi | RepositoryType::Go | RepositoryType::Oci => {}
+            RepositoryType::M2 | RepositoryType::M2Group => {
+                for file in maven_files(package) {
+                    requests.push(request(&label, format!("/{scope}/{file}")));
+                }
+            }
+        }
+    }
+    requests
+}
+
+/// Request a package from the proxy, returning the status, whether the policy denied it and
+/// the reason it could not be fetched. The whole artifact is read so that the proxy caches it.
+async fn fetch(client: &Client, url: &str) -> (u16, bool, Option<String>) {
+    let mut response = match client.get(url).send().await {
+        Ok(response) => response,
+        Err(error) => return (502, false, Some(error.to_string())),
+    };
+    let status = response.status();
+    let denied = response.headers().contains_key(POLICY_HEADER);
+    let mut payload = Vec::new();
+    while let Some(chunk) = response.next().await {
+        match chunk {
+            Ok(chunk) if !status.is_success() => payload.extend_from_slice(&chunk),
+            Ok(_) => {}
+            Err(error) => return (502, denied, Some(error.to_string())),
+        }
+    }
+    let reason = (!payload.is_empty() && !status.is_success())
+        .then(|| String::from_utf8_lossy(&payload).into_owned());
+    (status.as_u16(), denied, reason)
+}
+
+/// Fetch every package listed in the lockfiles through the repository handlers, caching the
+/// approved artifacts and reporting those which were denied or could not be fetched. The format
+/// of the lockfiles is detected from their names unless given.
+pub async fn prefetch(
+    mut config: Config,
+    scope: Option<&str>,
+    format: Option<LockfileFormat>,
+    lockfiles: &[PathBuf],
+) -> Result<Report> {
+    config.force_online();
+    if config.policy().decision() == Decision::Warn {
+        // Denied artifacts must not end up in the cache, even if the proxy only warns about them
+        *config.policy_mut().decision_mut() = Decision::Enforce;
+    }
+
+    let mut all_requests = Vec::new();
+    for lockfile in lockfiles {
+        let format = match format {
+            Some(format) => format,
+            None => LockfileFormat::detect(lockfile)?,
+        };
+        let packages = format.parse(&fs::read_to_string(lockfile)?)?;
+        let (scope, repository_type) = find_scope(&config, format, scope)?;
+        log::info!(
+            "Prefetching {} packages from {} into scope {scope}",
+            packages.len(),
+            lockfile.display()
+        );
+        all_requests.extend(requests(&scope, repository_type, &packages));
+    }
+
+    let mut proxy = Proxy::new(config);
+    proxy.init_repositories();
+    // Requests go through a server on a loopback port, exactly as a client would send them
+    let server = HttpServer::new(move || App::new().configure(|cfg| proxy.configure(cfg)))
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))?;
+    let address = server.addrs()[0];
+    let server = server.run();
+    let handle = server.handle();
+    rt::spawn(server);
+
+    let client = Client::builder().disable_timeout().finish();
+    let mut report = Report::default();
+    for PrefetchRequest { package, uri } in all_requests {
+        let (status, denied, reason) = fetch(&client, &format!("http://{address}{uri}")).await;
+        let entry = ReportEntry {
+            package,
+            uri,
+            status,
+            reason,
+        };
+        if (200..300).contains(&status) {
+            log::info!("Fetched {}", entry.uri);
+            report.fetched.push(entry);
+        } else if denied {
+            log::warn!("Policy denied {}", entry.package);
+            report.denied.push(entry);
+        } else {
+            log::warn!("Failed to fetch {}: {status}", entry.uri);
+            report.failed.push(entry);
+        }
+    }
+    handle.stop(true).await;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lockfile_scopes() {
+        let config = Config::new(
+            r#"
+            [policy]
+            url = 'http://localhost:8080/'
+
+            [repositories.npm]
+            type = "npm"
+            url = "https://registry.npmjs.org/"
+
+            [repositories.maven]
+            type = "m2"
+            url = "https://repo.maven.apache.org/maven2/"
+        "#
+            .as_bytes(),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let scope = |format, scope| find_scope(&config, format, scope).map(|(scope, _)| scope);
+        assert_eq!("npm", scope(LockfileFormat::Yarn, None).unwrap());
+        assert_eq!(
+            "maven",
+            scope(LockfileFormat::Maven, Some("maven")).unwrap()
+        );
+        // A scope which cannot serve the lockfile is not silently replaced
+        assert!(scope(LockfileFormat::Maven, Some("npm")).is_err());
+        assert!(scope(LockfileFormat::Maven, Some("mvn")).is_err());
+        assert!(scope(LockfileFormat::Cargo, None).is_err());
+    }
+
+    #[test]
+    fn package_requests() {
+        let uris = |repository_type, packages: &[Package]| -> Vec<String> {
+            requests("scope", repository_type, packages)
+                .into_iter()
+                .map(|request| request.uri)
+                .collect()
+        };
+        let package = |name: &str, version: &str| Package {
+            name: name.into(),
+            version: version.into(),
+            classifier: None,
+            packaging: None,
+        };
+
+        assert_eq!(
+            vec![
+                "/scope/index/ur/l-/url-parse",
+                "/scope/api/v1/crates/url-parse/1.0.0/download",
+            ],
+            uris(
+                RepositoryType::SparseCrates,
+                &[package("url-parse", "1.0.0")]
+            )
+        );
+        assert_eq!(
+            vec!["/scope/api/v1/crates/ünïcode/1.0.0/download"],
+            uris(RepositoryType::SparseCrates, &[package("ünïcode", "1.0.0")])
+        );
+        assert_eq!(
+            vec![
+                "/scope/@babel/core",
+                "/scope/@babel/core/-/core-7.22.0-beta.1.tgz",
+            ],
+            uris(
+                RepositoryType::Npm,
+                &[package("@babel/core", "7.22.0-beta.1")]
+            )
+        );
+        assert_eq!(
+            vec![
+                "/scope/org/slf4j/slf4j-api/1.7.36/slf4j-api-1.7.36.pom",
+                "/scope/org/slf4j/slf4j-api/1.7.36/slf4j-api-1.7.36-sources.jar",
+            ],
+            uris(
+                RepositoryType::M2,
+                &[Package {
+                    classifier: Some("sources".into()),
+                    ..package("org.slf4j:slf4j-api", "1.7.36")
+                }]
+            )
+        );
+    }
+}
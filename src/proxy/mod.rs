@@ -1,19 +1,25 @@
 use url::Url;
 
-use crate::config::repositories::RepositoryType;
+use crate::cache::ArtifactCache;
+use crate::config::repositories::{RepositoryConfig, RepositoryType};
 use crate::config::Config;
 use crate::policy::PolicyEngine;
 use crate::repositories::crates::git::IndexRepository;
 use crate::repositories::crates::sparse::SparseRepository;
-use crate::{repositories, ui};
+use crate::sigstore::trust::TrustedRoot;
+use crate::{admin, repositories, ui};
 use actix_web::middleware::Logger;
 use actix_web::{web, App, HttpServer};
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-const INDEX_PATH: &str = "/index";
-const API_PATH: &str = "/api/v1";
+pub(crate) const INDEX_PATH: &str = "/index";
+pub(crate) const API_PATH: &str = "/api/v1";
 
+const ARTIFACTS_DIR: &str = "artifacts";
+
+#[derive(Clone)]
 pub struct Proxy {
     config: Config,
     crate_repositories: HashMap<String, IndexRepository>,
@@ -29,33 +35,24 @@ impl Proxy {
         }
     }
 
-    pub async fn run(mut self) -> Result<(), std::io::Error> {
-        let bind_args: (String, u16) = self.config.proxy().into();
-
-        log::info!("========================================================================");
-        log::info!("Policy server {}", self.config.policy().url());
-        log::info!("------------------------------------------------------------------------");
-
-        for (scope, config) in self.config.repositories().iter() {
-            log::info!(
-                "{} endpoint at http://{}:{}/{scope}/",
-                config.repository_type(),
-                bind_args.0,
-                bind_args.1
-            );
-        }
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
 
+    pub fn init_repositories(&mut self) {
+        let bind_args: (String, u16) = self.config.proxy().into();
         let base_cache_dir = self.config.proxy().expanded_cache_dir();
 
         for (scope, config) in self.config.repositories().iter() {
+            let offline = self.is_offline(config);
             match config.repository_type() {
-                RepositoryType::Crates => {
+                RepositoryType::Crates | RepositoryType::Cargo => {
                     log::info!(
                         "------------------------------------------------------------------------"
                     );
                     log::info!("Initialising Crate Repository for scope {scope}");
                     let index_repository = IndexRepository::new(
-                        config.url(),
+                        config,
                         self.get_cache_dir(&base_cache_dir, scope, &bind_args.0, bind_args.1),
                         self.get_url(
                             scope,
@@ -64,7 +61,7 @@ impl Proxy {
                             Some(&format!("{API_PATH}/crates")),
                         ),
                         self.get_url(scope, &bind_args.0, bind_args.1, None),
-                        config.periodic_update(),
+                        offline,
                     );
                     log::info!(
                         "    Crate repository       : {}",
@@ -86,6 +83,16 @@ impl Proxy {
                         "    Periodic Update        : {}",
                         index_repository.get_periodic_update()
                     );
+                    log::info!(
+                        "    Update On Fetch        : {}",
+                        index_repository.get_update_on_fetch()
+                    );
+                    log::info!(
+                        "    Squash                 : {}",
+                        index_repository.get_squash()
+                    );
+                    log::info!("    Sparse                 : {}", config.sparse());
+                    log::info!("    Offline                : {offline}");
                     self.crate_repositories
                         .insert(scope.to_string(), index_repository);
                     log::info!(
@@ -121,59 +128,185 @@ impl Proxy {
                         "    Index Prefix           : {}",
                         sparse_repository.get_index_prefix()
                     );
+                    log::info!("    Offline                : {offline}");
                     self.crate_sparse_repositories
                         .insert(scope.to_string(), sparse_repository);
                     log::info!(
                         "------------------------------------------------------------------------"
                     );
                 }
-                RepositoryType::M2 | RepositoryType::Npm | RepositoryType::Gems => {}
+                RepositoryType::M2
+                | RepositoryType::M2Group
+                | RepositoryType::Npm
+                | RepositoryType::Gems
+                | RepositoryType::Pypi
+                | RepositoryType::Go
+                | RepositoryType::Oci => {}
             }
         }
+    }
 
-        let server = HttpServer::new(move || {
-            let mut app =
-                App::new()
-                    .wrap(Logger::default())
-                    .app_data(web::Data::new(PolicyEngine::new(
-                        self.config.policy().clone(),
-                    )));
-
-            for service in self.config.repositories().iter().map(|(scope, config)| {
-                match config.repository_type() {
-                    RepositoryType::Crates => {
-                        let index_repository = self.crate_repositories.get(scope).unwrap();
-                        let git_cmd = &self.config.proxy().git_cmd();
-                        repositories::crates::service(
-                            scope,
-                            git_cmd,
-                            index_repository.clone(),
-                            API_PATH,
-                        )
-                    }
-                    RepositoryType::M2 => repositories::maven::service(scope, config.url()),
-                    RepositoryType::SparseCrates => {
-                        let sparse_repository = self.crate_sparse_repositories.get(scope).unwrap();
-                        repositories::crates::service_sparse(
-                            scope,
-                            sparse_repository.clone(),
-                            INDEX_PATH,
-                            API_PATH,
-                        )
-                    }
-                    RepositoryType::Npm => repositories::npm::service(scope, config.url()),
-                    RepositoryType::Gems => repositories::gems::service(scope, config.url()),
+    /// Register the policy engine and the repository services, shared by the HTTP server and
+    /// any in-process clients such as the prefetch command.
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        let base_cache_dir = self.config.proxy().expanded_cache_dir();
+
+        cfg.app_data(web::Data::new(PolicyEngine::new(
+            self.config.policy().clone(),
+        )));
+
+        let trust = self
+            .config
+            .sigstore()
+            .trusted_root()
+            .and_then(|path| match TrustedRoot::load(path) {
+                Ok(trust) => Some(Arc::new(trust)),
+                Err(e) => {
+                    log::error!("Unable to load the trusted root {}: {e}", path.display());
+                    None
+                }
+            });
+
+        for (scope, config) in self.config.repositories().iter() {
+            let cache = self.get_artifact_cache(&base_cache_dir, scope);
+            let offline = self.is_offline(config);
+            match config.repository_type() {
+                RepositoryType::Crates | RepositoryType::Cargo => {
+                    let index_repository = self.crate_repositories.get(scope).unwrap();
+                    cfg.service(repositories::crates::service(
+                        scope,
+                        index_repository.clone(),
+                        cache,
+                        API_PATH,
+                        config.sparse().then_some(INDEX_PATH),
+                    ));
+                }
+                RepositoryType::M2 | RepositoryType::M2Group => {
+                    cfg.service(repositories::maven::service(scope, config, cache, offline));
+                }
+                RepositoryType::SparseCrates => {
+                    let sparse_repository = self.crate_sparse_repositories.get(scope).unwrap();
+                    cfg.service(repositories::crates::service_sparse(
+                        scope,
+                        sparse_repository.clone(),
+                        cache,
+                        offline,
+                        INDEX_PATH,
+                        API_PATH,
+                    ));
+                }
+                RepositoryType::Npm => {
+                    cfg.service(repositories::npm::service(
+                        scope,
+                        config,
+                        cache,
+                        offline,
+                        trust.clone(),
+                    ));
+                }
+                RepositoryType::Gems => {
+                    cfg.service(repositories::gems::service(
+                        scope,
+                        config.url(),
+                        cache,
+                        offline,
+                    ));
+                }
+                RepositoryType::Pypi => {
+                    cfg.service(repositories::pypi::service(
+                        scope,
+                        config.url(),
+                        cache,
+                        offline,
+                    ));
+                }
+                RepositoryType::Go => {
+                    cfg.service(repositories::go::service(scope, config, cache, offline));
+                }
+                RepositoryType::Oci => {
+                    cfg.service(repositories::oci::service(
+                        scope,
+                        config,
+                        cache,
+                        offline,
+                        trust.clone(),
+                    ));
                 }
-            }) {
-                app = app.service(service)
             }
+        }
+
+        let oci = self
+            .config
+            .repositories()
+            .iter()
+            .any(|(_, config)| config.repository_type() == RepositoryType::Oci);
+        if oci {
+            cfg.service(repositories::oci::api_version);
+        }
+    }
 
-            app.service(ui::service(self.config.clone()))
+    pub async fn run(mut self) -> Result<(), std::io::Error> {
+        let bind_args: (String, u16) = self.config.proxy().into();
+
+        log::info!("========================================================================");
+        log::info!("Policy server {}", self.config.policy().url());
+        log::info!("------------------------------------------------------------------------");
+
+        for (scope, config) in self.config.repositories().iter() {
+            log::info!(
+                "{} endpoint at http://{}:{}/{scope}/",
+                config.repository_type(),
+                bind_args.0,
+                bind_args.1
+            );
+        }
+
+        self.init_repositories();
+        let indexes: Vec<_> = self.crate_repositories.values().cloned().collect();
+
+        let server = HttpServer::new(move || {
+            App::new()
+                .wrap(Logger::default())
+                .configure(|cfg| self.configure(cfg))
+                .configure(|cfg| {
+                    if let Some(token) = self.config.admin().token() {
+                        cfg.service(admin::service(
+                            token.to_string(),
+                            self.crate_repositories.clone(),
+                        ));
+                    }
+                })
+                .service(ui::service(self.config.clone()))
         });
 
         log::info!("seedwing at http://{}:{}/", bind_args.0, bind_args.1);
         log::info!("========================================================================");
-        server.bind(bind_args)?.run().await
+        let result = server.bind(bind_args)?.run().await;
+
+        // Abort index updates rather than waiting for them to complete
+        for index in indexes {
+            index.shutdown();
+        }
+        result
+    }
+
+    /// The crates index mirror for a scope, available once the repositories are initialised.
+    pub(crate) fn index_repository(&self, scope: &str) -> Option<&IndexRepository> {
+        self.crate_repositories.get(scope)
+    }
+
+    pub(crate) fn artifact_cache(&self, scope: &str) -> ArtifactCache {
+        self.get_artifact_cache(&self.config.proxy().expanded_cache_dir(), scope)
+    }
+
+    fn is_offline(&self, config: &RepositoryConfig) -> bool {
+        config
+            .offline()
+            .unwrap_or_else(|| self.config.proxy().offline())
+    }
+
+    fn get_artifact_cache(&self, base_cache_dir: &Path, name: &str) -> ArtifactCache {
+        ArtifactCache::new(base_cache_dir.join(ARTIFACTS_DIR).join(name))
     }
 
     fn get_cache_dir(
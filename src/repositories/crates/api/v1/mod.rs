@@ -1,7 +1,9 @@
 use crate::errors::Result;
-use crate::policy::{context::Context, PolicyEngine};
+use crate::policy::{context::Context, PolicyEngine, Verdict};
+use crate::repositories::crates::sparse::is_crate_name;
 use crate::repositories::crates::CratesDownloadConfig;
 use actix_web::dev::HttpServiceFactory;
+use actix_web::http::header::AUTHORIZATION;
 use actix_web::{get, web, HttpResponse, HttpResponseBuilder, Responder};
 
 #[get("/{version}/download")]
@@ -12,48 +14,99 @@ async fn download(
 ) -> Result<impl Responder> {
     let (crate_name, version) = path.into_inner();
     log::info!("download {} {}", crate_name, version);
+    if !is_crate_name(&crate_name) {
+        return Ok(HttpResponse::NotFound().finish());
+    }
 
-    let client = &crates.client;
-    let info = client.get_crate(&crate_name).await?;
+    let cache_key = format!("crates/{crate_name}/{version}/download");
+    if crates.offline {
+        return match crates.cache.respond(&cache_key).await {
+            Some(response) => Ok(response),
+            None => {
+                log::warn!("Crate {crate_name} {version} is not available offline");
+                Ok(HttpResponse::NotFound().finish())
+            }
+        };
+    }
 
-    match info.versions.iter().find(|e| e.num == version) {
+    let (url, authorization) = match &crates.index_repository {
+        // Downloads follow the dl template of the mirrored index, which may be a private registry
+        Some(index_repository) => {
+            let index_repository = index_repository.clone();
+            let (name, vers) = (crate_name.clone(), version.clone());
+            let download = web::block(move || {
+                index_repository
+                    .upstream_download(&name, &vers)
+                    .map_err(|error| error.to_string())
+            })
+            .await
+            .map_err(actix_web::Error::from)?;
+            match download {
+                Ok(download) => download,
+                Err(msg) => {
+                    log::error!("Unable to locate {crate_name} {version} upstream: {msg}");
+                    return Ok(HttpResponse::BadGateway().body(msg));
+                }
+            }
+        }
         None => {
-            let msg = format!(
-                "Error encountered finding version {version} of crate {crate_name} in crate info"
-            );
-            log::error!("{msg}");
-            Ok(HttpResponse::NotFound().body("{msg}"))
+            let info = crates.client.get_crate(&crate_name).await?;
+            match info.versions.iter().find(|e| e.num == version) {
+                None => {
+                    let msg = format!(
+                        "Error encountered finding version {version} of crate {crate_name} in crate info"
+                    );
+                    log::error!("{msg}");
+                    return Ok(HttpResponse::NotFound().body(msg));
+                }
+                Some(crate_version) => {
+                    let link = &crate_version.dl_path;
+                    (format!("https://crates.io/{link}"), None)
+                }
+            }
         }
-        Some(crate_version) => {
-            let link = &crate_version.dl_path;
-            let url = format!("https://crates.io/{link}");
+    };
 
-            let mut upstream = policy.client.get(url.clone()).send().await?;
-            let payload = upstream.body().limit(20_000_000).await?;
+    let mut request = policy.client.get(url.clone());
+    if let Some(authorization) = authorization {
+        request = request.insert_header((AUTHORIZATION, authorization));
+    }
+    let mut upstream = request.send().await?;
+    let payload = upstream.body().limit(20_000_000).await?;
 
-            let context = Context::new(
-                format!("pkg:cargo/{crate_name}@{version}"),
-                url,
-                sha256::digest(payload.as_ref()), // todo: double check this
-            );
+    let context = Context::new(
+        format!("pkg:cargo/{crate_name}@{version}"),
+        url,
+        sha256::digest(payload.as_ref()), // todo: double check this
+    );
 
-            match policy.evaluate(&context, None).await? {
-                None => {
-                    log::info!("Policy evaluation success");
-                    let mut response = HttpResponseBuilder::new(upstream.status());
-                    for header in upstream.headers().iter() {
-                        response.insert_header(header);
-                    }
-                    Ok(response.body(payload))
-                }
-                Some(err_response) => {
-                    log::info!(
-                        "Policy evaluation returned failure: {}",
-                        err_response.status()
-                    );
-                    Ok(err_response)
-                }
+    match policy.check(&context, None).await? {
+        Verdict::Denied(err_response) => {
+            log::info!(
+                "Policy evaluation returned failure: {}",
+                err_response.status()
+            );
+            Ok(err_response)
+        }
+        verdict => {
+            log::info!("Policy evaluation success");
+            if upstream.status().is_success() {
+                crates
+                    .cache
+                    .store(
+                        &cache_key,
+                        Some("application/x-tar"),
+                        Some(&context),
+                        policy.record(&verdict),
+                        payload.clone(),
+                    )
+                    .await;
+            }
+            let mut response = HttpResponseBuilder::new(upstream.status());
+            for header in upstream.headers().iter() {
+                response.insert_header(header);
             }
+            Ok(response.body(payload))
         }
     }
 }
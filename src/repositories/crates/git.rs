@@ -8,38 +8,28 @@ use std::{
     fs::{self, File},
     io::{self, Write},
     path::{Path, PathBuf},
-    process::Stdio,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use actix_web::{
-    body::BodyStream, dev::HttpServiceFactory, error::ErrorNotFound, http::StatusCode, web,
-    HttpMessage, HttpRequest, HttpResponse,
-};
-use bytes::Bytes;
 use fs2::FileExt;
 
 use futures::{
-    future::{BoxFuture, Shared},
+    future::{self, BoxFuture, Shared},
     FutureExt,
 };
 use git2::{
-    build::CheckoutBuilder, Direction, FetchOptions, MergeOptions, Oid, Remote, RemoteCallbacks,
-    Repository, Signature,
-};
-use substring::Substring;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt as _},
-    time,
-    {
-        process::{ChildStdout, Command},
-        sync::mpsc,
-    },
+    build::CheckoutBuilder, Commit, Cred, CredentialType, Direction, ErrorCode, FetchOptions,
+    MergeOptions, Oid, Remote, RemoteCallbacks, Repository, Signature, Tree,
 };
-use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use serde::Serialize;
+use tokio::{sync::watch, time};
 use url::Url;
 
-use super::CratesConfig;
+use crate::config::repositories::{Credentials, RepositoryConfig};
 use crate::errors::{Error, Result};
+use crate::repositories::crates::registry::{self, RegistryConfig};
+use crate::repositories::crates::sparse;
 
 const CACHEDIR_TAG_FILE: &str = "CACHEDIR.TAG";
 const CACHEDIR_TAG_CONTENTS: &str = "Signature: 8a477f597d28d172789f06886806bc55
@@ -50,13 +40,142 @@ const GIT_DIR: &str = "repository";
 
 const SEEDWING_BRANCH_FILE: &str = ".seedwing/branch";
 
+/// The upstream commit and config.json a squashed mirror was last updated to, as its history is
+/// not kept
+const SEEDWING_UPSTREAM_FILE: &str = ".seedwing/upstream";
+const SEEDWING_CONFIG_FILE: &str = ".seedwing/config.json";
+
 const GITIGNORE_FILE: &str = ".gitignore";
 
 const CONFIG_JSON_FILE: &str = "config.json";
 
 const TAG_NAME: &str = "seedwing";
 
-const GIT_HTTP_BACKEND: &str = "http-backend";
+const FILE_MODE: i32 = 0o100644;
+
+/// Length of the sha1 checksum ending a pack
+const PACK_TRAILER_LEN: usize = 20;
+
+/// How often fetch progress is logged
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Rejected credentials are not offered again after this many requests
+const MAX_CREDENTIAL_ATTEMPTS: u32 = 3;
+
+/// Repeated failures stretch the update interval by up to 2^6
+const MAX_BACKOFF_EXPONENT: u32 = 6;
+
+#[derive(Default)]
+struct UpdateState {
+    last_update: Option<Instant>,
+    updating: bool,
+    last_success: Option<SystemTime>,
+    last_failure: Option<(SystemTime, String)>,
+    last_duration: Option<Duration>,
+    /// Consecutive failed updates
+    failures: u32,
+}
+
+/// State of a local mirror as reported by the admin API
+#[derive(Serialize, Debug)]
+pub struct IndexStatus {
+    upstream: String,
+    /// Commit served to clients
+    head: Option<String>,
+    /// Upstream commit the mirror was last updated to
+    tagged: Option<String>,
+    /// Current head of the upstream branch
+    remote_head: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_error: Option<String>,
+    updating: bool,
+    /// Seconds since the unix epoch
+    last_success: Option<u64>,
+    last_failure: Option<u64>,
+    last_error: Option<String>,
+    last_duration_ms: Option<u128>,
+}
+
+/// Lengthen an update interval exponentially with each consecutive failure.
+fn backoff_delay(interval: Duration, failures: u32) -> Duration {
+    interval.saturating_mul(1 << failures.min(MAX_BACKOFF_EXPONENT))
+}
+
+fn epoch_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// When the local mirror is refreshed from the upstream repository. The state is shared by every
+/// clone so concurrent triggers are coalesced into a single fetch.
+#[derive(Clone)]
+pub struct IndexUpdates {
+    periodic_update: u64,
+    update_on_fetch: u64,
+    state: Arc<Mutex<UpdateState>>,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+impl IndexUpdates {
+    pub fn new(periodic_update: u64, update_on_fetch: u64) -> Self {
+        Self {
+            periodic_update,
+            update_on_fetch,
+            state: Arc::default(),
+            shutdown: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// Stop scheduling updates and abort any fetch in progress.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    fn is_shut_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    fn backoff(&self, interval: Duration) -> Duration {
+        backoff_delay(interval, self.state.lock().unwrap().failures)
+    }
+
+    /// Claim the update, unless one is running or the last one is more recent than stale_after.
+    fn begin(&self, stale_after: Option<Duration>) -> bool {
+        if self.is_shut_down() {
+            return false;
+        }
+        let stale_after = stale_after.map(|stale_after| self.backoff(stale_after));
+        let mut state = self.state.lock().unwrap();
+        if state.updating {
+            return false;
+        }
+        if let (Some(stale_after), Some(last_update)) = (stale_after, state.last_update) {
+            if last_update.elapsed() < stale_after {
+                return false;
+            }
+        }
+        state.updating = true;
+        true
+    }
+
+    fn finish(&self, started: Instant, result: &Result<()>) {
+        let mut state = self.state.lock().unwrap();
+        state.updating = false;
+        // Failed updates count too, so an unavailable upstream is not retried on every fetch
+        state.last_update = Some(Instant::now());
+        state.last_duration = Some(started.elapsed());
+        match result {
+            Ok(()) => {
+                state.last_success = Some(SystemTime::now());
+                state.failures = 0;
+            }
+            Err(error) => {
+                state.last_failure = Some((SystemTime::now(), error.to_string()));
+                state.failures = state.failures.saturating_add(1);
+            }
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct IndexRepository {
@@ -64,36 +183,34 @@ pub struct IndexRepository {
     local_repository_cache: PathBuf,
     dl: Url,
     api: Url,
-    periodic_update: u64,
+    updates: IndexUpdates,
+    credentials: Credentials,
+    squash: bool,
+    offline: bool,
     init_cache: Shared<BoxFuture<'static, bool>>,
 }
 
 impl IndexRepository {
     pub fn new(
-        repo: Url,
+        config: &RepositoryConfig,
         local_repository_cache: PathBuf,
         dl: Url,
         api: Url,
-        periodic_update: u64,
+        offline: bool,
     ) -> Self {
-        let init_cache = Self::wrap_prepare(
-            local_repository_cache.clone(),
-            repo.clone(),
-            dl.clone(),
-            api.clone(),
-            periodic_update,
-        )
-        .boxed()
-        .shared();
-
-        Self {
-            repo,
+        let mut index_repository = Self {
+            repo: config.url(),
             local_repository_cache,
             dl,
             api,
-            periodic_update,
-            init_cache,
-        }
+            updates: IndexUpdates::new(config.periodic_update(), config.update_on_fetch()),
+            credentials: config.credentials().clone(),
+            squash: config.squash(),
+            offline,
+            init_cache: future::ready(false).boxed().shared(),
+        };
+        index_repository.init_cache = index_repository.clone().wrap_prepare().boxed().shared();
+        index_repository
     }
 
     pub fn get_repo(&self) -> &Url {
@@ -104,6 +221,10 @@ impl IndexRepository {
         &self.local_repository_cache
     }
 
+    pub fn get_git_repository_dir(&self) -> PathBuf {
+        self.local_repository_cache.join(GIT_DIR)
+    }
+
     pub fn get_dl_url(&self) -> &Url {
         &self.dl
     }
@@ -113,14 +234,218 @@ impl IndexRepository {
     }
 
     pub fn get_periodic_update(&self) -> u64 {
-        self.periodic_update
+        self.updates.periodic_update
+    }
+
+    pub fn get_update_on_fetch(&self) -> u64 {
+        self.updates.update_on_fetch
+    }
+
+    pub fn get_squash(&self) -> bool {
+        self.squash
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Take a shared lock on the local mirror so it is not updated while being read, returning
+    /// the lock and the git repository directory, or None when no mirror has been created.
+    pub fn lock_mirror(&self) -> Result<Option<(File, PathBuf)>> {
+        let cache_dir_tag = self.local_repository_cache.join(CACHEDIR_TAG_FILE);
+        let git_repository_dir = self.local_repository_cache.join(GIT_DIR);
+        if !cache_dir_tag.exists() || !git_repository_dir.exists() {
+            return Ok(None);
+        }
+        let cache_dir_tag_file = File::open(cache_dir_tag)?;
+        cache_dir_tag_file.lock_shared()?;
+        Ok(Some((cache_dir_tag_file, git_repository_dir)))
+    }
+
+    /// Replace the local mirror with a git repository exported from another proxy, pointing the
+    /// remote and config.json at this proxy's registry, dl and api links.
+    pub fn import_mirror(&self, git_repository: &Path) -> Result<()> {
+        let git_repository_dir = self.get_git_repository_dir();
+        let cache_dir_tag_file = Self::open_cache_dir_tag(&self.local_repository_cache)?;
+        cache_dir_tag_file.lock_exclusive()?;
+
+        if git_repository_dir.exists() {
+            fs::remove_dir_all(&git_repository_dir)?;
+        }
+        fs::rename(git_repository, &git_repository_dir)?;
+
+        let repo = Repository::open(&git_repository_dir)?;
+        repo.remote_set_url(REMOTE_NAME, self.repo.as_str())?;
+
+        Self::write_config(
+            &git_repository_dir.join(CONFIG_JSON_FILE),
+            &self.dl,
+            &self.api,
+        )?;
+        let mut index = repo.index()?;
+        index.add_path(Path::new(CONFIG_JSON_FILE))?;
+        index.write()?;
+        let id = index.write_tree()?;
+
+        let head = repo.head()?;
+        let head_commit = head.peel_to_commit()?;
+        if self.squash {
+            let config_json = repo.blob(Self::config_contents(&self.dl, &self.api).as_bytes())?;
+            Self::squash_branch(
+                &repo,
+                head.name().unwrap_or("HEAD"),
+                &head_commit.tree()?,
+                config_json,
+                "Squashed snapshot of imported index",
+            )?;
+            // An imported mirror which was not squashed still holds the upstream branch
+            let remote_branch =
+                Self::get_seedwing_branch(&git_repository_dir.join(SEEDWING_BRANCH_FILE))?;
+            let upstream_commit = repo
+                .revparse_single(&Self::get_remote_branch_spec(&remote_branch))
+                .and_then(|object| object.peel_to_commit())
+                .ok();
+            Self::drop_upstream_history(&repo, &git_repository_dir, upstream_commit.as_ref())?;
+        } else if head_commit.tree_id() != id {
+            let sig = Signature::now("Seedwing", "seedwing@example.com")?;
+            let tree = repo.find_tree(id)?;
+            repo.commit(
+                Some("HEAD"),
+                &sig,
+                &sig,
+                "Committing imported config.json",
+                &tree,
+                &[&head_commit],
+            )?;
+        }
+        cache_dir_tag_file.unlock()?;
+        Ok(())
+    }
+
+    fn config_contents(dl_url: &Url, api_url: &Url) -> String {
+        format!("{{\n  \"dl\": \"{dl_url}\",\n  \"api\": \"{api_url}\"\n}}\n")
     }
 
     fn write_config(config_json_file: &Path, dl_url: &Url, api_url: &Url) -> io::Result<()> {
-        fs::write(
-            config_json_file,
-            format!("{{\n  \"dl\": \"{dl_url}\",\n  \"api\": \"{api_url}\"\n}}\n"),
-        )
+        fs::write(config_json_file, Self::config_contents(dl_url, api_url))
+    }
+
+    /// Point the branch at a single parentless commit holding the base tree along with our
+    /// config.json and .gitignore, replacing any previous history.
+    fn squash_branch(
+        repo: &Repository,
+        branch: &str,
+        base_tree: &Tree,
+        config_json: Oid,
+        message: &str,
+    ) -> Result<()> {
+        let gitignore = repo.blob(format!("/{SEEDWING_BRANCH_FILE}").as_bytes())?;
+        let mut builder = repo.treebuilder(Some(base_tree))?;
+        builder.insert(CONFIG_JSON_FILE, config_json, FILE_MODE)?;
+        builder.insert(GITIGNORE_FILE, gitignore, FILE_MODE)?;
+        let tree = repo.find_tree(builder.write()?)?;
+
+        let sig = Signature::now("Seedwing", "seedwing@example.com")?;
+        let commit = repo.commit(None, &sig, &sig, message, &tree, &[])?;
+        repo.reference(branch, commit, true, message)?;
+        repo.set_head(branch)?;
+
+        let mut cb = CheckoutBuilder::new();
+        repo.checkout_head(Some(cb.force()))?;
+        Ok(())
+    }
+
+    /// Drop everything but the squashed branch from a squashed mirror, so clients cannot fetch
+    /// the upstream history. The upstream commit and its config.json are kept in the worktree.
+    fn drop_upstream_history(
+        repo: &Repository,
+        git_repository_dir: &Path,
+        upstream_commit: Option<&Commit>,
+    ) -> Result<()> {
+        if let Some(upstream_commit) = upstream_commit {
+            let config_json = upstream_commit
+                .tree()?
+                .get_name(CONFIG_JSON_FILE)
+                .ok_or_else(|| Error::Other {
+                    message: String::from("Upstream branch does not contain config.json"),
+                })?
+                .to_object(repo)?
+                .peel_to_blob()?;
+            let upstream_file = git_repository_dir.join(SEEDWING_UPSTREAM_FILE);
+            fs::create_dir_all(upstream_file.parent().unwrap())?;
+            fs::write(upstream_file, upstream_commit.id().to_string())?;
+            fs::write(
+                git_repository_dir.join(SEEDWING_CONFIG_FILE),
+                config_json.content(),
+            )?;
+        }
+
+        let head = repo.head()?;
+        let branch = head.name().unwrap_or_default();
+        let mut names = Vec::new();
+        for reference in repo.references()? {
+            if let Some(name) = reference?.name() {
+                if name != branch {
+                    names.push(String::from(name));
+                }
+            }
+        }
+        for name in names {
+            repo.find_reference(&name)?.delete()?;
+        }
+        let logs = repo.path().join("logs");
+        if logs.exists() {
+            fs::remove_dir_all(logs)?;
+        }
+        Self::prune(repo, head.peel_to_commit()?.id())
+    }
+
+    /// Repack the objects reachable from a commit into a single pack, deleting every other pack
+    /// and loose object. Clients fetching a previous snapshot meanwhile have to fetch again.
+    fn prune(repo: &Repository, commit: Oid) -> Result<()> {
+        let objects_dir = repo.path().join("objects");
+        let pack_dir = objects_dir.join("pack");
+        let mut builder = repo.packbuilder()?;
+        builder.insert_commit(commit)?;
+        let odb = repo.odb()?;
+        let mut writer = odb.packwriter()?;
+        // Packs are named after their trailing checksum
+        let mut trailer = Vec::new();
+        builder.foreach(|chunk| {
+            trailer.extend_from_slice(chunk);
+            trailer.drain(..trailer.len().saturating_sub(PACK_TRAILER_LEN));
+            writer.write_all(chunk).is_ok()
+        })?;
+        writer.commit()?;
+        let checksum: String = trailer.iter().map(|b| format!("{b:02x}")).collect();
+        let pack = format!("pack-{checksum}.");
+        if !pack_dir.join(format!("{pack}pack")).exists() {
+            return Err(Error::Other {
+                message: format!("Pack {pack}pack was not written"),
+            });
+        }
+
+        for entry in fs::read_dir(&pack_dir)? {
+            let entry = entry?;
+            if !entry.file_name().to_string_lossy().starts_with(&pack) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        for entry in fs::read_dir(&objects_dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.len() == 2 && name.chars().all(|c| c.is_ascii_hexdigit()) {
+                fs::remove_dir_all(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The upstream commit a squashed mirror was last updated to.
+    fn squashed_upstream(git_repository_dir: &Path) -> Option<Oid> {
+        let upstream = fs::read_to_string(git_repository_dir.join(SEEDWING_UPSTREAM_FILE)).ok()?;
+        Oid::from_str(upstream.trim()).ok()
     }
 
     fn get_seedwing_branch(seedwing_branch_file: &PathBuf) -> Result<String> {
@@ -153,45 +478,84 @@ impl IndexRepository {
         )
     }
 
-    fn fetch_repo(remote: &mut Remote, remote_branch: &String) -> Result<()> {
+    /// Callbacks answering the credential requests of the upstream repository.
+    fn remote_callbacks(&self) -> RemoteCallbacks<'_> {
+        let credentials = &self.credentials;
+        let mut attempts = 0;
+        let mut cb = RemoteCallbacks::new();
+        cb.credentials(move |_url, username_from_url, allowed| {
+            // libgit2 asks again for as long as the credentials are rejected
+            attempts += 1;
+            if attempts > MAX_CREDENTIAL_ATTEMPTS {
+                return Err(git2::Error::from_str("Authentication failed"));
+            }
+            let username = credentials
+                .username()
+                .or(username_from_url)
+                .unwrap_or("git");
+            if allowed.contains(CredentialType::USERNAME) {
+                Cred::username(username)
+            } else if allowed.contains(CredentialType::SSH_KEY) {
+                match credentials.ssh_key() {
+                    Some(ssh_key) => {
+                        Cred::ssh_key(username, None, ssh_key, credentials.ssh_key_passphrase())
+                    }
+                    None => Cred::ssh_key_from_agent(username),
+                }
+            } else if let (true, Some(token)) = (
+                allowed.contains(CredentialType::USER_PASS_PLAINTEXT),
+                credentials.token(),
+            ) {
+                Cred::userpass_plaintext(username, token)
+            } else {
+                Err(git2::Error::from_str(
+                    "No credentials configured for the repository",
+                ))
+            }
+        });
+        cb
+    }
+
+    fn fetch_repo(&self, remote: &mut Remote, remote_branch: &String) -> Result<()> {
         log::info!("Fetching remote branch: {}", remote_branch);
 
-        let mut cb = RemoteCallbacks::new();
+        let mut cb = self.remote_callbacks();
         cb.sideband_progress(|data| {
-            print!(
-                "[sideband]: {}",
-                String::from_utf8(Vec::from(data)).unwrap()
-            );
-            io::stdout().flush().unwrap();
+            log::debug!("[sideband]: {}", String::from_utf8_lossy(data).trim_end());
             true
         });
 
         cb.update_tips(|refname, a, b| {
             if a.is_zero() {
-                println!("[update new]     {b} {refname}");
+                log::info!("[update new]     {b} {refname}");
             } else {
-                println!("[update updated] {a}..{b} {refname}");
+                log::info!("[update updated] {a}..{b} {refname}");
             }
             true
         });
-        cb.transfer_progress(|stats| {
-            if stats.received_objects() == stats.total_objects() {
-                print!(
-                    "[Transfer]: Resolving deltas {}/{}\r",
-                    stats.indexed_deltas(),
-                    stats.total_deltas()
-                );
-            } else if stats.total_objects() > 0 {
-                print!(
-                    "[Transfer]: Received {}/{} objects ({}) in {} bytes\r",
-                    stats.received_objects(),
-                    stats.total_objects(),
-                    stats.indexed_objects(),
-                    stats.received_bytes()
-                );
+        let updates = &self.updates;
+        let mut last_progress = Instant::now();
+        cb.transfer_progress(move |stats| {
+            if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                last_progress = Instant::now();
+                if stats.received_objects() == stats.total_objects() {
+                    log::info!(
+                        "[Transfer]: Resolving deltas {}/{}",
+                        stats.indexed_deltas(),
+                        stats.total_deltas()
+                    );
+                } else if stats.total_objects() > 0 {
+                    log::info!(
+                        "[Transfer]: Received {}/{} objects ({}) in {} bytes",
+                        stats.received_objects(),
+                        stats.total_objects(),
+                        stats.indexed_objects(),
+                        stats.received_bytes()
+                    );
+                }
             }
-            io::stdout().flush().unwrap();
-            true
+            // Returning false aborts the fetch
+            !updates.is_shut_down()
         });
         let mut fo = FetchOptions::new();
         fo.remote_callbacks(cb);
@@ -206,6 +570,7 @@ impl IndexRepository {
         git_repository_dir: &Path,
         dl_url: &Url,
         api_url: &Url,
+        squash: bool,
     ) -> Result<()> {
         let seedwing_branch_file = git_repository_dir.join(SEEDWING_BRANCH_FILE);
         let gitignore_file = git_repository_dir.join(GITIGNORE_FILE);
@@ -216,6 +581,24 @@ impl IndexRepository {
         let remote_branch_spec = Self::get_remote_branch_spec(remote_branch);
         let branch_name = Self::get_branch_short_name(remote_branch);
         if let Some(upstream_commit) = repo.revparse_single(&remote_branch_spec)?.as_commit() {
+            if squash {
+                log::info!("Squashing {}", &upstream_commit.id());
+                let config_json = repo.blob(Self::config_contents(dl_url, api_url).as_bytes())?;
+                Self::squash_branch(
+                    repo,
+                    &format!("refs/heads/{branch_name}"),
+                    &upstream_commit.tree()?,
+                    config_json,
+                    &format!("Squashed snapshot of {}", upstream_commit.id()),
+                )?;
+                fs::create_dir_all(seedwing_branch_file.parent().unwrap())?;
+                fs::write(&seedwing_branch_file, remote_branch)?;
+                return Self::drop_upstream_history(
+                    repo,
+                    git_repository_dir,
+                    Some(upstream_commit),
+                );
+            }
             repo.branch(branch_name, upstream_commit, true)?;
             log::info!("Tagging {}", &upstream_commit.id());
             repo.tag(
@@ -264,8 +647,8 @@ impl IndexRepository {
         Ok(())
     }
 
-    fn update_local_cache(local_repository_cache: &PathBuf) -> Result<()> {
-        let cache_dir = Path::new(local_repository_cache);
+    fn update_local_cache(&self) -> Result<()> {
+        let cache_dir = self.local_repository_cache.as_path();
         let cache_dir_tag = cache_dir.join(CACHEDIR_TAG_FILE);
         let git_repository_dir = cache_dir.join(GIT_DIR);
         let seedwing_branch_file = git_repository_dir.join(SEEDWING_BRANCH_FILE);
@@ -281,13 +664,25 @@ impl IndexRepository {
         let mut remote = repo.find_remote(REMOTE_NAME)?;
 
         let remote_branch = Self::get_seedwing_branch(&seedwing_branch_file)?;
-        Self::fetch_repo(&mut remote, &remote_branch)?;
-
-        let tag_spec = format!("refs/tags/{TAG_NAME}");
-        let tagged_id = match repo.revparse_single(&tag_spec) {
-            Ok(obj) => obj.as_tag().unwrap().target()?.id(),
-            Err(_) => Oid::zero(),
+        let tagged_id = if self.squash {
+            let squashed_id =
+                Self::squashed_upstream(&git_repository_dir).unwrap_or_else(Oid::zero);
+            // Without the upstream history every fetch is a full one, so it is only done when
+            // the upstream branch has moved on
+            if self.remote_head(&git_repository_dir)? == Some(squashed_id) {
+                log::info!("No update required");
+                cache_dir_tag_file.unlock()?;
+                return Ok(());
+            }
+            squashed_id
+        } else {
+            let tag_spec = format!("refs/tags/{TAG_NAME}");
+            match repo.revparse_single(&tag_spec) {
+                Ok(obj) => obj.as_tag().unwrap().target()?.id(),
+                Err(_) => Oid::zero(),
+            }
         };
+        self.fetch_repo(&mut remote, &remote_branch)?;
 
         let remote_branch_spec = Self::get_remote_branch_spec(&remote_branch);
         let remote_id = repo
@@ -304,6 +699,27 @@ impl IndexRepository {
 
             let remote_commit = repo.find_commit(remote_id)?;
 
+            if self.squash {
+                log::info!("Squashing {}", remote_id);
+                let config_json = head_commit
+                    .tree()?
+                    .get_name(CONFIG_JSON_FILE)
+                    .map(|entry| entry.id())
+                    .ok_or_else(|| Error::Other {
+                        message: String::from("Local branch does not contain config.json"),
+                    })?;
+                Self::squash_branch(
+                    &repo,
+                    &format!("refs/heads/{}", Self::get_branch_short_name(&remote_branch)),
+                    &remote_commit.tree()?,
+                    config_json,
+                    &format!("Squashed snapshot of {remote_id}"),
+                )?;
+                Self::drop_upstream_history(&repo, &git_repository_dir, Some(&remote_commit))?;
+                cache_dir_tag_file.unlock()?;
+                return Ok(());
+            }
+
             log::info!("Merging {}", remote_id);
             let mut merge_opts = MergeOptions::new();
             merge_opts.target_limit(1);
@@ -343,39 +759,254 @@ impl IndexRepository {
             repo.checkout_head(Some(cb))?;
         } else {
             log::info!("No updated required");
+            if self.squash {
+                Self::drop_upstream_history(&repo, &git_repository_dir, None)?;
+            }
         }
         cache_dir_tag_file.unlock()?;
         Ok(())
     }
 
-    async fn periodic_update_of_cache(local_repository_cache: String, periodic_update: u64) {
-        let path = PathBuf::from(local_repository_cache);
-        let mut interval = time::interval(time::Duration::from_secs(periodic_update));
+    fn run_update(&self) {
+        let started = Instant::now();
+        let result = self.update_local_cache();
+        if let Err(error) = &result {
+            log::info!("Error updating cache: {error}");
+        }
+        self.updates.finish(started, &result);
+    }
+
+    async fn periodic_update_of_cache(self) {
+        let mut shutdown = self.updates.shutdown.subscribe();
         loop {
-            interval.tick().await;
-            if let Err(error) = Self::update_local_cache(&path) {
-                log::info!("Error updating cache: {error}");
+            if self.updates.begin(None) {
+                let index_repository = self.clone();
+                let update =
+                    actix_web::rt::task::spawn_blocking(move || index_repository.run_update());
+                if let Err(error) = update.await {
+                    log::error!("Index update failed to complete: {error}");
+                }
+            }
+            let delay = self
+                .updates
+                .backoff(Duration::from_secs(self.updates.periodic_update));
+            tokio::select! {
+                _ = time::sleep(delay) => {}
+                _ = shutdown.wait_for(|shutdown| *shutdown) => break,
             }
         }
+        log::info!("Stopped updating {}", self.local_repository_cache.display());
     }
 
-    async fn wrap_prepare(
-        local_repository_cache: PathBuf,
-        repo_url: Url,
-        dl_url: Url,
-        api_url: Url,
-        periodic_update: u64,
-    ) -> bool {
-        let repo = repo_url.clone();
-        if let Err(error) = Self::prepare_local_cache(
-            local_repository_cache,
-            repo_url,
-            dl_url,
-            api_url,
-            periodic_update,
-        )
-        .await
+    /// Abort any update in progress and stop updating the mirror.
+    pub(crate) fn shutdown(&self) {
+        self.updates.shutdown();
+    }
+
+    /// Update the mirror in the background, returning false when an update is already running.
+    pub(crate) fn trigger_update(&self) -> bool {
+        if self.offline || !self.updates.begin(None) {
+            return false;
+        }
+        log::info!("Updating index {} on request", self.repo);
+        let index_repository = self.clone();
+        actix_web::rt::task::spawn_blocking(move || index_repository.run_update());
+        true
+    }
+
+    /// Wipe the mirror and clone it again in the background, returning false when an update is
+    /// already running.
+    pub(crate) fn reset(&self) -> bool {
+        if self.offline || !self.updates.begin(None) {
+            return false;
+        }
+        log::info!("Recreating index {} on request", self.repo);
+        let index_repository = self.clone();
+        actix_web::rt::task::spawn_blocking(move || {
+            let started = Instant::now();
+            let result = index_repository.recreate_local_cache();
+            if let Err(error) = &result {
+                log::info!("Error recreating cache: {error}");
+            }
+            index_repository.updates.finish(started, &result);
+        });
+        true
+    }
+
+    fn recreate_local_cache(&self) -> Result<()> {
+        let cache_dir_tag_file = Self::open_cache_dir_tag(&self.local_repository_cache)?;
+        cache_dir_tag_file.lock_exclusive()?;
+        self.create_mirror()?;
+        cache_dir_tag_file.unlock()?;
+        Ok(())
+    }
+
+    /// Report the state of the mirror, querying the upstream repository for its current head
+    /// unless running offline.
+    pub fn status(&self) -> IndexStatus {
+        let git_repository_dir = self.get_git_repository_dir();
+        let repo = Repository::open(&git_repository_dir).ok();
+        let head = repo
+            .as_ref()
+            .and_then(|repo| repo.head().ok()?.target())
+            .map(|oid| oid.to_string());
+        let tagged = if self.squash {
+            Self::squashed_upstream(&git_repository_dir)
+        } else {
+            repo.as_ref().and_then(|repo| {
+                let tag = repo
+                    .revparse_single(&format!("refs/tags/{TAG_NAME}"))
+                    .ok()?;
+                Some(tag.peel_to_commit().ok()?.id())
+            })
+        }
+        .map(|oid| oid.to_string());
+
+        let (remote_head, remote_error) = if self.offline {
+            (None, None)
+        } else {
+            match self.remote_head(&git_repository_dir) {
+                Ok(oid) => (oid.map(|oid| oid.to_string()), None),
+                Err(error) => (None, Some(error.to_string())),
+            }
+        };
+
+        let state = self.updates.state.lock().unwrap();
+        IndexStatus {
+            upstream: self.repo.to_string(),
+            head,
+            tagged,
+            remote_head,
+            remote_error,
+            updating: state.updating,
+            last_success: state.last_success.map(epoch_seconds),
+            last_failure: state
+                .last_failure
+                .as_ref()
+                .map(|(at, _)| epoch_seconds(*at)),
+            last_error: state.last_failure.as_ref().map(|(_, error)| error.clone()),
+            last_duration_ms: state.last_duration.map(|duration| duration.as_millis()),
+        }
+    }
+
+    fn remote_head(&self, git_repository_dir: &Path) -> Result<Option<Oid>> {
+        let remote_branch =
+            Self::get_seedwing_branch(&git_repository_dir.join(SEEDWING_BRANCH_FILE))?;
+        let mut remote = Remote::create_detached(self.repo.as_str())?;
+        let connection =
+            remote.connect_auth(Direction::Fetch, Some(self.remote_callbacks()), None)?;
+        let head = connection
+            .list()?
+            .iter()
+            .find(|head| head.name() == remote_branch)
+            .map(|head| head.oid());
+        Ok(head)
+    }
+
+    /// Read a file of the index as served to clients, along with the id of its blob, or None when
+    /// the index has no such file.
+    pub fn read_index_file(
+        &self,
+        path: &str,
+    ) -> std::result::Result<Option<(Vec<u8>, Oid)>, git2::Error> {
+        let repo = Repository::open(self.get_git_repository_dir())?;
+        let tree = repo.head()?.peel_to_tree()?;
+        let entry = match tree.get_path(Path::new(path)) {
+            Ok(entry) => entry,
+            Err(error) if error.code() == ErrorCode::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let file = match entry.to_object(&repo)?.into_blob() {
+            Ok(blob) => Some((blob.content().to_vec(), blob.id())),
+            // Directories are not files of the sparse index
+            Err(_) => None,
+        };
+        Ok(file)
+    }
+
+    /// Resolve where to download a crate version from, using the `dl` template of the upstream
+    /// config.json, along with the token to send when the registry requires authentication.
+    pub fn upstream_download(&self, name: &str, version: &str) -> Result<(String, Option<String>)> {
+        let invalid_name = || Error::Other {
+            message: format!("{name} is not a valid crate name"),
+        };
+        let index_path = sparse::index_path(name).ok_or_else(invalid_name)?;
+        let git_repository_dir = self.get_git_repository_dir();
+        let remote_branch =
+            Self::get_seedwing_branch(&git_repository_dir.join(SEEDWING_BRANCH_FILE))?;
+        let repo = Repository::open(&git_repository_dir)?;
+        // A squashed mirror serves the upstream index files but only keeps a copy of its
+        // config.json
+        let (tree, upstream_config) = if self.squash {
+            let upstream_config = fs::read(git_repository_dir.join(SEEDWING_CONFIG_FILE))?;
+            (repo.head()?.peel_to_tree()?, Some(upstream_config))
+        } else {
+            let tree = repo
+                .revparse_single(&Self::get_remote_branch_spec(&remote_branch))?
+                .peel_to_tree()?;
+            (tree, None)
+        };
+        let read = |path: &str| -> Result<Vec<u8>> {
+            let entry = tree.get_path(Path::new(path))?;
+            Ok(entry.to_object(&repo)?.peel_to_blob()?.content().to_vec())
+        };
+
+        let upstream_config = match upstream_config {
+            Some(upstream_config) => upstream_config,
+            None => read(CONFIG_JSON_FILE)?,
+        };
+        let config = RegistryConfig::parse(&upstream_config).map_err(|error| Error::Other {
+            message: format!("Invalid upstream config.json: {error}"),
+        })?;
+        let checksum = if config.needs_checksum() {
+            let index_file = read(&index_path)?;
+            let checksum = registry::checksum(&String::from_utf8_lossy(&index_file), version);
+            if checksum.is_none() {
+                return Err(Error::Other {
+                    message: format!("{name} {version} is not in the index"),
+                });
+            }
+            checksum
+        } else {
+            None
+        };
+        let authorization = if config.auth_required() {
+            if self.credentials.token().is_none() {
+                log::warn!(
+                    "{} requires authentication but no token is configured",
+                    self.repo
+                );
+            }
+            self.credentials.token().map(String::from)
+        } else {
+            None
+        };
+        let url = config
+            .download_url(name, version, checksum.as_deref())
+            .ok_or_else(invalid_name)?;
+        Ok((url, authorization))
+    }
+
+    /// Refresh the mirror in the background when the last update is older than the
+    /// `update_on_fetch` threshold. The current snapshot is served while the update runs.
+    pub(crate) fn update_if_stale(&self) {
+        if self.updates.update_on_fetch == 0 || self.offline {
+            return;
+        }
+        if !self
+            .updates
+            .begin(Some(Duration::from_secs(self.updates.update_on_fetch)))
         {
+            return;
+        }
+        log::info!("Index is stale, updating in the background");
+        let index_repository = self.clone();
+        actix_web::rt::task::spawn_blocking(move || index_repository.run_update());
+    }
+
+    async fn wrap_prepare(self) -> bool {
+        let repo = self.repo.clone();
+        if let Err(error) = self.prepare_local_cache().await {
             log::error!("Failed to prepare the local cache for repository {repo}: {error}");
             false
         } else {
@@ -383,196 +1014,362 @@ impl IndexRepository {
         }
     }
 
-    async fn prepare_local_cache(
-        local_repository_cache: PathBuf,
-        repo_url: Url,
-        dl_url: Url,
-        api_url: Url,
-        periodic_update: u64,
-    ) -> Result<()> {
-        let cache_dir = Path::new(&local_repository_cache);
-        let cache_dir_tag = cache_dir.join(CACHEDIR_TAG_FILE);
-        let git_repository_dir = cache_dir.join(GIT_DIR);
-        let seedwing_branch_file = git_repository_dir.join(SEEDWING_BRANCH_FILE);
+    /// Clone the upstream repository into a new local mirror, replacing any existing one.
+    fn create_mirror(&self) -> Result<()> {
+        log::info!("Creating cache");
+        let git_repository_dir = self.get_git_repository_dir();
+        if git_repository_dir.exists() {
+            fs::remove_dir_all(&git_repository_dir)?;
+        }
 
+        let repo = Repository::init(&git_repository_dir)?;
+
+        let mut remote = repo.remote(REMOTE_NAME, self.repo.as_str())?;
+        let remote_branch_buf = remote
+            .connect_auth(Direction::Fetch, Some(self.remote_callbacks()), None)?
+            .default_branch()?;
+        if let Some(remote_branch) = remote_branch_buf.as_str() {
+            let remote_branch = String::from(remote_branch);
+
+            self.fetch_repo(&mut remote, &remote_branch)?;
+
+            Self::init_local_branch(
+                &repo,
+                &remote_branch,
+                &git_repository_dir,
+                &self.dl,
+                &self.api,
+                self.squash,
+            )
+        } else {
+            Err(Error::Other {
+                message: String::from("Remote branch name is not valid UTF-8"),
+            })
+        }
+    }
+
+    /// Open the CACHEDIR.TAG file used to lock the mirror, creating it when missing.
+    fn open_cache_dir_tag(cache_dir: &Path) -> io::Result<File> {
+        let cache_dir_tag = cache_dir.join(CACHEDIR_TAG_FILE);
         fs::create_dir_all(cache_dir)?;
+        if !cache_dir_tag.exists() {
+            let mut cache_dir_tag_file = File::create(&cache_dir_tag)?;
+            writeln!(&mut cache_dir_tag_file, "{CACHEDIR_TAG_CONTENTS}")?;
+        }
+        File::open(&cache_dir_tag)
+    }
 
-        {
-            let mut cache_dir_tag_file;
-            if !cache_dir_tag.exists() {
-                cache_dir_tag_file = File::create(&cache_dir_tag)?;
-                writeln!(&mut cache_dir_tag_file, "{CACHEDIR_TAG_CONTENTS}")?;
-            } else {
-                cache_dir_tag_file = File::open(&cache_dir_tag)?;
-            }
+    /// Validate the existing mirror, or create it, holding the lock on the cache directory.
+    fn open_local_cache(&self) -> Result<()> {
+        let git_repository_dir = self.get_git_repository_dir();
+        let seedwing_branch_file = git_repository_dir.join(SEEDWING_BRANCH_FILE);
 
-            cache_dir_tag_file.try_lock_exclusive()?;
+        let cache_dir_tag_file = Self::open_cache_dir_tag(&self.local_repository_cache)?;
+        cache_dir_tag_file.try_lock_exclusive()?;
 
-            let mut is_repository_valid = false;
+        let mut is_repository_valid = false;
 
-            // Check the directory contains the git repository and the seedwing branch file
-            if git_repository_dir.exists() && seedwing_branch_file.exists() {
-                // Check repository has the same URL as the registry remote
-                if let Ok(repo) = Repository::open(&git_repository_dir) {
-                    if let Ok(remote) = repo.find_remote(REMOTE_NAME) {
-                        if let Some(url) = remote.url() {
-                            is_repository_valid = url == repo_url.as_str();
-                        }
+        // Check the directory contains the git repository and the seedwing branch file
+        if git_repository_dir.exists() && seedwing_branch_file.exists() {
+            // Check repository has the same URL as the registry remote
+            if let Ok(repo) = Repository::open(&git_repository_dir) {
+                if let Ok(remote) = repo.find_remote(REMOTE_NAME) {
+                    if let Some(url) = remote.url() {
+                        is_repository_valid = url == self.repo.as_str();
                     }
                 }
-            }
-
-            if !is_repository_valid {
-                log::info!("Creating cache");
-                if git_repository_dir.exists() {
-                    fs::remove_dir_all(&git_repository_dir)?;
+                // A squashed mirror has a single parentless commit, rebuild it when the
+                // squash setting has changed unless there is no way to fetch it again
+                if let Ok(head_commit) = repo.head().and_then(|head| head.peel_to_commit()) {
+                    if is_repository_valid
+                        && !self.offline
+                        && (head_commit.parent_count() == 0) != self.squash
+                    {
+                        log::info!("Squash setting changed, recreating cache");
+                        is_repository_valid = false;
+                    }
                 }
+            }
+        }
 
-                let repo = Repository::init(&git_repository_dir)?;
-
-                let mut remote = repo.remote(REMOTE_NAME, repo_url.as_str())?;
-                remote.connect(Direction::Fetch)?;
-
-                let remote_branch_buf = remote.default_branch()?;
-                if let Some(remote_branch) = remote_branch_buf.as_str() {
-                    let remote_branch = String::from(remote_branch);
-
-                    Self::fetch_repo(&mut remote, &remote_branch)?;
+        if !is_repository_valid && self.offline {
+            return Err(Error::Other {
+                message: format!(
+                    "No local mirror of {} is available while running offline",
+                    self.repo
+                ),
+            });
+        }
 
-                    Self::init_local_branch(
-                        &repo,
-                        &remote_branch,
-                        &git_repository_dir,
-                        &dl_url,
-                        &api_url,
-                    )?;
-                } else {
-                    return Err(Error::Other {
-                        message: String::from("Remote branch name is not valid UTF-8"),
-                    });
-                }
-            }
+        if !is_repository_valid {
+            let started = Instant::now();
+            self.create_mirror()?;
+            // The new mirror is as fresh as it gets
+            self.updates.finish(started, &Ok(()));
         }
+        Ok(())
+    }
+
+    async fn prepare_local_cache(self) -> Result<()> {
+        let index_repository = self.clone();
+        actix_web::rt::task::spawn_blocking(move || {
+            index_repository
+                .open_local_cache()
+                // The crate error is not Send
+                .map_err(|error| error.to_string())
+        })
+        .await
+        .map_err(|error| error.to_string())
+        .and_then(|result| result)
+        .map_err(|message| Error::Other { message })?;
 
-        let path = String::from(local_repository_cache.to_str().unwrap());
-        if periodic_update > 0 {
-            tokio::spawn(Self::periodic_update_of_cache(path, periodic_update));
+        if self.updates.periodic_update > 0 && !self.offline {
+            tokio::spawn(self.periodic_update_of_cache());
         }
         Ok(())
     }
 
-    async fn init_cache(&self) -> bool {
+    /// Wait for the mirror to be prepared, true when it can be served. A mirror recreated through
+    /// the admin API can be served even though the initial preparation failed.
+    pub(crate) async fn init_cache(&self) -> bool {
         self.init_cache.clone().await
+            || self
+                .get_git_repository_dir()
+                .join(SEEDWING_BRANCH_FILE)
+                .exists()
     }
 }
 
-async fn read_line(child: &mut ChildStdout) -> Result<String> {
-    let mut vec: Vec<u8> = Vec::new();
-
-    // Need a better way of handling this
-    loop {
-        match child.read_u8().await {
-            Ok(byte) => {
-                if byte == 13 { // '\r'
-                     // ignore
-                } else if byte == 10 {
-                    // '\n'
-                    break;
-                } else {
-                    vec.push(byte);
-                }
-            }
-            Err(error) => return Err(error.into()),
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// Commit files on top of the checked out branch of a repository.
+    pub(crate) fn commit(repo: &Repository, files: &[(&str, &str)]) -> Oid {
+        let workdir = repo.workdir().unwrap();
+        let mut index = repo.index().unwrap();
+        for (path, contents) in files {
+            fs::create_dir_all(workdir.join(path).parent().unwrap()).unwrap();
+            fs::write(workdir.join(path), contents).unwrap();
+            index.add_path(Path::new(path)).unwrap();
         }
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        let sig = Signature::now("Upstream", "upstream@example.com").unwrap();
+        repo.commit(
+            Some("HEAD"),
+            &sig,
+            &sig,
+            "Update index",
+            &tree,
+            &parent.iter().collect::<Vec<_>>(),
+        )
+        .unwrap()
     }
-    match String::from_utf8(vec) {
-        Ok(result) => Ok(result),
-        Err(error) => Err(error.into()),
-    }
-}
 
-async fn handle_backend_service(
-    req: HttpRequest,
-    mut payload: web::Payload,
-    crates: web::Data<CratesConfig>,
-) -> Result<HttpResponse> {
-    if !crates.index_repository.init_cache().await {
-        return Err(ErrorNotFound("Failed to initialise local cache").into());
-    }
-
-    let git_dir = crates
-        .index_repository
-        .get_local_repository_cache()
-        .join(GIT_DIR);
-    let git_dir = git_dir.to_str().unwrap();
-
-    let req_path = req.uri().path();
-
-    let scope = crates.scope.as_str();
-    let new_path = format!("{git_dir}{}", req_path.strip_prefix(scope).unwrap());
-
-    let mut cmd = Command::new(&crates.git_cmd)
-        .arg(GIT_HTTP_BACKEND)
-        .env("GIT_HTTP_EXPORT_ALL", "")
-        .env("REQUEST_METHOD", req.method().to_string())
-        .env("QUERY_STRING", req.query_string())
-        .env("PATH_TRANSLATED", new_path)
-        .env("CONTENT_TYPE", req.content_type())
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
+    /// A prepared mirror of a new upstream index with two commits, in a temporary directory.
+    pub(crate) async fn mirror(name: &str, squash: bool) -> (IndexRepository, Repository) {
+        let root =
+            std::env::temp_dir().join(format!("seedwing-index-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let upstream = Repository::init(root.join("upstream")).unwrap();
+        commit(
+            &upstream,
+            &[
+                (
+                    CONFIG_JSON_FILE,
+                    r#"{"dl": "https://static.example.com/{crate}"}"#,
+                ),
+                ("3/s/syn", "{\"name\":\"syn\",\"vers\":\"2.0.0\"}\n"),
+            ],
+        );
+        commit(
+            &upstream,
+            &[("se/rd/serde", "{\"name\":\"serde\",\"vers\":\"1.0.0\"}\n")],
+        );
+
+        let config: RepositoryConfig = toml::from_str(&format!(
+            "type = \"crates\"\nurl = \"{}\"\nperiodic_update = 0\nsquash = {squash}",
+            Url::from_directory_path(root.join("upstream")).unwrap()
+        ))
         .unwrap();
+        let index_repository = IndexRepository::new(
+            &config,
+            root.join("cache"),
+            Url::parse("http://localhost:8181/crates/api/v1/crates").unwrap(),
+            Url::parse("http://localhost:8181/crates").unwrap(),
+            false,
+        );
+        assert!(index_repository.init_cache().await);
+        (index_repository, upstream)
+    }
 
-    let mut stdin = cmd.stdin.take().unwrap();
-    let mut stdout = cmd.stdout.take().unwrap();
+    /// Claim the update of a mirror, as if one was running.
+    pub(crate) fn begin_update(index_repository: &IndexRepository) -> bool {
+        index_repository.updates.begin(None)
+    }
 
-    actix_web::rt::spawn(async move {
-        while let Some(chunk) = payload.next().await {
-            let content = chunk.unwrap().to_vec();
-            if let Err(error) = stdin.write_all(&content).await {
-                log::info!("Unexpected error writing to child process input: {}", error);
-                break;
-            }
-        }
-    });
+    pub(crate) fn finish_update(index_repository: &IndexRepository) {
+        index_repository.updates.finish(Instant::now(), &Ok(()));
+    }
 
-    let mut resp = HttpResponse::build(StatusCode::OK);
+    /// Every commit reachable from the head of a repository.
+    fn history(repo: &Repository) -> Vec<Oid> {
+        let mut walk = repo.revwalk().unwrap();
+        walk.push_head().unwrap();
+        walk.map(|oid| oid.unwrap()).collect()
+    }
 
-    loop {
-        let read_line = read_line(&mut stdout).await;
-        match read_line {
-            Ok(line) => {
-                if line.is_empty() {
-                    break;
-                } else if let Some(index) = line.find(':') {
-                    let header_name = line.substring(0, index);
-                    let header_value = line.substring(index + 1, line.len());
-                    resp.insert_header((header_name, header_value));
-                }
-            }
-            Err(_) => break,
-        }
+    #[actix_web::test]
+    async fn squashed_mirror_updates() {
+        let (index_repository, upstream) = mirror("squash", true).await;
+        let repo = Repository::open(index_repository.get_git_repository_dir()).unwrap();
+        let upstream_head = upstream.head().unwrap().target().unwrap();
+        let references: Vec<String> = repo
+            .references()
+            .unwrap()
+            .map(|reference| reference.unwrap().name().unwrap().to_string())
+            .collect();
+        assert_eq!(vec![repo.head().unwrap().name().unwrap()], references);
+        assert_eq!(1, history(&repo).len());
+        assert!(repo.find_commit(upstream_head).is_err());
+        assert_eq!(
+            Some(upstream_head.to_string()),
+            index_repository.status().tagged
+        );
+
+        let updated = commit(&upstream, &[("to/ki/tokio", "{}\n")]);
+        index_repository.update_local_cache().unwrap();
+        let repo = Repository::open(index_repository.get_git_repository_dir()).unwrap();
+        assert_eq!(1, history(&repo).len());
+        assert!(repo.find_commit(updated).is_err());
+        assert_eq!(1, repo.references().unwrap().count());
+        assert!(index_repository
+            .read_index_file("to/ki/tokio")
+            .unwrap()
+            .is_some());
+        let (config, _) = index_repository
+            .read_index_file(CONFIG_JSON_FILE)
+            .unwrap()
+            .unwrap();
+        assert!(String::from_utf8_lossy(&config).contains("localhost:8181"));
+        assert_eq!(
+            ("https://static.example.com/serde".to_string(), None),
+            index_repository
+                .upstream_download("serde", "1.0.0")
+                .unwrap()
+        );
     }
 
-    let (tx, rx) = mpsc::channel::<Result<Bytes>>(100);
+    #[test]
+    fn coalesced_updates() {
+        let updates = IndexUpdates::new(60, 10);
+        let stale_after = Some(Duration::from_secs(10));
+        assert!(updates.begin(stale_after));
+        // Triggers during an update are dropped
+        assert!(!updates.begin(None));
+        assert!(!updates.begin(Some(Duration::ZERO)));
+        updates.finish(Instant::now(), &Ok(()));
+        assert!(!updates.state.lock().unwrap().updating);
+
+        // A fresh snapshot is kept, a stale one updated
+        assert!(!updates.begin(stale_after));
+        updates.state.lock().unwrap().last_update = Some(Instant::now() - Duration::from_secs(11));
+        assert!(updates.begin(stale_after));
+        updates.finish(
+            Instant::now(),
+            &Err(Error::Other {
+                message: String::from("upstream is unavailable"),
+            }),
+        );
+
+        // A failed update counts as an update, and backs off the next one
+        let state = updates.state.lock().unwrap();
+        assert_eq!(1, state.failures);
+        assert!(state.last_success.is_some());
+        assert_eq!(
+            Some("Error: upstream is unavailable"),
+            state.last_failure.as_ref().map(|(_, error)| error.as_str())
+        );
+        drop(state);
+        assert!(!updates.begin(stale_after));
+        updates.state.lock().unwrap().last_update = Some(Instant::now() - Duration::from_secs(11));
+        assert!(!updates.begin(stale_after));
+        updates.state.lock().unwrap().last_update = Some(Instant::now() - Duration::from_secs(21));
+        assert!(updates.begin(stale_after));
+
+        updates.finish(Instant::now(), &Ok(()));
+        assert_eq!(0, updates.state.lock().unwrap().failures);
+        updates.shutdown();
+        assert!(!updates.begin(None));
+    }
 
-    actix_web::rt::spawn(async move {
-        let mut buf: [u8; 8192] = [0; 8192];
-        while let Ok(count) = stdout.read(&mut buf).await {
-            if count > 0 {
-                if (tx.send(Ok(Bytes::from(buf[0..count].to_vec()))).await).is_err() {
-                    break;
-                }
-            } else {
-                break;
-            }
-        }
-    });
+    #[test]
+    fn backoff_delays() {
+        let minute = Duration::from_secs(60);
+        assert_eq!(minute, backoff_delay(minute, 0));
+        assert_eq!(minute * 2, backoff_delay(minute, 1));
+        assert_eq!(minute * 32, backoff_delay(minute, 5));
+        assert_eq!(minute * 64, backoff_delay(minute, 6));
+        assert_eq!(minute * 64, backoff_delay(minute, u32::MAX));
+        assert_eq!(Duration::MAX, backoff_delay(Duration::MAX, 1));
+        assert_eq!(Duration::ZERO, backoff_delay(Duration::ZERO, 3));
+    }
 
-    let body_stream = BodyStream::new(ReceiverStream::new(rx));
-    Ok(resp.body(body_stream))
-}
+    #[actix_web::test]
+    async fn shutdown_stops_periodic_updates() {
+        let (mut index_repository, _upstream) = mirror("periodic", false).await;
+        index_repository.updates = IndexUpdates::new(3600, 0);
+        let updates = actix_web::rt::spawn(index_repository.clone().periodic_update_of_cache());
+
+        // The first update runs straight away, the next one is an hour later
+        while index_repository.status().last_success.is_none() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!updates.is_finished());
+        index_repository.shutdown();
+        time::timeout(Duration::from_secs(5), updates)
+            .await
+            .expect("periodic updates stop on shutdown")
+            .unwrap();
+        assert!(!index_repository.updates.begin(None));
+    }
 
-pub fn git_backend_service(scope: &str) -> impl HttpServiceFactory {
-    web::resource(scope).to(handle_backend_service)
+    #[actix_web::test]
+    async fn update_stale_mirror() {
+        let (mut index_repository, upstream) = mirror("stale", false).await;
+        index_repository.updates = IndexUpdates::new(0, 10);
+        index_repository.updates.finish(Instant::now(), &Ok(()));
+
+        // The mirror was just created
+        index_repository.update_if_stale();
+        assert!(!index_repository.status().updating);
+
+        let updated = commit(&upstream, &[("to/ki/tokio", "{}\n")]);
+        index_repository.updates.state.lock().unwrap().last_update =
+            Some(Instant::now() - Duration::from_secs(11));
+        index_repository.update_if_stale();
+        assert!(index_repository.status().updating);
+        assert!(!index_repository.updates.begin(None));
+        while index_repository.status().updating {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        let status = index_repository.status();
+        assert_eq!(Some(updated.to_string()), status.tagged);
+        assert_eq!(None, status.last_error);
+
+        // An unavailable upstream fails the update, the snapshot is still served
+        fs::remove_dir_all(upstream.path().parent().unwrap()).unwrap();
+        assert!(index_repository.updates.begin(None));
+        index_repository.run_update();
+        let status = index_repository.status();
+        assert!(!status.updating);
+        assert!(status.last_error.is_some());
+        assert_eq!(Some(updated.to_string()), status.tagged);
+        assert!(index_repository
+            .read_index_file("to/ki/tokio")
+            .unwrap()
+            .is_some());
+    }
 }
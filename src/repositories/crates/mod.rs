@@ -3,43 +3,53 @@ use awc::Client;
 use crates_io_api::AsyncClient;
 
 use self::{git::IndexRepository, sparse::SparseRepository};
+use crate::cache::ArtifactCache;
 
 pub mod api;
 
 pub mod git;
 
+pub mod registry;
+
 pub mod sparse;
 
+pub mod upload_pack;
+
 pub struct CratesDownloadConfig {
     client: AsyncClient,
+    cache: ArtifactCache,
+    offline: bool,
+    /// Mirrored index whose config.json locates downloads, crates.io is queried without one.
+    index_repository: Option<IndexRepository>,
 }
 
-impl Default for CratesDownloadConfig {
-    fn default() -> Self {
+impl CratesDownloadConfig {
+    pub fn new(
+        cache: ArtifactCache,
+        offline: bool,
+        index_repository: Option<IndexRepository>,
+    ) -> Self {
         let client = AsyncClient::new(
             "seedwing-io (seedwing@example.com)",
             std::time::Duration::from_millis(1000),
         )
         .expect("Unable to construct crates.io async client");
-        Self { client }
+        Self {
+            client,
+            cache,
+            offline,
+            index_repository,
+        }
     }
 }
 
 pub struct CratesConfig {
-    scope: String,
     index_repository: IndexRepository,
-    git_cmd: String,
 }
 
 impl CratesConfig {
-    pub fn new(scope: &str, git_cmd: &str, index_repository: IndexRepository) -> Self {
-        let scope = String::from(scope);
-        let git_cmd = String::from(git_cmd);
-        Self {
-            scope,
-            index_repository,
-            git_cmd,
-        }
+    pub fn new(index_repository: IndexRepository) -> Self {
+        Self { index_repository }
     }
 }
 
@@ -47,53 +57,77 @@ pub struct CratesSparseConfig {
     scope: String,
     sparse_repository: SparseRepository,
     awc: Client,
+    cache: ArtifactCache,
+    offline: bool,
 }
 
 impl CratesSparseConfig {
-    pub fn new(scope: &str, sparse_repository: SparseRepository) -> Self {
+    pub fn new(
+        scope: &str,
+        sparse_repository: SparseRepository,
+        cache: ArtifactCache,
+        offline: bool,
+    ) -> Self {
         let scope = String::from(scope);
         let awc = Client::default();
         Self {
             scope,
             sparse_repository,
             awc,
+            cache,
+            offline,
         }
     }
 }
 
 pub fn service(
     scope: &str,
-    git_cmd: &str,
     index_repository: IndexRepository,
+    cache: ArtifactCache,
     api_path: &str,
+    sparse_index_path: Option<&str>,
 ) -> Scope {
     let scope = format!("/{scope}");
     log::info!("Creating cargo service with scope {scope}");
-    web::scope(&scope)
-        .app_data(web::Data::new(CratesDownloadConfig::default()))
-        .app_data(web::Data::new(CratesConfig::new(
-            &scope,
-            git_cmd,
-            index_repository,
+    let offline = index_repository.is_offline();
+    let mut service = web::scope(&scope)
+        .app_data(web::Data::new(CratesDownloadConfig::new(
+            cache,
+            offline,
+            Some(index_repository.clone()),
         )))
+        .app_data(web::Data::new(CratesConfig::new(index_repository)))
         .service(web::scope(api_path).service(api::v1::service()))
-        .service(git::git_backend_service("/info/refs"))
-        .service(git::git_backend_service("/git-upload-pack"))
+        .service(upload_pack::info_refs_service("/info/refs"))
+        .service(upload_pack::upload_pack_service("/git-upload-pack"));
+    if let Some(index_path) = sparse_index_path {
+        log::info!("Serving the git index over the sparse protocol at {scope}{index_path}/");
+        service = service.service(web::scope(index_path).service(sparse::index_file_service()));
+    }
+    service
 }
 
 pub fn service_sparse(
     scope: &str,
     sparse_repository: SparseRepository,
+    cache: ArtifactCache,
+    offline: bool,
     index_path: &str,
     api_path: &str,
 ) -> Scope {
     let scope = format!("/{scope}");
     log::info!("Creating cargo sparse service with scope {scope}");
     web::scope(&scope)
-        .app_data(web::Data::new(CratesDownloadConfig::default()))
+        .app_data(web::Data::new(CratesDownloadConfig::new(
+            cache.clone(),
+            offline,
+            None,
+        )))
         .app_data(web::Data::new(CratesSparseConfig::new(
             &scope,
             sparse_repository,
+            cache,
+            offline,
         )))
         .service(
             web::scope(index_path)
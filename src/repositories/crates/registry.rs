@@ -0,0 +1,127 @@
+/*
+ Helpers for the layout of a cargo registry index and its config.json
+*/
+
+use serde::Deserialize;
+
+use crate::repositories::crates::sparse::index_prefix;
+
+const CRATE_MARKER: &str = "{crate}";
+const VERSION_MARKER: &str = "{version}";
+const PREFIX_MARKER: &str = "{prefix}";
+const LOWER_PREFIX_MARKER: &str = "{lowerprefix}";
+const CHECKSUM_MARKER: &str = "{sha256-checksum}";
+
+/// The config.json of an upstream registry index.
+#[derive(Deserialize, Debug)]
+pub struct RegistryConfig {
+    dl: String,
+    #[serde(rename = "auth-required", default)]
+    auth_required: bool,
+}
+
+impl RegistryConfig {
+    pub fn parse(config_json: &[u8]) -> serde_json::Result<Self> {
+        serde_json::from_slice(config_json)
+    }
+
+    /// Whether the registry expects its token on every request, downloads included.
+    pub fn auth_required(&self) -> bool {
+        self.auth_required
+    }
+
+    /// Whether the download template needs the checksum from the index entry.
+    pub fn needs_checksum(&self) -> bool {
+        self.dl.contains(CHECKSUM_MARKER)
+    }
+
+    /// Expand the `dl` template for a crate version, following cargo's rules: without any
+    /// markers `/{crate}/{version}/download` is appended. None for invalid crate names.
+    pub fn download_url(
+        &self,
+        name: &str,
+        version: &str,
+        checksum: Option<&str>,
+    ) -> Option<String> {
+        let markers = [
+            CRATE_MARKER,
+            VERSION_MARKER,
+            PREFIX_MARKER,
+            LOWER_PREFIX_MARKER,
+            CHECKSUM_MARKER,
+        ];
+        let prefix = index_prefix(name)?;
+        if !markers.iter().any(|marker| self.dl.contains(marker)) {
+            return Some(format!(
+                "{}/{name}/{version}/download",
+                self.dl.trim_end_matches('/')
+            ));
+        }
+        let url = self
+            .dl
+            .replace(CRATE_MARKER, name)
+            .replace(VERSION_MARKER, version)
+            .replace(PREFIX_MARKER, &prefix)
+            .replace(LOWER_PREFIX_MARKER, &prefix.to_lowercase())
+            .replace(CHECKSUM_MARKER, checksum.unwrap_or_default());
+        Some(url)
+    }
+}
+
+#[derive(Deserialize)]
+struct IndexEntry {
+    vers: String,
+    cksum: String,
+}
+
+/// Find the checksum of a version in a crate's index file, one JSON entry per line.
+pub fn checksum(index_file: &str, version: &str) -> Option<String> {
+    index_file
+        .lines()
+        .filter_map(|line| serde_json::from_str::<IndexEntry>(line).ok())
+        .find(|entry| entry.vers == version)
+        .map(|entry| entry.cksum)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn download_url() {
+        let config =
+            RegistryConfig::parse(br#"{"dl": "https://static.crates.io/crates"}"#).unwrap();
+        assert!(!config.auth_required());
+        assert_eq!(
+            Some("https://static.crates.io/crates/serde/1.0.0/download"),
+            config.download_url("serde", "1.0.0", None).as_deref()
+        );
+
+        let config = RegistryConfig::parse(
+            br#"{"dl": "https://example.com/{lowerprefix}/{crate}-{version}.crate?sum={sha256-checksum}", "api": "https://example.com", "auth-required": true}"#,
+        )
+        .unwrap();
+        assert!(config.auth_required());
+        assert!(config.needs_checksum());
+        assert_eq!(
+            Some("https://example.com/se/rd/Serde-1.0.0.crate?sum=abc"),
+            config
+                .download_url("Serde", "1.0.0", Some("abc"))
+                .as_deref()
+        );
+        assert_eq!(None, config.download_url("Sérde", "1.0.0", Some("abc")));
+        assert_eq!(None, config.download_url("", "1.0.0", Some("abc")));
+    }
+
+    #[test]
+    fn index_checksum() {
+        let index_file = concat!(
+            r#"{"name":"serde","vers":"1.0.0","deps":[],"cksum":"aaa","features":{},"yanked":false}"#,
+            "\n",
+            r#"{"name":"serde","vers":"1.0.1","deps":[],"cksum":"bbb","features":{},"yanked":false}"#,
+            "\n"
+        );
+        assert_eq!(Some(String::from("bbb")), checksum(index_file, "1.0.1"));
+        assert_eq!(None, checksum(index_file, "2.0.0"));
+    }
+}
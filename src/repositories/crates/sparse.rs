@@ -1,10 +1,14 @@
 use actix_web::dev::HttpServiceFactory;
-use actix_web::http::header::{CONNECTION, HOST, UPGRADE};
+use actix_web::http::header::{
+    ACCEPT_ENCODING, CONNECTION, CONTENT_TYPE, ETAG, HOST, IF_NONE_MATCH, UPGRADE,
+};
 use actix_web::http::StatusCode;
 use actix_web::{error, guard, web, Error, HttpRequest, HttpResponse, Route};
 use url::Url;
 
-use super::CratesSparseConfig;
+use super::{CratesConfig, CratesSparseConfig};
+
+const CONFIG_JSON: &str = "config.json";
 
 #[derive(Clone)]
 pub struct SparseRepository {
@@ -61,11 +65,19 @@ async fn forward(
     let req_path = req.uri().path();
 
     let index_prefix = format!("{}/", &sparse_repository.index_prefix);
-    let new_path = format!(
-        "{}{}",
-        repo_url.path(),
-        req_path.strip_prefix(&index_prefix).unwrap()
-    );
+    let index_file = req_path.strip_prefix(&index_prefix).unwrap();
+    let new_path = format!("{}{}", repo_url.path(), index_file);
+
+    let cache_key = format!("index/{index_file}");
+    if crates.offline {
+        return match crates.cache.respond(&cache_key).await {
+            Some(response) => Ok(response),
+            None => {
+                log::info!("Index file {index_file} is not available offline");
+                Ok(HttpResponse::NotFound().finish())
+            }
+        };
+    }
 
     repo_url.set_path(new_path.as_str());
     repo_url.set_query(req.uri().query());
@@ -76,27 +88,73 @@ async fn forward(
     );
     let mut forwarded_req = crates.awc.request(req.method().clone(), repo_url.as_str());
 
+    // Index files are small, request them uncompressed so they can be cached as is
     forwarded_req.headers_mut().clone_from(&remove_headers!(
         req.headers(),
+        ACCEPT_ENCODING,
         CONNECTION,
         HOST,
         UPGRADE
     ));
     forwarded_req = forwarded_req.no_decompress();
 
-    let res = forwarded_req
+    let mut res = forwarded_req
         .send()
         .await
         .map_err(error::ErrorInternalServerError)?;
 
     let headers = remove_headers!(res.headers(), CONNECTION);
 
+    if res.status() == StatusCode::OK {
+        let payload = res.body().limit(20_000_000).await?;
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        crates
+            .cache
+            .store(&cache_key, content_type, None, None, payload.clone())
+            .await;
+
+        let mut client_resp = HttpResponse::build(res.status()).body(payload);
+        client_resp.headers_mut().clone_from(&headers);
+        return Ok(client_resp);
+    }
+
     let mut client_resp = HttpResponse::build(res.status()).streaming(res);
     client_resp.headers_mut().clone_from(&headers);
 
     Ok(client_resp)
 }
 
+/// Whether a name is a valid crate name, made of ASCII alphanumerics, `-` and `_`.
+pub fn is_crate_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// The directory holding a crate's index file, `1`, `2`, `3/a` or `ab/cd`, in the case of the
+/// name. None for names which are not valid crate names.
+pub fn index_prefix(crate_name: &str) -> Option<String> {
+    if !is_crate_name(crate_name) {
+        return None;
+    }
+    Some(match crate_name.len() {
+        1 => String::from("1"),
+        2 => String::from("2"),
+        3 => format!("3/{}", &crate_name[0..1]),
+        _ => format!("{}/{}", &crate_name[0..2], &crate_name[2..4]),
+    })
+}
+
+/// Location of a crate's file within the index, following the layout used by cargo.
+/// None for names which are not valid crate names.
+pub fn index_path(crate_name: &str) -> Option<String> {
+    let name = crate_name.to_ascii_lowercase();
+    Some(format!("{}/{name}", index_prefix(&name)?))
+}
+
 pub fn proxy_service() -> Route {
     web::get().to(forward)
 }
@@ -118,3 +176,176 @@ async fn generate_config(crates: web::Data<CratesSparseConfig>) -> Result<HttpRe
 pub fn config_service(scope: &str) -> impl HttpServiceFactory {
     web::resource(scope).guard(guard::Get()).to(generate_config)
 }
+
+/// Serve a file of a mirrored git index through the sparse protocol, tagged with its blob id so
+/// clients can revalidate their copy.
+async fn serve_index_file(
+    req: HttpRequest,
+    path: web::Path<String>,
+    crates: web::Data<CratesConfig>,
+) -> Result<HttpResponse, Error> {
+    let index_repository = crates.index_repository.clone();
+    if !index_repository.init_cache().await {
+        return Err(error::ErrorNotFound("Failed to initialise local cache"));
+    }
+    let index_file = path.into_inner();
+    if index_file == CONFIG_JSON {
+        // Cargo fetches config.json first whenever it updates the index
+        index_repository.update_if_stale();
+    }
+
+    let file = index_repository.clone();
+    let path = index_file.clone();
+    let file = web::block(move || file.read_index_file(&path))
+        .await?
+        .map_err(error::ErrorInternalServerError)?;
+    let (contents, id) = match file {
+        Some(file) => file,
+        None => {
+            log::debug!("Index file {index_file} not found");
+            return Ok(HttpResponse::NotFound().finish());
+        }
+    };
+
+    let etag = format!("\"{id}\"");
+    let matched = req
+        .headers()
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        });
+    if matched {
+        return Ok(HttpResponse::NotModified()
+            .insert_header((ETAG, etag))
+            .finish());
+    }
+
+    let content_type = if index_file == CONFIG_JSON {
+        "application/json"
+    } else {
+        "text/plain"
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((ETAG, etag))
+        .body(contents))
+}
+
+pub fn index_file_service() -> impl HttpServiceFactory {
+    web::resource("/{path:.+}")
+        .guard(guard::Get())
+        .to(serve_index_file)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::crates::git::test::mirror;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+    use std::path::Path;
+
+    #[test]
+    fn crate_index_paths() {
+        assert_eq!(Some("1/a"), index_path("a").as_deref());
+        assert_eq!(Some("2/io"), index_path("io").as_deref());
+        assert_eq!(Some("3/u/url"), index_path("url").as_deref());
+        assert_eq!(
+            Some("se/ed/seedwing-proxy"),
+            index_path("Seedwing-Proxy").as_deref()
+        );
+        assert_eq!(None, index_path(""));
+        assert_eq!(None, index_path("ééé"));
+        assert_eq!(None, index_path("aé"));
+        assert_eq!(None, index_path("../config.json"));
+        assert_eq!(Some("Se/rd"), index_prefix("Serde").as_deref());
+        assert_eq!(Some("3/U"), index_prefix("Url").as_deref());
+        assert_eq!(None, index_prefix("sé"));
+    }
+
+    #[actix_web::test]
+    async fn index_files() {
+        let (index_repository, upstream) = mirror("sparse", false).await;
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(CratesConfig::new(index_repository)))
+                .service(web::scope("/index").service(index_file_service())),
+        )
+        .await;
+        let blob_id = upstream
+            .head()
+            .unwrap()
+            .peel_to_tree()
+            .unwrap()
+            .get_path(Path::new("se/rd/serde"))
+            .unwrap()
+            .id();
+        let etag = format!("\"{blob_id}\"");
+
+        let response = call_service(
+            &app,
+            TestRequest::get().uri("/index/se/rd/serde").to_request(),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            Some(etag.as_str()),
+            response
+                .headers()
+                .get(ETAG)
+                .and_then(|value| value.to_str().ok())
+        );
+        assert_eq!(
+            "{\"name\":\"serde\",\"vers\":\"1.0.0\"}\n",
+            read_body(response).await
+        );
+
+        for if_none_match in [etag.clone(), format!("\"0000\", {etag}"), String::from("*")] {
+            let request = TestRequest::get()
+                .uri("/index/se/rd/serde")
+                .insert_header((IF_NONE_MATCH, if_none_match))
+                .to_request();
+            let response = call_service(&app, request).await;
+            assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+            assert_eq!(
+                Some(etag.as_str()),
+                response
+                    .headers()
+                    .get(ETAG)
+                    .and_then(|value| value.to_str().ok())
+            );
+        }
+        let request = TestRequest::get()
+            .uri("/index/se/rd/serde")
+            .insert_header((IF_NONE_MATCH, "\"0000\""))
+            .to_request();
+        assert_eq!(StatusCode::OK, call_service(&app, request).await.status());
+
+        for missing in [
+            "/index/se/rd/serde-json",
+            "/index/se/rd",
+            "/index/3/s/syn/extra",
+        ] {
+            let response = call_service(&app, TestRequest::get().uri(missing).to_request()).await;
+            assert_eq!(StatusCode::NOT_FOUND, response.status(), "{missing}");
+        }
+
+        let response = call_service(
+            &app,
+            TestRequest::get().uri("/index/config.json").to_request(),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            Some("application/json"),
+            response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+        );
+        assert!(String::from_utf8_lossy(&read_body(response).await).contains("localhost:8181"));
+    }
+}
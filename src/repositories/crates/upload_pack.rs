@@ -0,0 +1,544 @@
+/*
+ Crates upload-pack module serving the local index mirror over the git smart HTTP protocol.
+
+ Only the stateless upload-pack service (protocol version 0/1) is implemented, which is all cargo
+ and git need to clone and fetch the index. Negotiation is handled without multi_ack: the first
+ common commit sent by the client is acknowledged and every common commit is excluded from the
+ pack, which is built by libgit2 and streamed back on side-band 1 when the client requests it.
+*/
+
+use std::path::{Path, PathBuf};
+
+use actix_web::{
+    body::BodyStream,
+    dev::HttpServiceFactory,
+    error::{ErrorBadRequest, ErrorForbidden, ErrorNotFound},
+    guard,
+    http::header::{CACHE_CONTROL, CONTENT_TYPE},
+    web, HttpRequest, HttpResponse,
+};
+use bytes::Bytes;
+use git2::{ObjectType, Oid, Repository};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use super::CratesConfig;
+use crate::errors::Result;
+
+const UPLOAD_PACK_SERVICE: &str = "git-upload-pack";
+const ADVERTISEMENT_CONTENT_TYPE: &str = "application/x-git-upload-pack-advertisement";
+const REQUEST_CONTENT_TYPE: &str = "application/x-git-upload-pack-request";
+const RESULT_CONTENT_TYPE: &str = "application/x-git-upload-pack-result";
+
+const FLUSH_PKT: &[u8] = b"0000";
+/// Largest payload of a side-band-64k packet, after the length and band bytes
+const MAX_SIDE_BAND_DATA: usize = 65515;
+const PACK_BAND: u8 = 1;
+const ERROR_BAND: u8 = 3;
+
+const SIDE_BAND_64K: &str = "side-band-64k";
+const AGENT: &str = concat!("seedwing-proxy/", env!("CARGO_PKG_VERSION"));
+
+const REQUEST_LIMIT: usize = 10_000_000;
+
+fn write_pkt_line(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(format!("{:04x}", data.len() + 4).as_bytes());
+    out.extend_from_slice(data);
+}
+
+/// Split a request body into pkt-lines, a flush-pkt is returned as None.
+fn parse_pkt_lines(mut body: &[u8]) -> std::result::Result<Vec<Option<&[u8]>>, String> {
+    let mut lines = Vec::new();
+    while !body.is_empty() {
+        let len = body
+            .get(0..4)
+            .and_then(|len| std::str::from_utf8(len).ok())
+            .and_then(|len| usize::from_str_radix(len, 16).ok())
+            .ok_or_else(|| String::from("Invalid pkt-line length"))?;
+        match len {
+            0 => lines.push(None),
+            1..=3 => return Err(format!("Unexpected pkt-line length {len}")),
+            _ => {
+                let line = body
+                    .get(4..len)
+                    .ok_or_else(|| String::from("Truncated pkt-line"))?;
+                lines.push(Some(line));
+            }
+        }
+        body = &body[len.max(4)..];
+    }
+    Ok(lines)
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct UploadRequest {
+    wants: Vec<Oid>,
+    haves: Vec<Oid>,
+    capabilities: Vec<String>,
+    done: bool,
+}
+
+fn parse_oid(oid: &str) -> std::result::Result<Oid, String> {
+    Oid::from_str(oid).map_err(|_| format!("Invalid object id {oid}"))
+}
+
+fn parse_request(body: &[u8]) -> std::result::Result<UploadRequest, String> {
+    let mut request = UploadRequest::default();
+    for line in parse_pkt_lines(body)?.into_iter().flatten() {
+        let line = std::str::from_utf8(line)
+            .map_err(|_| String::from("Request is not valid UTF-8"))?
+            .trim_end_matches('\n');
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+        match command {
+            "want" => {
+                let mut arguments = argument.split(' ');
+                request
+                    .wants
+                    .push(parse_oid(arguments.next().unwrap_or_default())?);
+                if request.wants.len() == 1 {
+                    request.capabilities = arguments.map(String::from).collect();
+                }
+            }
+            "have" => request.haves.push(parse_oid(argument)?),
+            "done" => request.done = true,
+            "shallow" | "deepen" | "deepen-since" | "deepen-not" => {
+                return Err(String::from("Shallow fetches are not supported"))
+            }
+            _ => return Err(format!("Unexpected request line: {line}")),
+        }
+    }
+    if request.wants.is_empty() {
+        return Err(String::from("No objects were requested"));
+    }
+    Ok(request)
+}
+
+struct AdvertisedRef {
+    name: String,
+    oid: Oid,
+    peeled: Option<Oid>,
+}
+
+/// The branches and tags served to clients, only the branch of HEAD for a squashed mirror.
+fn advertised_refs(
+    repo: &Repository,
+    squash: bool,
+) -> std::result::Result<Vec<AdvertisedRef>, git2::Error> {
+    let head = repo.head()?;
+    let head_name = head.name().unwrap_or_default();
+    let mut refs = vec![AdvertisedRef {
+        name: String::from("HEAD"),
+        oid: head.peel_to_commit()?.id(),
+        peeled: None,
+    }];
+    for reference in repo.references()? {
+        let reference = reference?;
+        let Some(name) = reference.name() else {
+            continue;
+        };
+        if squash && name != head_name
+            || !name.starts_with("refs/heads/") && !name.starts_with("refs/tags/")
+        {
+            continue;
+        }
+        let Some(oid) = reference.resolve()?.target() else {
+            continue;
+        };
+        let object = repo.find_object(oid, None)?;
+        let peeled = match object.kind() {
+            Some(ObjectType::Tag) => Some(object.peel(ObjectType::Any)?.id()),
+            _ => None,
+        };
+        refs.push(AdvertisedRef {
+            name: String::from(name),
+            oid,
+            peeled,
+        });
+    }
+    refs[1..].sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(refs)
+}
+
+fn advertise(git_dir: &Path, squash: bool) -> std::result::Result<Vec<u8>, git2::Error> {
+    let repo = Repository::open(git_dir)?;
+    let head = repo.head()?;
+    let capabilities = format!(
+        "{SIDE_BAND_64K} no-progress symref=HEAD:{} agent={AGENT}",
+        head.name().unwrap_or("HEAD")
+    );
+
+    let mut out = Vec::new();
+    write_pkt_line(
+        &mut out,
+        format!("# service={UPLOAD_PACK_SERVICE}\n").as_bytes(),
+    );
+    out.extend_from_slice(FLUSH_PKT);
+    for (index, reference) in advertised_refs(&repo, squash)?.iter().enumerate() {
+        let line = if index == 0 {
+            format!("{} {}\0{capabilities}\n", reference.oid, reference.name)
+        } else {
+            format!("{} {}\n", reference.oid, reference.name)
+        };
+        write_pkt_line(&mut out, line.as_bytes());
+        if let Some(peeled) = reference.peeled {
+            write_pkt_line(
+                &mut out,
+                format!("{peeled} {}^{{}}\n", reference.name).as_bytes(),
+            );
+        }
+    }
+    out.extend_from_slice(FLUSH_PKT);
+    Ok(out)
+}
+
+struct Negotiation {
+    /// ACK or NAK response to the client's haves
+    response: Vec<u8>,
+    common: Vec<Oid>,
+    unknown_want: Option<Oid>,
+}
+
+fn negotiate(
+    git_dir: &Path,
+    squash: bool,
+    wants: &[Oid],
+    haves: &[Oid],
+) -> std::result::Result<Negotiation, git2::Error> {
+    let repo = Repository::open(git_dir)?;
+    let tips: Vec<Oid> = advertised_refs(&repo, squash)?
+        .iter()
+        .flat_map(|reference| [Some(reference.oid), reference.peeled])
+        .flatten()
+        .collect();
+    // Objects reachable from the advertised refs may be requested, as the refs can move on
+    // between the advertisement and this request while the index is updated
+    let unknown_want = wants.iter().copied().find(|want| {
+        !tips
+            .iter()
+            .any(|tip| tip == want || repo.graph_descendant_of(*tip, *want).unwrap_or_default())
+    });
+    let common: Vec<Oid> = haves
+        .iter()
+        .copied()
+        .filter(|have| repo.find_commit(*have).is_ok())
+        .collect();
+
+    let mut response = Vec::new();
+    match common.first() {
+        Some(oid) => write_pkt_line(&mut response, format!("ACK {oid}\n").as_bytes()),
+        None => write_pkt_line(&mut response, b"NAK\n"),
+    }
+    Ok(Negotiation {
+        response,
+        common,
+        unknown_want,
+    })
+}
+
+/// Stream the pack for the wanted objects, excluding everything reachable from the common commits.
+fn write_pack(
+    git_dir: &Path,
+    wants: &[Oid],
+    common: &[Oid],
+    side_band: bool,
+    tx: &mpsc::Sender<std::io::Result<Bytes>>,
+) -> std::result::Result<(), git2::Error> {
+    let repo = Repository::open(git_dir)?;
+    let mut builder = repo.packbuilder()?;
+    let mut walk = repo.revwalk()?;
+    for want in wants {
+        let object = repo.find_object(*want, None)?;
+        if let Some(ObjectType::Tag) = object.kind() {
+            builder.insert_object(*want, None)?;
+        }
+        walk.push(object.peel_to_commit()?.id())?;
+    }
+    for have in common {
+        walk.hide(*have)?;
+    }
+    builder.insert_walk(&mut walk)?;
+
+    builder.foreach(|chunk| {
+        if side_band {
+            chunk.chunks(MAX_SIDE_BAND_DATA).all(|data| {
+                let mut packet = Vec::with_capacity(data.len() + 5);
+                write_pkt_line(&mut packet, &[&[PACK_BAND], data].concat());
+                tx.blocking_send(Ok(Bytes::from(packet))).is_ok()
+            })
+        } else {
+            tx.blocking_send(Ok(Bytes::copy_from_slice(chunk))).is_ok()
+        }
+    })?;
+    if side_band {
+        let _ = tx.blocking_send(Ok(Bytes::from_static(FLUSH_PKT)));
+    }
+    Ok(())
+}
+
+async fn git_dir(crates: &CratesConfig) -> Result<PathBuf> {
+    if !crates.index_repository.init_cache().await {
+        return Err(ErrorNotFound("Failed to initialise local cache").into());
+    }
+    Ok(crates.index_repository.get_git_repository_dir())
+}
+
+#[derive(Deserialize)]
+struct InfoRefsQuery {
+    service: Option<String>,
+}
+
+async fn info_refs(
+    query: web::Query<InfoRefsQuery>,
+    crates: web::Data<CratesConfig>,
+) -> Result<HttpResponse> {
+    if query.service.as_deref() != Some(UPLOAD_PACK_SERVICE) {
+        return Err(ErrorForbidden("Only the smart upload-pack service is supported").into());
+    }
+    let git_dir = git_dir(&crates).await?;
+    crates.index_repository.update_if_stale();
+    let squash = crates.index_repository.get_squash();
+    let advertisement = web::block(move || advertise(&git_dir, squash))
+        .await
+        .map_err(actix_web::Error::from)??;
+    Ok(HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, ADVERTISEMENT_CONTENT_TYPE))
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .body(advertisement))
+}
+
+async fn upload_pack(
+    req: HttpRequest,
+    body: web::Bytes,
+    crates: web::Data<CratesConfig>,
+) -> Result<HttpResponse> {
+    let content_type = req.headers().get(CONTENT_TYPE);
+    if content_type.and_then(|value| value.to_str().ok()) != Some(REQUEST_CONTENT_TYPE) {
+        return Err(ErrorBadRequest("Unexpected content type").into());
+    }
+    let request = parse_request(&body).map_err(ErrorBadRequest)?;
+    let git_dir = git_dir(&crates).await?;
+
+    let negotiation = {
+        let git_dir = git_dir.clone();
+        let wants = request.wants.clone();
+        let haves = request.haves.clone();
+        let squash = crates.index_repository.get_squash();
+        web::block(move || negotiate(&git_dir, squash, &wants, &haves))
+            .await
+            .map_err(actix_web::Error::from)??
+    };
+    if let Some(want) = negotiation.unknown_want {
+        return Err(ErrorBadRequest(format!("upload-pack: not our ref {want}")).into());
+    }
+
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header((CONTENT_TYPE, RESULT_CONTENT_TYPE))
+        .insert_header((CACHE_CONTROL, "no-cache"));
+    if !request.done {
+        // Stateless negotiation continues with another request from the client
+        return Ok(response.body(negotiation.response));
+    }
+
+    let side_band = request
+        .capabilities
+        .iter()
+        .any(|capability| capability == SIDE_BAND_64K);
+    let (tx, rx) = mpsc::channel::<std::io::Result<Bytes>>(16);
+    tx.send(Ok(Bytes::from(negotiation.response)))
+        .await
+        .expect("receiver is still held");
+    actix_web::rt::task::spawn_blocking(move || {
+        if let Err(error) = write_pack(
+            &git_dir,
+            &request.wants,
+            &negotiation.common,
+            side_band,
+            &tx,
+        ) {
+            // The client has already received the 200, the error can only be reported in-band
+            log::warn!("Failed to write pack: {error}");
+            if side_band {
+                let mut packet = Vec::new();
+                let message = format!("upload-pack: {}", error.message());
+                write_pkt_line(&mut packet, &[&[ERROR_BAND], message.as_bytes()].concat());
+                let _ = tx.blocking_send(Ok(Bytes::from(packet)));
+            }
+        }
+    });
+    Ok(response.body(BodyStream::new(ReceiverStream::new(rx))))
+}
+
+pub fn info_refs_service(path: &str) -> impl HttpServiceFactory {
+    web::resource(path).guard(guard::Get()).to(info_refs)
+}
+
+pub fn upload_pack_service(path: &str) -> impl HttpServiceFactory {
+    web::resource(path)
+        .app_data(web::PayloadConfig::new(REQUEST_LIMIT))
+        .guard(guard::Post())
+        .to(upload_pack)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::crates::git::test::mirror;
+    use std::io::Write;
+
+    #[test]
+    fn pkt_lines() {
+        let mut body = Vec::new();
+        write_pkt_line(&mut body, b"done\n");
+        body.extend_from_slice(FLUSH_PKT);
+        assert_eq!(b"0009done\n0000", body.as_slice());
+        assert_eq!(
+            vec![Some(b"done\n".as_slice()), None],
+            parse_pkt_lines(&body).unwrap()
+        );
+        assert!(parse_pkt_lines(b"0009do").is_err());
+        assert!(parse_pkt_lines(b"zzzz").is_err());
+    }
+
+    #[test]
+    fn upload_request() {
+        let want = "7ab1e6e3c9bfb1d6c4b0e0e5f5a5ef3b2b9a7c11";
+        let have = "0e0a2ad2a3dbb3d8e8c3f6d9ba1f3a5c7e6b4d22";
+        let mut body = Vec::new();
+        write_pkt_line(
+            &mut body,
+            format!("want {want} side-band-64k agent=git/2.40.0\n").as_bytes(),
+        );
+        body.extend_from_slice(FLUSH_PKT);
+        write_pkt_line(&mut body, format!("have {have}\n").as_bytes());
+        write_pkt_line(&mut body, b"done\n");
+
+        assert_eq!(
+            UploadRequest {
+                wants: vec![Oid::from_str(want).unwrap()],
+                haves: vec![Oid::from_str(have).unwrap()],
+                capabilities: vec!["side-band-64k".into(), "agent=git/2.40.0".into()],
+                done: true,
+            },
+            parse_request(&body).unwrap()
+        );
+
+        let mut body = Vec::new();
+        write_pkt_line(&mut body, format!("want {want}\n").as_bytes());
+        write_pkt_line(&mut body, b"deepen 1\n");
+        assert!(parse_request(&body).is_err());
+        assert!(parse_request(FLUSH_PKT).is_err());
+    }
+
+    #[actix_web::test]
+    async fn clone_squashed_mirror() {
+        let (index_repository, upstream) = mirror("clone", true).await;
+        let git_dir = index_repository.get_git_repository_dir();
+        let repo = Repository::open(&git_dir).unwrap();
+        let refs = advertised_refs(&repo, true).unwrap();
+        assert_eq!(
+            vec!["HEAD", repo.head().unwrap().name().unwrap()],
+            refs.iter()
+                .map(|reference| reference.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        let upstream_head = upstream.head().unwrap().target().unwrap();
+        let negotiation = negotiate(&git_dir, true, &[upstream_head], &[]).unwrap();
+        assert_eq!(Some(upstream_head), negotiation.unknown_want);
+
+        let head = refs[0].oid;
+        let (tx, mut rx) = mpsc::channel(16);
+        let writer = std::thread::spawn(move || write_pack(&git_dir, &[head], &[], false, &tx));
+        let mut pack = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            pack.extend_from_slice(&chunk.unwrap());
+        }
+        writer.join().unwrap().unwrap();
+
+        let clone_dir = index_repository.get_local_repository_cache().join("clone");
+        let clone = Repository::init(clone_dir).unwrap();
+        let odb = clone.odb().unwrap();
+        let mut pack_writer = odb.packwriter().unwrap();
+        pack_writer.write_all(&pack).unwrap();
+        pack_writer.commit().unwrap();
+        let mut walk = clone.revwalk().unwrap();
+        walk.push(head).unwrap();
+        assert_eq!(1, walk.count());
+        let tree = clone.find_commit(head).unwrap().tree().unwrap();
+        assert!(tree.get_path(Path::new("se/rd/serde")).is_ok());
+    }
+    #[actix_web::test]
+    async fn smart_http_handlers() {
+        let (index_repository, upstream) = mirror("handlers", true).await;
+        let git_dir = index_repository.get_git_repository_dir();
+        let head = Repository::open(&git_dir)
+            .unwrap()
+            .head()
+            .unwrap()
+            .target()
+            .unwrap();
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(web::Data::new(CratesConfig::new(index_repository)))
+                .service(info_refs_service("/info/refs"))
+                .service(upload_pack_service("/git-upload-pack")),
+        )
+        .await;
+
+        let request = actix_web::test::TestRequest::get()
+            .uri("/info/refs?service=git-upload-pack")
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(200, response.status());
+        assert_eq!(
+            ADVERTISEMENT_CONTENT_TYPE,
+            response.headers().get(CONTENT_TYPE).unwrap()
+        );
+        let advertisement = actix_web::test::read_body(response).await;
+        assert!(advertisement.starts_with(b"001e# service=git-upload-pack\n0000"));
+        let first_ref = format!("{head} HEAD\0");
+        assert!(String::from_utf8_lossy(&advertisement).contains(&first_ref));
+
+        // Only the smart upload-pack service is offered
+        for uri in ["/info/refs", "/info/refs?service=git-receive-pack"] {
+            let request = actix_web::test::TestRequest::get().uri(uri).to_request();
+            let response = actix_web::test::call_service(&app, request).await;
+            assert_eq!(403, response.status(), "{uri}");
+        }
+
+        let upload = |content_type: &'static str, body: Vec<u8>| {
+            let request = actix_web::test::TestRequest::post()
+                .uri("/git-upload-pack")
+                .insert_header((CONTENT_TYPE, content_type))
+                .set_payload(body)
+                .to_request();
+            actix_web::test::call_service(&app, request)
+        };
+        let request = |want: Oid| {
+            let mut body = Vec::new();
+            write_pkt_line(&mut body, format!("want {want} no-progress\n").as_bytes());
+            body.extend_from_slice(FLUSH_PKT);
+            write_pkt_line(&mut body, b"done\n");
+            body
+        };
+
+        let response = upload(REQUEST_CONTENT_TYPE, request(head)).await;
+        assert_eq!(200, response.status());
+        assert_eq!(
+            RESULT_CONTENT_TYPE,
+            response.headers().get(CONTENT_TYPE).unwrap()
+        );
+        let result = actix_web::test::read_body(response).await;
+        assert!(result.starts_with(b"0008NAK\nPACK"));
+
+        // Malformed requests, and wants the mirror does not serve, are refused
+        let response = upload(REQUEST_CONTENT_TYPE, b"zzzz".to_vec()).await;
+        assert_eq!(400, response.status());
+        let response = upload("text/plain", request(head)).await;
+        assert_eq!(400, response.status());
+        let upstream_head = upstream.head().unwrap().target().unwrap();
+        let response = upload(REQUEST_CONTENT_TYPE, request(upstream_head)).await;
+        assert_eq!(400, response.status());
+    }
+}
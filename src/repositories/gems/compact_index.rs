@@ -0,0 +1,151 @@
+/*
+ The compact index of a gem server: the `names` and `versions` files and the `info/{name}` files
+ listing the versions of each gem with their dependencies and checksum. Clients keep copies of
+ these append-only files and request what was appended with a `Range` header, checking the result
+ against the digest of the whole file.
+*/
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::ops::Range;
+
+/// A byte range requested by a client, out of a file of a given length.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// No range, or one the server may ignore
+    Full,
+    Partial(Range<usize>),
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// Parse a `Range` header. Only a single range is served, others get the whole file.
+    pub fn parse(header: Option<&str>, len: usize) -> Self {
+        let Some(range) = header.and_then(|header| header.trim().strip_prefix("bytes=")) else {
+            return Self::Full;
+        };
+        if range.contains(',') {
+            return Self::Full;
+        }
+        let Some((start, end)) = range.split_once('-') else {
+            return Self::Full;
+        };
+        let (start, end) = match (start.trim().parse::<usize>(), end.trim()) {
+            (Ok(start), "") => (start, len.saturating_sub(1)),
+            (Ok(start), end) => match end.parse::<usize>() {
+                Ok(end) if end >= start => (start, end.min(len.saturating_sub(1))),
+                _ => return Self::Full,
+            },
+            // The last bytes of the file
+            (Err(_), suffix) if start.trim().is_empty() => match suffix.parse::<usize>() {
+                Ok(0) => return Self::Unsatisfiable,
+                Ok(suffix) => (len.saturating_sub(suffix), len.saturating_sub(1)),
+                Err(_) => return Self::Full,
+            },
+            _ => return Self::Full,
+        };
+        if start >= len {
+            Self::Unsatisfiable
+        } else {
+            Self::Partial(start..end + 1)
+        }
+    }
+}
+
+/// The `Repr-Digest` of a whole file.
+pub fn repr_digest(body: &[u8]) -> String {
+    format!("sha-256=:{}:", STANDARD.encode(Sha256::digest(body)))
+}
+
+/// The legacy `Digest` of a whole file, still read by older clients.
+pub fn digest(body: &[u8]) -> String {
+    format!("sha-256={}", STANDARD.encode(Sha256::digest(body)))
+}
+
+/// The `ETag` of a file, its quoted md5 as with rubygems.org.
+pub fn etag(body: &[u8]) -> String {
+    format!("\"{:x}\"", Md5::digest(body))
+}
+
+/// Whether a file matches the sha-256 of a `Repr-Digest` or `Digest` header. Digests of other
+/// algorithms are not checked.
+pub fn matches_digest(header: &str, body: &[u8]) -> bool {
+    let expected = STANDARD.encode(Sha256::digest(body));
+    header
+        .split(',')
+        .filter_map(|digest| digest.trim().split_once('='))
+        .filter(|(algorithm, _)| algorithm.trim().eq_ignore_ascii_case("sha-256"))
+        .all(|(_, value)| value.trim().trim_matches(':') == expected)
+}
+
+/// The sha256 checksum listed for a version, or a `{version}-{platform}`, in an info file.
+pub fn checksum<'a>(info: &'a str, version: &str) -> Option<&'a str> {
+    info.lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(listed, _)| *listed == version)
+        .and_then(|(_, rest)| {
+            let (_, requirements) = rest.rsplit_once('|')?;
+            requirements
+                .split(',')
+                .find_map(|requirement| requirement.strip_prefix("checksum:"))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(ByteRange::Full, ByteRange::parse(None, 10));
+        assert_eq!(
+            ByteRange::Partial(9..10),
+            ByteRange::parse(Some("bytes=9-"), 10)
+        );
+        assert_eq!(
+            ByteRange::Partial(2..5),
+            ByteRange::parse(Some("bytes=2-4"), 10)
+        );
+        assert_eq!(
+            ByteRange::Partial(2..10),
+            ByteRange::parse(Some("bytes=2-40"), 10)
+        );
+        assert_eq!(
+            ByteRange::Partial(7..10),
+            ByteRange::parse(Some("bytes=-3"), 10)
+        );
+        assert_eq!(
+            ByteRange::Unsatisfiable,
+            ByteRange::parse(Some("bytes=10-"), 10)
+        );
+        assert_eq!(ByteRange::Full, ByteRange::parse(Some("bytes=0-1,4-5"), 10));
+        assert_eq!(ByteRange::Full, ByteRange::parse(Some("lines=1-"), 10));
+        assert_eq!(ByteRange::Full, ByteRange::parse(Some("bytes=5-2"), 10));
+    }
+
+    #[test]
+    fn file_digests() {
+        let body = b"created_at: 2024-01-01T00:00:00Z\n---\nrake 13.0.6 abc\n";
+        assert!(matches_digest(&repr_digest(body), body));
+        assert!(matches_digest(&digest(body), body));
+        assert!(matches_digest(
+            &format!("md5=:abc:, {}", repr_digest(body)),
+            body
+        ));
+        assert!(!matches_digest(&repr_digest(b"other"), body));
+        assert_eq!("\"d41d8cd98f00b204e9800998ecf8427e\"", etag(b""));
+    }
+
+    #[test]
+    fn info_checksums() {
+        let info = "---\n\
+            1.15.4 mini_portile2:~> 2.8.2,racc:~> 1.4|checksum:e4d5,ruby:>= 2.7.0\n\
+            1.15.4-x86_64-linux racc:~> 1.4|checksum:a1b2,ruby:< 3.3.dev, >= 2.7\n\
+            13.0.6 |checksum:5ce4\n";
+        assert_eq!(Some("e4d5"), checksum(info, "1.15.4"));
+        assert_eq!(Some("a1b2"), checksum(info, "1.15.4-x86_64-linux"));
+        assert_eq!(Some("5ce4"), checksum(info, "13.0.6"));
+        assert_eq!(None, checksum(info, "1.15.5"));
+    }
+}
@@ -0,0 +1,128 @@
+/*
+ Parsing of gem file names, `{name}-{version}[-{platform}].gem`, and their package URLs
+*/
+
+use urlencoding::encode;
+
+pub const GEM_EXTENSION: &str = "gem";
+pub const GEMSPEC_EXTENSION: &str = "gemspec.rz";
+
+/// A gem, or its specification, identified by its file name.
+#[derive(Debug, PartialEq, Eq)]
+pub struct GemFile<'a> {
+    pub name: &'a str,
+    pub version: &'a str,
+    /// None for pure ruby gems
+    pub platform: Option<&'a str>,
+    pub extension: &'static str,
+}
+
+/// Whether a part of a file name is a version: numeric first segment, then alphanumeric
+/// segments separated by dots.
+fn is_version(part: &str) -> bool {
+    let mut segments = part.split('.');
+    segments
+        .next()
+        .is_some_and(|first| !first.is_empty() && first.bytes().all(|b| b.is_ascii_digit()))
+        && segments.all(|segment| {
+            !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_alphanumeric())
+        })
+}
+
+impl<'a> GemFile<'a> {
+    /// Parse a file name. The version is the first part of the name which is one, as gem
+    /// names may contain dashes but platforms such as `universal-darwin-22` contain numbers.
+    pub fn parse(file: &'a str) -> Option<Self> {
+        let (stem, extension) = match file.strip_suffix(".gemspec.rz") {
+            Some(stem) => (stem, GEMSPEC_EXTENSION),
+            None => (file.strip_suffix(".gem")?, GEM_EXTENSION),
+        };
+        let mut offset = 0;
+        for part in stem.split('-') {
+            if offset > 0 && is_version(part) {
+                let version_start = offset;
+                let version_end = version_start + part.len();
+                let platform = stem
+                    .get(version_end + 1..)
+                    .filter(|platform| !platform.is_empty());
+                return Some(Self {
+                    name: &stem[..version_start - 1],
+                    version: &stem[version_start..version_end],
+                    platform,
+                    extension,
+                });
+            }
+            offset += part.len() + 1;
+        }
+        None
+    }
+
+    /// The version as listed in the compact index, followed by the platform if any.
+    pub fn full_version(&self) -> String {
+        match self.platform {
+            Some(platform) => format!("{}-{platform}", self.version),
+            None => String::from(self.version),
+        }
+    }
+
+    /// The package URL, with the platform of native gems as a qualifier.
+    pub fn purl(&self) -> String {
+        let purl = format!("pkg:gem/{}@{}", encode(self.name), encode(self.version));
+        match self.platform {
+            Some(platform) => format!("{purl}?platform={}", encode(platform)),
+            None => purl,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_gem_files() {
+        let gem = GemFile::parse("nokogiri-1.15.4-x86_64-linux.gem").unwrap();
+        assert_eq!(
+            GemFile {
+                name: "nokogiri",
+                version: "1.15.4",
+                platform: Some("x86_64-linux"),
+                extension: GEM_EXTENSION,
+            },
+            gem
+        );
+        assert_eq!("pkg:gem/nokogiri@1.15.4?platform=x86_64-linux", gem.purl());
+        assert_eq!("1.15.4-x86_64-linux", gem.full_version());
+
+        let gem = GemFile::parse("aws-sdk-s3-1.136.0.gem").unwrap();
+        assert_eq!(
+            ("aws-sdk-s3", "1.136.0", None),
+            (gem.name, gem.version, gem.platform)
+        );
+        assert_eq!("pkg:gem/aws-sdk-s3@1.136.0", gem.purl());
+
+        let gem = GemFile::parse("rails-7.1.0.beta1.gemspec.rz").unwrap();
+        assert_eq!(("rails", "7.1.0.beta1"), (gem.name, gem.version));
+        assert_eq!(GEMSPEC_EXTENSION, gem.extension);
+
+        let gem = GemFile::parse("ffi-1.16.3-universal-darwin-22.gem").unwrap();
+        assert_eq!(
+            ("ffi", "1.16.3", Some("universal-darwin-22")),
+            (gem.name, gem.version, gem.platform)
+        );
+        let gem = GemFile::parse("sqlite3-1.6.8-x64-mingw-ucrt.gem").unwrap();
+        assert_eq!(Some("x64-mingw-ucrt"), gem.platform);
+        let gem = GemFile::parse("jruby-openssl-0.14.2-java.gem").unwrap();
+        assert_eq!(
+            ("jruby-openssl", "0.14.2", Some("java")),
+            (gem.name, gem.version, gem.platform)
+        );
+        let gem = GemFile::parse("ed25519-1.3.0.gem").unwrap();
+        assert_eq!(("ed25519", "1.3.0"), (gem.name, gem.version));
+
+        assert_eq!(None, GemFile::parse("rake.gem"));
+        assert_eq!(None, GemFile::parse("1.0.0.gem"));
+        assert_eq!(None, GemFile::parse("rake-1.0.0.tar"));
+        assert_eq!(None, GemFile::parse("rake-latest.gem"));
+    }
+}
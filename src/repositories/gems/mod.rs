@@ -1,42 +1,383 @@
 use actix_web::{
-    get, route, web, Error, HttpRequest, HttpResponse, HttpResponseBuilder, Responder, Scope,
+    get,
+    http::{
+        header::{
+            HeaderValue, ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+            IF_NONE_MATCH, RANGE,
+        },
+        Method, StatusCode,
+    },
+    route, web, Error, HttpRequest, HttpResponse, HttpResponseBuilder, Responder, Scope,
 };
+use awc::ClientResponse;
+use bytes::{Bytes, BytesMut};
 use url::Url;
 
-use crate::policy::{context::Context, PolicyEngine};
+use crate::cache::ArtifactCache;
+use crate::policy::{context::Context, PolicyEngine, Verdict};
+use crate::repositories::send_retrying;
+
+pub mod compact_index;
+pub mod coordinates;
+
+use self::compact_index::ByteRange;
+use self::coordinates::{GemFile, GEM_EXTENSION};
 
 pub struct GemsConfig {
     url: Url,
-}
-
-impl Default for GemsConfig {
-    fn default() -> Self {
-        Self::new("https://rubygems.org".try_into().unwrap())
-    }
+    cache: ArtifactCache,
+    offline: bool,
 }
 
 impl GemsConfig {
-    pub fn new(url: Url) -> Self {
-        Self { url }
+    pub fn new(url: Url, cache: ArtifactCache, offline: bool) -> Self {
+        Self {
+            url,
+            cache,
+            offline,
+        }
     }
 }
 
-pub fn service(scope: &str, url: Url) -> Scope {
+pub fn service(scope: &str, url: Url, cache: ArtifactCache, offline: bool) -> Scope {
     web::scope(scope)
-        .app_data(web::Data::new(GemsConfig::new(url)))
+        .app_data(web::Data::new(GemsConfig::new(url, cache, offline)))
+        .service(index)
+        .service(dependencies)
         .service(proxy)
         .service(pass_through)
 }
 
-#[get(r"{pkg}/{name:.*}-{version:[0-9]+\.[0-9]+.*}.{ext}")]
+const METADATA_LIMIT: usize = 100_000_000;
+const INDEX_TYPE: &str = "text/plain; charset=utf-8";
+const REPR_DIGEST: &str = "repr-digest";
+const DIGEST: &str = "digest";
+
+async fn from_cache(cache: &ArtifactCache, key: &str) -> HttpResponse {
+    match cache.respond(key).await {
+        Some(response) => response,
+        None => {
+            log::info!("{key} is not available offline");
+            HttpResponse::NotFound().body("This rubygem could not be found.")
+        }
+    }
+}
+
+/// Serve a compact index file, or the part of it appended since the copy of the client.
+fn respond_index(req: &HttpRequest, content_type: Option<&str>, body: Bytes) -> HttpResponse {
+    let etag = compact_index::etag(&body);
+    let if_none_match = req
+        .headers()
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok());
+    if if_none_match.is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == etag)) {
+        return HttpResponse::NotModified()
+            .insert_header((ETAG, etag))
+            .finish();
+    }
+    let range = req
+        .headers()
+        .get(RANGE)
+        .and_then(|value| value.to_str().ok());
+    let (mut response, range) = match ByteRange::parse(range, body.len()) {
+        ByteRange::Full => (HttpResponse::Ok(), None),
+        ByteRange::Partial(range) => (HttpResponse::PartialContent(), Some(range)),
+        ByteRange::Unsatisfiable => {
+            return HttpResponse::RangeNotSatisfiable()
+                .insert_header((CONTENT_RANGE, format!("bytes */{}", body.len())))
+                .insert_header((REPR_DIGEST, compact_index::repr_digest(&body)))
+                .insert_header((DIGEST, compact_index::digest(&body)))
+                .finish();
+        }
+    };
+    response
+        .insert_header((CONTENT_TYPE, content_type.unwrap_or(INDEX_TYPE)))
+        .insert_header((ACCEPT_RANGES, "bytes"))
+        .insert_header((ETAG, etag))
+        .insert_header((REPR_DIGEST, compact_index::repr_digest(&body)))
+        .insert_header((DIGEST, compact_index::digest(&body)));
+    match range {
+        Some(range) => {
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, body.len());
+            response
+                .insert_header((CONTENT_RANGE, content_range))
+                .body(body.slice(range))
+        }
+        None => response.body(body),
+    }
+}
+
+/// The digest header of an upstream response which the file does not match, if any.
+fn mismatched_digest<S>(upstream: &ClientResponse<S>, body: &[u8]) -> Option<&'static str> {
+    [REPR_DIGEST, DIGEST].into_iter().find(|header| {
+        upstream
+            .headers()
+            .get(*header)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| !compact_index::matches_digest(value, body))
+    })
+}
+
+/// Whether an upstream response has a digest of the whole file, and the file matches it.
+fn matches_digests<S>(upstream: &ClientResponse<S>, body: &[u8]) -> bool {
+    let has_digest = [REPR_DIGEST, DIGEST]
+        .iter()
+        .any(|header| upstream.headers().contains_key(*header));
+    has_digest && mismatched_digest(upstream, body).is_none()
+}
+
+/// Bring a cached compact index file up to date with what the upstream appended to it. None when
+/// the result cannot be checked against the upstream digests and the file must be fetched whole.
+async fn refresh_index(
+    config: &GemsConfig,
+    client: &awc::Client,
+    file: &str,
+    content_type: Option<&str>,
+    cached: Bytes,
+) -> Option<Bytes> {
+    let uri = format!("{}{file}", config.url);
+    log::debug!("index: {uri} from {}", cached.len());
+    let range = format!("bytes={}-", cached.len());
+    let mut upstream = match send_retrying(|| {
+        client
+            .get(&uri)
+            .insert_header((ACCEPT_ENCODING, "identity"))
+            .insert_header((RANGE, range.as_str()))
+            .send()
+    })
+    .await
+    {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            log::warn!("Error encountered proxying {uri} -> {e}");
+            return None;
+        }
+    };
+    match upstream.status() {
+        StatusCode::PARTIAL_CONTENT => {
+            let appended = match upstream.body().limit(METADATA_LIMIT).await {
+                Ok(appended) => appended,
+                Err(e) => {
+                    log::warn!("Error encountered reading {uri} -> {e}");
+                    return None;
+                }
+            };
+            let mut body = BytesMut::from(cached.as_ref());
+            body.extend_from_slice(&appended);
+            let body = body.freeze();
+            if !matches_digests(&upstream, &body) {
+                log::info!("{uri} was rewritten upstream, fetching it again");
+                return None;
+            }
+            config
+                .cache
+                .store(file, content_type, None, None, body.clone())
+                .await;
+            Some(body)
+        }
+        // Nothing was appended when the upstream file is as long as the cached copy, and it was
+        // not rewritten when the copy matches its digest
+        StatusCode::RANGE_NOT_SATISFIABLE => {
+            let length = upstream
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("bytes */"))
+                .and_then(|length| length.parse::<usize>().ok());
+            if length != Some(cached.len()) || !matches_digests(&upstream, &cached) {
+                log::info!("{uri} was rewritten upstream, fetching it again");
+                return None;
+            }
+            Some(cached)
+        }
+        _ => None,
+    }
+}
+
+/// Fetch a compact index file, checked against its digests and cached when found. A cached copy
+/// is refreshed with the part appended upstream since, rather than downloaded again.
+async fn fetch_index(
+    config: &GemsConfig,
+    client: &awc::Client,
+    file: &str,
+) -> Result<(StatusCode, Option<String>, Bytes), String> {
+    if let Some((entry, cached)) = config.cache.load(file).await {
+        let content_type = entry.content_type();
+        if let Some(body) = refresh_index(config, client, file, content_type, cached).await {
+            return Ok((StatusCode::OK, content_type.map(String::from), body));
+        }
+    }
+
+    let uri = format!("{}{file}", config.url);
+    log::debug!("index: {uri}");
+    let mut upstream = send_retrying(|| {
+        client
+            .get(&uri)
+            .insert_header((ACCEPT_ENCODING, "identity"))
+            .send()
+    })
+    .await
+    .map_err(|e| format!("Error encountered proxying {uri} -> {e}"))?;
+    let body = upstream
+        .body()
+        .limit(METADATA_LIMIT)
+        .await
+        .map_err(|e| format!("Error encountered reading {uri} -> {e}"))?;
+    let content_type = upstream
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    if upstream.status() == StatusCode::OK {
+        if let Some(header) = mismatched_digest(&upstream, &body) {
+            return Err(format!("{uri} does not match its {header}"));
+        }
+        config
+            .cache
+            .store(file, content_type.as_deref(), None, None, body.clone())
+            .await;
+    }
+    Ok((upstream.status(), content_type, body))
+}
+
+#[route("{file:versions|names|info/[^/]+}", method = "GET", method = "HEAD")]
+async fn index(
+    req: HttpRequest,
+    config: web::Data<GemsConfig>,
+    policy: web::Data<PolicyEngine>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let file = path.into_inner();
+    if config.offline {
+        return match config.cache.load(&file).await {
+            Some((entry, body)) => respond_index(&req, entry.content_type(), body),
+            None => from_cache(&config.cache, &file).await,
+        };
+    }
+    match fetch_index(&config, &policy.client, &file).await {
+        Ok((StatusCode::OK, content_type, body)) => {
+            respond_index(&req, content_type.as_deref(), body)
+        }
+        Ok((status, _, body)) => HttpResponseBuilder::new(status).body(body),
+        Err(msg) => {
+            log::error!("{msg}");
+            HttpResponse::BadGateway().body(msg)
+        }
+    }
+}
+
+/// The checksum of a gem listed in the compact index, from the cached info file when it lists
+/// the version.
+async fn gem_checksum(
+    config: &GemsConfig,
+    client: &awc::Client,
+    name: &str,
+    version: &str,
+) -> Option<String> {
+    let file = format!("info/{name}");
+    if let Some((_, info)) = config.cache.load(&file).await {
+        let info = String::from_utf8_lossy(&info);
+        if let Some(checksum) = compact_index::checksum(&info, version) {
+            return Some(String::from(checksum));
+        }
+    }
+    match fetch_index(config, client, &file).await {
+        Ok((StatusCode::OK, _, info)) => {
+            compact_index::checksum(&String::from_utf8_lossy(&info), version).map(String::from)
+        }
+        Ok(_) => None,
+        Err(msg) => {
+            log::warn!("{msg}");
+            None
+        }
+    }
+}
+
+/// The dependency API, cached under the sorted list of gems so offline clients find it whatever
+/// order they ask in.
+#[get("api/v1/dependencies{format:(\\.json)?}")]
+async fn dependencies(
+    req: HttpRequest,
+    config: web::Data<GemsConfig>,
+    policy: web::Data<PolicyEngine>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let format = path.into_inner();
+    let query = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .map(web::Query::into_inner)
+        .unwrap_or_default();
+    let mut gems: Vec<&str> = query
+        .iter()
+        .filter(|(key, _)| key == "gems")
+        .flat_map(|(_, gems)| gems.split(','))
+        .filter(|gem| !gem.is_empty())
+        .collect();
+    gems.sort_unstable();
+    gems.dedup();
+    let cache_key = format!("api/v1/dependencies{format}?gems={}", gems.join(","));
+    if config.offline {
+        return from_cache(&config.cache, &cache_key).await;
+    }
+    let encoded: Vec<_> = gems.iter().map(|gem| urlencoding::encode(gem)).collect();
+    let uri = format!(
+        "{}api/v1/dependencies{format}?gems={}",
+        config.url,
+        encoded.join(",")
+    );
+    log::debug!("dependencies: {uri}");
+    let upstream = send_retrying(|| {
+        policy
+            .client
+            .get(&uri)
+            .insert_header((ACCEPT_ENCODING, "identity"))
+            .send()
+    })
+    .await;
+    match upstream {
+        Ok(mut upstream) => match upstream.body().limit(METADATA_LIMIT).await {
+            Ok(body) => {
+                let content_type = upstream
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok());
+                if upstream.status() == StatusCode::OK {
+                    config
+                        .cache
+                        .store(&cache_key, content_type, None, None, body.clone())
+                        .await;
+                }
+                let mut response = HttpResponseBuilder::new(upstream.status());
+                if let Some(content_type) = content_type {
+                    response.insert_header((CONTENT_TYPE, content_type));
+                }
+                response.body(body)
+            }
+            Err(e) => Error::from(e).into(),
+        },
+        Err(e) => {
+            log::error!("proxy error: {}", e);
+            HttpResponse::BadGateway().body(e.to_string())
+        }
+    }
+}
+
+/// Gems, and the specifications fetched by clients resolving with the full index.
+#[get(r"{pkg:gems|quick/Marshal\.4\.8}/{file:[^/]+\.gem|[^/]+\.gemspec\.rz}")]
 async fn proxy(
     req: HttpRequest,
     config: web::Data<GemsConfig>,
     policy: web::Data<PolicyEngine>,
-    path: web::Path<(String, String, String, String)>,
+    path: web::Path<(String, String)>,
 ) -> impl Responder {
-    let (pkg, name, version, ext) = path.into_inner();
-    let uri = format!("{}{pkg}/{name}-{version}.{ext}", config.url);
+    let (pkg, file) = path.into_inner();
+    let cache_key = format!("{pkg}/{file}");
+    if config.offline {
+        return from_cache(&config.cache, &cache_key).await;
+    }
+    let Some(gem) = GemFile::parse(&file) else {
+        return HttpResponse::NotFound().body("This rubygem could not be found.");
+    };
+    let uri = format!("{}{pkg}/{file}", config.url);
     log::debug!("upstream: {uri}");
     let mut request = policy.client.request_from(&uri, req.head()).no_decompress();
     request.headers_mut().remove("keep-alive");
@@ -44,20 +385,45 @@ async fn proxy(
     match request.send().await {
         Ok(mut upstream) => match upstream.body().limit(20_000_000).await {
             Ok(payload) => {
-                let context = Context::new(
-                    format!("pkg:gem/{name}@{version}"),
-                    uri,
-                    sha256::digest(payload.as_ref()),
-                );
-                match policy.evaluate(&context, Some(&ext)).await {
-                    Ok(None) => {
+                let hash = sha256::digest(payload.as_ref());
+                if upstream.status().is_success() && gem.extension == GEM_EXTENSION {
+                    let version = gem.full_version();
+                    match gem_checksum(&config, &policy.client, gem.name, &version).await {
+                        Some(checksum) if checksum != hash => {
+                            let msg = format!("{file} does not match its checksum");
+                            log::error!("{msg}");
+                            return HttpResponse::BadGateway().body(msg);
+                        }
+                        Some(_) => {}
+                        None => {
+                            let msg = format!("No checksum of {file} to verify");
+                            log::error!("{msg}");
+                            return HttpResponse::BadGateway().body(msg);
+                        }
+                    }
+                }
+                let context = Context::new(gem.purl(), uri, hash);
+                match policy.check(&context, Some(gem.extension)).await {
+                    Ok(Verdict::Denied(response)) => response,
+                    Ok(verdict) => {
+                        if upstream.status().is_success() {
+                            config
+                                .cache
+                                .store(
+                                    &cache_key,
+                                    Some("application/octet-stream"),
+                                    Some(&context),
+                                    policy.record(&verdict),
+                                    payload.clone(),
+                                )
+                                .await;
+                        }
                         let mut response = HttpResponseBuilder::new(upstream.status());
                         for header in upstream.headers().iter() {
                             response.insert_header(header);
                         }
                         response.body(payload)
                     }
-                    Ok(Some(response)) => response,
                     Err(e) => e.into(),
                 }
             }
@@ -80,11 +446,50 @@ async fn pass_through(
     path: web::Path<String>,
 ) -> impl Responder {
     let path = path.into_inner();
+    let cache_key = match req.query_string() {
+        "" => path.clone(),
+        query => format!("{path}?{query}"),
+    };
+    if config.offline {
+        return from_cache(&config.cache, &cache_key).await;
+    }
     let uri = format!("{}{path}", config.url);
     log::debug!("pass: {uri}");
     let mut request = policy.client.request_from(&uri, req.head()).no_decompress();
     request.headers_mut().remove("keep-alive");
     log::debug!("request: {request:?}");
+    if req.method() == Method::GET {
+        // Index files are buffered uncompressed so complete responses can be cached for offline use
+        request
+            .headers_mut()
+            .insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+        return match request.send().await {
+            Ok(mut upstream) => match upstream.body().limit(METADATA_LIMIT).await {
+                Ok(body) => {
+                    if upstream.status() == StatusCode::OK {
+                        let content_type = upstream
+                            .headers()
+                            .get(CONTENT_TYPE)
+                            .and_then(|value| value.to_str().ok());
+                        config
+                            .cache
+                            .store(&cache_key, content_type, None, None, body.clone())
+                            .await;
+                    }
+                    let mut response = HttpResponseBuilder::new(upstream.status());
+                    for header in upstream.headers().iter() {
+                        response.insert_header(header);
+                    }
+                    response.body(body)
+                }
+                Err(e) => Error::from(e).into(),
+            },
+            Err(e) => {
+                log::error!("proxy error: {}", e);
+                HttpResponse::NotFound().body("not found")
+            }
+        };
+    }
     match request.send_stream(payload).await {
         Ok(upstream) => {
             let mut response = HttpResponseBuilder::new(upstream.status());
@@ -99,3 +504,103 @@ async fn pass_through(
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::policy::PolicyConfig;
+    use actix_web::dev::ServerHandle;
+    use actix_web::{rt, App, HttpServer};
+    use std::sync::Mutex;
+
+    /// The upstream versions file, and the ranges the proxy asked for it.
+    type Upstream = web::Data<Mutex<(Bytes, Vec<Option<String>>)>>;
+
+    /// A compact index upstream on a loopback port serving the given versions file.
+    fn upstream(versions: &'static str) -> (Url, Upstream, ServerHandle) {
+        let state: Upstream = web::Data::new(Mutex::new((Bytes::from(versions), Vec::new())));
+        let data = state.clone();
+        let server = HttpServer::new(move || {
+            App::new().app_data(data.clone()).route(
+                "/versions",
+                web::get().to(|req: HttpRequest, state: Upstream| async move {
+                    let mut state = state.lock().unwrap();
+                    let range = req.headers().get(RANGE);
+                    state.1.push(
+                        range
+                            .and_then(|range| range.to_str().ok())
+                            .map(String::from),
+                    );
+                    respond_index(&req, None, state.0.clone())
+                }),
+            )
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = Url::parse(&format!("http://{}/", server.addrs()[0])).unwrap();
+        let server = server.run();
+        let handle = server.handle();
+        rt::spawn(server);
+        (url, state, handle)
+    }
+
+    #[actix_web::test]
+    async fn refresh_versions() {
+        const VERSIONS: &str = "created_at: 2024-01-01T00:00:00Z\n---\nrake 13.0.6 abc\n";
+        const APPENDED: &str = "rack 3.0.0 def\n";
+        const REWRITTEN: &str = "created_at: 2024-02-01T00:00:00Z\n---\nrake 13.0.6,13.1.0 fed\n";
+        let (url, state, handle) = upstream(VERSIONS);
+        let policy: PolicyConfig = toml::from_str("url = \"http://127.0.0.1:1\"").unwrap();
+        let root = std::env::temp_dir().join(format!("seedwing-gems-{}", std::process::id()));
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(PolicyEngine::new(policy)))
+                .service(service(
+                    "/gems",
+                    url,
+                    ArtifactCache::new(root.clone()),
+                    false,
+                )),
+        )
+        .await;
+        let versions = || async {
+            let request = actix_web::test::TestRequest::get()
+                .uri("/gems/versions")
+                .to_request();
+            let response = actix_web::test::call_service(&app, request).await;
+            assert_eq!(StatusCode::OK, response.status());
+            actix_web::test::read_body(response).await
+        };
+        let ranges = || std::mem::take(&mut state.lock().unwrap().1);
+
+        // The first request fetches the whole file
+        assert_eq!(VERSIONS, versions().await);
+        assert_eq!(vec![None], ranges());
+
+        // Later ones only fetch what was appended, or nothing at all
+        let appended = format!("{VERSIONS}{APPENDED}");
+        state.lock().unwrap().0 = Bytes::from(appended.clone());
+        assert_eq!(appended, versions().await);
+        let range = format!("bytes={}-", VERSIONS.len());
+        assert_eq!(vec![Some(range)], ranges());
+        assert_eq!(appended, versions().await);
+        let range = format!("bytes={}-", appended.len());
+        assert_eq!(vec![Some(range.clone())], ranges());
+
+        // Or a file rewritten with the same length, which no longer matches the copy
+        let same_length = appended.replace("def", "xyz");
+        state.lock().unwrap().0 = Bytes::from(same_length.clone());
+        assert_eq!(same_length, versions().await);
+        assert_eq!(vec![Some(range.clone()), None], ranges());
+
+        // A file rewritten upstream no longer matches its digest once appended to the copy
+        state.lock().unwrap().0 = Bytes::from(format!("{REWRITTEN}{APPENDED}{APPENDED}"));
+        assert_eq!(format!("{REWRITTEN}{APPENDED}{APPENDED}"), versions().await);
+        assert_eq!(vec![Some(range), None], ranges());
+
+        handle.stop(true).await;
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+/*
+ Module paths and versions as they appear in the URLs of a Go module proxy, upper case letters
+ being escaped as `!` followed by the lower case letter, and the package URLs of modules.
+*/
+
+use urlencoding::encode;
+
+pub const ZIP_EXTENSION: &str = "zip";
+
+/// Unescape a module path or version, None when it is not validly escaped.
+pub fn unescape(escaped: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(escaped.len());
+    let mut bang = false;
+    for c in escaped.chars() {
+        if bang {
+            if !c.is_ascii_lowercase() {
+                return None;
+            }
+            unescaped.push(c.to_ascii_uppercase());
+            bang = false;
+        } else if c == '!' {
+            bang = true;
+        } else if c.is_ascii_uppercase() {
+            return None;
+        } else {
+            unescaped.push(c);
+        }
+    }
+    (!bang).then_some(unescaped)
+}
+
+/// A file served for a module by the proxy, under `{module}/@v/`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ModuleFile<'a> {
+    List,
+    Info(&'a str),
+    Mod(&'a str),
+    Zip(&'a str),
+}
+
+impl<'a> ModuleFile<'a> {
+    pub fn parse(file: &'a str) -> Option<Self> {
+        if file == "list" {
+            return Some(Self::List);
+        }
+        match file.rsplit_once('.')? {
+            ("", _) => None,
+            (version, "info") => Some(Self::Info(version)),
+            (version, "mod") => Some(Self::Mod(version)),
+            (version, ZIP_EXTENSION) => Some(Self::Zip(version)),
+            _ => None,
+        }
+    }
+}
+
+/// The package URL of a module version, given their unescaped path and version.
+pub fn purl(module: &str, version: &str) -> String {
+    let module: Vec<_> = module.split('/').map(encode).collect();
+    format!("pkg:golang/{}@{}", module.join("/"), encode(version))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn escaped_paths() {
+        assert_eq!(
+            Some("github.com/Azure/azure-sdk-for-go".into()),
+            unescape("github.com/!azure/azure-sdk-for-go")
+        );
+        assert_eq!(Some("v1.0.0-RC1".into()), unescape("v1.0.0-!r!c1"));
+        assert_eq!(None, unescape("github.com/Azure/azure-sdk-for-go"));
+        assert_eq!(None, unescape("github.com/!!azure"));
+        assert_eq!(None, unescape("github.com/azure!"));
+    }
+
+    #[test]
+    fn module_files() {
+        assert_eq!(Some(ModuleFile::List), ModuleFile::parse("list"));
+        assert_eq!(
+            Some(ModuleFile::Info("v0.3.0")),
+            ModuleFile::parse("v0.3.0.info")
+        );
+        assert_eq!(
+            Some(ModuleFile::Mod("v2.0.0+incompatible")),
+            ModuleFile::parse("v2.0.0+incompatible.mod")
+        );
+        assert_eq!(
+            Some(ModuleFile::Zip("v0.0.0-20230905180637-6a6e2a5c7b7b")),
+            ModuleFile::parse("v0.0.0-20230905180637-6a6e2a5c7b7b.zip")
+        );
+        assert_eq!(None, ModuleFile::parse("v0.3.0.tar.gz"));
+        assert_eq!(None, ModuleFile::parse(".zip"));
+
+        assert_eq!(
+            "pkg:golang/github.com/Azure/go-autorest@v14.2.0%2Bincompatible",
+            purl("github.com/Azure/go-autorest", "v14.2.0+incompatible")
+        );
+    }
+}
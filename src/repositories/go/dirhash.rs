@@ -0,0 +1,94 @@
+/*
+ The `h1:` hashes of Go modules recorded in `go.sum` and in the checksum database: the base64
+ sha256 of a summary listing the sha256 of every file, sorted by name.
+*/
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Seek};
+use zip::ZipArchive;
+
+/// The largest size of the files of a module once unzipped, as allowed by the go command
+pub const UNZIPPED_LIMIT: u64 = 500 << 20;
+
+/// The summary of files given with their hex encoded sha256.
+fn hash1(mut files: Vec<(String, String)>) -> String {
+    files.sort();
+    let mut summary = Sha256::new();
+    for (name, digest) in files {
+        summary.update(format!("{digest}  {name}\n"));
+    }
+    format!("h1:{}", STANDARD.encode(summary.finalize()))
+}
+
+/// The hash of the `go.mod` of a module.
+pub fn hash_mod(go_mod: &[u8]) -> String {
+    let digest = format!("{:x}", Sha256::digest(go_mod));
+    hash1(vec![(String::from("go.mod"), digest)])
+}
+
+/// The hash of the zip of a module, over the files named with their `{module}@{version}/` prefix.
+pub fn hash_zip(zip: impl Read + Seek) -> Result<String, String> {
+    hash_zip_limited(zip, UNZIPPED_LIMIT)
+}
+
+/// The hash of the zip of a module, refused when its files unzip to more than `limit` bytes. The
+/// sizes the zip declares are not trusted.
+fn hash_zip_limited(zip: impl Read + Seek, limit: u64) -> Result<String, String> {
+    let mut archive = ZipArchive::new(zip).map_err(|e| e.to_string())?;
+    let mut files = Vec::with_capacity(archive.len());
+    let mut total = 0;
+    for index in 0..archive.len() {
+        let file = archive.by_index(index).map_err(|e| e.to_string())?;
+        let name = String::from(file.name());
+        if name.contains('\n') {
+            return Err(format!("Invalid file name {name:?}"));
+        }
+        let mut digest = Sha256::new();
+        let size =
+            io::copy(&mut file.take(limit - total + 1), &mut digest).map_err(|e| e.to_string())?;
+        total += size;
+        if total > limit {
+            return Err(format!("Module is larger than {limit} bytes once unzipped"));
+        }
+        files.push((name, format!("{:x}", digest.finalize())));
+    }
+    Ok(hash1(files))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::write::{FileOptions, ZipWriter};
+
+    #[test]
+    fn module_hashes() {
+        // As recorded in go.sum for golang.org/x/text v0.3.0
+        assert_eq!(
+            "h1:NqM8EUOU14njkJ3fqMW+pc6Ldnwhi/IjpwHt7yyuwOQ=",
+            hash_mod(b"module golang.org/x/text\n")
+        );
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in [
+            ("m@v1.0.0/b.go", "package b\n"),
+            ("m@v1.0.0/a.go", "package a\n"),
+        ] {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        let zip = zip.finish().unwrap().into_inner();
+        assert_eq!(
+            Ok(String::from(
+                "h1:maBVHrAwR7claZHLqLeQ1uhyhx27SgYXNQqtlnMAgus="
+            )),
+            hash_zip(Cursor::new(&zip))
+        );
+        assert!(hash_zip(Cursor::new(b"not a zip")).is_err());
+
+        // The unzipped size is counted, whatever the zip declares
+        assert!(hash_zip_limited(Cursor::new(&zip), 20).is_ok());
+        assert!(hash_zip_limited(Cursor::new(&zip), 19).is_err());
+        assert!(hash_zip_limited(Cursor::new(&zip), 9).is_err());
+    }
+}
@@ -0,0 +1,594 @@
+use actix_web::{
+    http::{header::CONTENT_TYPE, StatusCode},
+    route, web, HttpResponse, HttpResponseBuilder, Responder, Scope,
+};
+use bytes::Bytes;
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use std::collections::{hash_map::Entry, HashMap};
+use std::{fs, io};
+use url::Url;
+
+use crate::cache::{ArtifactCache, PendingArtifact};
+use crate::config::repositories::{ChecksumDb, RepositoryConfig};
+use crate::policy::{context::Context, PolicyEngine, Verdict};
+use crate::repositories::send_retrying;
+
+pub mod coordinates;
+pub mod dirhash;
+pub mod sumdb;
+
+use self::coordinates::{purl, unescape, ModuleFile, ZIP_EXTENSION};
+use self::sumdb::{Record, Tile, Tree, VerifierKey};
+
+/// A checksum database along with its parsed verifier key.
+struct ChecksumDatabase {
+    config: ChecksumDb,
+    key: Result<VerifierKey, String>,
+}
+
+pub struct GoConfig {
+    url: Url,
+    cache: ArtifactCache,
+    offline: bool,
+    checksum_db: Option<ChecksumDatabase>,
+}
+
+impl GoConfig {
+    pub fn new(config: &RepositoryConfig, cache: ArtifactCache, offline: bool) -> Self {
+        let checksum_db = config.checksum_db().map(|checksum_db| {
+            let key = VerifierKey::parse(checksum_db.key());
+            if let Err(msg) = &key {
+                log::error!("{msg}, Go modules will be refused");
+            }
+            ChecksumDatabase {
+                config: checksum_db.clone(),
+                key,
+            }
+        });
+        Self {
+            url: config.url(),
+            cache,
+            offline,
+            checksum_db,
+        }
+    }
+}
+
+pub fn service(
+    scope: &str,
+    config: &RepositoryConfig,
+    cache: ArtifactCache,
+    offline: bool,
+) -> Scope {
+    web::scope(scope)
+        .app_data(web::Data::new(GoConfig::new(config, cache, offline)))
+        .service(checksum_database)
+        .service(latest)
+        .service(module_file)
+}
+
+const METADATA_LIMIT: usize = 16_000_000;
+/// The largest module zip accepted by the go command
+const ZIP_LIMIT: u64 = 500 << 20;
+
+async fn from_cache(cache: &ArtifactCache, key: &str) -> HttpResponse {
+    match cache.respond(key).await {
+        Some(response) => response,
+        None => {
+            log::info!("{key} is not available offline");
+            HttpResponse::NotFound().body("not found")
+        }
+    }
+}
+
+/// A module version, escaped as in the URLs of the proxy and unescaped as in `go.sum`.
+struct ModuleVersion<'a> {
+    escaped_path: &'a str,
+    escaped_version: &'a str,
+    path: String,
+    version: String,
+}
+
+impl<'a> ModuleVersion<'a> {
+    fn new(escaped_path: &'a str, escaped_version: &'a str) -> Option<Self> {
+        Some(Self {
+            escaped_path,
+            escaped_version,
+            path: unescape(escaped_path)?,
+            version: unescape(escaped_version)?,
+        })
+    }
+}
+
+/// Fetch a file of a checksum database, from the cache when it has been fetched before as
+/// lookups and tiles never change.
+async fn fetch_checksum_db(
+    config: &GoConfig,
+    client: &awc::Client,
+    (db, key): (&ChecksumDatabase, &VerifierKey),
+    path: &str,
+    cached: bool,
+) -> Result<Bytes, String> {
+    let cache_key = format!("sumdb/{}/{path}", key.name());
+    if cached || config.offline {
+        if let Some((_, body)) = config.cache.load(&cache_key).await {
+            return Ok(body);
+        }
+    }
+    if config.offline {
+        return Err(format!("{cache_key} is not available offline"));
+    }
+    let uri = format!("{}{path}", db.config.url());
+    log::debug!("checksum database: {uri}");
+    let mut upstream = send_retrying(|| client.get(&uri).send())
+        .await
+        .map_err(|e| format!("Error encountered fetching {uri} -> {e}"))?;
+    let body = upstream
+        .body()
+        .limit(METADATA_LIMIT)
+        .await
+        .map_err(|e| format!("Error encountered reading {uri} -> {e}"))?;
+    if upstream.status() != StatusCode::OK {
+        return Err(format!("{uri} -> {}", upstream.status()));
+    }
+    config
+        .cache
+        .store(&cache_key, Some("text/plain"), None, None, body.clone())
+        .await;
+    Ok(body)
+}
+
+/// Check the hash of a module zip, or of its `go.mod`, against the checksum database, proving
+/// the record of the module is part of the log signed by the database.
+async fn verify(
+    config: &GoConfig,
+    client: &awc::Client,
+    module: &ModuleVersion<'_>,
+    go_mod: bool,
+    hash: &str,
+) -> Result<(), String> {
+    let Some(db) = &config.checksum_db else {
+        return Ok(());
+    };
+    if db.config.excludes(&module.path) {
+        log::debug!("{} is excluded from the checksum database", module.path);
+        return Ok(());
+    }
+    let key = db.key.as_ref().map_err(Clone::clone)?;
+    let path = format!("lookup/{}@{}", module.escaped_path, module.escaped_version);
+    let lookup = fetch_checksum_db(config, client, (db, key), &path, true).await?;
+    let lookup = String::from_utf8_lossy(&lookup);
+    let record = Record::parse(&lookup)?;
+    let tree = Tree::verify(record.note, key)?;
+    let mut tiles = HashMap::new();
+    let mut nodes = HashMap::new();
+    for (level, index) in sumdb::proof(record.id, tree.size) {
+        let tile = Tile::of(level, index, tree.size);
+        if let Entry::Vacant(entry) = tiles.entry(tile) {
+            entry.insert(fetch_checksum_db(config, client, (db, key), &tile.path(), true).await?);
+        }
+        let node = tile
+            .node(&tiles[&tile], level, index)
+            .ok_or_else(|| format!("Malformed tile {}", tile.path()))?;
+        nodes.insert((level, index), node);
+    }
+    let root = (record.id < tree.size)
+        .then(|| sumdb::root(record.id, &record.leaf(), tree.size, &nodes))
+        .flatten();
+    if root != Some(tree.hash) {
+        return Err(format!(
+            "The record of {}@{} is not part of the checksum database",
+            module.path, module.version
+        ));
+    }
+    let version = match go_mod {
+        true => format!("{}/go.mod", module.version),
+        false => module.version.clone(),
+    };
+    match record.hash(&module.path, &version) {
+        Some(recorded) if recorded == hash => Ok(()),
+        Some(recorded) => Err(format!(
+            "{}@{version} is {hash}, the checksum database has {recorded}",
+            module.path
+        )),
+        None => Err(format!(
+            "{}@{version} is not in the checksum database",
+            module.path
+        )),
+    }
+}
+
+/// Serve the checksum database to the go command, which asks the proxy whether it does with
+/// `supported` before verifying modules.
+#[route("sumdb/{name}/{path:.*}", method = "GET", method = "HEAD")]
+async fn checksum_database(
+    config: web::Data<GoConfig>,
+    policy: web::Data<PolicyEngine>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (name, path) = path.into_inner();
+    let db = (config.checksum_db.as_ref()).and_then(|db| Some((db, db.key.as_ref().ok()?)));
+    let Some((db, key)) = db.filter(|(_, key)| key.name() == name) else {
+        return HttpResponse::NotFound().body("not found");
+    };
+    let cached = path.starts_with("lookup/") || path.starts_with("tile/");
+    if path == "supported" {
+        return HttpResponse::Ok().finish();
+    } else if !cached && path != "latest" {
+        return HttpResponse::NotFound().body("not found");
+    }
+    match fetch_checksum_db(&config, &policy.client, (db, key), &path, cached).await {
+        Ok(body) => HttpResponse::Ok()
+            .insert_header((CONTENT_TYPE, "text/plain; charset=utf-8"))
+            .body(body),
+        Err(msg) => {
+            log::warn!("{msg}");
+            HttpResponse::NotFound().body(msg)
+        }
+    }
+}
+
+/// Fetch a file of a module from the proxy, cached when found.
+async fn fetch(
+    config: &GoConfig,
+    client: &awc::Client,
+    path: &str,
+    limit: usize,
+) -> Result<(StatusCode, Option<String>, Bytes), String> {
+    let uri = format!("{}{path}", config.url);
+    log::debug!("upstream: {uri}");
+    let mut upstream = send_retrying(|| client.get(&uri).send())
+        .await
+        .map_err(|e| format!("Error encountered proxying {uri} -> {e}"))?;
+    let body = upstream
+        .body()
+        .limit(limit)
+        .await
+        .map_err(|e| format!("Error encountered reading {uri} -> {e}"))?;
+    let content_type = upstream
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    Ok((upstream.status(), content_type, body))
+}
+
+fn respond(status: StatusCode, content_type: Option<&str>, body: Bytes) -> HttpResponse {
+    let mut response = HttpResponseBuilder::new(status);
+    if let Some(content_type) = content_type {
+        response.insert_header((CONTENT_TYPE, content_type));
+    }
+    response.body(body)
+}
+
+/// Version lists and info, which are neither verified nor evaluated against the policy.
+async fn metadata(config: &GoConfig, client: &awc::Client, path: &str) -> HttpResponse {
+    if config.offline {
+        return from_cache(&config.cache, path).await;
+    }
+    match fetch(config, client, path, METADATA_LIMIT).await {
+        Ok((status, content_type, body)) => {
+            if status == StatusCode::OK {
+                config
+                    .cache
+                    .store(path, content_type.as_deref(), None, None, body.clone())
+                    .await;
+            }
+            respond(status, content_type.as_deref(), body)
+        }
+        Err(msg) => {
+            log::error!("{msg}");
+            HttpResponse::BadGateway().body(msg)
+        }
+    }
+}
+
+#[route("{module:.+}/@latest", method = "GET", method = "HEAD")]
+async fn latest(
+    config: web::Data<GoConfig>,
+    policy: web::Data<PolicyEngine>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let module = path.into_inner();
+    metadata(&config, &policy.client, &format!("{module}/@latest")).await
+}
+
+/// The files of a module version: its info, `go.mod` and zip, along with the list of versions.
+#[route("{module:.+}/@v/{file}", method = "GET", method = "HEAD")]
+async fn module_file(
+    config: web::Data<GoConfig>,
+    policy: web::Data<PolicyEngine>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (module, file) = path.into_inner();
+    let cache_key = format!("{module}/@v/{file}");
+    let (version, go_mod) = match ModuleFile::parse(&file) {
+        Some(ModuleFile::List) | Some(ModuleFile::Info(_)) => {
+            return metadata(&config, &policy.client, &cache_key).await;
+        }
+        Some(ModuleFile::Mod(version)) => (version, true),
+        Some(ModuleFile::Zip(version)) => (version, false),
+        None => return HttpResponse::NotFound().body("not found"),
+    };
+    if config.offline {
+        return from_cache(&config.cache, &cache_key).await;
+    }
+    let Some(module) = ModuleVersion::new(&module, version) else {
+        return HttpResponse::NotFound().body("not found");
+    };
+    if go_mod {
+        mod_file(&config, &policy.client, &module, &cache_key).await
+    } else {
+        zip_file(&config, &policy, &module, &cache_key).await
+    }
+}
+
+/// The `go.mod` of a module version, verified against the checksum database.
+async fn mod_file(
+    config: &GoConfig,
+    client: &awc::Client,
+    module: &ModuleVersion<'_>,
+    cache_key: &str,
+) -> HttpResponse {
+    let (status, content_type, payload) =
+        match fetch(config, client, cache_key, METADATA_LIMIT).await {
+            Ok(fetched) => fetched,
+            Err(msg) => {
+                log::error!("{msg}");
+                return HttpResponse::BadGateway().body(msg);
+            }
+        };
+    if status != StatusCode::OK {
+        return respond(status, content_type.as_deref(), payload);
+    }
+    let hash = dirhash::hash_mod(&payload);
+    if let Err(msg) = verify(config, client, module, true, &hash).await {
+        log::error!("{msg}");
+        return HttpResponse::BadGateway().body(msg);
+    }
+    config
+        .cache
+        .store(
+            cache_key,
+            content_type.as_deref(),
+            None,
+            None,
+            payload.clone(),
+        )
+        .await;
+    respond(status, content_type.as_deref(), payload)
+}
+
+/// The outcome of downloading a module zip.
+enum Download {
+    /// The zip, written to the cache but not committed yet, along with its sha256.
+    Found {
+        pending: PendingArtifact,
+        sha256: String,
+    },
+    /// The response of the proxy when it does not have the zip.
+    Failed(HttpResponse),
+}
+
+/// Stream a module zip from the proxy to the cache.
+async fn download_zip(
+    config: &GoConfig,
+    client: &awc::Client,
+    cache_key: &str,
+) -> Result<Download, String> {
+    let uri = format!("{}{cache_key}", config.url);
+    log::debug!("upstream: {uri}");
+    let mut upstream = send_retrying(|| client.get(&uri).send())
+        .await
+        .map_err(|e| format!("Error encountered proxying {uri} -> {e}"))?;
+    if upstream.status() != StatusCode::OK {
+        let body = upstream
+            .body()
+            .limit(METADATA_LIMIT)
+            .await
+            .map_err(|e| format!("Error encountered reading {uri} -> {e}"))?;
+        let content_type = upstream
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        return Ok(Download::Failed(respond(
+            upstream.status(),
+            content_type,
+            body,
+        )));
+    }
+    let mut pending = config
+        .cache
+        .begin(cache_key)
+        .await
+        .map_err(|e| format!("Unable to cache {cache_key} -> {e}"))?;
+    let mut sha256 = Sha256::new();
+    while let Some(chunk) = upstream.next().await {
+        let chunk = chunk.map_err(|e| format!("Error encountered reading {uri} -> {e}"))?;
+        if pending.written() + chunk.len() as u64 > ZIP_LIMIT {
+            return Err(format!("{uri} is larger than {ZIP_LIMIT} bytes"));
+        }
+        sha256.update(&chunk);
+        pending
+            .write(chunk)
+            .await
+            .map_err(|e| format!("Unable to cache {cache_key} -> {e}"))?;
+    }
+    Ok(Download::Found {
+        pending,
+        sha256: format!("{:x}", sha256.finalize()),
+    })
+}
+
+/// A module zip, verified against the checksum database from the cache before being evaluated
+/// against the policy, and only committed to the cache once allowed.
+async fn zip_file(
+    config: &GoConfig,
+    policy: &PolicyEngine,
+    module: &ModuleVersion<'_>,
+    cache_key: &str,
+) -> HttpResponse {
+    let (pending, sha256) = match download_zip(config, &policy.client, cache_key).await {
+        Ok(Download::Found { pending, sha256 }) => (pending, sha256),
+        Ok(Download::Failed(response)) => return response,
+        Err(msg) => {
+            log::error!("{msg}");
+            return HttpResponse::BadGateway().body(msg);
+        }
+    };
+    let path = pending.path().to_path_buf();
+    let hash = web::block(move || {
+        let file = fs::File::open(path).map_err(|e| e.to_string())?;
+        dirhash::hash_zip(io::BufReader::new(file))
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|hash| hash);
+    let verified = match hash {
+        Ok(hash) => verify(config, &policy.client, module, false, &hash).await,
+        Err(e) => Err(format!(
+            "Invalid zip of {}@{}: {e}",
+            module.path, module.version
+        )),
+    };
+    if let Err(msg) = verified {
+        log::error!("{msg}");
+        return HttpResponse::BadGateway().body(msg);
+    }
+    let context = Context::new(
+        purl(&module.path, &module.version),
+        format!("{}{cache_key}", config.url),
+        sha256,
+    );
+    let verdict = match policy.check(&context, Some(ZIP_EXTENSION)).await {
+        Ok(Verdict::Denied(response)) => return response,
+        Ok(verdict) => policy.record(&verdict),
+        Err(e) => return e.into(),
+    };
+    if let Err(e) = pending
+        .commit(Some("application/zip"), Some(&context), verdict)
+        .await
+    {
+        let msg = format!("Unable to cache {cache_key} -> {e}");
+        log::error!("{msg}");
+        return HttpResponse::InternalServerError().body(msg);
+    }
+    match config.cache.stream(cache_key).await {
+        Some((_, len, chunks)) => HttpResponse::Ok()
+            .insert_header((CONTENT_TYPE, "application/zip"))
+            .no_chunking(len)
+            .streaming(chunks),
+        None => HttpResponse::InternalServerError().body(format!("Unable to read {cache_key}")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{rt, App, HttpRequest, HttpServer};
+    use std::io::{Cursor, Write};
+    use zip::{write::FileOptions, ZipWriter};
+
+    fn module_zip(module: &str, content: &str) -> Bytes {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file(format!("{module}/m.go"), FileOptions::default())
+            .unwrap();
+        zip.write_all(content.as_bytes()).unwrap();
+        Bytes::from(zip.finish().unwrap().into_inner())
+    }
+
+    #[actix_web::test]
+    async fn verify_module_zips() {
+        let good = module_zip("example.com/good@v1.0.0", "package m\n");
+        let bad = module_zip("example.com/bad@v1.0.0", "package m // tampered\n");
+        let recorded = module_zip("example.com/bad@v1.0.0", "package m\n");
+        let lines = format!(
+            "example.com/good v1.0.0 {}\nexample.com/bad v1.0.0 {}\n",
+            dirhash::hash_zip(Cursor::new(&good)).unwrap(),
+            dirhash::hash_zip(Cursor::new(&recorded)).unwrap()
+        );
+        let (verifier, lookup) = sumdb::test::single_record_database("sum.example.com", &lines);
+        let files: HashMap<String, Bytes> = [
+            ("/example.com/good/@v/v1.0.0.zip", good.clone()),
+            ("/example.com/bad/@v/v1.0.0.zip", bad),
+            (
+                "/sumdb/lookup/example.com/good@v1.0.0",
+                lookup.clone().into(),
+            ),
+            ("/sumdb/lookup/example.com/bad@v1.0.0", lookup.into()),
+        ]
+        .into_iter()
+        .map(|(path, body)| (String::from(path), body))
+        .collect();
+        let files = web::Data::new(files);
+        let server = HttpServer::new(move || {
+            App::new().app_data(files.clone()).default_service(web::to(
+                |req: HttpRequest, files: web::Data<HashMap<String, Bytes>>| async move {
+                    match files.get(req.path()) {
+                        Some(body) => HttpResponse::Ok().body(body.clone()),
+                        None => HttpResponse::NotFound().finish(),
+                    }
+                },
+            ))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        rt::spawn(server);
+
+        let config: RepositoryConfig = toml::from_str(&format!(
+            "type = \"go\"\nurl = \"http://{addr}/\"\n\
+             checksum_db = {{ url = \"http://{addr}/sumdb/\", key = \"{verifier}\" }}"
+        ))
+        .unwrap();
+        let policy: crate::config::policy::PolicyConfig =
+            toml::from_str("url = \"http://127.0.0.1:1\"").unwrap();
+        let policy = web::Data::new(PolicyEngine::new(policy));
+        let root = std::env::temp_dir().join(format!("seedwing-go-{}", std::process::id()));
+        let cache = ArtifactCache::new(root.clone());
+        let get = |offline: bool, path: &str| {
+            let app = App::new().app_data(policy.clone()).service(service(
+                "/go",
+                &config,
+                cache.clone(),
+                offline,
+            ));
+            let request = actix_web::test::TestRequest::get()
+                .uri(&format!("/go/{path}"))
+                .to_request();
+            async move {
+                let app = actix_web::test::init_service(app).await;
+                let response = actix_web::test::call_service(&app, request).await;
+                (
+                    response.status(),
+                    actix_web::test::read_body(response).await,
+                )
+            }
+        };
+
+        // A zip matching the checksum database is cached and served
+        let (status, body) = get(false, "example.com/good/@v/v1.0.0.zip").await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(good, body);
+
+        // A zip not matching it is refused and not cached
+        let (status, _) = get(false, "example.com/bad/@v/v1.0.0.zip").await;
+        assert_eq!(StatusCode::BAD_GATEWAY, status);
+        assert!(cache.load("example.com/bad/@v/v1.0.0.zip").await.is_none());
+
+        // Only the verified zip is served offline
+        handle.stop(true).await;
+        let (status, body) = get(true, "example.com/good/@v/v1.0.0.zip").await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(good, body);
+        let (status, _) = get(true, "example.com/bad/@v/v1.0.0.zip").await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
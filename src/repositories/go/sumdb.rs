@@ -0,0 +1,441 @@
+/*
+ Verification against a Go checksum database: a transparency log of the hashes of every module
+ version. A lookup returns the record of a module version along with a note of the size and root
+ hash of the log signed by the database, and the tiles of the log, holding the hashes of its nodes,
+ prove the record is part of it.
+*/
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+use sigstore::crypto::{CosignVerificationKey, Signature, SigningScheme};
+use std::collections::HashMap;
+
+pub type Hash = [u8; 32];
+
+/// Levels of the log covered by a tile
+const TILE_HEIGHT: u32 = 8;
+const TILE_WIDTH: u64 = 1 << TILE_HEIGHT;
+/// DER prefix of an Ed25519 SubjectPublicKeyInfo, followed by the 32 bytes of the key
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+const ED25519_ALGORITHM: u8 = 1;
+
+/// The key verifying the notes signed by a database, `{name}+{hash}+{key}` as in `GOSUMDB`.
+pub struct VerifierKey {
+    name: String,
+    hash: [u8; 4],
+    key: CosignVerificationKey,
+}
+
+impl VerifierKey {
+    pub fn parse(verifier: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid checksum database key {verifier}");
+        let mut parts = verifier.splitn(3, '+');
+        let (Some(name), Some(hash), Some(key)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let hash = u32::from_str_radix(hash, 16).map_err(|_| invalid())?;
+        let key = STANDARD.decode(key).map_err(|_| invalid())?;
+        if key.len() != 33 || key[0] != ED25519_ALGORITHM {
+            return Err(invalid());
+        }
+        let hash = hash.to_be_bytes();
+        if key_hash(name, &key) != hash {
+            return Err(invalid());
+        }
+        let der = [&ED25519_SPKI_PREFIX[..], &key[1..]].concat();
+        let key = CosignVerificationKey::from_der(&der, &SigningScheme::ED25519)
+            .map_err(|e| format!("{}: {e}", invalid()))?;
+        Ok(Self {
+            name: String::from(name),
+            hash,
+            key,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+fn key_hash(name: &str, key: &[u8]) -> [u8; 4] {
+    let digest = Sha256::new()
+        .chain_update(name)
+        .chain_update("\n")
+        .chain_update(key)
+        .finalize();
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+/// The size and root hash of the log.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Tree {
+    pub size: u64,
+    pub hash: Hash,
+}
+
+impl Tree {
+    /// Parse a note of the log signed by the database.
+    pub fn verify(note: &str, key: &VerifierKey) -> Result<Self, String> {
+        let end = note
+            .find("\n\n")
+            .ok_or_else(|| String::from("Malformed tree note"))?;
+        let (text, signatures) = (&note[..end + 1], &note[end + 2..]);
+        let signed = signatures
+            .lines()
+            .filter_map(|line| line.strip_prefix("\u{2014} ")?.split_once(' '))
+            .filter(|(name, _)| *name == key.name)
+            .filter_map(|(_, signature)| STANDARD.decode(signature).ok())
+            .any(|signature| {
+                signature.len() == 68
+                    && signature[..4] == key.hash
+                    && key
+                        .key
+                        .verify_signature(Signature::Raw(&signature[4..]), text.as_bytes())
+                        .is_ok()
+            });
+        if !signed {
+            return Err(format!("Tree note not signed by {}", key.name));
+        }
+        let mut lines = text.lines();
+        let (Some("go.sum database tree"), Some(size), Some(hash), None) =
+            (lines.next(), lines.next(), lines.next(), lines.next())
+        else {
+            return Err(String::from("Malformed tree note"));
+        };
+        let size = size
+            .parse()
+            .map_err(|_| String::from("Malformed tree size"))?;
+        let hash = STANDARD
+            .decode(hash)
+            .ok()
+            .and_then(|hash| Hash::try_from(hash).ok())
+            .ok_or_else(|| String::from("Malformed tree hash"))?;
+        Ok(Self { size, hash })
+    }
+}
+
+/// The record of a module version in the log, as returned by a lookup.
+pub struct Record<'a> {
+    pub id: u64,
+    data: &'a str,
+    /// The signed note of the log the record was looked up in
+    pub note: &'a str,
+}
+
+impl<'a> Record<'a> {
+    pub fn parse(lookup: &'a str) -> Result<Self, String> {
+        let malformed = || String::from("Malformed checksum database record");
+        let (id, rest) = lookup.split_once('\n').ok_or_else(malformed)?;
+        let end = rest.find("\n\n").ok_or_else(malformed)?;
+        Ok(Self {
+            id: id.parse().map_err(|_| malformed())?,
+            data: &rest[..end + 1],
+            note: &rest[end + 2..],
+        })
+    }
+
+    /// The hash of a module version, or of its `go.mod` with a `{version}/go.mod` version.
+    pub fn hash(&self, module: &str, version: &str) -> Option<&'a str> {
+        self.data.lines().find_map(|line| {
+            let mut fields = line.split(' ');
+            match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                (Some(m), Some(v), Some(hash), None) if m == module && v == version => Some(hash),
+                _ => None,
+            }
+        })
+    }
+
+    pub fn leaf(&self) -> Hash {
+        Sha256::new()
+            .chain_update([0])
+            .chain_update(self.data)
+            .finalize()
+            .into()
+    }
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    Sha256::new()
+        .chain_update([1])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+/// The largest power of two smaller than a size of at least 2.
+fn split(size: u64) -> u64 {
+    1 << (63 - (size - 1).leading_zeros())
+}
+
+fn collect(lo: u64, hi: u64, leaf: u64, nodes: &mut Vec<(u32, u64)>) {
+    let size = hi - lo;
+    if !(lo..hi).contains(&leaf) && size.is_power_of_two() {
+        let level = size.trailing_zeros();
+        nodes.push((level, lo >> level));
+    } else if size > 1 {
+        let k = split(size);
+        collect(lo, lo + k, leaf, nodes);
+        collect(lo + k, hi, leaf, nodes);
+    }
+}
+
+/// The complete subtrees of a tree, as `(level, index)`, whose hashes prove a leaf is part of it.
+pub fn proof(leaf: u64, size: u64) -> Vec<(u32, u64)> {
+    let mut nodes = Vec::new();
+    collect(0, size, leaf, &mut nodes);
+    nodes
+}
+
+fn subtree_hash(
+    (lo, hi): (u64, u64),
+    (leaf, leaf_hash): (u64, &Hash),
+    nodes: &HashMap<(u32, u64), Hash>,
+) -> Option<Hash> {
+    let size = hi - lo;
+    if !(lo..hi).contains(&leaf) && size.is_power_of_two() {
+        let level = size.trailing_zeros();
+        nodes.get(&(level, lo >> level)).copied()
+    } else if size == 1 {
+        Some(*leaf_hash)
+    } else {
+        let k = split(size);
+        let left = subtree_hash((lo, lo + k), (leaf, leaf_hash), nodes)?;
+        let right = subtree_hash((lo + k, hi), (leaf, leaf_hash), nodes)?;
+        Some(node_hash(&left, &right))
+    }
+}
+
+/// The root hash of a tree from the hash of a leaf and those of its proof.
+pub fn root(
+    leaf: u64,
+    leaf_hash: &Hash,
+    size: u64,
+    nodes: &HashMap<(u32, u64), Hash>,
+) -> Option<Hash> {
+    subtree_hash((0, size), (leaf, leaf_hash), nodes)
+}
+
+/// A tile of the log, holding the hashes of up to 256 consecutive nodes at level `8 * level`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Tile {
+    pub level: u32,
+    pub index: u64,
+    pub width: u64,
+}
+
+impl Tile {
+    /// The tile a node is computed from, in a tree of a given size.
+    pub fn of(level: u32, index: u64, size: u64) -> Self {
+        let tile_level = level / TILE_HEIGHT;
+        let tile_index = (index << (level % TILE_HEIGHT)) >> TILE_HEIGHT;
+        let stored = size >> (tile_level * TILE_HEIGHT);
+        Self {
+            level: tile_level,
+            index: tile_index,
+            width: (stored - tile_index * TILE_WIDTH).min(TILE_WIDTH),
+        }
+    }
+
+    /// The path of the tile, its index split in groups of three digits.
+    pub fn path(&self) -> String {
+        let mut index = self.index;
+        let mut path = format!("{:03}", index % 1000);
+        while index >= 1000 {
+            index /= 1000;
+            path = format!("x{:03}/{path}", index % 1000);
+        }
+        let mut path = format!("tile/{TILE_HEIGHT}/{}/{path}", self.level);
+        if self.width < TILE_WIDTH {
+            path.push_str(&format!(".p/{}", self.width));
+        }
+        path
+    }
+
+    /// The hash of a node, from the content of this tile.
+    pub fn node(&self, data: &[u8], level: u32, index: u64) -> Option<Hash> {
+        if data.len() as u64 != self.width * 32 {
+            return None;
+        }
+        let height = level - self.level * TILE_HEIGHT;
+        let start = ((index << height) - self.index * TILE_WIDTH) as usize;
+        let mut hashes: Vec<Hash> = data
+            .get(start * 32..(start + (1 << height)) * 32)?
+            .chunks(32)
+            .map(|hash| Hash::try_from(hash).unwrap())
+            .collect();
+        while hashes.len() > 1 {
+            hashes = hashes
+                .chunks(2)
+                .map(|pair| node_hash(&pair[0], &pair[1]))
+                .collect();
+        }
+        hashes.first().copied()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use sigstore::crypto::signing_key::SigStoreSigner;
+
+    /// A signer of the notes of a database, with its verifier key.
+    fn database_signer(name: &str) -> (SigStoreSigner, String) {
+        let signer = SigningScheme::ED25519.create_signer().unwrap();
+        let key = [&[ED25519_ALGORITHM][..], &signer_key(&signer)].concat();
+        let hash = u32::from_be_bytes(key_hash(name, &key));
+        let verifier = format!("{name}+{hash:08x}+{}", STANDARD.encode(&key));
+        (signer, verifier)
+    }
+
+    fn signed_note(signer: &SigStoreSigner, name: &str, size: u64, root: &Hash) -> String {
+        let text = format!("go.sum database tree\n{size}\n{}\n", STANDARD.encode(root));
+        let key = [&[ED25519_ALGORITHM][..], &signer_key(signer)].concat();
+        let signature = signer.sign(text.as_bytes()).unwrap();
+        let signature = STANDARD.encode([&key_hash(name, &key)[..], &signature].concat());
+        format!("{text}\n\u{2014} {name} {signature}\n")
+    }
+
+    fn signer_key(signer: &SigStoreSigner) -> Vec<u8> {
+        let der = signer
+            .to_sigstore_keypair()
+            .unwrap()
+            .public_key_to_der()
+            .unwrap();
+        der[der.len() - 32..].to_vec()
+    }
+
+    /// A database whose log holds a single record, the `go.sum` lines given. Returns the verifier
+    /// key of the database and the lookup of the record.
+    pub(crate) fn single_record_database(name: &str, lines: &str) -> (String, String) {
+        let (signer, verifier) = database_signer(name);
+        let record = Record {
+            id: 0,
+            data: lines,
+            note: "",
+        };
+        let note = signed_note(&signer, name, 1, &record.leaf());
+        (verifier, format!("0\n{lines}\n{note}"))
+    }
+
+    fn leaf_hash(index: u64) -> Hash {
+        Sha256::new()
+            .chain_update([0])
+            .chain_update(index.to_string())
+            .finalize()
+            .into()
+    }
+
+    /// Every complete node of a tree, level by level.
+    fn levels(size: u64) -> Vec<Vec<Hash>> {
+        let mut levels = vec![(0..size).map(leaf_hash).collect::<Vec<_>>()];
+        while levels.last().unwrap().len() > 1 {
+            let below = levels.last().unwrap();
+            let level = below
+                .chunks_exact(2)
+                .map(|pair| node_hash(&pair[0], &pair[1]))
+                .collect();
+            levels.push(level);
+        }
+        levels
+    }
+
+    fn tree_hash(leaves: &[Hash]) -> Hash {
+        if leaves.len() == 1 {
+            return leaves[0];
+        }
+        let k = split(leaves.len() as u64) as usize;
+        node_hash(&tree_hash(&leaves[..k]), &tree_hash(&leaves[k..]))
+    }
+
+    #[test]
+    fn inclusion_proofs() {
+        for size in [1, 2, 7, 256, 257, 600, 70_000] {
+            let levels = levels(size);
+            let expected = tree_hash(&levels[0]);
+            for leaf in [0, size / 3, size - 1] {
+                let mut tiles = HashMap::new();
+                for (level, index) in proof(leaf, size) {
+                    let tile = Tile::of(level, index, size);
+                    let first = (tile.index * TILE_WIDTH) as usize;
+                    let data = tiles.entry(tile).or_insert_with(|| {
+                        levels[(tile.level * TILE_HEIGHT) as usize]
+                            [first..first + tile.width as usize]
+                            .concat()
+                    });
+                    assert_eq!(
+                        Some(levels[level as usize][index as usize]),
+                        tile.node(data, level, index)
+                    );
+                }
+                let nodes: HashMap<_, _> = proof(leaf, size)
+                    .into_iter()
+                    .map(|(level, index)| ((level, index), levels[level as usize][index as usize]))
+                    .collect();
+                assert_eq!(
+                    Some(expected),
+                    root(leaf, &leaf_hash(leaf), size, &nodes),
+                    "leaf {leaf} of {size}"
+                );
+                assert_ne!(
+                    Some(expected),
+                    root(leaf, &leaf_hash(leaf + 1), size, &nodes)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn tile_paths() {
+        let tile = Tile {
+            level: 0,
+            index: 1234067,
+            width: 256,
+        };
+        assert_eq!("tile/8/0/x001/x234/067", tile.path());
+        assert_eq!("tile/8/1/002.p/5", Tile::of(9, 257, 517 * 256).path());
+        assert_eq!(
+            Tile {
+                level: 1,
+                index: 0,
+                width: 2
+            },
+            Tile::of(8, 1, 600)
+        );
+    }
+
+    #[test]
+    fn signed_tree_notes() {
+        let (signer, verifier) = database_signer("sum.example.com");
+        let verifier = VerifierKey::parse(&verifier).unwrap();
+        assert_eq!("sum.example.com", verifier.name());
+
+        let note = signed_note(&signer, "sum.example.com", 1234, &[7; 32]);
+        assert_eq!(
+            Ok(Tree {
+                size: 1234,
+                hash: [7; 32]
+            }),
+            Tree::verify(&note, &verifier)
+        );
+        assert!(Tree::verify(&note.replace("1234", "1235"), &verifier).is_err());
+        assert!(
+            Tree::verify(&note.replace("sum.example.com", "sum.other.com"), &verifier).is_err()
+        );
+
+        let lookup = format!(
+            "42\ngolang.org/x/text v0.3.0 h1:g61t\ngolang.org/x/text v0.3.0/go.mod h1:NqM8\n\n{note}"
+        );
+        let record = Record::parse(&lookup).unwrap();
+        assert_eq!(42, record.id);
+        assert_eq!(Some("h1:g61t"), record.hash("golang.org/x/text", "v0.3.0"));
+        assert_eq!(
+            Some("h1:NqM8"),
+            record.hash("golang.org/x/text", "v0.3.0/go.mod")
+        );
+        assert_eq!(None, record.hash("golang.org/x/text", "v0.3.1"));
+        assert_eq!(note, record.note);
+    }
+}
@@ -0,0 +1,247 @@
+/*
+ Parsing of the files of a Maven repository into the coordinates of the artifact they hold
+*/
+
+use url::Url;
+use urlencoding::encode;
+
+/// Extensions of the checksum files published next to every artifact
+pub const CHECKSUM_EXTENSIONS: [&str; 4] = ["md5", "sha1", "sha256", "sha512"];
+pub const SIGNATURE_EXTENSION: &str = "asc";
+
+const SNAPSHOT: &str = "SNAPSHOT";
+
+/// A file requested from `{group}/{artifact}/{version}/`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MavenFile<'a> {
+    group: &'a str,
+    artifact: &'a str,
+    version: &'a str,
+    /// Timestamped version of a SNAPSHOT file, such as `1.0-20230125.103005-3`
+    snapshot_version: Option<&'a str>,
+    classifier: Option<&'a str>,
+    extension: &'a str,
+    /// Extension of a checksum or signature of the artifact file
+    sidecar: Option<&'a str>,
+}
+
+impl<'a> MavenFile<'a> {
+    /// Parse `{artifact}-{version}[-{classifier}].{extension}[.{sidecar}]`, where the version of a
+    /// SNAPSHOT may be timestamped. Returns None for files which are not artifacts, such as
+    /// `maven-metadata.xml`.
+    pub fn parse(
+        group: &'a str,
+        artifact: &'a str,
+        version: &'a str,
+        file: &'a str,
+    ) -> Option<Self> {
+        let (base, sidecar) = split_sidecar(file);
+        let rest = base.strip_prefix(artifact)?.strip_prefix('-')?;
+        let (snapshot_version, rest) = match rest.strip_prefix(version) {
+            Some(rest) => (None, rest),
+            None => {
+                let snapshot_version = snapshot_version(version, rest)?;
+                (Some(snapshot_version), &rest[snapshot_version.len()..])
+            }
+        };
+        let (classifier, extension) = match rest.strip_prefix('-') {
+            Some(rest) => {
+                let (classifier, extension) = rest.split_once('.')?;
+                (Some(classifier), extension)
+            }
+            None => (None, rest.strip_prefix('.')?),
+        };
+        if extension.is_empty() || classifier == Some("") {
+            return None;
+        }
+        Some(Self {
+            group,
+            artifact,
+            version,
+            snapshot_version,
+            classifier,
+            extension,
+            sidecar,
+        })
+    }
+
+    pub fn version(&self) -> &str {
+        self.version
+    }
+
+    pub fn snapshot_version(&self) -> Option<&str> {
+        self.snapshot_version
+    }
+
+    pub fn is_snapshot(&self) -> bool {
+        self.version.ends_with(SNAPSHOT)
+    }
+
+    pub fn classifier(&self) -> Option<&str> {
+        self.classifier
+    }
+
+    pub fn extension(&self) -> &str {
+        self.extension
+    }
+
+    pub fn sidecar(&self) -> Option<&str> {
+        self.sidecar
+    }
+
+    /// The package URL of the artifact, qualifiers sorted as the purl specification requires.
+    pub fn purl(&self, repository_url: &Url) -> String {
+        let mut qualifiers = Vec::new();
+        if let Some(classifier) = self.classifier {
+            qualifiers.push(format!("classifier={}", encode(classifier)));
+        }
+        qualifiers.push(format!(
+            "repository_url={}",
+            encode(repository_url.as_str())
+        ));
+        qualifiers.push(format!("type={}", encode(self.extension)));
+        format!(
+            "pkg:maven/{}/{}@{}?{}",
+            self.group.replace('/', "."),
+            self.artifact,
+            encode(self.snapshot_version.unwrap_or(self.version)),
+            qualifiers.join("&")
+        )
+    }
+}
+
+/// The package URL of an artifact version, as listed by its metadata.
+pub fn version_purl(group: &str, artifact: &str, version: &str, repository_url: &Url) -> String {
+    format!(
+        "pkg:maven/{}/{artifact}@{}?repository_url={}",
+        group.replace('/', "."),
+        encode(version),
+        encode(repository_url.as_str())
+    )
+}
+
+/// Split a checksum or signature file into the file it describes and its extension.
+pub fn split_sidecar(file: &str) -> (&str, Option<&str>) {
+    CHECKSUM_EXTENSIONS
+        .iter()
+        .chain([SIGNATURE_EXTENSION].iter())
+        .find_map(|ext| {
+            file.strip_suffix(ext)
+                .and_then(|base| base.strip_suffix('.'))
+                .map(|base| (base, Some(*ext)))
+        })
+        .unwrap_or((file, None))
+}
+
+/// Match the `{base}-{yyyyMMdd.HHmmss}-{build}` version a SNAPSHOT is deployed as at the start of
+/// a file name.
+fn snapshot_version<'a>(version: &str, file: &'a str) -> Option<&'a str> {
+    let base = version.strip_suffix(SNAPSHOT)?;
+    let rest = file.strip_prefix(base)?;
+    let timestamp = rest.get(..15)?;
+    let (date, time) = timestamp.split_once('.')?;
+    if date.len() != 8 || !date.bytes().chain(time.bytes()).all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let build = rest[15..].strip_prefix('-')?;
+    let build_len = build.bytes().take_while(|b| b.is_ascii_digit()).count();
+    if build_len == 0 {
+        return None;
+    }
+    Some(&file[..base.len() + 15 + 1 + build_len])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn release_files() {
+        let file = MavenFile::parse(
+            "com/google/guava",
+            "guava",
+            "31.1-jre",
+            "guava-31.1-jre.jar",
+        )
+        .unwrap();
+        assert_eq!(None, file.classifier());
+        assert_eq!("jar", file.extension());
+        assert_eq!(None, file.sidecar());
+        assert!(!file.is_snapshot());
+        assert_eq!(
+            "pkg:maven/com.google.guava/guava@31.1-jre?repository_url=https%3A%2F%2Frepo.maven.apache.org%2Fmaven2&type=jar",
+            file.purl(&Url::parse("https://repo.maven.apache.org/maven2").unwrap())
+        );
+
+        let file =
+            MavenFile::parse("org/example", "lib", "1.0", "lib-1.0-sources.jar.sha1").unwrap();
+        assert_eq!(Some("sources"), file.classifier());
+        assert_eq!("jar", file.extension());
+        assert_eq!(Some("sha1"), file.sidecar());
+
+        let file = MavenFile::parse(
+            "org/apache/maven",
+            "maven",
+            "3.9.0",
+            "maven-3.9.0-bin.tar.gz",
+        )
+        .unwrap();
+        assert_eq!(Some("bin"), file.classifier());
+        assert_eq!("tar.gz", file.extension());
+
+        let file = MavenFile::parse("org/example", "lib", "1.0", "lib-1.0.pom.asc").unwrap();
+        assert_eq!("pom", file.extension());
+        assert_eq!(Some("asc"), file.sidecar());
+
+        assert_eq!(
+            None,
+            MavenFile::parse("org/example", "lib", "1.0", "maven-metadata.xml")
+        );
+        assert_eq!(
+            None,
+            MavenFile::parse("org/example", "lib", "1.0", "lib-1.0")
+        );
+        assert_eq!(
+            None,
+            MavenFile::parse("org/example", "lib", "1.0", "lib-2.0.jar")
+        );
+    }
+
+    #[test]
+    fn snapshot_files() {
+        let file = MavenFile::parse(
+            "org/example",
+            "lib",
+            "1.0-SNAPSHOT",
+            "lib-1.0-20230125.103005-3-javadoc.jar",
+        )
+        .unwrap();
+        assert!(file.is_snapshot());
+        assert_eq!(Some("1.0-20230125.103005-3"), file.snapshot_version());
+        assert_eq!(Some("javadoc"), file.classifier());
+        assert_eq!("jar", file.extension());
+        assert_eq!(
+            "pkg:maven/org.example/lib@1.0-20230125.103005-3?classifier=javadoc&repository_url=http%3A%2F%2Flocalhost%2F&type=jar",
+            file.purl(&Url::parse("http://localhost/").unwrap())
+        );
+
+        let file =
+            MavenFile::parse("org/example", "lib", "1.0-SNAPSHOT", "lib-1.0-SNAPSHOT.pom").unwrap();
+        assert_eq!(None, file.snapshot_version());
+        assert_eq!("pom", file.extension());
+
+        assert_eq!(
+            None,
+            MavenFile::parse("org/example", "lib", "1.0-SNAPSHOT", "lib-1.0-2023.jar")
+        );
+        assert_eq!(
+            "pkg:maven/org.example/lib@1.0-SNAPSHOT?repository_url=http%3A%2F%2Flocalhost%2F",
+            version_purl(
+                "org.example",
+                "lib",
+                "1.0-SNAPSHOT",
+                &Url::parse("http://localhost/").unwrap()
+            )
+        );
+    }
+}
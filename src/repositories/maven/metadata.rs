@@ -0,0 +1,410 @@
+/*
+ Reading and rewriting of the maven-metadata.xml listing the versions of an artifact
+*/
+
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use std::cmp::Ordering;
+
+const VERSIONS_PATH: [&[u8]; 3] = [b"metadata", b"versioning", b"versions"];
+const VERSION_PATH: [&[u8]; 4] = [b"metadata", b"versioning", b"versions", b"version"];
+const LATEST_PATH: [&[u8]; 3] = [b"metadata", b"versioning", b"latest"];
+const RELEASE_PATH: [&[u8]; 3] = [b"metadata", b"versioning", b"release"];
+const LAST_UPDATED_PATH: [&[u8]; 3] = [b"metadata", b"versioning", b"lastUpdated"];
+
+/// Qualifiers ordered before a release, and `sp` after it. Others follow in alphabetical order.
+const QUALIFIERS: [&str; 6] = ["alpha", "beta", "milestone", "rc", "snapshot", ""];
+
+/// The coordinates and versions listed by an artifact's maven-metadata.xml.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub group: String,
+    pub artifact: String,
+    pub versions: Vec<String>,
+    /// When the metadata was last updated, as `yyyyMMddHHmmss`
+    pub last_updated: Option<String>,
+}
+
+/// An item of a version, between separators or where digits and letters meet.
+#[derive(Debug, PartialEq, Eq)]
+enum Item<'a> {
+    Number(u64),
+    Qualifier(&'a str),
+}
+
+fn qualifier_rank(qualifier: &str) -> (usize, String) {
+    let qualifier = qualifier.to_ascii_lowercase();
+    let canonical = match qualifier.as_str() {
+        "a" => "alpha",
+        "b" => "beta",
+        "m" => "milestone",
+        "cr" => "rc",
+        "ga" | "final" | "release" => "",
+        other => other,
+    };
+    match QUALIFIERS.iter().position(|known| *known == canonical) {
+        Some(rank) => (rank, String::new()),
+        None if canonical == "sp" => (QUALIFIERS.len(), String::new()),
+        None => (QUALIFIERS.len() + 1, qualifier),
+    }
+}
+
+impl Ord for Item<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Item::Number(a), Item::Number(b)) => a.cmp(b),
+            (Item::Number(_), Item::Qualifier(_)) => Ordering::Greater,
+            (Item::Qualifier(_), Item::Number(_)) => Ordering::Less,
+            (Item::Qualifier(a), Item::Qualifier(b)) => qualifier_rank(a).cmp(&qualifier_rank(b)),
+        }
+    }
+}
+
+impl PartialOrd for Item<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn version_items(version: &str) -> Vec<Item<'_>> {
+    let mut items = Vec::new();
+    for token in version.split(['.', '-']).filter(|token| !token.is_empty()) {
+        let mut start = 0;
+        while start < token.len() {
+            let digits = token[start..].starts_with(|c: char| c.is_ascii_digit());
+            let end = token[start..]
+                .find(|c: char| c.is_ascii_digit() != digits)
+                .map_or(token.len(), |length| start + length);
+            let item = &token[start..end];
+            items.push(match item.parse() {
+                Ok(number) if digits => Item::Number(number),
+                _ => Item::Qualifier(item),
+            });
+            start = end;
+        }
+    }
+    items
+}
+
+/// Drop the trailing zeros and release qualifiers, so `1.0-final` and `1` are equal.
+fn trimmed(mut items: Vec<Item<'_>>) -> Vec<Item<'_>> {
+    while let Some(item) = items.last() {
+        match item {
+            Item::Number(0) => items.pop(),
+            Item::Qualifier(qualifier) if qualifier_rank(qualifier) == qualifier_rank("") => {
+                items.pop()
+            }
+            _ => break,
+        };
+    }
+    items
+}
+
+/// Compare versions the way Maven orders them: numbers numerically, `alpha`, `beta`,
+/// `milestone`, `rc` and `SNAPSHOT` before the release, and other qualifiers after it.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a, b) = (trimmed(version_items(a)), trimmed(version_items(b)));
+    // Missing items compare as the zero or release they are equivalent to
+    let missing = |other: &Item| match other {
+        Item::Number(_) => Item::Number(0),
+        Item::Qualifier(_) => Item::Qualifier(""),
+    };
+    for index in 0..a.len().max(b.len()) {
+        let ordering = match (a.get(index), b.get(index)) {
+            (Some(a), Some(b)) => a.cmp(b),
+            (Some(a), None) => a.cmp(&missing(a)),
+            (None, Some(b)) => missing(b).cmp(b),
+            (None, None) => Ordering::Equal,
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+fn read_events(xml: &[u8]) -> quick_xml::Result<Vec<Event<'static>>> {
+    let mut reader = Reader::from_reader(xml);
+    let mut events = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Eof => return Ok(events),
+            event => events.push(event.into_owned()),
+        }
+    }
+}
+
+/// Read the group, artifact and versions of maven-metadata.xml.
+pub fn parse(xml: &[u8]) -> quick_xml::Result<Metadata> {
+    let mut metadata = Metadata::default();
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    for event in read_events(xml)? {
+        match event {
+            Event::Start(start) => path.push(start.name().as_ref().to_vec()),
+            Event::End(_) => {
+                path.pop();
+            }
+            Event::Text(text) => {
+                let text = text.unescape()?.trim().to_string();
+                let path: Vec<&[u8]> = path.iter().map(Vec::as_slice).collect();
+                match path.as_slice() {
+                    [b"metadata", b"groupId"] => metadata.group = text,
+                    [b"metadata", b"artifactId"] => metadata.artifact = text,
+                    path if path == VERSION_PATH => metadata.versions.push(text),
+                    path if path == LAST_UPDATED_PATH => metadata.last_updated = Some(text),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(metadata)
+}
+
+/// Index of the event closing the element started at `start`.
+fn element_end(events: &[Event], start: usize) -> usize {
+    let mut depth = 0;
+    for (index, event) in events.iter().enumerate().skip(start) {
+        match event {
+            Event::Start(_) => depth += 1,
+            Event::End(_) => {
+                depth -= 1;
+                if depth == 0 {
+                    return index;
+                }
+            }
+            _ => {}
+        }
+    }
+    events.len() - 1
+}
+
+/// The value of `latest` or `release` once the versions are rewritten: the highest suitable
+/// version.
+fn pointer(versions: &[String], suitable: fn(&str) -> bool) -> Option<&str> {
+    versions
+        .iter()
+        .map(String::as_str)
+        .filter(|version| suitable(version))
+        .max_by(|a, b| compare_versions(a, b))
+}
+
+/// Rewrite maven-metadata.xml to list the given versions, updating `latest` and `release` to
+/// match, along with `lastUpdated` when given. Either pointer is dropped when no suitable
+/// version is left.
+pub fn rewrite_versions(
+    xml: &[u8],
+    versions: &[String],
+    last_updated: Option<&str>,
+) -> quick_xml::Result<Vec<u8>> {
+    let events = read_events(xml)?;
+    let mut writer = Writer::new(Vec::new());
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    // Indentation is only written along with the element following it
+    let mut indentation: Option<&Event> = None;
+    let mut index = 0;
+    while index < events.len() {
+        let event = &events[index];
+        if let Event::Text(text) = event {
+            if text.iter().all(u8::is_ascii_whitespace) {
+                if let Some(indentation) = indentation.replace(event) {
+                    writer.write_event(indentation.borrow())?;
+                }
+                index += 1;
+                continue;
+            }
+        }
+        if let Some(indentation) = indentation.take() {
+            writer.write_event(indentation.borrow())?;
+        }
+
+        if let Event::Start(start) = event {
+            path.push(start.name().as_ref().to_vec());
+            let element: Vec<&[u8]> = path.iter().map(Vec::as_slice).collect();
+            let end = element_end(&events, index);
+            if element == VERSIONS_PATH {
+                write_versions(&mut writer, &events[index..=end], versions)?;
+                path.pop();
+                index = end + 1;
+                continue;
+            }
+            let replacement = if element == LATEST_PATH {
+                Some(pointer(versions, |_| true))
+            } else if element == RELEASE_PATH {
+                Some(pointer(versions, |version| !version.ends_with("-SNAPSHOT")))
+            } else if element == LAST_UPDATED_PATH && last_updated.is_some() {
+                Some(last_updated)
+            } else {
+                None
+            };
+            if let Some(replacement) = replacement {
+                if let Some(text) = replacement {
+                    writer.write_event(event.borrow())?;
+                    writer.write_event(Event::Text(BytesText::new(text)))?;
+                    writer.write_event(events[end].borrow())?;
+                }
+                path.pop();
+                index = end + 1;
+                continue;
+            }
+        }
+
+        if let Event::End(_) = event {
+            path.pop();
+        }
+        writer.write_event(event.borrow())?;
+        index += 1;
+    }
+    if let Some(indentation) = indentation {
+        writer.write_event(indentation.borrow())?;
+    }
+    Ok(writer.into_inner())
+}
+
+/// Write the `versions` element, reusing the indentation of the original one.
+fn write_versions(
+    writer: &mut Writer<Vec<u8>>,
+    element: &[Event],
+    versions: &[String],
+) -> quick_xml::Result<()> {
+    let whitespace = |event: &&Event| matches!(event, Event::Text(text) if text.iter().all(u8::is_ascii_whitespace));
+    let inner = &element[1..element.len() - 1];
+    let item_indentation = inner.iter().find(whitespace);
+    let closing_indentation = inner.last().filter(whitespace);
+
+    writer.write_event(element[0].borrow())?;
+    for version in versions {
+        if let Some(indentation) = item_indentation {
+            writer.write_event(indentation.borrow())?;
+        }
+        writer.write_event(Event::Start(BytesStart::new("version")))?;
+        writer.write_event(Event::Text(BytesText::new(version)))?;
+        writer.write_event(Event::End(BytesEnd::new("version")))?;
+    }
+    if let Some(indentation) = closing_indentation {
+        writer.write_event(indentation.borrow())?;
+    }
+    writer.write_event(element[element.len() - 1].borrow())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const METADATA: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<metadata>
+  <groupId>org.example</groupId>
+  <artifactId>lib</artifactId>
+  <versioning>
+    <latest>2.0-SNAPSHOT</latest>
+    <release>1.1</release>
+    <versions>
+      <version>1.0</version>
+      <version>1.1</version>
+      <version>2.0-SNAPSHOT</version>
+    </versions>
+    <lastUpdated>20230125103005</lastUpdated>
+  </versioning>
+</metadata>
+"#;
+
+    #[test]
+    fn parse_metadata() {
+        let metadata = parse(METADATA.as_bytes()).unwrap();
+        assert_eq!("org.example", metadata.group);
+        assert_eq!("lib", metadata.artifact);
+        assert_eq!(vec!["1.0", "1.1", "2.0-SNAPSHOT"], metadata.versions);
+        assert_eq!(Some("20230125103005"), metadata.last_updated.as_deref());
+    }
+
+    #[test]
+    fn version_ordering() {
+        let ordered = [
+            "1.0-alpha-1",
+            "1.0-beta",
+            "1.0-M2",
+            "1.0-RC1",
+            "1.0-SNAPSHOT",
+            "1.0",
+            "1.0-sp1",
+            "1.0-internal",
+            "1.0.1",
+            "1.2",
+            "1.10",
+            "2.0-SNAPSHOT",
+            "10.0",
+        ];
+        for window in ordered.windows(2) {
+            assert_eq!(
+                Ordering::Less,
+                compare_versions(window[0], window[1]),
+                "{} < {}",
+                window[0],
+                window[1]
+            );
+        }
+        assert_eq!(Ordering::Equal, compare_versions("1.0", "1"));
+        assert_eq!(Ordering::Equal, compare_versions("1.0.0-GA", "1-final"));
+        assert_eq!(Ordering::Equal, compare_versions("1.0-rc1", "1.0-CR1"));
+    }
+
+    #[test]
+    fn rewrite_metadata_versions() {
+        let versions = |versions: &[&str]| -> Vec<String> {
+            versions
+                .iter()
+                .map(|version| String::from(*version))
+                .collect()
+        };
+
+        let xml = rewrite_versions(
+            METADATA.as_bytes(),
+            &versions(&["1.0", "2.0-SNAPSHOT"]),
+            None,
+        )
+        .unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        assert_eq!(
+            METADATA
+                .replace("<release>1.1</release>", "<release>1.0</release>")
+                .replace("\n      <version>1.1</version>", ""),
+            xml
+        );
+
+        let xml =
+            rewrite_versions(METADATA.as_bytes(), &versions(&["2.0-SNAPSHOT"]), None).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        assert!(!xml.contains("<release>"));
+        assert!(xml.contains("<latest>2.0-SNAPSHOT</latest>"));
+        assert_eq!(
+            vec!["2.0-SNAPSHOT"],
+            parse(xml.as_bytes()).unwrap().versions
+        );
+
+        // The pointers are the highest versions across the merged documents, not the first one's
+        let merged = versions(&["1.0", "1.1", "2.0-SNAPSHOT", "1.1-internal", "2.1", "1.2"]);
+        let xml = rewrite_versions(METADATA.as_bytes(), &merged, Some("20240301120000")).unwrap();
+        let metadata = parse(&xml).unwrap();
+        assert_eq!(merged, metadata.versions);
+        assert_eq!(Some("20240301120000"), metadata.last_updated.as_deref());
+        let xml = String::from_utf8(xml).unwrap();
+        assert!(xml.contains("<latest>2.1</latest>"));
+        assert!(xml.contains("<release>2.1</release>"));
+
+        let xml = rewrite_versions(
+            METADATA.as_bytes(),
+            &versions(&["1.0", "1.1", "1.1-internal"]),
+            None,
+        )
+        .unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        assert!(xml.contains("<latest>1.1-internal</latest>"));
+        assert!(xml.contains("<release>1.1-internal</release>"));
+        assert!(xml.contains("<lastUpdated>20230125103005</lastUpdated>"));
+
+        let xml = rewrite_versions(METADATA.as_bytes(), &[], None).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        assert!(!xml.contains("<latest>"));
+        assert!(xml.contains("<versions>\n    </versions>"));
+    }
+}
@@ -1,32 +1,334 @@
-use crate::policy::{context::Context, PolicyEngine};
+use crate::cache::ArtifactCache;
+use crate::config::repositories::RepositoryConfig;
+use crate::policy::{context::Context, PolicyEngine, Verdict};
+use crate::repositories::POLICY_CONCURRENCY;
 use actix_web::{
+    dev::RequestHead,
+    error::PayloadError,
+    http::{
+        header::{CONNECTION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING},
+        Method,
+    },
     route, web, Error, HttpRequest, HttpResponse, HttpResponseBuilder, Responder, Scope,
 };
+use awc::ClientResponse;
+use bytes::Bytes;
+use futures::{future, stream, Stream, StreamExt};
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::HashSet;
 use url::Url;
-use urlencoding::encode;
 
-pub struct MavenConfig {
-    url: Url,
-}
+use self::coordinates::{split_sidecar, version_purl, MavenFile};
+use self::metadata::Metadata;
 
-impl Default for MavenConfig {
-    fn default() -> Self {
-        Self::new("https://repo.maven.apache.org/maven2".try_into().unwrap())
-    }
+pub mod coordinates;
+pub mod metadata;
+
+const SNAPSHOT_SUFFIX: &str = "-SNAPSHOT";
+
+pub struct MavenConfig {
+    /// Tried in turn, the first upstream having a file serves it
+    upstreams: Vec<Url>,
+    cache: ArtifactCache,
+    offline: bool,
+    packaging: Vec<String>,
+    block_snapshots: bool,
+    filter_metadata: bool,
 }
 
 impl MavenConfig {
-    pub fn new(url: Url) -> Self {
-        Self { url }
+    pub fn new(config: &RepositoryConfig, cache: ArtifactCache, offline: bool) -> Self {
+        Self {
+            upstreams: config.upstreams(),
+            cache,
+            offline,
+            packaging: config.packaging(),
+            block_snapshots: config.block_snapshots(),
+            filter_metadata: config.filter_metadata(),
+        }
     }
 }
 
-pub fn service(scope: &str, url: Url) -> Scope {
+pub fn service(
+    scope: &str,
+    config: &RepositoryConfig,
+    cache: ArtifactCache,
+    offline: bool,
+) -> Scope {
     web::scope(scope)
-        .app_data(web::Data::new(MavenConfig::new(url)))
+        .app_data(web::Data::new(MavenConfig::new(config, cache, offline)))
+        .service(maven_metadata)
         .service(proxy)
 }
 
+fn checksum(algorithm: &str, payload: &[u8]) -> Option<String> {
+    match algorithm {
+        "md5" => Some(format!("{:x}", Md5::digest(payload))),
+        "sha1" => Some(format!("{:x}", Sha1::digest(payload))),
+        "sha256" => Some(format!("{:x}", Sha256::digest(payload))),
+        "sha512" => Some(format!("{:x}", Sha512::digest(payload))),
+        _ => None,
+    }
+}
+
+/// A response relaying the status and headers of an upstream response, without those describing
+/// the framing of the body, which is decompressed and re-framed by the proxy.
+fn relay_response<S>(upstream: &ClientResponse<S>) -> HttpResponseBuilder {
+    let mut response = HttpResponseBuilder::new(upstream.status());
+    for (name, value) in upstream.headers().iter() {
+        if ![
+            CONTENT_LENGTH,
+            CONTENT_ENCODING,
+            TRANSFER_ENCODING,
+            CONNECTION,
+        ]
+        .contains(name)
+        {
+            response.append_header((name.clone(), value.clone()));
+        }
+    }
+    response
+}
+
+/// Stream an upstream response to the client, writing it to the cache as it goes and storing it
+/// once fully received.
+async fn stream_response<S>(
+    upstream: awc::ClientResponse<S>,
+    cache: Option<(ArtifactCache, String)>,
+) -> HttpResponse
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin + 'static,
+{
+    let mut response = relay_response(&upstream);
+    let content_type = upstream
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let pending = match cache {
+        Some((cache, key)) => match cache.begin(&key).await {
+            Ok(pending) => Some(pending),
+            Err(e) => {
+                log::warn!("Unable to write {key} to the cache: {e}");
+                None
+            }
+        },
+        None => None,
+    };
+
+    // A pending artifact which is dropped, as with a partial download, is never stored
+    let body = stream::unfold((upstream, pending), move |(mut upstream, mut pending)| {
+        let content_type = content_type.clone();
+        async move {
+            match upstream.next().await {
+                Some(Ok(chunk)) => {
+                    if let Some(artifact) = pending.as_mut() {
+                        if let Err(e) = artifact.write(chunk.clone()).await {
+                            log::warn!("Unable to write to the cache: {e}");
+                            pending = None;
+                        }
+                    }
+                    Some((Ok(chunk), (upstream, pending)))
+                }
+                Some(Err(error)) => Some((Err(error), (upstream, None))),
+                None => {
+                    if let Some(pending) = pending {
+                        if let Err(e) = pending.commit(content_type.as_deref(), None, None).await {
+                            log::warn!("Unable to write to the cache: {e}");
+                        }
+                    }
+                    None
+                }
+            }
+        }
+    });
+    response.streaming(body)
+}
+
+/// Request a file from each upstream in priority order, returning the first successful response,
+/// or the last one received when no upstream has the file.
+async fn fetch_upstream<'a>(
+    config: &'a MavenConfig,
+    client: &awc::Client,
+    path: &str,
+    head: &RequestHead,
+) -> Option<(
+    &'a Url,
+    ClientResponse<impl Stream<Item = Result<Bytes, PayloadError>> + Unpin + 'static>,
+)> {
+    let mut last = None;
+    for url in &config.upstreams {
+        let uri = format!("{url}/{path}");
+        log::debug!("upstream -> {uri}");
+        match client.request_from(&uri, head).send().await {
+            Ok(upstream) if upstream.status().is_success() => return Some((url, upstream)),
+            Ok(upstream) => last = Some((url, upstream)),
+            Err(e) => log::warn!("Error encountered proxying {uri} -> {e}"),
+        }
+    }
+    last
+}
+
+/// The maven-metadata.xml of an upstream, None when it is missing or unusable.
+async fn fetch_metadata<'a>(
+    client: &awc::Client,
+    url: &'a Url,
+    path: &str,
+    head: &RequestHead,
+) -> Option<(&'a Url, Bytes, Metadata)> {
+    let uri = format!("{url}/{path}");
+    log::debug!("upstream -> {uri}");
+    let mut upstream = match client.request_from(&uri, head).send().await {
+        Ok(upstream) if upstream.status().is_success() => upstream,
+        Ok(upstream) => {
+            log::debug!("{uri} -> {}", upstream.status());
+            return None;
+        }
+        Err(e) => {
+            log::warn!("Error encountered proxying {uri} -> {e}");
+            return None;
+        }
+    };
+    let payload = match upstream.body().limit(20_000_000).await {
+        Ok(payload) => payload,
+        Err(e) => {
+            log::warn!("Error reading {uri} -> {e}");
+            return None;
+        }
+    };
+    match metadata::parse(&payload) {
+        Ok(metadata) => Some((url, payload, metadata)),
+        Err(e) => {
+            log::warn!("Invalid metadata at {uri} -> {e}");
+            None
+        }
+    }
+}
+
+/// Versions listed by the upstreams' metadata, in priority order, which the configuration and
+/// policy allow clients to resolve.
+async fn allowed_versions(
+    config: &MavenConfig,
+    policy: &PolicyEngine,
+    documents: &[(&Url, Bytes, Metadata)],
+) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let checks = documents
+        .iter()
+        .flat_map(|(url, _, metadata)| {
+            metadata
+                .versions
+                .iter()
+                .map(move |version| (*url, metadata, version))
+        })
+        .filter(|(_, _, version)| seen.insert(version.as_str()))
+        .filter(|(_, _, version)| !(config.block_snapshots && version.ends_with(SNAPSHOT_SUFFIX)))
+        .map(|(url, metadata, version)| async move {
+            if !config.filter_metadata {
+                return Some(version.clone());
+            }
+            let purl = version_purl(&metadata.group, &metadata.artifact, version, url);
+            let uri = format!(
+                "{url}/{}/{}/{version}",
+                metadata.group.replace('.', "/"),
+                metadata.artifact
+            );
+            let context = Context::new(purl, uri, String::new()).with_upstream(url);
+            match policy.check(&context, None).await {
+                Ok(Verdict::Denied(_)) => None,
+                Ok(_) => Some(version.clone()),
+                Err(e) => {
+                    // Fail closed, a version the policy could not evaluate is not advertised
+                    log::warn!("Unable to evaluate {}: {e}", context.purl());
+                    None
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    stream::iter(checks)
+        .buffered(POLICY_CONCURRENCY)
+        .filter_map(future::ready)
+        .collect()
+        .await
+}
+
+#[route("{path:.*}/maven-metadata.xml", method = "GET", method = "HEAD")]
+async fn maven_metadata(
+    req: HttpRequest,
+    config: web::Data<MavenConfig>,
+    policy: web::Data<PolicyEngine>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let path = path.into_inner();
+    let cache_key = format!("{path}/maven-metadata.xml");
+    if config.block_snapshots && path.ends_with(SNAPSHOT_SUFFIX) {
+        return HttpResponse::Forbidden().body("SNAPSHOT resolution is disabled");
+    }
+
+    if config.offline {
+        return match config.cache.respond(&cache_key).await {
+            Some(response) => response,
+            None => {
+                log::info!("{cache_key} is not available offline");
+                HttpResponse::NotFound().finish()
+            }
+        };
+    }
+    if req.method() != Method::GET {
+        return match fetch_upstream(&config, &policy.client, &cache_key, req.head()).await {
+            Some((_, upstream)) => stream_response(upstream, None).await,
+            None => HttpResponse::BadGateway().finish(),
+        };
+    }
+
+    // The versions of a group are those of all its upstreams
+    let documents = future::join_all(
+        config
+            .upstreams
+            .iter()
+            .map(|url| fetch_metadata(&policy.client, url, &cache_key, req.head())),
+    )
+    .await;
+    let documents: Vec<_> = documents.into_iter().flatten().collect();
+    let payload = match documents.as_slice() {
+        [] => return HttpResponse::NotFound().finish(),
+        [(_, payload, _)] if !config.block_snapshots && !config.filter_metadata => payload.clone(),
+        [(_, payload, _), ..] => {
+            let versions = allowed_versions(&config, &policy, &documents).await;
+            // Timestamps are yyyyMMddHHmmss, so the longest and then greatest is the newest
+            let last_updated = documents
+                .iter()
+                .filter_map(|(_, _, metadata)| metadata.last_updated.as_deref())
+                .max_by_key(|last_updated| (last_updated.len(), *last_updated));
+            match metadata::rewrite_versions(payload, &versions, last_updated) {
+                Ok(rewritten) => Bytes::from(rewritten),
+                Err(e) => {
+                    let msg = format!("Unable to rewrite {cache_key} -> {e}");
+                    log::error!("{msg}");
+                    return HttpResponse::InternalServerError().body(msg);
+                }
+            }
+        }
+    };
+
+    // Checksums of the metadata are computed from the cached copy, so they match what was served
+    config
+        .cache
+        .store(
+            &cache_key,
+            Some("application/xml"),
+            None,
+            None,
+            payload.clone(),
+        )
+        .await;
+    HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(payload)
+}
+
 #[route(
     "{group:.*}/{artifact}/{version}/{file}",
     method = "GET",
@@ -39,41 +341,277 @@ async fn proxy(
     path: web::Path<(String, String, String, String)>,
 ) -> impl Responder {
     let (group, artifact, version, file) = path.into_inner();
-    let uri = format!("{}/{}/{}/{}/{}", config.url, group, artifact, version, file);
-    log::debug!("upstream -> {uri}");
-    let request = policy.client.request_from(&uri, req.head());
-    log::debug!("request: {request:?}");
-    match request.send().await {
-        Ok(mut upstream) => match upstream.body().limit(20_000_000).await {
-            Ok(payload) => {
-                let context = Context::new(
-                    format!(
-                        "pkg:maven/{}/{artifact}@{version}?type={}&repository_url={}",
-                        group.replace('/', "."),
-                        file.rsplit_once('.').unwrap_or(("", "unknown")).1,
-                        encode(config.url.as_str())
-                    ),
-                    uri,
-                    sha256::digest(payload.as_ref()),
-                );
-                match policy.evaluate(&context, Some("jar")).await {
-                    Ok(None) => {
-                        let mut response = HttpResponseBuilder::new(upstream.status());
-                        for header in upstream.headers().iter() {
-                            response.insert_header(header);
-                        }
-                        response.body(payload)
+    if config.block_snapshots && version.ends_with(SNAPSHOT_SUFFIX) {
+        return HttpResponse::Forbidden().body("SNAPSHOT resolution is disabled");
+    }
+    let cache_key = format!("{group}/{artifact}/{version}/{file}");
+
+    let (base, sidecar) = split_sidecar(&file);
+    let maven_file = MavenFile::parse(&group, &artifact, &version, &file);
+    // Artifacts of the packaging gated by the policy must be named so they can be evaluated
+    let packaged = sidecar.is_none()
+        && base
+            .rsplit_once('.')
+            .is_some_and(|(_, extension)| config.packaging.iter().any(|p| p == extension));
+    if packaged && maven_file.is_none() {
+        let msg = format!("{file} is not an artifact of {group}:{artifact}:{version}");
+        log::warn!("Refused {msg}");
+        return HttpResponse::Forbidden().body(msg);
+    }
+    if let Some(algorithm) = sidecar {
+        // Checksums of an artifact the proxy has served describe the copy it served
+        let base_key = format!("{group}/{artifact}/{version}/{base}");
+        if let Some((_, payload)) = config.cache.load(&base_key).await {
+            if let Some(checksum) = checksum(algorithm, &payload) {
+                return HttpResponse::Ok().content_type("text/plain").body(checksum);
+            }
+        }
+    }
+
+    if config.offline {
+        return match config.cache.respond(&cache_key).await {
+            Some(response) => response,
+            None => {
+                log::info!("{cache_key} is not available offline");
+                HttpResponse::NotFound().finish()
+            }
+        };
+    }
+    let (url, mut upstream) =
+        match fetch_upstream(&config, &policy.client, &cache_key, req.head()).await {
+            Some(upstream) => upstream,
+            None => {
+                let msg = format!("No upstream available for {cache_key}");
+                log::error!("{msg}");
+                return HttpResponse::BadGateway().body(msg);
+            }
+        };
+    let uri = format!("{url}/{cache_key}");
+
+    let gated = match &maven_file {
+        Some(maven_file) if maven_file.sidecar().is_none() => config
+            .packaging
+            .iter()
+            .any(|packaging| packaging == maven_file.extension()),
+        _ => false,
+    };
+    let cacheable = upstream.status().is_success() && req.method() == Method::GET;
+    if !gated {
+        let cache = cacheable.then(|| (config.cache.clone(), cache_key));
+        return stream_response(upstream, cache).await;
+    }
+
+    match upstream.body().limit(20_000_000).await {
+        Ok(payload) => {
+            let purl = maven_file
+                .map(|maven_file| maven_file.purl(url))
+                .unwrap_or_default();
+            let context =
+                Context::new(purl, uri, sha256::digest(payload.as_ref())).with_upstream(url);
+            match policy.check(&context, None).await {
+                Ok(Verdict::Denied(response)) => response,
+                Ok(verdict) => {
+                    if cacheable {
+                        let content_type = upstream
+                            .headers()
+                            .get(CONTENT_TYPE)
+                            .and_then(|value| value.to_str().ok());
+                        config
+                            .cache
+                            .store(
+                                &cache_key,
+                                content_type,
+                                Some(&context),
+                                policy.record(&verdict),
+                                payload.clone(),
+                            )
+                            .await;
                     }
-                    Ok(Some(response)) => response,
-                    Err(e) => e.into(),
+                    relay_response(&upstream).body(payload)
                 }
+                Err(e) => e.into(),
             }
-            Err(e) => Error::from(e).into(),
-        },
-        Err(e) => {
-            let msg = format!("Error encountered proxying {uri} -> {e}");
-            log::error!("{msg}");
-            HttpResponse::InternalServerError().body(msg)
         }
+        Err(e) => Error::from(e).into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::dev::ServerHandle;
+    use actix_web::http::header::ETAG;
+    use actix_web::{rt, App, HttpServer};
+    use awc::test::TestResponse;
+    use std::collections::HashMap;
+
+    /// An upstream repository on a loopback port serving the given files.
+    fn upstream(files: &[(&str, &str)]) -> (Url, ServerHandle) {
+        let files: HashMap<String, String> = files
+            .iter()
+            .map(|(path, content)| (format!("/maven2/{path}"), String::from(*content)))
+            .collect();
+        let files = web::Data::new(files);
+        let server = HttpServer::new(move || {
+            App::new().app_data(files.clone()).default_service(web::to(
+                |req: HttpRequest, files: web::Data<HashMap<String, String>>| async move {
+                    match files.get(req.path()) {
+                        Some(content) => HttpResponse::Ok().body(content.clone()),
+                        None => HttpResponse::NotFound().finish(),
+                    }
+                },
+            ))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = Url::parse(&format!("http://{}/maven2", server.addrs()[0])).unwrap();
+        let server = server.run();
+        let handle = server.handle();
+        rt::spawn(server);
+        (url, handle)
+    }
+
+    fn document(latest: &str, release: &str, versions: &[&str], last_updated: &str) -> String {
+        let versions: String = versions
+            .iter()
+            .map(|version| format!("<version>{version}</version>"))
+            .collect();
+        format!(
+            "<metadata><groupId>org.example</groupId><artifactId>lib</artifactId><versioning>\
+             <latest>{latest}</latest><release>{release}</release>\
+             <versions>{versions}</versions><lastUpdated>{last_updated}</lastUpdated>\
+             </versioning></metadata>"
+        )
+    }
+
+    #[actix_web::test]
+    async fn group_metadata() {
+        const METADATA: &str = "org/example/lib/maven-metadata.xml";
+        const POM: &str = "org/example/lib/1.1/lib-1.1.pom";
+        let first = document("1.1", "1.1", &["1.0", "1.1"], "20230101000000");
+        let third = document(
+            "2.1-SNAPSHOT",
+            "2.0",
+            &["1.1", "2.0", "2.1-SNAPSHOT"],
+            "20240101000000",
+        );
+        let upstreams = [
+            upstream(&[(METADATA, &first)]),
+            upstream(&[(POM, "<project>second</project>")]),
+            upstream(&[(METADATA, &third), (POM, "<project>third</project>")]),
+        ];
+        let url = |index: usize| format!("\"{}\"", upstreams[index].0);
+        let config: RepositoryConfig = toml::from_str(&format!(
+            "type = \"m2-group\"\nurl = {}\nupstreams = [{}, {}]",
+            url(0),
+            url(1),
+            url(2)
+        ))
+        .unwrap();
+        let policy: crate::config::policy::PolicyConfig =
+            toml::from_str("url = \"http://127.0.0.1:1\"").unwrap();
+        let root = std::env::temp_dir().join(format!("seedwing-maven-{}", std::process::id()));
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(PolicyEngine::new(policy)))
+                .service(service(
+                    "/maven",
+                    &config,
+                    ArtifactCache::new(root.clone()),
+                    false,
+                )),
+        )
+        .await;
+
+        // Versions are merged in priority order, the pointers and timestamp are the newest
+        let request = actix_web::test::TestRequest::get()
+            .uri(&format!("/maven/{METADATA}"))
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(200, response.status());
+        let body = actix_web::test::read_body(response).await;
+        let merged = metadata::parse(&body).unwrap();
+        assert_eq!(vec!["1.0", "1.1", "2.0", "2.1-SNAPSHOT"], merged.versions);
+        assert_eq!(Some("20240101000000"), merged.last_updated.as_deref());
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("<latest>2.1-SNAPSHOT</latest>"));
+        assert!(body.contains("<release>2.0</release>"));
+
+        // Files come from the first upstream having them
+        let request = actix_web::test::TestRequest::get()
+            .uri(&format!("/maven/{POM}"))
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(200, response.status());
+        assert_eq!(
+            "<project>second</project>",
+            actix_web::test::read_body(response).await
+        );
+
+        for (_, handle) in upstreams {
+            handle.stop(true).await;
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn stream_artifacts() {
+        const POM: &str = "org/example/lib/1.1/lib-1.1.pom";
+        const UNNAMED: &str = "org/example/lib/1.1/other-2.0.jar";
+        let pom = "<project>".repeat(20_000);
+        let (url, handle) = upstream(&[(POM, &pom), (UNNAMED, "jar")]);
+        let config: RepositoryConfig =
+            toml::from_str(&format!("type = \"m2\"\nurl = \"{url}\"")).unwrap();
+        let policy: crate::config::policy::PolicyConfig =
+            toml::from_str("url = \"http://127.0.0.1:1\"").unwrap();
+        let root = std::env::temp_dir().join(format!("seedwing-m2-{}", std::process::id()));
+        let cache = ArtifactCache::new(root.clone());
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(PolicyEngine::new(policy)))
+                .service(service("/maven", &config, cache.clone(), false)),
+        )
+        .await;
+        let get = |path: &str| {
+            actix_web::test::TestRequest::get()
+                .uri(&format!("/maven/{path}"))
+                .to_request()
+        };
+
+        // Written to the cache as it is streamed to the client
+        let response = actix_web::test::call_service(&app, get(POM)).await;
+        assert_eq!(200, response.status());
+        assert_eq!(pom, actix_web::test::read_body(response).await);
+        let (_, cached) = cache.load(POM).await.unwrap();
+        assert_eq!(pom, cached);
+
+        // A jar which cannot be evaluated is never relayed around the policy
+        let response = actix_web::test::call_service(&app, get(UNNAMED)).await;
+        assert_eq!(403, response.status());
+        assert!(cache.load(UNNAMED).await.is_none());
+
+        handle.stop(true).await;
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn relayed_headers() {
+        let upstream = TestResponse::default()
+            .insert_header((CONTENT_TYPE, "application/java-archive"))
+            .insert_header((CONTENT_ENCODING, "gzip"))
+            .insert_header((CONTENT_LENGTH, "512"))
+            .insert_header((ETAG, "\"abc\""))
+            .finish();
+        let response = relay_response(&upstream).finish();
+        let headers = response.headers();
+        assert_eq!(
+            "application/java-archive",
+            headers.get(CONTENT_TYPE).unwrap()
+        );
+        assert_eq!("\"abc\"", headers.get(ETAG).unwrap());
+        assert!(!headers.contains_key(CONTENT_ENCODING));
+        assert!(!headers.contains_key(CONTENT_LENGTH));
     }
 }
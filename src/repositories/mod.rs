@@ -1,4 +1,27 @@
+use std::future::Future;
+
+use awc::error::{ConnectError, SendRequestError};
+
 pub mod crates;
 pub mod gems;
+pub mod go;
 pub mod maven;
 pub mod npm;
+pub mod oci;
+pub mod pypi;
+
+/// Versions evaluated against the policy at once when filtering metadata
+pub(crate) const POLICY_CONCURRENCY: usize = 16;
+
+/// Send a request to an upstream, once more when the pooled connection it went out on had been
+/// closed by the upstream in the meantime.
+pub(crate) async fn send_retrying<F, R, T>(send: F) -> Result<T, SendRequestError>
+where
+    F: Fn() -> R,
+    R: Future<Output = Result<T, SendRequestError>>,
+{
+    match send().await {
+        Err(SendRequestError::Connect(ConnectError::Disconnected)) => send().await,
+        result => result,
+    }
+}
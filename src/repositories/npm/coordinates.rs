@@ -0,0 +1,66 @@
+/*
+ Package URLs of npm packages and parsing of their tarball names
+*/
+
+use urlencoding::encode;
+
+pub const TARBALL_EXTENSION: &str = "tgz";
+
+/// The package URL of a version, the scope of a scoped package being its namespace.
+pub fn purl(name: &str, version: &str) -> String {
+    let name = match name.split_once('/') {
+        Some((scope, name)) if scope.starts_with('@') => {
+            format!("{}/{}", encode(scope), encode(name))
+        }
+        _ => encode(name).into_owned(),
+    };
+    format!("pkg:npm/{name}@{}", encode(version))
+}
+
+/// The version of a tarball named `{name}-{version}.tgz` after the package, without its scope.
+pub fn tarball_version<'a>(name: &str, file: &'a str) -> Option<&'a str> {
+    let base = name.rsplit('/').next().unwrap_or(name);
+    let version = file
+        .strip_prefix(base)?
+        .strip_prefix('-')?
+        .strip_suffix(TARBALL_EXTENSION)?
+        .strip_suffix('.')?;
+    semver::Version::parse(version).ok().map(|_| version)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn npm_purls() {
+        assert_eq!("pkg:npm/lodash@4.17.21", purl("lodash", "4.17.21"));
+        assert_eq!(
+            "pkg:npm/%40babel/core@8.0.0-beta.1",
+            purl("@babel/core", "8.0.0-beta.1")
+        );
+        assert_eq!(
+            "pkg:npm/is-number@1.0.0%2Bbuild.5",
+            purl("is-number", "1.0.0+build.5")
+        );
+    }
+
+    #[test]
+    fn tarball_versions() {
+        assert_eq!(
+            Some("4.17.21"),
+            tarball_version("lodash", "lodash-4.17.21.tgz")
+        );
+        assert_eq!(
+            Some("1.0.0-beta.1"),
+            tarball_version("is-number", "is-number-1.0.0-beta.1.tgz")
+        );
+        assert_eq!(
+            Some("7.22.0-rc.0"),
+            tarball_version("@babel/core", "core-7.22.0-rc.0.tgz")
+        );
+        assert_eq!(None, tarball_version("lodash", "lodash.tgz"));
+        assert_eq!(None, tarball_version("lodash", "underscore-1.0.0.tgz"));
+        assert_eq!(None, tarball_version("lodash", "lodash-latest.tgz"));
+    }
+}
@@ -1,61 +1,348 @@
 use actix_web::{
-    get, route, web, Error, HttpRequest, HttpResponse, HttpResponseBuilder, Responder, Scope,
+    error::PayloadError,
+    get,
+    http::{
+        header::{
+            HeaderName, HeaderValue, ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, CONNECTION,
+            CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, ETAG, LAST_MODIFIED,
+            TRANSFER_ENCODING,
+        },
+        Method, StatusCode,
+    },
+    route, web, Error, HttpRequest, HttpResponse, HttpResponseBuilder, Responder, Scope,
 };
+use awc::{ClientRequest, ClientResponse};
+use bytes::Bytes;
+use futures::{future, stream, Stream, StreamExt};
+use serde_json::json;
+use std::borrow::Cow;
+use std::sync::Arc;
+use tokio::sync::OnceCell;
 use url::Url;
 
-use crate::policy::{context::Context, PolicyEngine};
+use crate::cache::ArtifactCache;
+use crate::config::repositories::RepositoryConfig;
+use crate::policy::{
+    context::{Context, Provenance},
+    PolicyEngine, Verdict,
+};
+use crate::repositories::{send_retrying, POLICY_CONCURRENCY};
+use crate::sigstore::trust::TrustedRoot;
+
+pub mod coordinates;
+pub mod operations;
+pub mod packument;
+pub mod signatures;
+
+use self::coordinates::{purl, tarball_version, TARBALL_EXTENSION};
+use self::operations::Operation;
+use self::packument::{Packument, Publication};
+use self::signatures::{Attestations, Dist, RegistryKeys, KEYS_PATH};
 
 pub struct NpmConfig {
+    scope: String,
     url: Url,
-}
-
-impl Default for NpmConfig {
-    fn default() -> Self {
-        Self::new("https://registry.npmjs.org".try_into().unwrap())
-    }
+    cache: ArtifactCache,
+    offline: bool,
+    filter_metadata: bool,
+    operations: Vec<String>,
+    verify_signatures: bool,
+    trust: Option<Arc<TrustedRoot>>,
+    /// Public keys of the registry, fetched on first use
+    keys: OnceCell<RegistryKeys>,
 }
 
 impl NpmConfig {
-    pub fn new(url: Url) -> Self {
-        Self { url }
+    pub fn new(
+        scope: &str,
+        config: &RepositoryConfig,
+        cache: ArtifactCache,
+        offline: bool,
+        trust: Option<Arc<TrustedRoot>>,
+    ) -> Self {
+        Self {
+            scope: String::from(scope),
+            url: config.url(),
+            cache,
+            offline,
+            filter_metadata: config.filter_metadata(),
+            operations: config.operations(),
+            verify_signatures: config.verify_signatures(),
+            trust,
+            keys: OnceCell::new(),
+        }
     }
 }
 
-pub fn service(scope: &str, url: Url) -> Scope {
+pub fn service(
+    scope: &str,
+    config: &RepositoryConfig,
+    cache: ArtifactCache,
+    offline: bool,
+    trust: Option<Arc<TrustedRoot>>,
+) -> Scope {
     web::scope(scope)
-        .app_data(web::Data::new(NpmConfig::new(url)))
+        .app_data(web::Data::new(NpmConfig::new(
+            scope, config, cache, offline, trust,
+        )))
         .service(proxy)
         .service(pass_through)
 }
 
-#[get("{pkg:.*}/-/{name:.*}-{version}.{ext}")]
+const METADATA_LIMIT: usize = 100_000_000;
+const PUBLISH_LIMIT: usize = 100_000_000;
+
+/// Headers of an upstream response which no longer hold once its body is rewritten: its framing
+/// and validators.
+const REWRITTEN_HEADERS: [HeaderName; 6] = [
+    CONTENT_LENGTH,
+    CONTENT_ENCODING,
+    TRANSFER_ENCODING,
+    CONNECTION,
+    ETAG,
+    LAST_MODIFIED,
+];
+
+async fn from_cache(cache: &ArtifactCache, key: &str) -> HttpResponse {
+    match cache.respond(key).await {
+        Some(response) => response,
+        None => {
+            log::info!("{key} is not available offline");
+            HttpResponse::NotFound().json(json!({ "error": "Not found" }))
+        }
+    }
+}
+
+/// Point the tarballs of a packument at the proxy, as seen by the client, so they are never
+/// fetched around the policy.
+fn rewrite_packument(
+    config: &NpmConfig,
+    req: &HttpRequest,
+    content_type: Option<&str>,
+    body: Bytes,
+) -> Bytes {
+    if !content_type.is_some_and(packument::is_json) {
+        return body;
+    }
+    let connection = req.connection_info();
+    let base = format!(
+        "{}://{}/{}/",
+        connection.scheme(),
+        connection.host(),
+        config.scope.trim_matches('/')
+    );
+    let rewritten = Packument::parse(&body).and_then(|packument| match packument {
+        Some(mut packument) => {
+            packument.rewrite_tarballs(&config.url, &base);
+            packument.to_vec().map(Some)
+        }
+        None => Ok(None),
+    });
+    match rewritten {
+        Ok(Some(rewritten)) => Bytes::from(rewritten),
+        Ok(None) => body,
+        Err(e) => {
+            log::warn!("Unable to rewrite the tarballs of {}: {e}", req.path());
+            body
+        }
+    }
+}
+
+/// Remove the versions denied by the policy from a packument, so clients never select them.
+async fn filter_packument(policy: &PolicyEngine, body: Bytes) -> serde_json::Result<Bytes> {
+    let mut packument = match Packument::parse(&body)? {
+        Some(packument) => packument,
+        None => return Ok(body),
+    };
+    let name = packument.name();
+    let checks = packument.tarballs().into_iter().map(|(version, tarball)| {
+        let context = Context::new(purl(name, &version), tarball, String::new());
+        async move {
+            match policy.check(&context, None).await {
+                Ok(Verdict::Denied(_)) => None,
+                Ok(_) => Some(version),
+                Err(e) => {
+                    // Fail closed, a version the policy could not evaluate is not advertised
+                    log::warn!("Unable to evaluate {}: {e}", context.purl());
+                    None
+                }
+            }
+        }
+    });
+    let allowed: Vec<String> = stream::iter(checks)
+        .buffered(POLICY_CONCURRENCY)
+        .filter_map(future::ready)
+        .collect()
+        .await;
+    packument.retain_versions(&allowed);
+    packument.to_vec().map(Bytes::from)
+}
+
+/// Fetch a JSON document of the registry.
+async fn fetch_json(client: &awc::Client, uri: &str, accept: &str) -> Option<Bytes> {
+    let upstream = send_retrying(|| client.get(uri).insert_header((ACCEPT, accept)).send()).await;
+    let mut upstream = upstream
+        .map_err(|e| log::warn!("Unable to fetch {uri}: {e}"))
+        .ok()?;
+    if !upstream.status().is_success() {
+        return None;
+    }
+    upstream.body().limit(METADATA_LIMIT).await.ok()
+}
+
+/// Fetch the abbreviated packument of a package.
+async fn fetch_packument(
+    config: &NpmConfig,
+    client: &awc::Client,
+    name: &str,
+    accept: &str,
+) -> Option<Packument> {
+    let uri = format!("{}{}", config.url, name.replace('/', "%2f"));
+    let body = fetch_json(client, &uri, accept).await?;
+    Packument::parse(&body).ok()?
+}
+
+/// Find the version of a tarball which is not named after its package in the package metadata.
+async fn packument_version(
+    config: &NpmConfig,
+    client: &awc::Client,
+    name: &str,
+    file: &str,
+) -> Option<String> {
+    fetch_packument(config, client, name, packument::ABBREVIATED_TYPE)
+        .await?
+        .version_of(file)
+}
+
+/// Verify the provenance attested for a tarball, which is None when the trusted root is not
+/// configured or the attestation does not verify.
+async fn verify_provenance(
+    config: &NpmConfig,
+    client: &awc::Client,
+    dist: &Dist,
+    tarball: &[u8],
+) -> Option<Provenance> {
+    let (trust, url) = (config.trust.as_ref()?, dist.attestations_url()?);
+    let body = fetch_json(client, url, "application/json").await?;
+    let attestations: Attestations = match serde_json::from_slice(&body) {
+        Ok(attestations) => attestations,
+        Err(e) => {
+            log::warn!("Invalid attestations at {url}: {e}");
+            return None;
+        }
+    };
+    match attestations.provenance()?.verify(trust) {
+        Ok(verified) if verified.covers("sha512", &signatures::sha512_hex(tarball)) => {
+            Some(signatures::provenance(verified))
+        }
+        Ok(_) => {
+            log::warn!("Provenance at {url} does not cover the tarball");
+            None
+        }
+        Err(e) => {
+            log::warn!("Unable to verify the provenance at {url}: {e}");
+            None
+        }
+    }
+}
+
+/// Verify a tarball against its integrity and the registry signatures, returning the ids of
+/// the keys which signed it and its verified provenance.
+async fn verify_tarball(
+    config: &NpmConfig,
+    client: &awc::Client,
+    name: &str,
+    version: &str,
+    tarball: &[u8],
+) -> Result<(Vec<String>, Option<Provenance>), String> {
+    // The full packument, for the time each version was published
+    let packument = fetch_packument(config, client, name, packument::FULL_TYPE).await;
+    let (dist, published) = packument
+        .as_ref()
+        .and_then(|packument| Some((packument.dist(version)?, packument.published(version))))
+        .ok_or_else(|| format!("Unable to fetch the metadata of {name}@{version}"))?;
+    let dist: Dist = serde_json::from_value(dist.clone())
+        .map_err(|e| format!("Invalid metadata of {name}@{version}: {e}"))?;
+    dist.check_integrity(tarball)?;
+    let keys = config
+        .keys
+        .get_or_try_init(|| async {
+            let uri = format!("{}{KEYS_PATH}", config.url);
+            let body = fetch_json(client, &uri, "application/json").await;
+            body.and_then(|body| serde_json::from_slice(&body).ok())
+                .ok_or_else(|| format!("Unable to fetch the registry keys from {uri}"))
+        })
+        .await?;
+    let signatures = dist.verify_signatures(keys, name, version, published)?;
+    let provenance = verify_provenance(config, client, &dist, tarball).await;
+    Ok((signatures, provenance))
+}
+
+#[get("{pkg:.+}/-/{file:[^/]+\\.tgz}")]
 async fn proxy(
     req: HttpRequest,
     config: web::Data<NpmConfig>,
     policy: web::Data<PolicyEngine>,
-    path: web::Path<(String, String, String, String)>,
+    path: web::Path<(String, String)>,
 ) -> impl Responder {
-    let (pkg, name, version, ext) = path.into_inner();
-    let uri = format!("{}{pkg}/-/{name}-{version}.{ext}", config.url);
+    let (pkg, file) = path.into_inner();
+    let cache_key = format!("{pkg}/-/{file}");
+    if config.offline {
+        return from_cache(&config.cache, &cache_key).await;
+    }
+    let name = urlencoding::decode(&pkg).map_or_else(|_| pkg.clone(), Cow::into_owned);
+    let version = match tarball_version(&name, &file) {
+        Some(version) => String::from(version),
+        None => match packument_version(&config, &policy.client, &name, &file).await {
+            Some(version) => version,
+            None => {
+                let error = format!("{file} is not a tarball of {name}");
+                return HttpResponse::NotFound().json(json!({ "error": error }));
+            }
+        },
+    };
+    let uri = format!("{}{pkg}/-/{file}", config.url);
     log::debug!("upstream -> {uri}");
     let request = policy.client.request_from(&uri, req.head());
     match request.send().await {
         Ok(mut upstream) => match upstream.body().limit(20_000_000).await {
             Ok(payload) => {
-                let context = Context::new(
-                    format!("pkg:npm/{}@{version}", pkg.replace('@', "%40")),
-                    uri,
-                    sha256::digest(payload.as_ref()),
-                );
-                match policy.evaluate(&context, Some(&ext)).await {
-                    Ok(None) => {
+                let mut context =
+                    Context::new(purl(&name, &version), uri, sha256::digest(payload.as_ref()));
+                if config.verify_signatures && upstream.status().is_success() {
+                    match verify_tarball(&config, &policy.client, &name, &version, &payload).await {
+                        Ok((signatures, provenance)) => {
+                            context = context
+                                .with_signatures(signatures)
+                                .with_provenance(provenance);
+                        }
+                        Err(error) => {
+                            log::warn!("Refused {}: {error}", context.purl());
+                            return HttpResponse::Forbidden().json(json!({ "error": error }));
+                        }
+                    }
+                }
+                match policy.check(&context, Some(TARBALL_EXTENSION)).await {
+                    Ok(Verdict::Denied(response)) => response,
+                    Ok(verdict) => {
+                        if upstream.status().is_success() {
+                            config
+                                .cache
+                                .store(
+                                    &cache_key,
+                                    Some("application/octet-stream"),
+                                    Some(&context),
+                                    policy.record(&verdict),
+                                    payload.clone(),
+                                )
+                                .await;
+                        }
                         let mut response = HttpResponseBuilder::new(upstream.status());
                         for header in upstream.headers().iter() {
                             response.insert_header(header);
                         }
                         response.body(payload)
                     }
-                    Ok(Some(response)) => response,
                     Err(e) => e.into(),
                 }
             }
@@ -69,7 +356,76 @@ async fn proxy(
     }
 }
 
-#[route("{any:.*}", method = "GET", method = "HEAD", method = "POST")]
+/// Relay a registry response to the client.
+fn forward_response<S>(upstream: ClientResponse<S>) -> HttpResponse
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin + 'static,
+{
+    let mut response = HttpResponseBuilder::new(upstream.status());
+    for header in upstream.headers().iter() {
+        response.insert_header(header);
+    }
+    response.streaming(upstream)
+}
+
+/// Evaluate the tarballs of a publication against the policy before sending it to the registry.
+async fn publish(
+    config: &NpmConfig,
+    policy: &PolicyEngine,
+    request: ClientRequest,
+    payload: web::Payload,
+) -> HttpResponse {
+    let body = match payload.to_bytes_limited(PUBLISH_LIMIT).await {
+        Ok(Ok(body)) => body,
+        Ok(Err(e)) => return e.into(),
+        Err(e) => return HttpResponse::PayloadTooLarge().json(json!({ "error": e.to_string() })),
+    };
+    let publication = match Publication::parse(&body) {
+        Ok(publication) => publication,
+        Err(e) => {
+            let error = format!("Invalid publication: {e}");
+            return HttpResponse::BadRequest().json(json!({ "error": error }));
+        }
+    };
+    let tarballs = match publication.tarballs() {
+        Ok(tarballs) => tarballs,
+        Err(error) => return HttpResponse::BadRequest().json(json!({ "error": error })),
+    };
+
+    let name = publication.name();
+    let file = name.rsplit('/').next().unwrap_or(name);
+    for (version, tarball) in tarballs {
+        let context = Context::new(
+            purl(name, version),
+            format!(
+                "{}{name}/-/{file}-{version}.{TARBALL_EXTENSION}",
+                config.url
+            ),
+            sha256::digest(tarball.as_slice()),
+        );
+        match policy.check(&context, None).await {
+            Ok(Verdict::Denied(response)) => return response,
+            Ok(_) => log::info!("Publishing {}", context.purl()),
+            Err(e) => return e.into(),
+        }
+    }
+    match request.send_body(body).await {
+        Ok(upstream) => forward_response(upstream),
+        Err(e) => {
+            log::error!("proxy error: {}", e);
+            HttpResponse::BadGateway().json(json!({ "error": e.to_string() }))
+        }
+    }
+}
+
+#[route(
+    "{any:.*}",
+    method = "GET",
+    method = "HEAD",
+    method = "POST",
+    method = "PUT",
+    method = "DELETE"
+)]
 async fn pass_through(
     req: HttpRequest,
     payload: web::Payload,
@@ -78,20 +434,205 @@ async fn pass_through(
     path: web::Path<String>,
 ) -> impl Responder {
     let path = path.into_inner();
+    let cache_key = match req.query_string() {
+        "" => path.clone(),
+        query => format!("{path}?{query}"),
+    };
+    let operation = Operation::classify(req.method(), &cache_key);
+    let allowed = operation == Operation::Install
+        || config
+            .operations
+            .iter()
+            .any(|name| name == operation.name());
+    if !allowed {
+        log::info!("Refused npm {} of {path}", operation.name());
+        let error = format!("npm {} is disabled on this proxy", operation.name());
+        return HttpResponse::Forbidden().json(json!({ "error": error }));
+    }
+    let abbreviated = packument::accepts_abbreviated(
+        req.headers()
+            .get(ACCEPT)
+            .and_then(|value| value.to_str().ok()),
+    );
+    let variant_key = packument::variant_key(&cache_key, abbreviated);
+    if config.offline {
+        // A full packument is also acceptable to clients asking for the abbreviated one
+        let cached = match config.cache.load(&variant_key).await {
+            Some(cached) => Some(cached),
+            None if abbreviated => config.cache.load(&cache_key).await,
+            None => None,
+        };
+        return match cached {
+            Some((entry, body)) => {
+                let mut response = HttpResponse::Ok();
+                if let Some(content_type) = entry.content_type() {
+                    response.insert_header((CONTENT_TYPE, content_type));
+                }
+                response.body(rewrite_packument(&config, &req, entry.content_type(), body))
+            }
+            None => from_cache(&config.cache, &cache_key).await,
+        };
+    }
     let uri = format!("{}{path}", config.url,);
     log::debug!("pass: {uri}");
-    let request = policy.client.request_from(uri, req.head());
-    match request.send_stream(payload).await {
-        Ok(upstream) => {
-            let mut response = HttpResponseBuilder::new(upstream.status());
-            for header in upstream.headers().iter() {
-                response.insert_header(header);
+    let mut request = policy.client.request_from(&uri, req.head());
+    if !operation.forwards_credentials() {
+        request.headers_mut().remove(AUTHORIZATION);
+        request.headers_mut().remove(COOKIE);
+    }
+    if req.method() == Method::GET {
+        // Package metadata is requested uncompressed so it can be cached for offline use
+        request
+            .headers_mut()
+            .insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+        return match request.send().await {
+            Ok(mut upstream) => match upstream.body().limit(METADATA_LIMIT).await {
+                Ok(body) => {
+                    let content_type = upstream
+                        .headers()
+                        .get(CONTENT_TYPE)
+                        .and_then(|value| value.to_str().ok());
+                    let filter =
+                        config.filter_metadata && content_type.is_some_and(packument::is_json);
+                    let body = if upstream.status() == StatusCode::OK && filter {
+                        // Filtered before caching, so offline clients see the same versions
+                        match filter_packument(&policy, body).await {
+                            Ok(body) => body,
+                            Err(e) => {
+                                let msg = format!("Invalid package metadata at {uri} -> {e}");
+                                log::error!("{msg}");
+                                return HttpResponse::BadGateway().body(msg);
+                            }
+                        }
+                    } else {
+                        body
+                    };
+                    if upstream.status() == StatusCode::OK {
+                        config
+                            .cache
+                            .store(&variant_key, content_type, None, None, body.clone())
+                            .await;
+                    }
+                    let body = rewrite_packument(&config, &req, content_type, body);
+                    let mut response = HttpResponseBuilder::new(upstream.status());
+                    for header in upstream.headers().iter() {
+                        if !REWRITTEN_HEADERS.contains(header.0) {
+                            response.insert_header(header);
+                        }
+                    }
+                    response.body(body)
+                }
+                Err(e) => Error::from(e).into(),
+            },
+            Err(e) => {
+                log::error!("proxy error: {}", e);
+                HttpResponse::NotFound().body("not found")
             }
-            response.streaming(upstream)
-        }
+        };
+    }
+    if operation == Operation::Publish {
+        return publish(&config, &policy, request, payload).await;
+    }
+    match request.send_stream(payload).await {
+        Ok(upstream) => forward_response(upstream),
         Err(e) => {
             log::error!("proxy error: {}", e);
             HttpResponse::NotFound().body("not found")
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::policy::PolicyConfig;
+    use actix_web::{rt, App, HttpServer};
+
+    #[actix_web::test]
+    async fn packument_variants() {
+        // A registry answering with the packument variant asked for
+        let server = HttpServer::new(|| {
+            App::new().route(
+                "/demo",
+                web::get().to(|req: HttpRequest| async move {
+                    let accept = req.headers().get(ACCEPT).and_then(|v| v.to_str().ok());
+                    let (content_type, description) = match accept {
+                        Some(packument::ABBREVIATED_TYPE) => {
+                            (packument::ABBREVIATED_TYPE, "abbreviated")
+                        }
+                        _ => (packument::FULL_TYPE, "full"),
+                    };
+                    let host = req.connection_info().host().to_string();
+                    let tarball = format!("http://{host}/demo/-/demo-1.0.0.tgz");
+                    HttpResponse::Ok()
+                        .insert_header((CONTENT_TYPE, content_type))
+                        .insert_header((ETAG, format!("\"{description}\"")))
+                        .insert_header((LAST_MODIFIED, "Mon, 01 Jan 2024 00:00:00 GMT"))
+                        .json(json!({
+                            "name": "demo",
+                            "description": description,
+                            "versions": { "1.0.0": { "dist": { "tarball": tarball } } },
+                        }))
+                }),
+            )
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}/", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        rt::spawn(server);
+
+        let config: RepositoryConfig =
+            toml::from_str(&format!("type = \"npm\"\nurl = \"{url}\"")).unwrap();
+        let policy: PolicyConfig = toml::from_str("url = \"http://127.0.0.1:1\"").unwrap();
+        let policy = web::Data::new(PolicyEngine::new(policy));
+        let root = std::env::temp_dir().join(format!("seedwing-npm-{}", std::process::id()));
+        let cache = ArtifactCache::new(root.clone());
+        let get = |offline: bool, accept: Option<&'static str>| {
+            let app = App::new().app_data(policy.clone()).service(service(
+                "/npm",
+                &config,
+                cache.clone(),
+                offline,
+                None,
+            ));
+            let mut request = actix_web::test::TestRequest::get().uri("/npm/demo");
+            if let Some(accept) = accept {
+                request = request.insert_header((ACCEPT, accept));
+            }
+            async move {
+                let app = actix_web::test::init_service(app).await;
+                let response = actix_web::test::call_service(&app, request.to_request()).await;
+                assert_eq!(StatusCode::OK, response.status());
+                let headers = response.headers().clone();
+                let body: serde_json::Value = actix_web::test::read_body_json(response).await;
+                (headers, body)
+            }
+        };
+
+        // Each variant is relayed without the validators of the document before its rewrite
+        let (headers, full) = get(false, None).await;
+        assert_eq!("full", full["description"]);
+        assert!(!headers.contains_key(ETAG));
+        assert!(!headers.contains_key(LAST_MODIFIED));
+        let tarball = full["versions"]["1.0.0"]["dist"]["tarball"]
+            .as_str()
+            .unwrap();
+        assert!(tarball.ends_with("/npm/demo/-/demo-1.0.0.tgz"), "{tarball}");
+        let (headers, abbreviated) = get(false, Some(packument::ABBREVIATED_TYPE)).await;
+        assert_eq!("abbreviated", abbreviated["description"]);
+        assert!(!headers.contains_key(ETAG));
+
+        // And cached apart from the other
+        handle.stop(true).await;
+        let (_, body) = get(true, None).await;
+        assert_eq!("full", body["description"]);
+        let (_, body) = get(true, Some(packument::ABBREVIATED_TYPE)).await;
+        assert_eq!("abbreviated", body["description"]);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
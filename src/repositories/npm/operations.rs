@@ -0,0 +1,137 @@
+/*
+ Classification of the requests made by the npm CLI to a registry
+*/
+
+use actix_web::http::Method;
+
+/// An operation of the npm registry API, as far as the proxy tells them apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Fetching package metadata and tarballs, always allowed
+    Install,
+    Publish,
+    /// Removing, deprecating or otherwise changing published versions
+    Unpublish,
+    DistTag,
+    /// Logging in and managing users and tokens
+    Login,
+    Audit,
+    Search,
+    /// Any other change sent to the registry
+    Other,
+}
+
+impl Operation {
+    /// Classify a request by its method and path relative to the registry.
+    pub fn classify(method: &Method, path: &str) -> Self {
+        let path = path.trim_start_matches('/');
+        let read = method == Method::GET || method == Method::HEAD;
+        if path.starts_with("-/npm/v1/security/") {
+            Self::Audit
+        } else if path.starts_with("-/v1/search") {
+            Self::Search
+        } else if [
+            "-/user/",
+            "-/v1/login",
+            "-/whoami",
+            "-/npm/v1/tokens",
+            "-/npm/v1/user",
+        ]
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+        {
+            Self::Login
+        } else if path.starts_with("-/package/") && path.contains("/dist-tags") && !read {
+            Self::DistTag
+        } else if read {
+            Self::Install
+        } else if path.starts_with("-/") {
+            Self::Other
+        } else if path.contains("/-rev/") || method == Method::DELETE {
+            Self::Unpublish
+        } else if method == Method::PUT {
+            Self::Publish
+        } else {
+            Self::Other
+        }
+    }
+
+    /// Name of the operation in the `operations` of a repository.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Install => "install",
+            Self::Publish => "publish",
+            Self::Unpublish => "unpublish",
+            Self::DistTag => "dist-tag",
+            Self::Login => "login",
+            Self::Audit => "audit",
+            Self::Search => "search",
+            Self::Other => "other",
+        }
+    }
+
+    /// Whether the credentials of the client are forwarded to the registry. Audits and searches
+    /// never need them.
+    pub fn forwards_credentials(&self) -> bool {
+        !matches!(self, Self::Audit | Self::Search)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classify_operations() {
+        let cases = [
+            (Method::GET, "lodash", Operation::Install),
+            (Method::GET, "@babel%2fcore", Operation::Install),
+            (
+                Method::GET,
+                "lodash/-/lodash-4.17.21.tgz",
+                Operation::Install,
+            ),
+            (Method::PUT, "@babel%2fcore", Operation::Publish),
+            (Method::PUT, "lodash/-rev/3-abc", Operation::Unpublish),
+            (
+                Method::DELETE,
+                "lodash/-/lodash-4.17.21.tgz/-rev/4-def",
+                Operation::Unpublish,
+            ),
+            (
+                Method::PUT,
+                "-/package/lodash/dist-tags/next",
+                Operation::DistTag,
+            ),
+            (
+                Method::GET,
+                "-/package/lodash/dist-tags",
+                Operation::Install,
+            ),
+            (Method::PUT, "-/user/org.couchdb.user:ci", Operation::Login),
+            (Method::POST, "-/v1/login", Operation::Login),
+            (Method::GET, "-/whoami", Operation::Login),
+            (
+                Method::POST,
+                "-/npm/v1/security/advisories/bulk",
+                Operation::Audit,
+            ),
+            (
+                Method::POST,
+                "-/npm/v1/security/audits/quick",
+                Operation::Audit,
+            ),
+            (Method::GET, "-/v1/search?text=react", Operation::Search),
+            (Method::POST, "-/org/example/user", Operation::Other),
+        ];
+        for (method, path, operation) in cases {
+            assert_eq!(
+                operation,
+                Operation::classify(&method, path),
+                "{method} {path}"
+            );
+        }
+        assert!(!Operation::Audit.forwards_credentials());
+        assert!(Operation::Publish.forwards_credentials());
+    }
+}
@@ -0,0 +1,358 @@
+/*
+ Rewriting of npm package metadata (packuments), full or abbreviated, and reading of the
+ documents sent when publishing
+*/
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use semver::Version;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use url::Url;
+
+/// Media type of the abbreviated packuments requested by npm install
+pub const ABBREVIATED_TYPE: &str = "application/vnd.npm.install-v1+json";
+/// Media type of the full packuments, which list when each version was published
+pub const FULL_TYPE: &str = "application/json";
+
+/// Whether a response of this content type is a packument, or another JSON document of the
+/// registry.
+pub fn is_json(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    essence == "application/json" || essence == ABBREVIATED_TYPE
+}
+
+/// Whether a request accepts abbreviated packuments, as npm install does.
+pub fn accepts_abbreviated(accept: Option<&str>) -> bool {
+    accept.is_some_and(|accept| {
+        accept.split(',').any(|range| {
+            let mut params = range.split(';').map(str::trim);
+            params.next() == Some(ABBREVIATED_TYPE) && params.all(|param| param != "q=0")
+        })
+    })
+}
+
+/// The key a packument is cached under. Abbreviated packuments are another document served at
+/// the same URL.
+pub fn variant_key(key: &str, abbreviated: bool) -> String {
+    match abbreviated {
+        true => format!("{key}#{ABBREVIATED_TYPE}"),
+        false => String::from(key),
+    }
+}
+
+/// Location of a tarball relative to the registry. Tarballs hosted elsewhere are looked up at
+/// the same path of the registry.
+fn tarball_path(tarball: &str, upstream: &Url) -> Option<String> {
+    let url = Url::parse(tarball).ok()?;
+    let path = url.path();
+    let path = match path.strip_prefix(upstream.path()) {
+        Some(path) if url.host() == upstream.host() => path,
+        _ => path.trim_start_matches('/'),
+    };
+    Some(String::from(path))
+}
+
+/// The newest of the versions, releases preferred over pre-releases.
+fn newest<'a>(versions: impl Iterator<Item = &'a String>) -> Option<&'a String> {
+    versions
+        .filter_map(|version| Some((Version::parse(version).ok()?, version)))
+        .max_by(|(a, _), (b, _)| {
+            a.pre
+                .is_empty()
+                .cmp(&b.pre.is_empty())
+                .then_with(|| a.cmp(b))
+        })
+        .map(|(_, version)| version)
+}
+
+/// A packument, the metadata of a package listing all its versions.
+pub struct Packument(Value);
+
+impl Packument {
+    /// Parse a registry document, returning None when it is not a packument.
+    pub fn parse(document: &[u8]) -> serde_json::Result<Option<Self>> {
+        let document: Value = serde_json::from_slice(document)?;
+        Ok(document
+            .get("versions")
+            .is_some_and(Value::is_object)
+            .then_some(Self(document)))
+    }
+
+    pub fn name(&self) -> &str {
+        self.0
+            .get("name")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+    }
+
+    fn versions_mut(&mut self) -> &mut Map<String, Value> {
+        self.0
+            .get_mut("versions")
+            .and_then(Value::as_object_mut)
+            .expect("a packument lists its versions")
+    }
+
+    /// The versions with the URL of their tarball.
+    pub fn tarballs(&self) -> Vec<(String, String)> {
+        let versions = self.0.get("versions").and_then(Value::as_object);
+        versions
+            .into_iter()
+            .flatten()
+            .map(|(version, manifest)| {
+                let tarball = manifest
+                    .pointer("/dist/tarball")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                (version.clone(), String::from(tarball))
+            })
+            .collect()
+    }
+
+    /// The version whose tarball is named `file`.
+    pub fn version_of(&self, file: &str) -> Option<String> {
+        let suffix = format!("/{file}");
+        self.tarballs()
+            .into_iter()
+            .find(|(_, tarball)| tarball.ends_with(&suffix))
+            .map(|(version, _)| version)
+    }
+
+    /// When a version was published, as listed in the `time` of a full packument.
+    pub fn published(&self, version: &str) -> Option<&str> {
+        self.0.get("time")?.get(version)?.as_str()
+    }
+
+    /// The `dist` of a version, describing its tarball.
+    pub fn dist(&self, version: &str) -> Option<&Value> {
+        self.0.get("versions")?.get(version)?.get("dist")
+    }
+
+    /// Point the `dist.tarball` of every version at `base`, the URL of the repository in the
+    /// proxy.
+    pub fn rewrite_tarballs(&mut self, upstream: &Url, base: &str) {
+        for manifest in self.versions_mut().values_mut() {
+            if let Some(tarball) = manifest.pointer_mut("/dist/tarball") {
+                let path = tarball
+                    .as_str()
+                    .and_then(|tarball| tarball_path(tarball, upstream));
+                if let Some(path) = path {
+                    *tarball = Value::String(format!("{base}{path}"));
+                }
+            }
+        }
+    }
+
+    /// Remove every version but the allowed ones. A removed `latest` is moved to the newest version
+    /// left, other dist-tags pointing at a removed version are dropped.
+    pub fn retain_versions(&mut self, allowed: &[String]) {
+        self.versions_mut()
+            .retain(|version, _| allowed.contains(version));
+        if let Some(time) = self.0.get_mut("time").and_then(Value::as_object_mut) {
+            time.retain(|key, _| key == "created" || key == "modified" || allowed.contains(key));
+        }
+        if let Some(tags) = self.0.get_mut("dist-tags").and_then(Value::as_object_mut) {
+            tags.retain(|_, version| {
+                version
+                    .as_str()
+                    .is_some_and(|version| allowed.iter().any(|allowed| allowed == version))
+            });
+            if !tags.contains_key("latest") {
+                if let Some(latest) = newest(allowed.iter()) {
+                    tags.insert(String::from("latest"), Value::String(latest.clone()));
+                }
+            }
+        }
+    }
+
+    pub fn to_vec(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(&self.0)
+    }
+}
+
+/// The document sent by `npm publish`, holding the published tarballs as attachments.
+#[derive(Deserialize)]
+pub struct Publication {
+    name: String,
+    versions: HashMap<String, Value>,
+    #[serde(rename = "_attachments", default)]
+    attachments: HashMap<String, Attachment>,
+}
+
+#[derive(Deserialize)]
+struct Attachment {
+    data: String,
+}
+
+impl Publication {
+    pub fn parse(document: &[u8]) -> serde_json::Result<Self> {
+        serde_json::from_slice(document)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The published versions with their tarball, named `{name}-{version}.tgz`.
+    pub fn tarballs(&self) -> Result<Vec<(&str, Vec<u8>)>, String> {
+        self.attachments
+            .iter()
+            .map(|(file, attachment)| {
+                let version = self
+                    .versions
+                    .keys()
+                    .find(|version| file.ends_with(&format!("-{version}.tgz")))
+                    .ok_or_else(|| format!("{file} does not match any published version"))?;
+                let tarball = STANDARD
+                    .decode(&attachment.data)
+                    .map_err(|e| format!("Invalid attachment {file}: {e}"))?;
+                Ok((version.as_str(), tarball))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn packument() -> Packument {
+        let packument = json!({
+            "name": "@babel/core",
+            "dist-tags": { "latest": "7.22.0", "next": "8.0.0-beta.1", "old": "7.21.0" },
+            "time": {
+                "created": "2014-01-01T00:00:00.000Z",
+                "7.21.0": "2023-02-20T00:00:00.000Z",
+                "7.22.0": "2023-05-26T00:00:00.000Z"
+            },
+            "versions": {
+                "7.21.0": {
+                    "dist": { "tarball": "https://cdn.example.com/@babel/core/-/core-7.21.0.tgz" }
+                },
+                "7.22.0": {
+                    "name": "@babel/core",
+                    "version": "7.22.0",
+                    "dist": {
+                        "shasum": "abc",
+                        "tarball": "https://registry.npmjs.org/@babel/core/-/core-7.22.0.tgz"
+                    }
+                },
+                "8.0.0-beta.1": {
+                    "dist": { "tarball": "https://registry.npmjs.org/@babel/core/-/core-8.0.0-beta.1.tgz" }
+                }
+            }
+        });
+        Packument::parse(&serde_json::to_vec(&packument).unwrap())
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn rewrite_packument_tarballs() {
+        let mut packument = packument();
+        assert_eq!("@babel/core", packument.name());
+        assert_eq!(
+            Some(String::from("8.0.0-beta.1")),
+            packument.version_of("core-8.0.0-beta.1.tgz")
+        );
+        assert_eq!(None, packument.version_of("core-9.0.0.tgz"));
+        packument.rewrite_tarballs(
+            &Url::parse("https://registry.npmjs.org/").unwrap(),
+            "http://localhost:8181/npm/",
+        );
+        let rewritten = packument.0;
+        assert_eq!(
+            "http://localhost:8181/npm/@babel/core/-/core-7.22.0.tgz",
+            rewritten["versions"]["7.22.0"]["dist"]["tarball"]
+        );
+        assert_eq!("abc", rewritten["versions"]["7.22.0"]["dist"]["shasum"]);
+        assert_eq!(
+            "http://localhost:8181/npm/@babel/core/-/core-7.21.0.tgz",
+            rewritten["versions"]["7.21.0"]["dist"]["tarball"]
+        );
+
+        let search = br#"{"objects": [], "total": 0}"#;
+        assert!(Packument::parse(search).unwrap().is_none());
+        assert!(is_json("application/json; charset=utf-8"));
+        assert!(is_json(ABBREVIATED_TYPE));
+        assert!(!is_json("application/octet-stream"));
+    }
+
+    #[test]
+    fn packument_variants() {
+        let npm = "application/vnd.npm.install-v1+json; q=1.0, application/json; q=0.8, */*";
+        assert!(accepts_abbreviated(Some(npm)));
+        assert!(!accepts_abbreviated(Some("application/json")));
+        assert!(!accepts_abbreviated(Some(
+            "application/vnd.npm.install-v1+json;q=0, application/json"
+        )));
+        assert!(!accepts_abbreviated(None));
+        assert_eq!("demo", variant_key("demo", false));
+        assert_ne!("demo", variant_key("demo", true));
+    }
+
+    #[test]
+    fn retain_packument_versions() {
+        let mut packument = packument();
+        packument.retain_versions(&["7.21.0".into(), "8.0.0-beta.1".into()]);
+        let filtered = packument.0;
+        assert_eq!(
+            json!({ "latest": "7.21.0", "next": "8.0.0-beta.1", "old": "7.21.0" }),
+            filtered["dist-tags"]
+        );
+        assert!(filtered["versions"].get("7.22.0").is_none());
+        assert!(filtered["time"].get("7.22.0").is_none());
+        assert!(filtered["time"].get("created").is_some());
+
+        let mut packument = self::packument();
+        packument.retain_versions(&["7.21.0".into(), "7.22.0".into()]);
+        assert_eq!(
+            json!({ "latest": "7.22.0", "old": "7.21.0" }),
+            packument.0["dist-tags"]
+        );
+
+        let mut packument = self::packument();
+        packument.retain_versions(&["8.0.0-beta.1".into()]);
+        assert_eq!(
+            json!({ "latest": "8.0.0-beta.1", "next": "8.0.0-beta.1" }),
+            packument.0["dist-tags"]
+        );
+
+        let mut packument = self::packument();
+        packument.retain_versions(&[]);
+        assert_eq!(json!({}), packument.0["dist-tags"]);
+        assert_eq!(json!({}), packument.0["versions"]);
+    }
+
+    #[test]
+    fn publication_tarballs() {
+        let document = json!({
+            "_id": "@scope/pkg",
+            "name": "@scope/pkg",
+            "dist-tags": { "latest": "1.0.0" },
+            "versions": { "1.0.0": { "name": "@scope/pkg", "version": "1.0.0" } },
+            "_attachments": {
+                "@scope/pkg-1.0.0.tgz": {
+                    "content_type": "application/octet-stream",
+                    "data": "dGFyYmFsbA==",
+                    "length": 7
+                }
+            }
+        });
+        let publication = Publication::parse(&serde_json::to_vec(&document).unwrap()).unwrap();
+        assert_eq!("@scope/pkg", publication.name());
+        assert_eq!(
+            vec![("1.0.0", b"tarball".to_vec())],
+            publication.tarballs().unwrap()
+        );
+
+        let document = json!({
+            "name": "pkg",
+            "versions": { "1.0.0": {} },
+            "_attachments": { "pkg-2.0.0.tgz": { "data": "" } }
+        });
+        let publication = Publication::parse(&serde_json::to_vec(&document).unwrap()).unwrap();
+        assert!(publication.tarballs().is_err());
+    }
+}
@@ -0,0 +1,281 @@
+/*
+ Verification of the signatures made by npm registries over the integrity of the tarballs they
+ serve, and of the provenance attested when publishing
+*/
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, FixedOffset};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha512};
+use sigstore::crypto::{CosignVerificationKey, Signature, SigningScheme};
+
+use crate::policy::context::Provenance;
+use crate::sigstore::bundle::{Bundle, Verified};
+
+/// Path of the public keys of a registry
+pub const KEYS_PATH: &str = "-/npm/v1/keys";
+const PROVENANCE_PREFIX: &str = "https://slsa.dev/provenance/";
+
+/// The public keys a registry signs with.
+#[derive(Deserialize)]
+pub struct RegistryKeys {
+    keys: Vec<RegistryKey>,
+}
+
+#[derive(Deserialize)]
+struct RegistryKey {
+    keyid: String,
+    /// base64 of the DER encoded SubjectPublicKeyInfo
+    key: String,
+    /// When the key stopped being used to sign, as RFC 3339
+    expires: Option<String>,
+}
+
+impl RegistryKey {
+    /// Whether the key was still in use when a version was published.
+    fn valid_at(&self, published: Option<&str>) -> bool {
+        let Some(expires) = &self.expires else {
+            return true;
+        };
+        let parse = |time: &str| DateTime::<FixedOffset>::parse_from_rfc3339(time).ok();
+        match (published.and_then(parse), parse(expires)) {
+            (Some(published), Some(expires)) => published < expires,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DistSignature {
+    keyid: String,
+    sig: String,
+}
+
+#[derive(Deserialize)]
+struct DistAttestations {
+    url: String,
+}
+
+/// The `dist` of a version in a packument.
+#[derive(Deserialize)]
+pub struct Dist {
+    integrity: Option<String>,
+    #[serde(default)]
+    signatures: Vec<DistSignature>,
+    attestations: Option<DistAttestations>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Attestation {
+    predicate_type: String,
+    bundle: Bundle,
+}
+
+/// The attestations of a version, found at the URL given in its `dist`.
+#[derive(Deserialize)]
+pub struct Attestations {
+    attestations: Vec<Attestation>,
+}
+
+impl Attestations {
+    /// The bundle attesting the SLSA provenance.
+    pub fn provenance(&self) -> Option<&Bundle> {
+        self.attestations
+            .iter()
+            .find(|attestation| attestation.predicate_type.starts_with(PROVENANCE_PREFIX))
+            .map(|attestation| &attestation.bundle)
+    }
+}
+
+/// The sha512 of a tarball, hex encoded as in the subjects of attestations.
+pub fn sha512_hex(tarball: &[u8]) -> String {
+    format!("{:x}", Sha512::digest(tarball))
+}
+
+impl Dist {
+    pub fn attestations_url(&self) -> Option<&str> {
+        self.attestations
+            .as_ref()
+            .map(|attestations| attestations.url.as_str())
+    }
+
+    /// Check the tarball against its sha512 integrity, when the registry has one.
+    pub fn check_integrity(&self, tarball: &[u8]) -> Result<(), String> {
+        let expected = self
+            .integrity
+            .iter()
+            .flat_map(|integrity| integrity.split_whitespace())
+            .find_map(|integrity| integrity.strip_prefix("sha512-"));
+        match expected {
+            Some(expected) if expected != STANDARD.encode(Sha512::digest(tarball)) => {
+                Err(String::from("Tarball does not match its integrity"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Verify the registry signatures of `{name}@{version}:{integrity}`, published at the given
+    /// time, returning the ids of the keys which signed it. Unsigned versions, and signatures by
+    /// unknown keys or keys which had expired when the version was published, are refused.
+    pub fn verify_signatures(
+        &self,
+        keys: &RegistryKeys,
+        name: &str,
+        version: &str,
+        published: Option<&str>,
+    ) -> Result<Vec<String>, String> {
+        if self.signatures.is_empty() {
+            return Err(format!("{name}@{version} is not signed"));
+        }
+        let integrity = self
+            .integrity
+            .as_deref()
+            .ok_or_else(|| String::from("Signed version has no integrity"))?;
+        let message = format!("{name}@{version}:{integrity}");
+        self.signatures
+            .iter()
+            .map(|signature| {
+                let key = keys
+                    .keys
+                    .iter()
+                    .find(|key| key.keyid == signature.keyid)
+                    .ok_or_else(|| format!("Signed by unknown key {}", signature.keyid))?;
+                if !key.valid_at(published) {
+                    return Err(format!("Signed by expired key {}", signature.keyid));
+                }
+                let verified = STANDARD.decode(&key.key).ok().and_then(|der| {
+                    let key = CosignVerificationKey::from_der(
+                        &der,
+                        &SigningScheme::ECDSA_P256_SHA256_ASN1,
+                    )
+                    .ok()?;
+                    let sig = STANDARD.decode(&signature.sig).ok()?;
+                    key.verify_signature(Signature::Raw(&sig), message.as_bytes())
+                        .ok()
+                });
+                match verified {
+                    Some(()) => Ok(signature.keyid.clone()),
+                    None => Err(format!("Invalid signature by key {}", signature.keyid)),
+                }
+            })
+            .collect()
+    }
+}
+
+/// The provenance of a verified SLSA statement.
+pub fn provenance(verified: Verified) -> Provenance {
+    let builder = verified
+        .statement
+        .pointer("/predicate/runDetails/builder/id")
+        .or_else(|| verified.statement.pointer("/predicate/builder/id"))
+        .and_then(Value::as_str)
+        .map(String::from);
+    Provenance {
+        builder,
+        identity: verified.identity,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sigstore::bundle::Identity;
+    use serde_json::json;
+
+    #[test]
+    fn verify_registry_signatures() {
+        let signer = SigningScheme::ECDSA_P256_SHA256_ASN1
+            .create_signer()
+            .unwrap();
+        let der = signer
+            .to_sigstore_keypair()
+            .unwrap()
+            .public_key_to_der()
+            .unwrap();
+        let registry_keys = |expires: Value| -> RegistryKeys {
+            serde_json::from_value(json!({
+                "keys": [{
+                    "expires": expires,
+                    "keyid": "SHA256:test",
+                    "keytype": "ecdsa-sha2-nistp256",
+                    "scheme": "ecdsa-sha2-nistp256",
+                    "key": STANDARD.encode(&der)
+                }]
+            }))
+            .unwrap()
+        };
+        let keys = registry_keys(Value::Null);
+
+        let integrity = format!("sha512-{}", STANDARD.encode(Sha512::digest(b"tarball")));
+        let sig = signer
+            .sign(format!("@scope/pkg@1.0.0:{integrity}").as_bytes())
+            .unwrap();
+        let dist: Dist = serde_json::from_value(json!({
+            "integrity": integrity,
+            "signatures": [{ "keyid": "SHA256:test", "sig": STANDARD.encode(sig) }]
+        }))
+        .unwrap();
+        assert!(dist.check_integrity(b"tarball").is_ok());
+        assert!(dist.check_integrity(b"tampered").is_err());
+        assert_eq!(
+            vec![String::from("SHA256:test")],
+            dist.verify_signatures(&keys, "@scope/pkg", "1.0.0", None)
+                .unwrap()
+        );
+        assert!(dist
+            .verify_signatures(&keys, "@scope/pkg", "1.0.1", None)
+            .is_err());
+
+        // Keys only vouch for the versions published before they expired
+        let expired = registry_keys(json!("2025-01-29T00:00:00.000Z"));
+        assert!(dist
+            .verify_signatures(
+                &expired,
+                "@scope/pkg",
+                "1.0.0",
+                Some("2024-06-01T12:00:00.000Z")
+            )
+            .is_ok());
+        assert!(dist
+            .verify_signatures(
+                &expired,
+                "@scope/pkg",
+                "1.0.0",
+                Some("2025-02-01T12:00:00.000Z")
+            )
+            .is_err());
+        assert!(dist
+            .verify_signatures(&expired, "@scope/pkg", "1.0.0", None)
+            .is_err());
+
+        let dist: Dist = serde_json::from_value(json!({
+            "integrity": "sha512-abc",
+            "signatures": [{ "keyid": "SHA256:other", "sig": "" }]
+        }))
+        .unwrap();
+        assert!(dist.verify_signatures(&keys, "pkg", "1.0.0", None).is_err());
+
+        let dist: Dist = serde_json::from_value(json!({ "shasum": "abc" })).unwrap();
+        assert!(dist.check_integrity(b"tarball").is_ok());
+        assert!(dist.verify_signatures(&keys, "pkg", "1.0.0", None).is_err());
+    }
+
+    #[test]
+    fn provenance_builder() {
+        let verified = Verified {
+            identity: Identity::default(),
+            statement: json!({
+                "predicateType": "https://slsa.dev/provenance/v1",
+                "predicate": {
+                    "runDetails": { "builder": { "id": "https://github.com/actions/runner/github-hosted" } }
+                }
+            }),
+        };
+        assert_eq!(
+            Some("https://github.com/actions/runner/github-hosted"),
+            provenance(verified).builder.as_deref()
+        );
+    }
+}
@@ -0,0 +1,146 @@
+/*
+ Authentication with a registry, which challenges clients to fetch a bearer token from its
+ token service, or to send basic credentials.
+*/
+
+use url::form_urlencoded::Serializer;
+
+/// A `WWW-Authenticate` challenge of a registry.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Challenge {
+    Basic,
+    Bearer {
+        realm: String,
+        service: Option<String>,
+        scope: Option<String>,
+    },
+}
+
+/// The `key=value` parameters of a challenge, the values of which may be quoted.
+fn parameters(mut input: &str) -> Vec<(&str, String)> {
+    let mut parameters = Vec::new();
+    while let Some((key, rest)) = input.split_once('=') {
+        let key = key.trim_start_matches([',', ' ']).trim();
+        let (value, rest) = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let mut end = quoted.len();
+                while let Some((index, c)) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = index + 1;
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+                (value, &quoted[end..])
+            }
+            None => {
+                let end = rest.find(',').unwrap_or(rest.len());
+                (String::from(rest[..end].trim()), &rest[end..])
+            }
+        };
+        parameters.push((key, value));
+        input = rest;
+    }
+    parameters
+}
+
+impl Challenge {
+    pub fn parse(header: &str) -> Option<Self> {
+        let (scheme, rest) = header.split_once(' ').unwrap_or((header, ""));
+        if scheme.eq_ignore_ascii_case("basic") {
+            return Some(Self::Basic);
+        } else if !scheme.eq_ignore_ascii_case("bearer") {
+            return None;
+        }
+        let mut realm = None;
+        let mut service = None;
+        let mut scope = None;
+        for (key, value) in parameters(rest) {
+            match key {
+                "realm" => realm = Some(value),
+                "service" => service = Some(value),
+                "scope" => scope = Some(value),
+                _ => {}
+            }
+        }
+        Some(Self::Bearer {
+            realm: realm?,
+            service,
+            scope,
+        })
+    }
+
+    /// The URI of the token service, None for basic authentication.
+    pub fn token_uri(&self) -> Option<String> {
+        let Self::Bearer {
+            realm,
+            service,
+            scope,
+        } = self
+        else {
+            return None;
+        };
+        let mut query = Serializer::new(String::new());
+        if let Some(service) = service {
+            query.append_pair("service", service);
+        }
+        if let Some(scope) = scope {
+            query.append_pair("scope", scope);
+        }
+        let query = query.finish();
+        match (query.is_empty(), realm.contains('?')) {
+            (true, _) => Some(realm.clone()),
+            (false, true) => Some(format!("{realm}&{query}")),
+            (false, false) => Some(format!("{realm}?{query}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn challenges() {
+        let challenge = Challenge::parse(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/alpine:pull""#,
+        )
+        .unwrap();
+        assert_eq!(
+            Challenge::Bearer {
+                realm: "https://auth.docker.io/token".into(),
+                service: Some("registry.docker.io".into()),
+                scope: Some("repository:library/alpine:pull".into()),
+            },
+            challenge
+        );
+        assert_eq!(
+            Some(String::from("https://auth.docker.io/token?service=registry.docker.io&scope=repository%3Alibrary%2Falpine%3Apull")),
+            challenge.token_uri()
+        );
+
+        let challenge = Challenge::parse(
+            r#"bearer realm="https://ghcr.io/token", scope="repository:org/app:pull,push", error="insufficient_scope""#,
+        )
+        .unwrap();
+        assert_eq!(
+            Some(String::from(
+                "https://ghcr.io/token?scope=repository%3Aorg%2Fapp%3Apull%2Cpush"
+            )),
+            challenge.token_uri()
+        );
+
+        assert_eq!(
+            Some(Challenge::Basic),
+            Challenge::parse(r#"Basic realm="Registry""#)
+        );
+        assert_eq!(None, Challenge::Basic.token_uri());
+        assert_eq!(None, Challenge::parse(r#"Bearer service="registry""#));
+        assert_eq!(None, Challenge::parse("Negotiate"));
+    }
+}
@@ -0,0 +1,183 @@
+/*
+ Repository names, references and digests of the OCI distribution API, and the package URLs of
+ the manifests.
+*/
+
+use sha2::{Digest, Sha256, Sha512};
+use url::Url;
+use urlencoding::encode;
+
+/// A manifest reference, either a tag or a digest.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reference<'a> {
+    Tag(&'a str),
+    Digest(&'a str),
+}
+
+impl<'a> Reference<'a> {
+    pub fn parse(reference: &'a str) -> Option<Self> {
+        if reference.contains(':') {
+            return is_digest(reference).then_some(Self::Digest(reference));
+        }
+        let tag = reference.len() <= 128
+            && reference.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+            && reference
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+        tag.then_some(Self::Tag(reference))
+    }
+}
+
+/// Whether a repository name is valid, its components being lower case alphanumerics
+/// separated by `.`, `_` or `-`.
+pub fn is_name(name: &str) -> bool {
+    name.split('/').all(|component| {
+        component.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && component.ends_with(|c: char| c.is_ascii_alphanumeric())
+            && !component.contains("..")
+            && component
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-".contains(c))
+    })
+}
+
+/// Whether a digest is a sha256 or sha512 digest, the only algorithms which can be verified.
+pub fn is_digest(digest: &str) -> bool {
+    let (hex, length) = match digest.split_once(':') {
+        Some(("sha256", hex)) => (hex, 64),
+        Some(("sha512", hex)) => (hex, 128),
+        _ => return false,
+    };
+    hex.len() == length
+        && hex
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+/// The sha256 digest of content.
+pub fn digest(content: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(content))
+}
+
+/// Content being hashed as it is received, to be checked against a digest.
+pub enum DigestHasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl DigestHasher {
+    /// A hasher for the algorithm of a digest, None when it is not supported.
+    pub fn new(digest: &str) -> Option<Self> {
+        match digest.split_once(':') {
+            Some(("sha256", _)) => Some(Self::Sha256(Sha256::new())),
+            Some(("sha512", _)) => Some(Self::Sha512(Sha512::new())),
+            _ => None,
+        }
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(chunk),
+            Self::Sha512(hasher) => hasher.update(chunk),
+        }
+    }
+
+    /// Whether the content hashed has a digest.
+    pub fn matches(self, digest: &str) -> bool {
+        let actual = match self {
+            Self::Sha256(hasher) => format!("sha256:{:x}", hasher.finalize()),
+            Self::Sha512(hasher) => format!("sha512:{:x}", hasher.finalize()),
+        };
+        actual == digest
+    }
+}
+
+/// Whether content has a digest.
+pub fn matches(digest: &str, content: &[u8]) -> bool {
+    DigestHasher::new(digest).is_some_and(|mut hasher| {
+        hasher.update(content);
+        hasher.matches(digest)
+    })
+}
+
+/// The package URL of a manifest of a registry, with its tag when it was pulled by tag.
+pub fn purl(registry: &Url, name: &str, digest: &str, tag: Option<&str>) -> String {
+    let mut repository = String::from(registry.host_str().unwrap_or_default());
+    if let Some(port) = registry.port() {
+        repository.push_str(&format!(":{port}"));
+    }
+    repository.push_str(registry.path().trim_end_matches('/'));
+    repository.push('/');
+    repository.push_str(name);
+    let repository: Vec<_> = repository.split('/').map(encode).collect();
+    let last = name.rsplit('/').next().unwrap_or(name);
+    let purl = format!(
+        "pkg:oci/{}@{}?repository_url={}",
+        encode(last),
+        encode(digest),
+        repository.join("/")
+    );
+    match tag {
+        Some(tag) => format!("{purl}&tag={}", encode(tag)),
+        None => purl,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DIGEST: &str = "sha256:2cdf0a2bf5a3c8e4c8f7c5c8c6b1dfc1e1fb9e2a9bd1d0f5c3bd2ce4a4d3e9f1";
+
+    #[test]
+    fn references() {
+        assert_eq!(Some(Reference::Tag("3.18")), Reference::parse("3.18"));
+        assert_eq!(Some(Reference::Tag("_v1-RC")), Reference::parse("_v1-RC"));
+        assert_eq!(Some(Reference::Digest(DIGEST)), Reference::parse(DIGEST));
+        assert_eq!(None, Reference::parse("-latest"));
+        assert_eq!(None, Reference::parse("sha256:abc"));
+        assert_eq!(
+            None,
+            Reference::parse("md5:d41d8cd98f00b204e9800998ecf8427e")
+        );
+        assert_eq!(None, Reference::parse(&"v".repeat(129)));
+
+        assert!(is_name("library/alpine"));
+        assert!(is_name("my-org/app.server/web_ui"));
+        assert!(!is_name("Library/alpine"));
+        assert!(!is_name("library/../alpine"));
+        assert!(!is_name("library//alpine"));
+        assert!(!is_name("-alpine"));
+    }
+
+    #[test]
+    fn digests() {
+        assert_eq!(
+            "sha256:b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9",
+            digest(b"hello world")
+        );
+        assert!(matches(&digest(b"hello world"), b"hello world"));
+        assert!(!matches(&digest(b"hello world"), b"hello"));
+        assert!(matches(
+            "sha512:309ecc489c12d6eb4cc40f50c902f2b4d0ed77ee511a7c7a9bcd3ca86d4cd86f989dd35bc5ff499670da34255b45b0cfd830e81f605dcf7dc5542e93ae9cd76f",
+            b"hello world"
+        ));
+    }
+
+    #[test]
+    fn package_urls() {
+        let registry = Url::parse("https://registry-1.docker.io").unwrap();
+        assert_eq!(
+            format!("pkg:oci/alpine@sha256%3A{}?repository_url=registry-1.docker.io/library/alpine&tag=3.18", &DIGEST[7..]),
+            purl(&registry, "library/alpine", DIGEST, Some("3.18"))
+        );
+        let registry = Url::parse("http://localhost:5000/mirror/").unwrap();
+        assert_eq!(
+            format!(
+                "pkg:oci/app@sha256%3A{}?repository_url=localhost%3A5000/mirror/app",
+                &DIGEST[7..]
+            ),
+            purl(&registry, "app", DIGEST, None)
+        );
+    }
+}
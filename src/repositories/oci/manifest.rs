@@ -0,0 +1,105 @@
+/*
+ Image manifests and indexes, in their OCI and Docker media types, of which only the
+ descriptors of the content they reference are parsed.
+*/
+
+use serde::Deserialize;
+use std::collections::HashMap;
+
+pub const OCI_INDEX_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+pub const OCI_MANIFEST_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const DOCKER_LIST_TYPE: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+pub const DOCKER_MANIFEST_TYPE: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+/// Manifests are requested in every media type, so each reference is cached once.
+pub const ACCEPT: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+/// A reference to content, by digest.
+#[derive(Deserialize)]
+pub struct Descriptor {
+    pub digest: String,
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+pub struct Manifest {
+    config: Option<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+    /// The manifests of an index, one per platform
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+}
+
+impl Manifest {
+    pub fn parse(body: &[u8]) -> serde_json::Result<Self> {
+        serde_json::from_slice(body)
+    }
+
+    pub fn layers(&self) -> &[Descriptor] {
+        &self.layers
+    }
+
+    /// Digests of the blobs and manifests referenced by this manifest.
+    pub fn references(&self) -> impl Iterator<Item = &str> {
+        self.config
+            .iter()
+            .chain(&self.layers)
+            .chain(&self.manifests)
+            .map(|descriptor| descriptor.digest.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn manifest_references() {
+        let manifest = Manifest::parse(
+            br#"{
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "config": { "mediaType": "application/vnd.oci.image.config.v1+json", "digest": "sha256:c0", "size": 2 },
+                "layers": [
+                    { "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "digest": "sha256:l1", "size": 3 },
+                    { "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "digest": "sha256:l2", "size": 4,
+                      "annotations": { "dev.cosignproject.cosign/signature": "c2ln" } }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["sha256:c0", "sha256:l1", "sha256:l2"],
+            manifest.references().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some("c2ln"),
+            manifest.layers()[1]
+                .annotations
+                .get("dev.cosignproject.cosign/signature")
+                .map(String::as_str)
+        );
+
+        let index = Manifest::parse(
+            br#"{
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.docker.distribution.manifest.list.v2+json",
+                "manifests": [
+                    { "digest": "sha256:amd64", "size": 5, "platform": { "architecture": "amd64", "os": "linux" } },
+                    { "digest": "sha256:arm64", "size": 5, "platform": { "architecture": "arm64", "os": "linux" } }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["sha256:amd64", "sha256:arm64"],
+            index.references().collect::<Vec<_>>()
+        );
+        assert!(Manifest::parse(b"[]").is_err());
+    }
+}
@@ -0,0 +1,754 @@
+use actix_web::{
+    get,
+    http::{
+        header::{HeaderMap, ACCEPT, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+        StatusCode,
+    },
+    route, web, HttpResponse, HttpResponseBuilder, Responder, Scope,
+};
+use awc::{error::PayloadError, ClientResponse};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use url::Url;
+
+use crate::cache::ArtifactCache;
+use crate::config::repositories::{Credentials, RepositoryConfig};
+use crate::policy::{
+    context::{Context, Provenance},
+    PolicyEngine, Verdict,
+};
+use crate::repositories::send_retrying;
+use crate::sigstore::cosign::{self, PublicKey, Signer};
+use crate::sigstore::trust::TrustedRoot;
+
+pub mod auth;
+pub mod coordinates;
+pub mod manifest;
+
+use self::auth::Challenge;
+use self::coordinates::{digest, is_digest, is_name, matches, purl, DigestHasher, Reference};
+use self::manifest::Manifest;
+
+pub struct OciConfig {
+    url: Url,
+    cache: ArtifactCache,
+    offline: bool,
+    credentials: Credentials,
+    verify_signatures: bool,
+    keys: Vec<PublicKey>,
+    trust: Option<Arc<TrustedRoot>>,
+    /// Authorization sent for each repository once the registry has challenged for it
+    authorizations: Mutex<HashMap<String, String>>,
+}
+
+impl OciConfig {
+    pub fn new(
+        config: &RepositoryConfig,
+        cache: ArtifactCache,
+        offline: bool,
+        trust: Option<Arc<TrustedRoot>>,
+    ) -> Self {
+        let keys = config
+            .cosign_keys()
+            .iter()
+            .filter_map(|path| match PublicKey::load(path) {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    log::error!("Unable to load the cosign key {}: {e}", path.display());
+                    None
+                }
+            })
+            .collect();
+        Self {
+            url: config.url(),
+            cache,
+            offline,
+            credentials: config.credentials().clone(),
+            verify_signatures: config.verify_signatures(),
+            keys,
+            trust,
+            authorizations: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Repositories are served under `/v2/{scope}/`, so images are pulled as `{proxy}/{scope}/{name}`.
+pub fn service(
+    scope: &str,
+    config: &RepositoryConfig,
+    cache: ArtifactCache,
+    offline: bool,
+    trust: Option<Arc<TrustedRoot>>,
+) -> Scope {
+    web::scope(&format!("v2/{scope}"))
+        .app_data(web::Data::new(OciConfig::new(
+            config, cache, offline, trust,
+        )))
+        .service(image_manifest)
+        .service(blob)
+}
+
+/// The version check clients start with, shared by every OCI repository.
+#[get("/v2/")]
+pub async fn api_version() -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header((API_VERSION_HEADER, "registry/2.0"))
+        .json(json!({}))
+}
+
+const API_VERSION_HEADER: &str = "Docker-Distribution-API-Version";
+const DIGEST_HEADER: &str = "Docker-Content-Digest";
+const BLOB_TYPE: &str = "application/octet-stream";
+const MANIFEST_LIMIT: usize = 4 << 20;
+const TOKEN_LIMIT: usize = 1 << 20;
+/// Blobs are streamed to the cache, up to this size
+const BLOB_LIMIT: u64 = 2 << 30;
+
+/// An error as reported by the distribution API.
+fn error(status: StatusCode, code: &str, message: &str) -> HttpResponse {
+    HttpResponseBuilder::new(status)
+        .json(json!({ "errors": [{ "code": code, "message": message }] }))
+}
+
+fn respond(content_type: Option<&str>, digest: &str, body: Bytes) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    if let Some(content_type) = content_type {
+        response.insert_header((CONTENT_TYPE, content_type));
+    }
+    response.insert_header((DIGEST_HEADER, digest)).body(body)
+}
+
+async fn from_cache(cache: &ArtifactCache, key: &str, code: &str) -> HttpResponse {
+    match cache.load(key).await {
+        Some((entry, body)) => respond(entry.content_type(), &digest(&body), body),
+        None => {
+            log::info!("{key} is not available offline");
+            error(StatusCode::NOT_FOUND, code, "Not available offline")
+        }
+    }
+}
+
+/// The cache key recording that a blob or manifest is referenced by an allowed manifest of a
+/// repository, with the context that manifest was allowed in.
+fn allowed_key(name: &str, digest: &str) -> String {
+    format!("{name}/allowed/{digest}")
+}
+
+/// A response of the registry, read in full.
+struct Fetched {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Fetched {
+    fn content_type(&self) -> Option<&str> {
+        self.headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    /// Relay a response which is not a success to the client.
+    fn relay(&self) -> HttpResponse {
+        let mut response = HttpResponseBuilder::new(self.status);
+        if let Some(content_type) = self.content_type() {
+            response.insert_header((CONTENT_TYPE, content_type));
+        }
+        response.body(self.body.clone())
+    }
+}
+
+async fn send(
+    client: &awc::Client,
+    uri: &str,
+    accept: &str,
+    authorization: Option<&str>,
+) -> Result<ClientResponse<impl Stream<Item = Result<Bytes, PayloadError>> + Unpin>, String> {
+    send_retrying(|| {
+        let mut request = client.get(uri).insert_header((ACCEPT, accept));
+        if let Some(authorization) = authorization {
+            request = request.insert_header((AUTHORIZATION, authorization));
+        }
+        request.send()
+    })
+    .await
+    .map_err(|e| format!("Error encountered fetching {uri} -> {e}"))
+}
+
+/// Read a response of the registry in full.
+async fn read<S>(
+    mut upstream: ClientResponse<S>,
+    uri: &str,
+    limit: usize,
+) -> Result<Fetched, String>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    let body = upstream
+        .body()
+        .limit(limit)
+        .await
+        .map_err(|e| format!("Error encountered reading {uri} -> {e}"))?;
+    Ok(Fetched {
+        status: upstream.status(),
+        headers: upstream.headers().clone(),
+        body,
+    })
+}
+
+async fn get(
+    client: &awc::Client,
+    uri: &str,
+    accept: &str,
+    authorization: Option<&str>,
+    limit: usize,
+) -> Result<Fetched, String> {
+    let upstream = send(client, uri, accept, authorization).await?;
+    read(upstream, uri, limit).await
+}
+
+/// The authorization answering a challenge of the registry, None when there is no way to.
+async fn authorize(
+    config: &OciConfig,
+    client: &awc::Client,
+    challenge: &Challenge,
+) -> Result<Option<String>, String> {
+    let basic = config.credentials.username().map(|username| {
+        let password = config.credentials.token().unwrap_or_default();
+        format!(
+            "Basic {}",
+            STANDARD.encode(format!("{username}:{password}"))
+        )
+    });
+    let Some(uri) = challenge.token_uri() else {
+        return Ok(basic);
+    };
+    let fetched = get(
+        client,
+        &uri,
+        "application/json",
+        basic.as_deref(),
+        TOKEN_LIMIT,
+    )
+    .await?;
+    if fetched.status != StatusCode::OK {
+        return Err(format!("{uri} -> {}", fetched.status));
+    }
+    let response: Value = serde_json::from_slice(&fetched.body)
+        .map_err(|e| format!("Invalid token response from {uri}: {e}"))?;
+    let token = response
+        .get("token")
+        .or_else(|| response.get("access_token"))
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("No token in the response from {uri}"))?;
+    Ok(Some(format!("Bearer {token}")))
+}
+
+/// Request a manifest or blob of a repository, authenticating when the registry challenges for
+/// it.
+async fn request(
+    config: &OciConfig,
+    client: &awc::Client,
+    uri: &str,
+    name: &str,
+    accept: &str,
+) -> Result<ClientResponse<impl Stream<Item = Result<Bytes, PayloadError>> + Unpin>, String> {
+    log::debug!("upstream: {uri}");
+    let authorization = config.authorizations.lock().unwrap().get(name).cloned();
+    let upstream = send(client, uri, accept, authorization.as_deref()).await?;
+    if upstream.status() != StatusCode::UNAUTHORIZED {
+        return Ok(upstream);
+    }
+    let challenge = upstream
+        .headers()
+        .get(WWW_AUTHENTICATE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(Challenge::parse);
+    let Some(challenge) = challenge else {
+        return Ok(upstream);
+    };
+    let Some(authorization) = authorize(config, client, &challenge).await? else {
+        return Ok(upstream);
+    };
+    config
+        .authorizations
+        .lock()
+        .unwrap()
+        .insert(String::from(name), authorization.clone());
+    send(client, uri, accept, Some(&authorization)).await
+}
+
+/// Fetch a manifest or blob of a repository in full.
+async fn fetch(
+    config: &OciConfig,
+    client: &awc::Client,
+    name: &str,
+    path: &str,
+    accept: &str,
+    limit: usize,
+) -> Result<Fetched, String> {
+    let uri = format!("{}v2/{name}/{path}", config.url);
+    let upstream = request(config, client, &uri, name, accept).await?;
+    read(upstream, &uri, limit).await
+}
+
+/// Verify the signatures cosign attached to a manifest, returning who signed it.
+async fn signers(
+    config: &OciConfig,
+    client: &awc::Client,
+    name: &str,
+    manifest_digest: &str,
+) -> Vec<Signer> {
+    let Some(tag) = cosign::signature_tag(manifest_digest) else {
+        return Vec::new();
+    };
+    let path = format!("manifests/{tag}");
+    let signatures = match fetch(
+        config,
+        client,
+        name,
+        &path,
+        manifest::ACCEPT,
+        MANIFEST_LIMIT,
+    )
+    .await
+    {
+        Ok(fetched) if fetched.status == StatusCode::OK => Manifest::parse(&fetched.body),
+        Ok(_) => return Vec::new(),
+        Err(msg) => {
+            log::warn!("{msg}");
+            return Vec::new();
+        }
+    };
+    let signatures = match signatures {
+        Ok(signatures) => signatures,
+        Err(e) => {
+            log::warn!("Invalid signatures of {name}@{manifest_digest}: {e}");
+            return Vec::new();
+        }
+    };
+    let mut signers = Vec::new();
+    for layer in signatures.layers() {
+        let path = format!("blobs/{}", layer.digest);
+        let payload = match fetch(config, client, name, &path, "*/*", MANIFEST_LIMIT).await {
+            Ok(fetched) if fetched.status == StatusCode::OK => fetched.body,
+            Ok(fetched) => {
+                log::warn!("Unable to fetch {path} of {name} -> {}", fetched.status);
+                continue;
+            }
+            Err(msg) => {
+                log::warn!("{msg}");
+                continue;
+            }
+        };
+        if !matches(&layer.digest, &payload) {
+            log::warn!("{path} of {name} does not match its digest");
+            continue;
+        }
+        let trust = config.trust.as_deref();
+        match cosign::verify(
+            manifest_digest,
+            &payload,
+            &layer.annotations,
+            &config.keys,
+            trust,
+        ) {
+            Ok(signer) => signers.push(signer),
+            Err(e) => log::warn!("Invalid signature of {name}@{manifest_digest}: {e}"),
+        }
+    }
+    signers
+}
+
+/// Add who signed a manifest to its context, which is either the manifest itself or the index
+/// it was allowed through. Unsigned manifests are refused.
+async fn verify_signatures(
+    config: &OciConfig,
+    client: &awc::Client,
+    name: &str,
+    manifest_digest: &str,
+    context: Context,
+) -> Result<Context, String> {
+    let index = config
+        .cache
+        .load(&allowed_key(name, manifest_digest))
+        .await
+        .and_then(|(entry, _)| entry.context().cloned())
+        .filter(|index| !index.signatures().is_empty() || index.provenance().is_some());
+    if let Some(index) = index {
+        return Ok(context
+            .with_signatures(index.signatures().to_vec())
+            .with_provenance(index.provenance().cloned()));
+    }
+    let mut keys = Vec::new();
+    let mut provenance = None;
+    for signer in signers(config, client, name, manifest_digest).await {
+        match signer {
+            Signer::Key(id) => keys.push(id),
+            Signer::Identity(identity) => {
+                provenance.get_or_insert(Provenance {
+                    builder: None,
+                    identity,
+                });
+            }
+        }
+    }
+    if keys.is_empty() && provenance.is_none() {
+        return Err(format!("{name}@{manifest_digest} is not signed"));
+    }
+    Ok(context.with_signatures(keys).with_provenance(provenance))
+}
+
+#[route("{name:.+}/manifests/{reference}", method = "GET", method = "HEAD")]
+async fn image_manifest(
+    config: web::Data<OciConfig>,
+    policy: web::Data<PolicyEngine>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (name, reference) = path.into_inner();
+    if !is_name(&name) {
+        return error(StatusCode::BAD_REQUEST, "NAME_INVALID", "Invalid name");
+    }
+    let Some(parsed) = Reference::parse(&reference) else {
+        return error(
+            StatusCode::NOT_FOUND,
+            "MANIFEST_UNKNOWN",
+            "Invalid or unsupported reference",
+        );
+    };
+    let cache_key = format!("{name}/manifests/{reference}");
+    if config.offline {
+        return from_cache(&config.cache, &cache_key, "MANIFEST_UNKNOWN").await;
+    }
+    let path = format!("manifests/{reference}");
+    let fetched = fetch(
+        &config,
+        &policy.client,
+        &name,
+        &path,
+        manifest::ACCEPT,
+        MANIFEST_LIMIT,
+    )
+    .await;
+    let fetched = match fetched {
+        Ok(fetched) if fetched.status == StatusCode::OK => fetched,
+        Ok(fetched) => return fetched.relay(),
+        Err(msg) => {
+            log::error!("{msg}");
+            return error(StatusCode::BAD_GATEWAY, "UNKNOWN", &msg);
+        }
+    };
+    let (manifest_digest, tag) = match parsed {
+        Reference::Digest(expected) if !matches(expected, &fetched.body) => {
+            let msg = format!("{name}@{expected} does not match its digest");
+            log::error!("{msg}");
+            return error(StatusCode::BAD_GATEWAY, "DIGEST_INVALID", &msg);
+        }
+        Reference::Digest(expected) => (String::from(expected), None),
+        Reference::Tag(tag) => (digest(&fetched.body), Some(tag)),
+    };
+    let references: Vec<_> = match Manifest::parse(&fetched.body) {
+        Ok(manifest) => manifest.references().map(String::from).collect(),
+        Err(e) => {
+            let msg = format!("Invalid manifest {name}:{reference} -> {e}");
+            log::error!("{msg}");
+            return error(StatusCode::BAD_GATEWAY, "MANIFEST_INVALID", &msg);
+        }
+    };
+    let mut context = Context::new(
+        purl(&config.url, &name, &manifest_digest, tag),
+        format!("{}v2/{name}/{path}", config.url),
+        sha256::digest(fetched.body.as_ref()),
+    );
+    if config.verify_signatures && !tag.is_some_and(cosign::is_attached) {
+        context = match verify_signatures(&config, &policy.client, &name, &manifest_digest, context)
+            .await
+        {
+            Ok(context) => context,
+            Err(msg) => {
+                log::warn!("Refused {msg}");
+                return error(StatusCode::FORBIDDEN, "DENIED", &msg);
+            }
+        };
+    }
+    match policy.check(&context, None).await {
+        Ok(Verdict::Denied(response)) => response,
+        Ok(verdict) => {
+            let verdict = policy.record(&verdict);
+            let mut keys = vec![cache_key];
+            if tag.is_some() {
+                keys.push(format!("{name}/manifests/{manifest_digest}"));
+            }
+            for key in keys {
+                config
+                    .cache
+                    .store(
+                        &key,
+                        fetched.content_type(),
+                        Some(&context),
+                        verdict.clone(),
+                        fetched.body.clone(),
+                    )
+                    .await;
+            }
+            // Blobs are only served for the manifests allowed by the policy
+            for reference in references.iter().filter(|reference| is_digest(reference)) {
+                config
+                    .cache
+                    .store(
+                        &allowed_key(&name, reference),
+                        None,
+                        Some(&context),
+                        verdict.clone(),
+                        Bytes::new(),
+                    )
+                    .await;
+            }
+            respond(
+                fetched.content_type(),
+                &manifest_digest,
+                fetched.body.clone(),
+            )
+        }
+        Err(e) => e.into(),
+    }
+}
+
+/// Serve a cached blob as a stream, None when it has not been cached.
+async fn stream_blob(cache: &ArtifactCache, key: &str, blob_digest: &str) -> Option<HttpResponse> {
+    let (entry, len, chunks) = cache.stream(key).await?;
+    log::debug!("Serving {key} from the cache");
+    let mut response = HttpResponse::Ok();
+    if let Some(content_type) = entry.content_type() {
+        response.insert_header((CONTENT_TYPE, content_type));
+    }
+    Some(
+        response
+            .insert_header((DIGEST_HEADER, blob_digest))
+            .no_chunking(len)
+            .streaming(chunks),
+    )
+}
+
+/// Stream a blob to the cache, hashing it as it is received, and store it once it matches its
+/// digest. The error to respond with otherwise.
+async fn download_blob<S>(
+    cache: &ArtifactCache,
+    mut upstream: ClientResponse<S>,
+    uri: &str,
+    cache_key: &str,
+    blob_digest: &str,
+) -> Result<(), HttpResponse>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    let failed = |status: StatusCode, code: &str, msg: String| {
+        log::error!("{msg}");
+        error(status, code, &msg)
+    };
+    let cache_failed = |e: std::io::Error| {
+        let msg = format!("Unable to cache {cache_key} -> {e}");
+        failed(StatusCode::INTERNAL_SERVER_ERROR, "UNKNOWN", msg)
+    };
+    let Some(mut hasher) = DigestHasher::new(blob_digest) else {
+        let msg = format!("Unsupported digest {blob_digest}");
+        return Err(failed(StatusCode::BAD_REQUEST, "DIGEST_INVALID", msg));
+    };
+    let mut pending = cache.begin(cache_key).await.map_err(cache_failed)?;
+    while let Some(chunk) = upstream.next().await {
+        let chunk = chunk.map_err(|e| {
+            let msg = format!("Error encountered reading {uri} -> {e}");
+            failed(StatusCode::BAD_GATEWAY, "UNKNOWN", msg)
+        })?;
+        if pending.written() + chunk.len() as u64 > BLOB_LIMIT {
+            let msg = format!("{uri} is larger than {BLOB_LIMIT} bytes");
+            return Err(failed(StatusCode::BAD_GATEWAY, "UNKNOWN", msg));
+        }
+        hasher.update(&chunk);
+        pending.write(chunk).await.map_err(cache_failed)?;
+    }
+    // The pending blob is removed as it is dropped
+    if !hasher.matches(blob_digest) {
+        let msg = format!("{uri} does not match its digest");
+        return Err(failed(StatusCode::BAD_GATEWAY, "DIGEST_INVALID", msg));
+    }
+    pending
+        .commit(Some(BLOB_TYPE), None, None)
+        .await
+        .map_err(cache_failed)
+}
+
+/// Blobs, which are evaluated through the manifests referencing them and verified against their
+/// digest as they are streamed to the cache.
+#[route("{name:.+}/blobs/{digest}", method = "GET", method = "HEAD")]
+async fn blob(
+    config: web::Data<OciConfig>,
+    policy: web::Data<PolicyEngine>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (name, blob_digest) = path.into_inner();
+    if !is_name(&name) {
+        return error(StatusCode::BAD_REQUEST, "NAME_INVALID", "Invalid name");
+    } else if !is_digest(&blob_digest) {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "DIGEST_INVALID",
+            "Invalid or unsupported digest",
+        );
+    }
+    let cache_key = format!("blobs/{blob_digest}");
+    if config.offline {
+        return match stream_blob(&config.cache, &cache_key, &blob_digest).await {
+            Some(response) => response,
+            None => {
+                log::info!("{cache_key} is not available offline");
+                error(
+                    StatusCode::NOT_FOUND,
+                    "BLOB_UNKNOWN",
+                    "Not available offline",
+                )
+            }
+        };
+    }
+    if config
+        .cache
+        .load(&allowed_key(&name, &blob_digest))
+        .await
+        .is_none()
+    {
+        let msg = format!("{blob_digest} is not referenced by an allowed manifest of {name}");
+        log::warn!("Refused {msg}");
+        return error(StatusCode::FORBIDDEN, "DENIED", &msg);
+    }
+    if let Some(response) = stream_blob(&config.cache, &cache_key, &blob_digest).await {
+        return response;
+    }
+    let uri = format!("{}v2/{name}/blobs/{blob_digest}", config.url);
+    let upstream = match request(&config, &policy.client, &uri, &name, "*/*").await {
+        Ok(upstream) if upstream.status() == StatusCode::OK => upstream,
+        Ok(upstream) => {
+            return match read(upstream, &uri, MANIFEST_LIMIT).await {
+                Ok(fetched) => fetched.relay(),
+                Err(msg) => error(StatusCode::BAD_GATEWAY, "UNKNOWN", &msg),
+            };
+        }
+        Err(msg) => {
+            log::error!("{msg}");
+            return error(StatusCode::BAD_GATEWAY, "UNKNOWN", &msg);
+        }
+    };
+    if let Err(response) =
+        download_blob(&config.cache, upstream, &uri, &cache_key, &blob_digest).await
+    {
+        return response;
+    }
+    match stream_blob(&config.cache, &cache_key, &blob_digest).await {
+        Some(response) => response,
+        None => {
+            let msg = format!("Unable to read {cache_key} from the cache");
+            error(StatusCode::INTERNAL_SERVER_ERROR, "UNKNOWN", &msg)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::policy::PolicyConfig;
+    use actix_web::dev::ServerHandle;
+    use actix_web::http::header::CONTENT_LENGTH;
+    use actix_web::{rt, App, HttpRequest, HttpServer};
+
+    /// A registry on a loopback port serving the given blobs of `library/demo`.
+    fn registry(blobs: Vec<(String, Bytes)>) -> (String, ServerHandle) {
+        let blobs: HashMap<String, Bytes> = blobs
+            .into_iter()
+            .map(|(blob_digest, content)| {
+                (format!("/v2/library/demo/blobs/{blob_digest}"), content)
+            })
+            .collect();
+        let blobs = web::Data::new(blobs);
+        let server = HttpServer::new(move || {
+            App::new().app_data(blobs.clone()).default_service(web::to(
+                |req: HttpRequest, blobs: web::Data<HashMap<String, Bytes>>| async move {
+                    match blobs.get(req.path()) {
+                        Some(content) => HttpResponse::Ok().body(content.clone()),
+                        None => error(StatusCode::NOT_FOUND, "BLOB_UNKNOWN", "blob unknown"),
+                    }
+                },
+            ))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}/", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        rt::spawn(server);
+        (url, handle)
+    }
+
+    #[actix_web::test]
+    async fn stream_blobs() {
+        let layer = Bytes::from(vec![7; 300_000]);
+        let layer_digest = digest(&layer);
+        let tampered_digest = digest(b"the original layer");
+        let (url, handle) = registry(vec![
+            (layer_digest.clone(), layer.clone()),
+            (tampered_digest.clone(), Bytes::from("a tampered layer")),
+        ]);
+        let config: RepositoryConfig =
+            toml::from_str(&format!("type = \"oci\"\nurl = \"{url}\"")).unwrap();
+        let policy: PolicyConfig = toml::from_str("url = \"http://127.0.0.1:1\"").unwrap();
+        let root = std::env::temp_dir().join(format!("seedwing-oci-{}", std::process::id()));
+        let cache = ArtifactCache::new(root.clone());
+        for blob_digest in [&layer_digest, &tampered_digest] {
+            let key = allowed_key("library/demo", blob_digest);
+            cache.store(&key, None, None, None, Bytes::new()).await;
+        }
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(PolicyEngine::new(policy)))
+                .service(service("oci", &config, cache.clone(), false, None)),
+        )
+        .await;
+        let get = |blob_digest: &str| {
+            actix_web::test::TestRequest::get()
+                .uri(&format!("/v2/oci/library/demo/blobs/{blob_digest}"))
+                .to_request()
+        };
+
+        // A blob which does not match its digest is neither served nor cached
+        let response = actix_web::test::call_service(&app, get(&tampered_digest)).await;
+        assert_eq!(StatusCode::BAD_GATEWAY, response.status());
+        let body: Value = actix_web::test::read_body_json(response).await;
+        assert_eq!("DIGEST_INVALID", body["errors"][0]["code"]);
+        assert!(cache
+            .load(&format!("blobs/{tampered_digest}"))
+            .await
+            .is_none());
+
+        // Served from the cache once downloaded, even when the registry is gone
+        for attempt in 0..2 {
+            let response = actix_web::test::call_service(&app, get(&layer_digest)).await;
+            assert_eq!(StatusCode::OK, response.status());
+            let headers = response.headers();
+            assert_eq!(
+                layer_digest,
+                headers.get(DIGEST_HEADER).unwrap().to_str().unwrap()
+            );
+            assert_eq!("300000", headers.get(CONTENT_LENGTH).unwrap());
+            assert_eq!(layer, actix_web::test::read_body(response).await);
+            if attempt == 0 {
+                handle.stop(true).await;
+            }
+        }
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
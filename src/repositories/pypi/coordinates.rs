@@ -0,0 +1,122 @@
+/*
+ Parsing of the wheel and sdist file names of Python distributions, and their package URLs
+*/
+
+use urlencoding::encode;
+
+pub const WHEEL_EXTENSION: &str = "whl";
+const SDIST_EXTENSIONS: [&str; 3] = ["tar.gz", "zip", "tar.bz2"];
+
+/// The normalized name of a project, runs of `-`, `_` and `.` being a single dash.
+pub fn normalize(name: &str) -> String {
+    let mut normalized = String::with_capacity(name.len());
+    for c in name.chars() {
+        if matches!(c, '-' | '_' | '.') {
+            if !normalized.ends_with('-') {
+                normalized.push('-');
+            }
+        } else {
+            normalized.push(c.to_ascii_lowercase());
+        }
+    }
+    normalized
+}
+
+/// A wheel or sdist, identified by its file name.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Distribution<'a> {
+    pub name: &'a str,
+    pub version: &'a str,
+    pub extension: &'static str,
+}
+
+impl<'a> Distribution<'a> {
+    /// Parse `{name}-{version}(-{build})?-{python}-{abi}-{platform}.whl` or
+    /// `{name}-{version}.tar.gz`, legacy sdists having a `.zip` or `.tar.bz2` extension.
+    pub fn parse(file: &'a str) -> Option<Self> {
+        if let Some(stem) = file.strip_suffix(".whl") {
+            let parts: Vec<&str> = stem.split('-').collect();
+            if !(5..=6).contains(&parts.len()) || parts.iter().any(|part| part.is_empty()) {
+                return None;
+            }
+            return Self::new(parts[0], parts[1], WHEEL_EXTENSION);
+        }
+        SDIST_EXTENSIONS.iter().find_map(|extension| {
+            let stem = file.strip_suffix(extension)?.strip_suffix('.')?;
+            // Legacy sdist names may contain dashes, versions do not
+            let (name, version) = stem.rsplit_once('-')?;
+            Self::new(name, version, extension)
+        })
+    }
+
+    fn new(name: &'a str, version: &'a str, extension: &'static str) -> Option<Self> {
+        let versioned = version.starts_with(|c: char| c.is_ascii_digit());
+        (!name.is_empty() && versioned).then_some(Self {
+            name,
+            version,
+            extension,
+        })
+    }
+
+    /// Whether the distribution belongs to a project, given its normalized name.
+    pub fn is_of(&self, project: &str) -> bool {
+        normalize(self.name) == project
+    }
+
+    /// The package URL of the distribution of a project, given its normalized name.
+    pub fn purl(&self, project: &str) -> String {
+        format!("pkg:pypi/{}@{}", encode(project), encode(self.version))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalized_names() {
+        assert_eq!("zope-interface", normalize("zope.interface"));
+        assert_eq!("friendly-bard", normalize("Friendly-._Bard"));
+        assert_eq!("requests", normalize("requests"));
+    }
+
+    #[test]
+    fn parse_distributions() {
+        let wheel = Distribution::parse("requests-2.31.0-py3-none-any.whl").unwrap();
+        assert_eq!(
+            Distribution {
+                name: "requests",
+                version: "2.31.0",
+                extension: WHEEL_EXTENSION,
+            },
+            wheel
+        );
+        assert_eq!("pkg:pypi/requests@2.31.0", wheel.purl("requests"));
+
+        let wheel = Distribution::parse(
+            "zope_interface-6.1-1-cp311-cp311-manylinux_2_17_x86_64.manylinux2014_x86_64.whl",
+        )
+        .unwrap();
+        assert_eq!(("zope_interface", "6.1"), (wheel.name, wheel.version));
+        assert!(wheel.is_of("zope-interface"));
+
+        let sdist = Distribution::parse("zope.interface-6.1.tar.gz").unwrap();
+        assert_eq!(
+            ("zope.interface", "6.1", "tar.gz"),
+            (sdist.name, sdist.version, sdist.extension)
+        );
+        assert!(sdist.is_of("zope-interface"));
+
+        let sdist = Distribution::parse("torch-2.1.0+cpu.zip").unwrap();
+        assert_eq!("pkg:pypi/torch@2.1.0%2Bcpu", sdist.purl("torch"));
+        let sdist = Distribution::parse("python-dateutil-2.8.2.tar.gz").unwrap();
+        assert_eq!(("python-dateutil", "2.8.2"), (sdist.name, sdist.version));
+
+        assert_eq!(None, Distribution::parse("requests-2.31.0.whl"));
+        assert_eq!(None, Distribution::parse("requests-latest.tar.gz"));
+        assert_eq!(None, Distribution::parse("requests-2.31.0-py3.6.egg"));
+        assert!(!Distribution::parse("requests-2.31.0.tar.gz")
+            .unwrap()
+            .is_of("urllib3"));
+    }
+}
@@ -0,0 +1,536 @@
+use actix_web::{
+    get,
+    http::{
+        header::{ACCEPT, CONTENT_TYPE, VARY},
+        StatusCode,
+    },
+    route, web, HttpRequest, HttpResponse, HttpResponseBuilder, Responder, Scope,
+};
+use bytes::Bytes;
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use url::Url;
+
+use crate::cache::{ArtifactCache, PendingArtifact};
+use crate::policy::{context::Context, PolicyEngine, PolicyVerdict, Verdict};
+use crate::repositories::send_retrying;
+
+pub mod coordinates;
+pub mod simple;
+
+use self::coordinates::{normalize, Distribution};
+use self::simple::{Format, Link, Verifier, HTML_TYPE};
+
+pub struct PypiConfig {
+    scope: String,
+    url: Url,
+    cache: ArtifactCache,
+    offline: bool,
+}
+
+impl PypiConfig {
+    pub fn new(scope: &str, url: Url, cache: ArtifactCache, offline: bool) -> Self {
+        Self {
+            scope: String::from(scope),
+            url,
+            cache,
+            offline,
+        }
+    }
+
+    fn page_url(&self, page: &str) -> Result<Url, String> {
+        let uri = format!("{}{page}", self.url);
+        Url::parse(&uri).map_err(|e| format!("Invalid page URL {uri}: {e}"))
+    }
+}
+
+pub fn service(scope: &str, url: Url, cache: ArtifactCache, offline: bool) -> Scope {
+    web::scope(scope)
+        .app_data(web::Data::new(PypiConfig::new(scope, url, cache, offline)))
+        .service(simple_page)
+        .service(package)
+}
+
+const PAGE_LIMIT: usize = 100_000_000;
+/// Distributions are streamed to the cache, up to this size
+const DISTRIBUTION_LIMIT: u64 = 1_000_000_000;
+/// Project pages are fetched as JSON, when the index supports it, to look up files
+const PAGE_ACCEPT: &str =
+    "application/vnd.pypi.simple.v1+json, application/vnd.pypi.simple.v1+html;q=0.2, text/html;q=0.1";
+
+async fn from_cache(cache: &ArtifactCache, key: &str) -> HttpResponse {
+    match cache.respond(key).await {
+        Some(response) => response,
+        None => {
+            log::info!("{key} is not available offline");
+            HttpResponse::NotFound().body("Not Found")
+        }
+    }
+}
+
+/// Serve a page with its links pointing at the proxy, as seen by the client, so files are never
+/// fetched around the policy.
+fn respond_page(
+    config: &PypiConfig,
+    req: &HttpRequest,
+    page: &str,
+    project: Option<&str>,
+    content_type: Option<&str>,
+    body: Bytes,
+) -> HttpResponse {
+    let page_url = match config.page_url(page) {
+        Ok(page_url) => page_url,
+        Err(msg) => return HttpResponse::InternalServerError().body(msg),
+    };
+    let connection = req.connection_info();
+    let base = format!(
+        "{}://{}/{}/",
+        connection.scheme(),
+        connection.host(),
+        config.scope.trim_matches('/')
+    );
+    let body = match (Format::of(content_type), project) {
+        (Format::Html, project) => Bytes::from(simple::rewrite_html(
+            &String::from_utf8_lossy(&body),
+            &page_url,
+            &config.url,
+            project,
+            &base,
+        )),
+        (Format::Json, Some(project)) => {
+            match simple::rewrite_json(&body, &page_url, project, &base) {
+                Ok(rewritten) => Bytes::from(rewritten),
+                Err(e) => {
+                    log::warn!("Unable to rewrite the files of {page}: {e}");
+                    body
+                }
+            }
+        }
+        (Format::Json, None) => body,
+    };
+    HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, content_type.unwrap_or(HTML_TYPE)))
+        .insert_header((VARY, "Accept"))
+        .body(body)
+}
+
+/// Fetch a page from the index, cached in the format it was served in when found.
+async fn fetch_page(
+    config: &PypiConfig,
+    client: &awc::Client,
+    page: &str,
+    accept: &str,
+) -> Result<(StatusCode, Option<String>, Bytes), String> {
+    let uri = format!("{}{page}", config.url);
+    log::debug!("page: {uri}");
+    let mut upstream = send_retrying(|| client.get(&uri).insert_header((ACCEPT, accept)).send())
+        .await
+        .map_err(|e| format!("Error encountered proxying {uri} -> {e}"))?;
+    let body = upstream
+        .body()
+        .limit(PAGE_LIMIT)
+        .await
+        .map_err(|e| format!("Error encountered reading {uri} -> {e}"))?;
+    let content_type = upstream
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    if upstream.status() == StatusCode::OK {
+        let key = Format::of(content_type.as_deref()).cache_key(page);
+        config
+            .cache
+            .store(&key, content_type.as_deref(), None, None, body.clone())
+            .await;
+    }
+    Ok((upstream.status(), content_type, body))
+}
+
+/// The index of the projects, and the pages of each project listing its files.
+#[route("{page:simple/(?:[^/]+/)?}", method = "GET", method = "HEAD")]
+async fn simple_page(
+    req: HttpRequest,
+    config: web::Data<PypiConfig>,
+    policy: web::Data<PolicyEngine>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let page = path.into_inner();
+    let project = page
+        .strip_prefix("simple/")
+        .and_then(|project| project.strip_suffix('/'))
+        .map(normalize);
+    let page = match &project {
+        Some(project) => format!("simple/{project}/"),
+        None => page,
+    };
+    let accept = req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok());
+    if config.offline {
+        for format in Format::accepted(accept) {
+            if let Some((entry, body)) = config.cache.load(&format.cache_key(&page)).await {
+                let content_type = entry.content_type();
+                return respond_page(&config, &req, &page, project.as_deref(), content_type, body);
+            }
+        }
+        log::info!("{page} is not available offline");
+        return HttpResponse::NotFound().body("Not Found");
+    }
+    let accept = accept.unwrap_or(HTML_TYPE);
+    match fetch_page(&config, &policy.client, &page, accept).await {
+        Ok((StatusCode::OK, content_type, body)) => respond_page(
+            &config,
+            &req,
+            &page,
+            project.as_deref(),
+            content_type.as_deref(),
+            body,
+        ),
+        Ok((status, _, body)) => HttpResponseBuilder::new(status).body(body),
+        Err(msg) => {
+            log::error!("{msg}");
+            HttpResponse::BadGateway().body(msg)
+        }
+    }
+}
+
+/// The file of a project listed on its page, from the cached page when it lists the file.
+async fn find_link(
+    config: &PypiConfig,
+    client: &awc::Client,
+    project: &str,
+    file: &str,
+) -> Result<Option<Link>, String> {
+    let page = format!("simple/{project}/");
+    let page_url = config.page_url(&page)?;
+    for format in [Format::Json, Format::Html] {
+        if let Some((_, body)) = config.cache.load(&format.cache_key(&page)).await {
+            let links = simple::links(format, &page_url, &body).unwrap_or_default();
+            if let Some(link) = links.into_iter().find(|link| link.filename == file) {
+                return Ok(Some(link));
+            }
+        }
+    }
+    match fetch_page(config, client, &page, PAGE_ACCEPT).await? {
+        (StatusCode::OK, content_type, body) => {
+            let links = simple::links(Format::of(content_type.as_deref()), &page_url, &body)?;
+            Ok(links.into_iter().find(|link| link.filename == file))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// A file of the index, written to the cache as it is received when the upstream has it.
+enum Download {
+    Found {
+        pending: PendingArtifact,
+        content_type: Option<String>,
+        sha256: String,
+    },
+    Failed(StatusCode, Bytes),
+}
+
+/// Fetch a file into the cache, checked against the hashes listed for it when it is found. Files
+/// without any hash which can be checked are refused.
+async fn fetch_file(
+    cache: &ArtifactCache,
+    client: &awc::Client,
+    uri: &str,
+    cache_key: &str,
+    hashes: &BTreeMap<String, String>,
+) -> Result<Download, String> {
+    log::debug!("upstream: {uri}");
+    let mut upstream = send_retrying(|| client.get(uri).send())
+        .await
+        .map_err(|e| format!("Error encountered proxying {uri} -> {e}"))?;
+    if !upstream.status().is_success() {
+        let body = upstream
+            .body()
+            .limit(PAGE_LIMIT)
+            .await
+            .map_err(|e| format!("Error encountered reading {uri} -> {e}"))?;
+        return Ok(Download::Failed(upstream.status(), body));
+    }
+    let content_type = upstream
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let mut pending = cache
+        .begin(cache_key)
+        .await
+        .map_err(|e| format!("Unable to cache {cache_key} -> {e}"))?;
+    let mut verifier = Verifier::new(hashes);
+    let mut sha256 = Sha256::new();
+    while let Some(chunk) = upstream.next().await {
+        let chunk = chunk.map_err(|e| format!("Error encountered reading {uri} -> {e}"))?;
+        if pending.written() + chunk.len() as u64 > DISTRIBUTION_LIMIT {
+            return Err(format!("{uri} is larger than {DISTRIBUTION_LIMIT} bytes"));
+        }
+        verifier.update(&chunk);
+        sha256.update(&chunk);
+        pending
+            .write(chunk)
+            .await
+            .map_err(|e| format!("Unable to cache {cache_key} -> {e}"))?;
+    }
+    match verifier.finish() {
+        Ok(true) => {}
+        Ok(false) => return Err(format!("No hash of {uri} to verify")),
+        Err(e) => return Err(format!("{uri} does not match its hash: {e}")),
+    }
+    Ok(Download::Found {
+        pending,
+        content_type,
+        sha256: format!("{:x}", sha256.finalize()),
+    })
+}
+
+/// Commit a file to the cache and serve it from there.
+async fn serve_file(
+    cache: &ArtifactCache,
+    cache_key: &str,
+    pending: PendingArtifact,
+    content_type: Option<&str>,
+    context: Option<&Context>,
+    verdict: Option<PolicyVerdict>,
+) -> HttpResponse {
+    if let Err(e) = pending.commit(content_type, context, verdict).await {
+        let msg = format!("Unable to cache {cache_key} -> {e}");
+        log::error!("{msg}");
+        return HttpResponse::InternalServerError().body(msg);
+    }
+    match cache.stream(cache_key).await {
+        Some((_, len, chunks)) => {
+            let mut response = HttpResponse::Ok();
+            if let Some(content_type) = content_type {
+                response.insert_header((CONTENT_TYPE, content_type));
+            }
+            response.no_chunking(len).streaming(chunks)
+        }
+        None => HttpResponse::InternalServerError().body(format!("Unable to read {cache_key}")),
+    }
+}
+
+/// Wheels and sdists, and the metadata served next to them.
+#[get("packages/{project}/{file}")]
+async fn package(
+    config: web::Data<PypiConfig>,
+    policy: web::Data<PolicyEngine>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (project, file) = path.into_inner();
+    let project = normalize(&project);
+    let cache_key = format!("packages/{project}/{file}");
+    if config.offline {
+        return from_cache(&config.cache, &cache_key).await;
+    }
+    let (name, metadata) = match file.strip_suffix(".metadata") {
+        Some(name) => (name, true),
+        None => (file.as_str(), false),
+    };
+    let Some(distribution) = Distribution::parse(name).filter(|d| d.is_of(&project)) else {
+        return HttpResponse::NotFound().body("Not Found");
+    };
+    let link = match find_link(&config, &policy.client, &project, name).await {
+        Ok(Some(link)) => link,
+        Ok(None) => return HttpResponse::NotFound().body("Not Found"),
+        Err(msg) => {
+            log::error!("{msg}");
+            return HttpResponse::BadGateway().body(msg);
+        }
+    };
+    let (uri, hashes) = match (metadata, link.metadata) {
+        (false, _) => (link.url.to_string(), link.hashes),
+        (true, Some(hashes)) => (format!("{}.metadata", link.url), hashes),
+        (true, None) => return HttpResponse::NotFound().body("Not Found"),
+    };
+    let download = fetch_file(&config.cache, &policy.client, &uri, &cache_key, &hashes).await;
+    let (pending, content_type, sha256) = match download {
+        Ok(Download::Found {
+            pending,
+            content_type,
+            sha256,
+        }) => (pending, content_type, sha256),
+        Ok(Download::Failed(status, body)) => return HttpResponseBuilder::new(status).body(body),
+        Err(msg) => {
+            log::error!("{msg}");
+            return HttpResponse::BadGateway().body(msg);
+        }
+    };
+    if metadata {
+        // Metadata is only used to resolve dependencies, the policy applies to the files
+        let content_type = content_type.as_deref();
+        return serve_file(&config.cache, &cache_key, pending, content_type, None, None).await;
+    }
+    let context = Context::new(distribution.purl(&project), uri, sha256);
+    match policy.check(&context, Some(distribution.extension)).await {
+        // The pending file is removed as it is dropped
+        Ok(Verdict::Denied(response)) => response,
+        Ok(verdict) => {
+            serve_file(
+                &config.cache,
+                &cache_key,
+                pending,
+                Some("application/octet-stream"),
+                Some(&context),
+                policy.record(&verdict),
+            )
+            .await
+        }
+        Err(e) => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::policy::PolicyConfig;
+    use actix_web::dev::ServerHandle;
+    use actix_web::{rt, App, HttpServer};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    /// An index on a loopback port serving the given files, with their content type.
+    fn upstream(files: Vec<(&str, &str, Bytes)>) -> (Url, ServerHandle) {
+        let files: HashMap<String, (String, Bytes)> = files
+            .into_iter()
+            .map(|(path, content_type, body)| {
+                (format!("/{path}"), (String::from(content_type), body))
+            })
+            .collect();
+        let files = web::Data::new(files);
+        let server = HttpServer::new(move || {
+            App::new().app_data(files.clone()).default_service(web::to(
+                |req: HttpRequest, files: web::Data<HashMap<String, (String, Bytes)>>| async move {
+                    match files.get(req.path()) {
+                        Some((content_type, body)) => HttpResponse::Ok()
+                            .insert_header((CONTENT_TYPE, content_type.as_str()))
+                            .body(body.clone()),
+                        None => HttpResponse::NotFound().finish(),
+                    }
+                },
+            ))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = Url::parse(&format!("http://{}/", server.addrs()[0])).unwrap();
+        let server = server.run();
+        let handle = server.handle();
+        rt::spawn(server);
+        (url, handle)
+    }
+
+    /// The files left behind in the cache by downloads which were never committed.
+    fn pending_files(root: &Path) -> usize {
+        std::fs::read_dir(root)
+            .unwrap()
+            .flat_map(|dir| std::fs::read_dir(dir.unwrap().path()).unwrap())
+            .filter(|file| {
+                file.as_ref()
+                    .unwrap()
+                    .path()
+                    .to_string_lossy()
+                    .ends_with(".pending")
+            })
+            .count()
+    }
+
+    #[actix_web::test]
+    async fn stream_distributions() {
+        let wheel = Bytes::from(vec![42; 200_000]);
+        let page = json!({
+            "files": [
+                {
+                    "filename": "demo-1.0-py3-none-any.whl",
+                    "url": "../../files/demo-1.0-py3-none-any.whl",
+                    "hashes": { "sha256": sha256::digest(wheel.as_ref()) },
+                },
+                {
+                    "filename": "demo-1.0.tar.gz",
+                    "url": "../../files/demo-1.0.tar.gz",
+                    "hashes": { "sha256": sha256::digest("another sdist") },
+                },
+                {
+                    "filename": "demo-1.1.tar.gz",
+                    "url": "../../files/demo-1.1.tar.gz",
+                    "hashes": {},
+                },
+            ]
+        });
+        let (url, handle) = upstream(vec![
+            (
+                "simple/demo/",
+                simple::JSON_TYPE,
+                Bytes::from(page.to_string()),
+            ),
+            (
+                "files/demo-1.0-py3-none-any.whl",
+                "application/octet-stream",
+                wheel.clone(),
+            ),
+            (
+                "files/demo-1.0.tar.gz",
+                "application/octet-stream",
+                Bytes::from("sdist"),
+            ),
+            (
+                "files/demo-1.1.tar.gz",
+                "application/octet-stream",
+                Bytes::from("sdist"),
+            ),
+        ]);
+        let policy: PolicyConfig = toml::from_str("url = \"http://127.0.0.1:1\"").unwrap();
+        let root = std::env::temp_dir().join(format!("seedwing-pypi-{}", std::process::id()));
+        let cache = ArtifactCache::new(root.clone());
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(PolicyEngine::new(policy)))
+                .service(service("/pypi", url, cache.clone(), false)),
+        )
+        .await;
+
+        let request = actix_web::test::TestRequest::get()
+            .uri("/pypi/packages/demo/demo-1.0-py3-none-any.whl")
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(wheel, actix_web::test::read_body(response).await);
+        let (entry, cached) = cache
+            .load("packages/demo/demo-1.0-py3-none-any.whl")
+            .await
+            .unwrap();
+        assert_eq!(wheel, cached);
+        assert!(entry
+            .context()
+            .unwrap()
+            .url()
+            .ends_with("/files/demo-1.0-py3-none-any.whl"));
+
+        // A file which does not match its hash is neither served nor cached
+        let request = actix_web::test::TestRequest::get()
+            .uri("/pypi/packages/demo/demo-1.0.tar.gz")
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(StatusCode::BAD_GATEWAY, response.status());
+        assert!(cache.load("packages/demo/demo-1.0.tar.gz").await.is_none());
+        assert_eq!(0, pending_files(&root));
+
+        // Nor is one without a hash to check it against
+        let request = actix_web::test::TestRequest::get()
+            .uri("/pypi/packages/demo/demo-1.1.tar.gz")
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(StatusCode::BAD_GATEWAY, response.status());
+        assert!(cache.load("packages/demo/demo-1.1.tar.gz").await.is_none());
+        assert_eq!(0, pending_files(&root));
+
+        handle.stop(true).await;
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
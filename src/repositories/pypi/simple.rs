@@ -0,0 +1,540 @@
+/*
+ The Simple Repository API of a Python package index: the pages listing the projects of an index
+ and the files of each project, as HTML anchors (PEP 503) or JSON (PEP 691), along with the hashes
+ of the files and of the metadata served next to them (PEP 658).
+*/
+
+use md5::Md5;
+use serde::Deserialize;
+use serde_json::Value;
+use sha1::Sha1;
+use sha2::{digest::DynDigest, Digest, Sha224, Sha256, Sha384, Sha512};
+use std::collections::BTreeMap;
+use std::ops::Range;
+use url::Url;
+
+pub const JSON_TYPE: &str = "application/vnd.pypi.simple.v1+json";
+pub const HTML_TYPE: &str = "application/vnd.pypi.simple.v1+html";
+
+/// The format of a page, negotiated with the `Accept` header.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Html,
+    Json,
+}
+
+impl Format {
+    /// The format of a page served with a content type.
+    pub fn of(content_type: Option<&str>) -> Self {
+        match content_type {
+            Some(content_type) if content_type.contains("json") => Self::Json,
+            _ => Self::Html,
+        }
+    }
+
+    /// The formats accepted by a client, most preferred first. Clients sending no `Accept`
+    /// header get HTML.
+    pub fn accepted(accept: Option<&str>) -> Vec<Self> {
+        let Some(accept) = accept else {
+            return vec![Self::Html];
+        };
+        let mut accepted: Vec<(Self, f32)> = accept
+            .split(',')
+            .filter_map(|media_range| {
+                let mut params = media_range.split(';');
+                let format = match params.next()?.trim().to_ascii_lowercase().as_str() {
+                    "application/vnd.pypi.simple.v1+json"
+                    | "application/vnd.pypi.simple.latest+json" => Self::Json,
+                    "application/vnd.pypi.simple.v1+html"
+                    | "application/vnd.pypi.simple.latest+html"
+                    | "text/html"
+                    | "text/*"
+                    | "*/*" => Self::Html,
+                    _ => return None,
+                };
+                let quality = params
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|quality| quality.trim().parse().ok())
+                    .unwrap_or(1.0);
+                (quality > 0.0).then_some((format, quality))
+            })
+            .collect();
+        accepted.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let mut formats = Vec::new();
+        for (format, _) in accepted {
+            if !formats.contains(&format) {
+                formats.push(format);
+            }
+        }
+        formats
+    }
+
+    /// The cache key of a page in this format.
+    pub fn cache_key(&self, page: &str) -> String {
+        match self {
+            Self::Html => format!("{page}index.html"),
+            Self::Json => format!("{page}index.json"),
+        }
+    }
+}
+
+/// A file of a project listed on its page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    pub filename: String,
+    /// The URL of the file, without the fragment holding its hash
+    pub url: Url,
+    pub hashes: BTreeMap<String, String>,
+    /// The hashes of the metadata served at `{url}.metadata`, when there is one
+    pub metadata: Option<BTreeMap<String, String>>,
+}
+
+impl Link {
+    fn new(
+        page: &Url,
+        href: &str,
+        mut hashes: BTreeMap<String, String>,
+        metadata: Option<BTreeMap<String, String>>,
+    ) -> Option<Self> {
+        let mut url = page.join(href).ok()?;
+        let fragment = url.fragment().map(String::from);
+        if let Some((algorithm, hash)) = fragment.as_deref().and_then(|f| f.split_once('=')) {
+            hashes
+                .entry(algorithm.to_ascii_lowercase())
+                .or_insert_with(|| hash.to_ascii_lowercase());
+        }
+        url.set_fragment(None);
+        let filename = urlencoding::decode(url.path_segments()?.next_back()?)
+            .ok()?
+            .into_owned();
+        (!filename.is_empty()).then_some(Self {
+            filename,
+            url,
+            hashes,
+            metadata,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct ProjectFiles {
+    files: Vec<ProjectFile>,
+}
+
+#[derive(Deserialize)]
+struct ProjectFile {
+    filename: String,
+    url: String,
+    #[serde(default)]
+    hashes: BTreeMap<String, String>,
+    #[serde(default, rename = "core-metadata")]
+    core_metadata: Option<Value>,
+    #[serde(default, rename = "dist-info-metadata")]
+    dist_info_metadata: Option<Value>,
+}
+
+/// The hashes of the metadata of a file from a JSON page: `true` or a map of hashes.
+fn json_metadata(value: Option<&Value>) -> Option<BTreeMap<String, String>> {
+    match value? {
+        Value::Bool(true) => Some(BTreeMap::new()),
+        Value::Object(hashes) => Some(
+            hashes
+                .iter()
+                .filter_map(|(algorithm, hash)| Some((algorithm.clone(), hash.as_str()?.into())))
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// The hashes of the metadata of a file from an HTML page: `true` or `{algorithm}={hash}`.
+fn html_metadata(value: &str) -> Option<BTreeMap<String, String>> {
+    match value.split_once('=') {
+        Some((algorithm, hash)) => Some(BTreeMap::from([(
+            algorithm.to_ascii_lowercase(),
+            hash.to_ascii_lowercase(),
+        )])),
+        None if value == "true" => Some(BTreeMap::new()),
+        None => None,
+    }
+}
+
+/// The attributes of the anchors of an HTML page, with the span of their values.
+fn anchors(html: &str) -> Vec<Vec<(&str, Range<usize>)>> {
+    let bytes = html.as_bytes();
+    let skip_whitespace = |mut i: usize| {
+        while bytes.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        i
+    };
+    let mut anchors = Vec::new();
+    let mut position = 0;
+    while let Some(offset) = html[position..].find('<') {
+        let mut i = position + offset + 1;
+        position = i;
+        let is_anchor = bytes.get(i).is_some_and(|b| b.eq_ignore_ascii_case(&b'a'))
+            && bytes.get(i + 1).is_some_and(u8::is_ascii_whitespace);
+        if !is_anchor {
+            continue;
+        }
+        i += 1;
+        let mut attributes = Vec::new();
+        loop {
+            i = skip_whitespace(i);
+            if i >= bytes.len() || bytes[i] == b'>' {
+                break;
+            }
+            let name_start = i;
+            while i < bytes.len() && !bytes[i].is_ascii_whitespace() && !b"=>".contains(&bytes[i]) {
+                i += 1;
+            }
+            let name = &html[name_start..i];
+            i = skip_whitespace(i);
+            if bytes.get(i) != Some(&b'=') {
+                attributes.push((name, i..i));
+                continue;
+            }
+            i = skip_whitespace(i + 1);
+            let value = match bytes.get(i) {
+                Some(&quote) if quote == b'"' || quote == b'\'' => {
+                    let start = i + 1;
+                    let Some(len) = html[start..].find(char::from(quote)) else {
+                        break;
+                    };
+                    i = start + len + 1;
+                    start..start + len
+                }
+                _ => {
+                    let start = i;
+                    while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'>' {
+                        i += 1;
+                    }
+                    start..i
+                }
+            };
+            attributes.push((name, value));
+        }
+        position = i.min(html.len());
+        anchors.push(attributes);
+    }
+    anchors
+}
+
+fn attribute<'a>(
+    html: &'a str,
+    attributes: &[(&str, Range<usize>)],
+    name: &str,
+) -> Option<(&'a str, Range<usize>)> {
+    attributes
+        .iter()
+        .find(|(attribute, _)| attribute.eq_ignore_ascii_case(name))
+        .map(|(_, value)| (&html[value.clone()], value.clone()))
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&amp;", "&")
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// The files listed on the page of a project, with their URLs resolved against the page.
+pub fn links(format: Format, page: &Url, body: &[u8]) -> Result<Vec<Link>, String> {
+    match format {
+        Format::Json => {
+            let project: ProjectFiles =
+                serde_json::from_slice(body).map_err(|e| format!("Invalid project page: {e}"))?;
+            Ok(project
+                .files
+                .into_iter()
+                .filter_map(|file| {
+                    let metadata = json_metadata(
+                        file.core_metadata
+                            .as_ref()
+                            .or(file.dist_info_metadata.as_ref()),
+                    );
+                    let mut link = Link::new(page, &file.url, file.hashes, metadata)?;
+                    link.filename = file.filename;
+                    Some(link)
+                })
+                .collect())
+        }
+        Format::Html => {
+            let html = String::from_utf8_lossy(body);
+            Ok(anchors(&html)
+                .iter()
+                .filter_map(|attributes| {
+                    let (href, _) = attribute(&html, attributes, "href")?;
+                    let metadata = attribute(&html, attributes, "data-core-metadata")
+                        .or_else(|| attribute(&html, attributes, "data-dist-info-metadata"))
+                        .and_then(|(value, _)| html_metadata(&unescape(value)));
+                    Link::new(page, &unescape(href), BTreeMap::new(), metadata)
+                })
+                .collect())
+        }
+    }
+}
+
+/// The URL of a file of a project on the proxy, `base` being the URL of the repository.
+fn proxied(base: &str, project: &str, url: &Url) -> Option<String> {
+    let file = url.path_segments()?.next_back().filter(|f| !f.is_empty())?;
+    let mut proxied = format!("{base}packages/{project}/{file}");
+    if let Some(fragment) = url.fragment() {
+        proxied.push('#');
+        proxied.push_str(fragment);
+    }
+    Some(proxied)
+}
+
+/// Point the links of an HTML page at the proxy: the files of a project, or the pages of the
+/// projects of the index.
+pub fn rewrite_html(
+    html: &str,
+    page: &Url,
+    upstream: &Url,
+    project: Option<&str>,
+    base: &str,
+) -> String {
+    let mut rewritten = String::with_capacity(html.len());
+    let mut last = 0;
+    for attributes in anchors(html) {
+        let Some((href, span)) = attribute(html, &attributes, "href") else {
+            continue;
+        };
+        let Ok(url) = page.join(&unescape(href)) else {
+            continue;
+        };
+        let replacement = match project {
+            Some(project) => proxied(base, project, &url),
+            None => url
+                .as_str()
+                .strip_prefix(upstream.as_str())
+                .map(|path| format!("{base}{path}")),
+        };
+        if let Some(replacement) = replacement {
+            rewritten.push_str(&html[last..span.start]);
+            rewritten.push_str(&escape(&replacement));
+            last = span.end;
+        }
+    }
+    rewritten.push_str(&html[last..]);
+    rewritten
+}
+
+/// Point the files of the JSON page of a project at the proxy.
+pub fn rewrite_json(
+    body: &[u8],
+    page: &Url,
+    project: &str,
+    base: &str,
+) -> serde_json::Result<Vec<u8>> {
+    let mut value: Value = serde_json::from_slice(body)?;
+    if let Some(files) = value.get_mut("files").and_then(Value::as_array_mut) {
+        for file in files {
+            let url = file.get("url").and_then(Value::as_str);
+            let proxied = url
+                .and_then(|url| page.join(url).ok())
+                .and_then(|url| proxied(base, project, &url));
+            if let Some(proxied) = proxied {
+                file["url"] = Value::String(proxied);
+            }
+        }
+    }
+    serde_json::to_vec(&value)
+}
+
+/// The hex digest of a payload, for the algorithms an index may use.
+fn hasher(algorithm: &str) -> Option<Box<dyn DynDigest>> {
+    Some(match algorithm {
+        "md5" => Box::new(Md5::new()),
+        "sha1" => Box::new(Sha1::new()),
+        "sha224" => Box::new(Sha224::new()),
+        "sha256" => Box::new(Sha256::new()),
+        "sha384" => Box::new(Sha384::new()),
+        "sha512" => Box::new(Sha512::new()),
+        _ => return None,
+    })
+}
+
+/// The hashes listed for a file, checked as the file is received.
+pub struct Verifier {
+    hashers: Vec<(String, String, Box<dyn DynDigest>)>,
+}
+
+impl Verifier {
+    pub fn new(hashes: &BTreeMap<String, String>) -> Self {
+        let hashers = hashes
+            .iter()
+            .filter_map(|(algorithm, expected)| {
+                let hasher = hasher(algorithm)?;
+                Some((algorithm.clone(), expected.clone(), hasher))
+            })
+            .collect();
+        Self { hashers }
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        for (_, _, hasher) in &mut self.hashers {
+            hasher.update(chunk);
+        }
+    }
+
+    /// Check the file against its hashes, returning whether any of them could be checked.
+    pub fn finish(self) -> Result<bool, String> {
+        let verified = !self.hashers.is_empty();
+        for (algorithm, expected, hasher) in self.hashers {
+            let actual: String = hasher
+                .finalize()
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect();
+            if !actual.eq_ignore_ascii_case(&expected) {
+                return Err(format!("{algorithm} mismatch"));
+            }
+        }
+        Ok(verified)
+    }
+}
+
+/// Check a payload against the hashes listed for it, returning whether any of them could be
+/// checked.
+pub fn verify(hashes: &BTreeMap<String, String>, payload: &[u8]) -> Result<bool, String> {
+    let mut verifier = Verifier::new(hashes);
+    verifier.update(payload);
+    verifier.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn accepted_formats() {
+        assert_eq!(vec![Format::Html], Format::accepted(None));
+        assert_eq!(
+            vec![Format::Json, Format::Html],
+            Format::accepted(Some(
+                "application/vnd.pypi.simple.v1+json, application/vnd.pypi.simple.v1+html; q=0.1, text/html; q=0.01"
+            ))
+        );
+        assert_eq!(
+            vec![Format::Html, Format::Json],
+            Format::accepted(Some(
+                "application/vnd.pypi.simple.latest+json;q=0.5, text/html"
+            ))
+        );
+        assert_eq!(
+            vec![Format::Json],
+            Format::accepted(Some("application/vnd.pypi.simple.v1+json, text/html;q=0"))
+        );
+        assert!(Format::accepted(Some("application/xml")).is_empty());
+        assert_eq!(Format::Json, Format::of(Some(JSON_TYPE)));
+        assert_eq!(Format::Html, Format::of(Some("text/html; charset=utf-8")));
+    }
+
+    #[test]
+    fn html_pages() {
+        let page = Url::parse("https://pypi.org/simple/requests/").unwrap();
+        let upstream = Url::parse("https://pypi.org/").unwrap();
+        let html = r#"<!DOCTYPE html>
+<html><body>
+<A HREF="https://files.pythonhosted.org/packages/70/8e/requests-2.31.0-py3-none-any.whl#sha256=58CD2187" data-requires-python="&gt;=3.7" data-dist-info-metadata="sha256=eb3f">requests-2.31.0-py3-none-any.whl</A><br />
+<a href='../../packages/requests-2.31.0.tar.gz#md5=9d4b&amp;x=1' data-core-metadata=true>requests-2.31.0.tar.gz</a>
+<abbr title="x">not a link</abbr>
+</body></html>"#;
+        let links = links(Format::Html, &page, html.as_bytes()).unwrap();
+        assert_eq!(2, links.len());
+        assert_eq!("requests-2.31.0-py3-none-any.whl", links[0].filename);
+        assert_eq!(
+            "https://files.pythonhosted.org/packages/70/8e/requests-2.31.0-py3-none-any.whl",
+            links[0].url.as_str()
+        );
+        assert_eq!(
+            Some("58cd2187"),
+            links[0].hashes.get("sha256").map(String::as_str)
+        );
+        assert_eq!(
+            Some(BTreeMap::from([("sha256".into(), "eb3f".into())])),
+            links[0].metadata
+        );
+        assert_eq!(
+            "https://pypi.org/packages/requests-2.31.0.tar.gz",
+            links[1].url.as_str()
+        );
+        assert_eq!(Some(BTreeMap::new()), links[1].metadata);
+
+        let base = "http://localhost:8181/pypi/";
+        let rewritten = rewrite_html(html, &page, &upstream, Some("requests"), base);
+        assert!(rewritten.contains(r#"<A HREF="http://localhost:8181/pypi/packages/requests/requests-2.31.0-py3-none-any.whl#sha256=58CD2187" data-requires-python"#));
+        assert!(rewritten.contains(r#"<a href='http://localhost:8181/pypi/packages/requests/requests-2.31.0.tar.gz#md5=9d4b&amp;x=1' data-core"#));
+        assert!(rewritten.ends_with("</body></html>"));
+
+        let index =
+            r#"<a href="/simple/requests/">requests</a><a href="https://elsewhere.org/x/">x</a>"#;
+        let root = Url::parse("https://pypi.org/simple/").unwrap();
+        assert_eq!(
+            r#"<a href="http://localhost:8181/pypi/simple/requests/">requests</a><a href="https://elsewhere.org/x/">x</a>"#,
+            rewrite_html(index, &root, &upstream, None, base)
+        );
+    }
+
+    #[test]
+    fn json_pages() {
+        let page = Url::parse("https://pypi.org/simple/requests/").unwrap();
+        let json = r#"{"meta":{"api-version":"1.1"},"name":"requests","files":[
+            {"filename":"requests-2.31.0.tar.gz","url":"https://files.pythonhosted.org/packages/9d/be/requests-2.31.0.tar.gz","hashes":{"sha256":"942c"},"core-metadata":{"sha256":"a1b2"}},
+            {"filename":"requests-2.30.0.tar.gz","url":"/files/requests-2.30.0.tar.gz","hashes":{},"yanked":true}
+        ]}"#;
+        let links = links(Format::Json, &page, json.as_bytes()).unwrap();
+        assert_eq!("requests-2.31.0.tar.gz", links[0].filename);
+        assert_eq!(
+            Some("942c"),
+            links[0].hashes.get("sha256").map(String::as_str)
+        );
+        assert_eq!(
+            Some(BTreeMap::from([("sha256".into(), "a1b2".into())])),
+            links[0].metadata
+        );
+        assert_eq!(
+            "https://pypi.org/files/requests-2.30.0.tar.gz",
+            links[1].url.as_str()
+        );
+        assert_eq!(None, links[1].metadata);
+
+        let rewritten =
+            rewrite_json(json.as_bytes(), &page, "requests", "http://proxy/pypi/").unwrap();
+        let rewritten: Value = serde_json::from_slice(&rewritten).unwrap();
+        assert_eq!(
+            "http://proxy/pypi/packages/requests/requests-2.31.0.tar.gz",
+            rewritten["files"][0]["url"]
+        );
+        assert_eq!(
+            "http://proxy/pypi/packages/requests/requests-2.30.0.tar.gz",
+            rewritten["files"][1]["url"]
+        );
+        assert_eq!(true, rewritten["files"][1]["yanked"]);
+    }
+
+    #[test]
+    fn verify_hashes() {
+        let sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let hashes = BTreeMap::from([("sha256".to_string(), sha256.to_string())]);
+        assert_eq!(Ok(true), verify(&hashes, b"hello"));
+        assert!(verify(&hashes, b"hullo").is_err());
+        let unknown = BTreeMap::from([("blake2b".to_string(), "00".to_string())]);
+        assert_eq!(Ok(false), verify(&unknown, b"hello"));
+        let md5 = BTreeMap::from([("md5".to_string(), "5D41402ABC4B2A76B9719D911017C592".into())]);
+        assert_eq!(Ok(true), verify(&md5, b"hello"));
+    }
+}
@@ -0,0 +1,446 @@
+/*
+ Verification of Sigstore bundles holding a DSSE envelope, such as the SLSA provenance of npm
+ packages. The envelope must be signed by a certificate issued by a trusted certificate authority
+ and logged in a trusted transparency log while the certificate was valid.
+*/
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sigstore::crypto::{CosignVerificationKey, Signature};
+use x509_parser::der_parser::der::parse_der_utf8string;
+use x509_parser::pem::parse_x509_pem;
+use x509_parser::prelude::{parse_x509_certificate, ASN1Time, GeneralName, X509Certificate};
+
+use crate::errors::{Error, Result};
+use crate::sigstore::trust::TrustedRoot;
+
+const OID_ISSUER: &str = "1.3.6.1.4.1.57264.1.1";
+const OID_ISSUER_V2: &str = "1.3.6.1.4.1.57264.1.8";
+const OID_SOURCE_REPOSITORY: &str = "1.3.6.1.4.1.57264.1.12";
+const OID_SOURCE_DIGEST: &str = "1.3.6.1.4.1.57264.1.13";
+const OID_SOURCE_REF: &str = "1.3.6.1.4.1.57264.1.14";
+
+#[derive(Deserialize)]
+struct RawBytes {
+    #[serde(rename = "rawBytes")]
+    raw_bytes: String,
+}
+
+#[derive(Deserialize)]
+struct CertChain {
+    certificates: Vec<RawBytes>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LogId {
+    key_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InclusionPromise {
+    signed_entry_timestamp: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TlogEntry {
+    /// int64 values are strings in the JSON encoding of the bundle
+    log_index: Value,
+    log_id: LogId,
+    integrated_time: Value,
+    inclusion_promise: Option<InclusionPromise>,
+    canonicalized_body: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VerificationMaterial {
+    /// Bundles up to version 0.2
+    x509_certificate_chain: Option<CertChain>,
+    certificate: Option<RawBytes>,
+    #[serde(default)]
+    tlog_entries: Vec<TlogEntry>,
+}
+
+#[derive(Deserialize)]
+struct EnvelopeSignature {
+    sig: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Envelope {
+    payload: String,
+    payload_type: String,
+    signatures: Vec<EnvelopeSignature>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bundle {
+    verification_material: VerificationMaterial,
+    dsse_envelope: Option<Envelope>,
+}
+
+/// Identity of the signer, as certified by the certificate authority.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Identity {
+    /// Subject alternative name, the workflow of a CI signer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    /// OIDC issuer which authenticated the signer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_repository: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_ref: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_digest: Option<String>,
+}
+
+/// The in-toto statement of a verified bundle, and who signed it.
+pub struct Verified {
+    pub identity: Identity,
+    pub statement: Value,
+}
+
+impl Verified {
+    /// Whether a subject of the statement has this digest, hex encoded.
+    pub fn covers(&self, algorithm: &str, digest: &str) -> bool {
+        let subjects = self.statement.get("subject").and_then(Value::as_array);
+        subjects.into_iter().flatten().any(|subject| {
+            subject
+                .pointer(&format!("/digest/{algorithm}"))
+                .and_then(Value::as_str)
+                .is_some_and(|value| value.eq_ignore_ascii_case(digest))
+        })
+    }
+}
+
+pub(crate) fn invalid(message: impl Into<String>) -> Error {
+    Error::SigstoreError(message.into())
+}
+
+pub(crate) fn decode(value: &str) -> Result<Vec<u8>> {
+    STANDARD
+        .decode(value)
+        .map_err(|e| invalid(format!("Invalid bundle encoding: {e}")))
+}
+
+fn integer(value: &Value) -> Option<i64> {
+    match value {
+        Value::String(value) => value.parse().ok(),
+        value => value.as_i64(),
+    }
+}
+
+/// The pre-authentication encoding of a DSSE envelope, which is what gets signed.
+pub fn pae(payload_type: &str, payload: &[u8]) -> Vec<u8> {
+    let mut message = format!(
+        "DSSEv1 {} {payload_type} {} ",
+        payload_type.len(),
+        payload.len()
+    )
+    .into_bytes();
+    message.extend_from_slice(payload);
+    message
+}
+
+/// An entry of a transparency log, along with the signed entry timestamp promising its inclusion.
+pub(crate) struct LogEntry<'a> {
+    pub log_id: Vec<u8>,
+    pub log_index: i64,
+    pub integrated_time: i64,
+    /// base64 of the canonicalized body of the entry
+    pub body: &'a str,
+    pub signed_entry_timestamp: Vec<u8>,
+}
+
+impl TlogEntry {
+    fn log_entry(&self) -> Result<LogEntry<'_>> {
+        let promise = self
+            .inclusion_promise
+            .as_ref()
+            .ok_or_else(|| invalid("Transparency log entry has no inclusion promise"))?;
+        let (Some(log_index), Some(integrated_time)) =
+            (integer(&self.log_index), integer(&self.integrated_time))
+        else {
+            return Err(invalid("Invalid transparency log entry"));
+        };
+        Ok(LogEntry {
+            log_id: decode(&self.log_id.key_id)?,
+            log_index,
+            integrated_time,
+            body: &self.canonicalized_body,
+            signed_entry_timestamp: decode(&promise.signed_entry_timestamp)?,
+        })
+    }
+}
+
+/// The canonical JSON document signed by the log in its signed entry timestamp.
+fn set_payload(entry: &LogEntry) -> Result<Vec<u8>> {
+    let log_id: String = entry.log_id.iter().map(|b| format!("{b:02x}")).collect();
+    // Keys of serde_json maps are sorted, which makes this the canonical form
+    let payload = json!({
+        "body": entry.body,
+        "integratedTime": entry.integrated_time,
+        "logID": log_id,
+        "logIndex": entry.log_index,
+    });
+    serde_json::to_vec(&payload).map_err(|e| invalid(e.to_string()))
+}
+
+fn extension(certificate: &X509Certificate, oid: &str) -> Option<String> {
+    let extension = certificate
+        .extensions()
+        .iter()
+        .find(|extension| extension.oid.to_id_string() == oid)?;
+    match parse_der_utf8string(extension.value) {
+        Ok((_, value)) => value.as_str().ok().map(String::from),
+        // The first extensions held the raw value
+        Err(_) => String::from_utf8(extension.value.to_vec()).ok(),
+    }
+}
+
+pub(crate) fn identity(certificate: &X509Certificate) -> Identity {
+    let subject = certificate
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .and_then(|names| {
+            names
+                .value
+                .general_names
+                .iter()
+                .find_map(|name| match name {
+                    GeneralName::URI(uri) => Some(uri.to_string()),
+                    GeneralName::RFC822Name(email) => Some(email.to_string()),
+                    _ => None,
+                })
+        });
+    Identity {
+        subject,
+        issuer: extension(certificate, OID_ISSUER_V2)
+            .or_else(|| extension(certificate, OID_ISSUER)),
+        source_repository: extension(certificate, OID_SOURCE_REPOSITORY),
+        source_ref: extension(certificate, OID_SOURCE_REF),
+        source_digest: extension(certificate, OID_SOURCE_DIGEST),
+    }
+}
+
+/// Verify the certificate was issued by one of the trusted certificate authorities.
+pub(crate) fn verify_issuer(certificate: &X509Certificate, trust: &TrustedRoot) -> Result<()> {
+    let trusted = trust.authorities().iter().flatten().any(|authority| {
+        parse_x509_certificate(authority).is_ok_and(|(_, authority)| {
+            certificate
+                .verify_signature(Some(authority.public_key()))
+                .is_ok()
+        })
+    });
+    if trusted {
+        Ok(())
+    } else {
+        Err(invalid(
+            "Signing certificate is not issued by a trusted authority",
+        ))
+    }
+}
+
+/// Verify the signed entry timestamp of the log, and that the certificate was valid when the
+/// entry was logged, returning the body of the entry.
+pub(crate) fn verify_log_entry(
+    entry: &LogEntry,
+    certificate: &X509Certificate,
+    trust: &TrustedRoot,
+) -> Result<Value> {
+    let log = trust
+        .log(&entry.log_id)
+        .ok_or_else(|| invalid("Entry logged in an untrusted transparency log"))?;
+    let key = CosignVerificationKey::try_from_der(&log.key)?;
+    key.verify_signature(
+        Signature::Raw(&entry.signed_entry_timestamp),
+        &set_payload(entry)?,
+    )
+    .map_err(|_| invalid("Invalid signed entry timestamp"))?;
+
+    let logged = ASN1Time::from_timestamp(entry.integrated_time)
+        .map_err(|_| invalid("Invalid transparency log entry"))?;
+    if !certificate.validity().is_valid_at(logged) {
+        return Err(invalid("Entry logged outside of the certificate validity"));
+    }
+    serde_json::from_slice(&decode(entry.body)?)
+        .map_err(|e| invalid(format!("Invalid transparency log entry: {e}")))
+}
+
+/// Whether an entry of the log was made with a certificate, given as DER. Entries hold it as
+/// base64 encoded PEM: the `verifier` of dsse signatures, the `publicKey` of intoto signatures or
+/// the `publicKey.content` of a hashedrekord.
+pub(crate) fn logged_with(body: &Value, certificate: &[u8]) -> bool {
+    let signatures = ["/spec/signatures", "/spec/content/envelope/signatures"]
+        .iter()
+        .filter_map(|pointer| body.pointer(pointer).and_then(Value::as_array))
+        .flatten()
+        .filter_map(|signature| {
+            signature
+                .get("verifier")
+                .or_else(|| signature.get("publicKey"))
+        });
+    let hashed_rekord = body.pointer("/spec/signature/publicKey/content");
+    signatures
+        .chain(hashed_rekord)
+        .filter_map(Value::as_str)
+        .filter_map(|verifier| decode(verifier).ok())
+        .any(|pem| parse_x509_pem(&pem).is_ok_and(|(_, pem)| pem.contents == certificate))
+}
+
+/// Verify an entry of the bundle, which must be the one of this envelope and its certificate.
+fn verify_entry(
+    entry: &TlogEntry,
+    der: &[u8],
+    certificate: &X509Certificate,
+    payload: &[u8],
+    trust: &TrustedRoot,
+) -> Result<()> {
+    let body = verify_log_entry(&entry.log_entry()?, certificate, trust)?;
+    if !logged_with(&body, der) {
+        return Err(invalid(
+            "Transparency log entry is not the one of the signing certificate",
+        ));
+    }
+    let logged_hash = body
+        .pointer("/spec/content/payloadHash/value")
+        .or_else(|| body.pointer("/spec/payloadHash/value"))
+        .and_then(Value::as_str);
+    match logged_hash {
+        Some(hash) if hash == format!("{:x}", Sha256::digest(payload)) => Ok(()),
+        _ => Err(invalid(
+            "Transparency log entry does not match the envelope",
+        )),
+    }
+}
+
+impl Bundle {
+    pub fn parse(document: &[u8]) -> Result<Self> {
+        serde_json::from_slice(document).map_err(|e| invalid(format!("Invalid bundle: {e}")))
+    }
+
+    /// Verify the envelope of the bundle, returning its statement.
+    pub fn verify(&self, trust: &TrustedRoot) -> Result<Verified> {
+        let material = &self.verification_material;
+        let certificate = material
+            .certificate
+            .as_ref()
+            .or_else(|| {
+                material
+                    .x509_certificate_chain
+                    .as_ref()
+                    .and_then(|chain| chain.certificates.first())
+            })
+            .ok_or_else(|| invalid("Bundle has no signing certificate"))?;
+        let der = decode(&certificate.raw_bytes)?;
+        let (_, certificate) = parse_x509_certificate(&der)
+            .map_err(|e| invalid(format!("Invalid signing certificate: {e}")))?;
+        verify_issuer(&certificate, trust)?;
+
+        let envelope = self
+            .dsse_envelope
+            .as_ref()
+            .ok_or_else(|| invalid("Bundle has no DSSE envelope"))?;
+        let payload = decode(&envelope.payload)?;
+        let key = CosignVerificationKey::try_from_der(certificate.public_key().raw)?;
+        let message = pae(&envelope.payload_type, &payload);
+        let signed = envelope.signatures.iter().any(|signature| {
+            decode(&signature.sig)
+                .is_ok_and(|sig| key.verify_signature(Signature::Raw(&sig), &message).is_ok())
+        });
+        if !signed {
+            return Err(invalid("Envelope is not signed by the certificate"));
+        }
+
+        if material.tlog_entries.is_empty() {
+            return Err(invalid("Bundle has no transparency log entry"));
+        }
+        for entry in &material.tlog_entries {
+            verify_entry(entry, &der, &certificate, &payload, trust)?;
+        }
+
+        let statement = serde_json::from_slice(&payload)
+            .map_err(|e| invalid(format!("Invalid in-toto statement: {e}")))?;
+        Ok(Verified {
+            identity: identity(&certificate),
+            statement,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pre_authentication_encoding() {
+        assert_eq!(
+            b"DSSEv1 29 http://example.com/HelloWorld 11 hello world".to_vec(),
+            pae("http://example.com/HelloWorld", b"hello world")
+        );
+    }
+
+    #[test]
+    fn signed_entry_timestamp_payload() {
+        let entry: TlogEntry = serde_json::from_value(json!({
+            "logIndex": "25579",
+            "logId": { "keyId": "wNI9atQGlz+VWfO6LRygH4QUfY/8W4RFwiT5i5WRgB0=" },
+            "kindVersion": { "kind": "intoto", "version": "0.0.2" },
+            "integratedTime": "1680000000",
+            "inclusionPromise": { "signedEntryTimestamp": "" },
+            "canonicalizedBody": "eyJ9"
+        }))
+        .unwrap();
+        assert_eq!(
+            r#"{"body":"eyJ9","integratedTime":1680000000,"logID":"c0d23d6ad406973f9559f3ba2d1ca01f84147d8ffc5b8445c224f98b9591801d","logIndex":25579}"#,
+            String::from_utf8(set_payload(&entry.log_entry().unwrap()).unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn logged_certificates() {
+        let pem = |der: &[u8]| {
+            let pem = format!(
+                "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+                STANDARD.encode(der)
+            );
+            STANDARD.encode(pem)
+        };
+        let dsse =
+            json!({ "spec": { "signatures": [{ "signature": "", "verifier": pem(b"signer") }] } });
+        assert!(logged_with(&dsse, b"signer"));
+        assert!(!logged_with(&dsse, b"someone else"));
+        let intoto = json!({
+            "spec": { "content": { "envelope": { "signatures": [{ "publicKey": pem(b"signer") }] } } }
+        });
+        assert!(logged_with(&intoto, b"signer"));
+        let hashed_rekord =
+            json!({ "spec": { "signature": { "publicKey": { "content": pem(b"signer") } } } });
+        assert!(logged_with(&hashed_rekord, b"signer"));
+        assert!(!logged_with(&json!({ "spec": {} }), b"signer"));
+    }
+
+    #[test]
+    fn statement_subjects() {
+        let verified = Verified {
+            identity: Identity::default(),
+            statement: json!({
+                "_type": "https://in-toto.io/Statement/v0.1",
+                "subject": [{ "name": "pkg:npm/lodash@4.17.21", "digest": { "sha512": "ABCDEF" } }]
+            }),
+        };
+        assert!(verified.covers("sha512", "abcdef"));
+        assert!(!verified.covers("sha512", "012345"));
+        assert!(!verified.covers("sha256", "abcdef"));
+    }
+}
@@ -0,0 +1,263 @@
+/*
+ Verification of the signatures cosign attaches to container images: a manifest tagged after
+ the digest of the image, each layer of which is a simple signing payload naming the digest.
+ Payloads are signed either with a key pair, or keylessly with a certificate issued by a trusted
+ certificate authority and logged in a trusted transparency log.
+*/
+
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sigstore::crypto::{CosignVerificationKey, Signature};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use x509_parser::pem::parse_x509_pem;
+
+use crate::errors::Result;
+use crate::sigstore::bundle::{
+    decode, identity, invalid, logged_with, verify_issuer, verify_log_entry, Identity, LogEntry,
+};
+use crate::sigstore::trust::TrustedRoot;
+
+pub const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+pub const CERTIFICATE_ANNOTATION: &str = "dev.sigstore.cosign/certificate";
+pub const BUNDLE_ANNOTATION: &str = "dev.sigstore.cosign/bundle";
+const SIGNATURE_TYPE: &str = "cosign container image signature";
+
+/// Suffixes of the tags of signatures, attestations and SBOMs attached by cosign
+const ATTACHED_SUFFIXES: [&str; 3] = [".sig", ".att", ".sbom"];
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RekorPayload {
+    body: String,
+    integrated_time: i64,
+    log_index: i64,
+    #[serde(rename = "logID")]
+    log_id: String,
+}
+
+/// The transparency log entry of a keyless signature, as cosign annotates it.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RekorBundle {
+    signed_entry_timestamp: String,
+    payload: RekorPayload,
+}
+
+/// A public key images may be signed with, identified by the sha256 of its DER encoding.
+pub struct PublicKey {
+    pub id: String,
+    key: CosignVerificationKey,
+}
+
+impl PublicKey {
+    /// Load a PEM encoded public key, such as the `cosign.pub` of `cosign generate-key-pair`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let (_, pem) = parse_x509_pem(&fs::read(path)?)
+            .map_err(|e| invalid(format!("Invalid public key {}: {e}", path.display())))?;
+        Ok(Self {
+            id: format!("sha256:{:x}", Sha256::digest(&pem.contents)),
+            key: CosignVerificationKey::try_from_der(&pem.contents)?,
+        })
+    }
+}
+
+/// Who signed an image.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Signer {
+    /// The id of the public key the signature verified with
+    Key(String),
+    /// The identity certified for a keyless signature
+    Identity(Identity),
+}
+
+/// The tag cosign attaches the signatures of a manifest to, given its digest.
+pub fn signature_tag(digest: &str) -> Option<String> {
+    let (algorithm, hex) = digest.split_once(':')?;
+    Some(format!("{algorithm}-{hex}.sig"))
+}
+
+/// Whether a tag holds signatures, attestations or an SBOM attached to a manifest by cosign,
+/// which are not signed themselves.
+pub fn is_attached(tag: &str) -> bool {
+    ATTACHED_SUFFIXES.iter().any(|suffix| {
+        tag.strip_suffix(suffix)
+            .and_then(|digest| digest.split_once('-'))
+            .is_some_and(|(algorithm, hex)| {
+                !algorithm.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit())
+            })
+    })
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) || !value.is_ascii() {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Verify a keyless signature with the certificate it was made with, and its log entry.
+fn verify_certificate(
+    pem: &str,
+    signature: &[u8],
+    payload: &[u8],
+    bundle: Option<&String>,
+    trust: &TrustedRoot,
+) -> Result<Identity> {
+    let (_, pem) = parse_x509_pem(pem.as_bytes())
+        .map_err(|e| invalid(format!("Invalid signing certificate: {e}")))?;
+    let certificate = pem
+        .parse_x509()
+        .map_err(|e| invalid(format!("Invalid signing certificate: {e}")))?;
+    verify_issuer(&certificate, trust)?;
+    let key = CosignVerificationKey::try_from_der(certificate.public_key().raw)?;
+    key.verify_signature(Signature::Raw(signature), payload)
+        .map_err(|_| invalid("Image is not signed by the certificate"))?;
+
+    let bundle =
+        bundle.ok_or_else(|| invalid("Keyless signature has no transparency log entry"))?;
+    let bundle: RekorBundle = serde_json::from_str(bundle)
+        .map_err(|e| invalid(format!("Invalid transparency log entry: {e}")))?;
+    let entry = LogEntry {
+        log_id: decode_hex(&bundle.payload.log_id)
+            .ok_or_else(|| invalid("Invalid transparency log entry"))?,
+        log_index: bundle.payload.log_index,
+        integrated_time: bundle.payload.integrated_time,
+        body: &bundle.payload.body,
+        signed_entry_timestamp: decode(&bundle.signed_entry_timestamp)?,
+    };
+    let body = verify_log_entry(&entry, &certificate, trust)?;
+    if !logged_with(&body, &pem.contents) {
+        return Err(invalid(
+            "Transparency log entry is not the one of the signing certificate",
+        ));
+    }
+    match body
+        .pointer("/spec/data/hash/value")
+        .and_then(Value::as_str)
+    {
+        Some(hash) if hash == format!("{:x}", Sha256::digest(payload)) => {
+            Ok(identity(&certificate))
+        }
+        _ => Err(invalid(
+            "Transparency log entry does not match the signature",
+        )),
+    }
+}
+
+/// Verify a signature layer of the image with this digest, given its payload and annotations.
+/// Keyless signatures are only verified when the trusted root is configured.
+pub fn verify(
+    digest: &str,
+    payload: &[u8],
+    annotations: &HashMap<String, String>,
+    keys: &[PublicKey],
+    trust: Option<&TrustedRoot>,
+) -> Result<Signer> {
+    let simple_signing: Value = serde_json::from_slice(payload)
+        .map_err(|e| invalid(format!("Invalid signature payload: {e}")))?;
+    let critical = |pointer| {
+        simple_signing
+            .pointer(&format!("/critical/{pointer}"))
+            .and_then(Value::as_str)
+    };
+    if critical("type") != Some(SIGNATURE_TYPE) {
+        return Err(invalid("Not a cosign image signature"));
+    }
+    if critical("image/docker-manifest-digest") != Some(digest) {
+        return Err(invalid(format!("Signature is not the one of {digest}")));
+    }
+    let signature = annotations
+        .get(SIGNATURE_ANNOTATION)
+        .ok_or_else(|| invalid("Signature layer has no signature"))?;
+    let signature = decode(signature)?;
+
+    if let Some(certificate) = annotations.get(CERTIFICATE_ANNOTATION) {
+        let trust = trust.ok_or_else(|| {
+            invalid("Keyless signatures are not verified without the trusted root")
+        })?;
+        let bundle = annotations.get(BUNDLE_ANNOTATION);
+        return verify_certificate(certificate, &signature, payload, bundle, trust)
+            .map(Signer::Identity);
+    }
+    keys.iter()
+        .find(|key| {
+            key.key
+                .verify_signature(Signature::Raw(&signature), payload)
+                .is_ok()
+        })
+        .map(|key| Signer::Key(key.id.clone()))
+        .ok_or_else(|| invalid("Image is not signed by any of the keys"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde_json::json;
+    use sigstore::crypto::SigningScheme;
+
+    const DIGEST: &str = "sha256:3e2a2d8e5bcd5c8a9b6b1c4f6a1e6d0f7d6c3f4b5a2e1d0c9b8a7f6e5d4c3b2a";
+
+    #[test]
+    fn attached_tags() {
+        assert_eq!(
+            Some(String::from(
+                "sha256-3e2a2d8e5bcd5c8a9b6b1c4f6a1e6d0f7d6c3f4b5a2e1d0c9b8a7f6e5d4c3b2a.sig"
+            )),
+            signature_tag(DIGEST)
+        );
+        assert!(is_attached(&signature_tag(DIGEST).unwrap()));
+        assert!(is_attached("sha256-abc123.att"));
+        assert!(!is_attached("1.0.sig"));
+        assert!(!is_attached("latest"));
+    }
+
+    #[test]
+    fn verify_key_signatures() {
+        let signer = SigningScheme::ECDSA_P256_SHA256_ASN1
+            .create_signer()
+            .unwrap();
+        let der = signer
+            .to_sigstore_keypair()
+            .unwrap()
+            .public_key_to_der()
+            .unwrap();
+        let keys = [PublicKey {
+            id: String::from("test"),
+            key: CosignVerificationKey::try_from_der(&der).unwrap(),
+        }];
+        let payload = serde_json::to_vec(&json!({
+            "critical": {
+                "identity": { "docker-reference": "registry.example.com/app" },
+                "image": { "docker-manifest-digest": DIGEST },
+                "type": SIGNATURE_TYPE
+            },
+            "optional": null
+        }))
+        .unwrap();
+        let annotations = HashMap::from([(
+            String::from(SIGNATURE_ANNOTATION),
+            STANDARD.encode(signer.sign(&payload).unwrap()),
+        )]);
+
+        assert_eq!(
+            Signer::Key(String::from("test")),
+            verify(DIGEST, &payload, &annotations, &keys, None).unwrap()
+        );
+        assert!(verify("sha256:0123", &payload, &annotations, &keys, None).is_err());
+        assert!(verify(DIGEST, &payload, &annotations, &[], None).is_err());
+        let mut tampered = payload.clone();
+        tampered.push(b'\n');
+        assert!(verify(DIGEST, &tampered, &annotations, &keys, None).is_err());
+
+        let mut keyless = annotations.clone();
+        keyless.insert(String::from(CERTIFICATE_ANNOTATION), String::new());
+        assert!(verify(DIGEST, &payload, &keyless, &keys, None).is_err());
+    }
+}
@@ -1,3 +1,7 @@
+pub mod bundle;
+pub mod cosign;
+pub mod trust;
+
 use sigstore::rekor::apis::{
     configuration::Configuration,
     entries_api::get_log_entry_by_uuid,
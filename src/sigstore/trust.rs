@@ -0,0 +1,141 @@
+/*
+ The Sigstore trusted root, listing the certificate authorities issuing signing certificates
+ (Fulcio) and the keys of the transparency logs (Rekor), as distributed in trusted_root.json.
+*/
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+use crate::errors::{Error, Result};
+
+#[derive(Deserialize)]
+struct RawBytes {
+    #[serde(rename = "rawBytes")]
+    raw_bytes: String,
+}
+
+#[derive(Deserialize)]
+struct CertChain {
+    certificates: Vec<RawBytes>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CertificateAuthority {
+    cert_chain: CertChain,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LogId {
+    key_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TransparencyLog {
+    public_key: RawBytes,
+    log_id: LogId,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Document {
+    #[serde(default)]
+    certificate_authorities: Vec<CertificateAuthority>,
+    #[serde(default)]
+    tlogs: Vec<TransparencyLog>,
+}
+
+/// Key of a transparency log, identified by the sha256 of the key.
+pub struct LogKey {
+    pub id: Vec<u8>,
+    /// DER encoded SubjectPublicKeyInfo
+    pub key: Vec<u8>,
+}
+
+pub struct TrustedRoot {
+    /// DER encoded certificate chains, from the issuing certificate up to the root
+    authorities: Vec<Vec<Vec<u8>>>,
+    logs: Vec<LogKey>,
+}
+
+fn decode(value: &str) -> Result<Vec<u8>> {
+    STANDARD
+        .decode(value)
+        .map_err(|e| Error::SigstoreError(format!("Invalid trusted root: {e}")))
+}
+
+impl TrustedRoot {
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    pub fn parse(document: &[u8]) -> Result<Self> {
+        let document: Document = serde_json::from_slice(document)
+            .map_err(|e| Error::SigstoreError(format!("Invalid trusted root: {e}")))?;
+        let authorities = document
+            .certificate_authorities
+            .iter()
+            .map(|authority| {
+                authority
+                    .cert_chain
+                    .certificates
+                    .iter()
+                    .map(|certificate| decode(&certificate.raw_bytes))
+                    .collect()
+            })
+            .collect::<Result<_>>()?;
+        let logs = document
+            .tlogs
+            .iter()
+            .map(|log| {
+                Ok(LogKey {
+                    id: decode(&log.log_id.key_id)?,
+                    key: decode(&log.public_key.raw_bytes)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { authorities, logs })
+    }
+
+    pub fn authorities(&self) -> &[Vec<Vec<u8>>] {
+        &self.authorities
+    }
+
+    pub fn log(&self, id: &[u8]) -> Option<&LogKey> {
+        self.logs.iter().find(|log| log.id == id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_trusted_root() {
+        let document = br#"{
+            "mediaType": "application/vnd.dev.sigstore.trustedroot+json;version=0.1",
+            "tlogs": [{
+                "baseUrl": "https://rekor.sigstore.dev",
+                "hashAlgorithm": "SHA2_256",
+                "publicKey": { "rawBytes": "a2V5", "keyDetails": "PKIX_ECDSA_P256_SHA_256" },
+                "logId": { "keyId": "aWQ=" }
+            }],
+            "certificateAuthorities": [{
+                "uri": "https://fulcio.sigstore.dev",
+                "certChain": { "certificates": [{ "rawBytes": "aW50ZXJtZWRpYXRl" }, { "rawBytes": "cm9vdA==" }] }
+            }],
+            "ctlogs": []
+        }"#;
+        let root = TrustedRoot::parse(document).unwrap();
+        assert_eq!(
+            vec![vec![b"intermediate".to_vec(), b"root".to_vec()]],
+            root.authorities()
+        );
+        assert_eq!(b"key".to_vec(), root.log(b"id").unwrap().key);
+        assert!(root.log(b"other").is_none());
+    }
+}